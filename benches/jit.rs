@@ -0,0 +1,111 @@
+//! Compares the interpreter with the experimental JIT on a few hot loops.
+//! Run with `cargo bench --features jit`.
+
+use std::time::{Duration, Instant};
+
+use nes_rs::cpu::jit::Jit;
+use nes_rs::cpu::prog::asm;
+use nes_rs::cpu::{StopReason, CPU};
+
+/// Where `load_for_snake` puts the program
+const ORIGIN: u16 = 0x0600;
+const RUNS: u32 = 5;
+
+const PROGRAMS: [(&str, &str); 3] = [
+    (
+        "fill and checksum",
+        "LDY #$00
+        outer:
+        LDX #$00
+        inner:
+        TXA
+        CLC
+        ADC $10
+        STA $0300,X
+        EOR $0200,X
+        STA $10
+        INX
+        BNE inner
+        DEY
+        BNE outer",
+    ),
+    (
+        "16-bit counter",
+        "LDA #$00
+        STA $10
+        STA $11
+        loop:
+        INC $10
+        BNE loop
+        INC $11
+        LDA $11
+        CMP #$00
+        BNE loop",
+    ),
+    (
+        "shift and mix",
+        "LDX #$00
+        LDY #$00
+        loop:
+        LDA $0200,Y
+        ASL A
+        ROL $12
+        EOR $12
+        LSR A
+        SEC
+        SBC $13
+        STA $13
+        INY
+        BNE loop
+        INX
+        BNE loop",
+    ),
+];
+
+fn cpu_for(bytes: &[u8]) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.load_for_snake(bytes);
+    cpu.load(0x0200, &(0..=0xFF).map(|i: u8| i.wrapping_mul(13)).collect::<Vec<_>>());
+    cpu.interrupt_reset();
+    cpu
+}
+
+/// The fastest of `RUNS` runs, with the CPU left by the last one
+fn time(bytes: &[u8], mut run: impl FnMut(&mut CPU) -> StopReason) -> (Duration, CPU) {
+    let mut best = Duration::MAX;
+    let mut cpu = cpu_for(bytes);
+    for _ in 0..RUNS {
+        cpu = cpu_for(bytes);
+        let start = Instant::now();
+        assert_eq!(run(&mut cpu), StopReason::Break);
+        best = best.min(start.elapsed());
+    }
+    (best, cpu)
+}
+
+fn main() {
+    println!("{:20} {:>12} {:>12} {:>8}", "program", "interpreter", "jit", "speedup");
+    for (name, source) in PROGRAMS {
+        let assembly = asm::assemble(&format!(".org ${:04X}\n{}\nBRK", ORIGIN, source)).unwrap();
+
+        let (interpreted, expected) = time(&assembly.bytes, CPU::run);
+        let mut stats = Default::default();
+        let (jitted, cpu) = time(&assembly.bytes, |cpu| {
+            let mut jit = Jit::new().unwrap();
+            let reason = jit.run(cpu);
+            stats = jit.stats();
+            reason
+        });
+
+        assert_eq!(cpu.registers(), expected.registers(), "{}", name);
+        assert!((0..0x0800).all(|addr| cpu.peek(addr) == expected.peek(addr)), "{}: RAM differs", name);
+        println!(
+            "{:20} {:>10.2?} {:>10.2?} {:>7.1}x  {:?}",
+            name,
+            interpreted,
+            jitted,
+            interpreted.as_secs_f64() / jitted.as_secs_f64(),
+            stats
+        );
+    }
+}
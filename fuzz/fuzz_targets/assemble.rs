@@ -0,0 +1,17 @@
+//! Arbitrary text into the assembler, in every dialect. It may reject the
+//! source but must never panic. Add anything it finds to `tests/corpus`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nes_rs::cpu::prog::asm::{Assembler, Dialect};
+use nes_rs::cpu::prog::Program;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+    for dialect in [Dialect::Native, Dialect::Ca65, Dialect::Nesasm] {
+        let _ = Assembler::new().with_dialect(dialect).assemble(source);
+    }
+    let _ = source.parse::<Program>();
+});
@@ -0,0 +1,14 @@
+//! Any machine code that disassembles must assemble back to the same bytes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nes_rs::cpu::prog::Program;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(program) = Program::try_from(data) else {
+        return;
+    };
+    let text = program.to_string();
+    let reassembled: Program = text.parse().unwrap_or_else(|e| panic!("`{}` doesn't reassemble: {}", text, e));
+    assert_eq!(reassembled.to_bytes(), data, "{}", text);
+});
@@ -0,0 +1,61 @@
+/* Generated with cbindgen from src/ffi.rs - do not edit by hand */
+
+#ifndef NES_RS_H
+#define NES_RS_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/* Result of nes_step: the instruction was executed */
+#define NES_STEP_OK 0
+
+/* Result of nes_step: the CPU is sitting on a BRK and did not advance */
+#define NES_STEP_BREAK 1
+
+/* Result of nes_step: the CPU hit a JAM opcode and is locked up until reset */
+#define NES_STEP_HALTED 2
+
+/* Result of any call given a null handle or buffer */
+#define NES_ERR_NULL -1
+
+/* Result of nes_load_program: the program would run into the vectors at $FFFA */
+#define NES_ERR_TOO_LONG -2
+
+/* The NES CPU - Ricoh 2A03 (Modified MOS 6502) */
+typedef struct CPU CPU;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/* Allocates a new CPU, to be freed with nes_destroy */
+CPU *nes_create(void);
+
+/* Frees a CPU created by nes_create. Null is ignored. */
+void nes_destroy(CPU *cpu);
+
+/*
+ * Loads len bytes of program code into PRG ROM and points the reset vector at it.
+ * Nothing is loaded if it's longer than CPU::MAX_PROGRAM_LEN.
+ */
+int32_t nes_load_program(CPU *cpu, const uint8_t *data, uintptr_t len);
+
+/* Resets the CPU, jumping to the reset vector */
+int32_t nes_reset(CPU *cpu);
+
+/* Executes a single instruction */
+int32_t nes_step(CPU *cpu);
+
+/* Reads a byte of CPU memory, returning 0 for a null handle */
+uint8_t nes_read(const CPU *cpu, uint16_t addr);
+
+/* Writes a byte of CPU memory */
+int32_t nes_write(CPU *cpu, uint16_t addr, uint8_t value);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* NES_RS_H */
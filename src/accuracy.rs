@@ -0,0 +1,241 @@
+//! Accuracy scorecard: pass/fail for the generated CPU tests and for test ROMs.
+//!
+//! Test ROMs aren't shipped with the crate. Any iNES ROM that reports in
+//! blargg's convention can be pointed at: while `$6001-$6003` hold the
+//! signature DE B0 61, `$6000` is 0x80 while running, 0x81 when it wants a
+//! reset, and otherwise the result code, 0 for a pass, with a message at `$6004`.
+//! ROMs that need the PPU or APU will time out until those are emulated.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::cart::{Cartridge, HeaderlessOptions};
+#[cfg(feature = "assembler")]
+use crate::cpu::testgen;
+use crate::CPU;
+
+/// Where test ROMs, and the generated CPU tests, leave their result
+pub const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE_ADDR: u16 = 0x6004;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+/// Instructions a test ROM gets before it counts as hung
+const ROM_STEP_LIMIT: u64 = 50_000_000;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TestResult {
+    pub suite: String,
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Scorecard {
+    pub results: Vec<TestResult>,
+}
+
+impl Scorecard {
+    pub fn new() -> Self {
+        Scorecard::default()
+    }
+
+    /// Adds a result for every generated CPU test case
+    #[cfg(feature = "assembler")]
+    pub fn run_generated(&mut self) {
+        for case in testgen::generate(0x0600) {
+            let outcome = match case.run() {
+                Ok(()) => Outcome::Pass,
+                Err(message) => Outcome::Fail(message),
+            };
+            self.results.push(TestResult { suite: "cpu (generated)".to_string(), name: case.name, outcome });
+        }
+    }
+
+    /// Runs an iNES test ROM and adds its result
+    pub fn run_rom(&mut self, suite: &str, name: &str, image: &[u8]) {
+        let outcome = match run_rom(image) {
+            Ok(outcome) => outcome,
+            Err(error) => Outcome::Fail(error.to_string()),
+        };
+        self.results.push(TestResult { suite: suite.to_string(), name: name.to_string(), outcome });
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|result| result.outcome == Outcome::Pass).count()
+    }
+
+    /// Per suite totals, then a row for each failure
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::from("| suite | passed | total |\n|---|---|---|\n");
+        for suite in self.suites() {
+            let results: Vec<_> = self.results.iter().filter(|result| result.suite == suite).collect();
+            let passed = results.iter().filter(|result| result.outcome == Outcome::Pass).count();
+            let _ = writeln!(out, "| {} | {} | {} |", suite, passed, results.len());
+        }
+        let _ = writeln!(out, "| **all** | **{}** | **{}** |", self.passed(), self.results.len());
+
+        let failures: Vec<_> = self.results.iter().filter(|result| result.outcome != Outcome::Pass).collect();
+        if !failures.is_empty() {
+            out += "\n| suite | test | failure |\n|---|---|---|\n";
+            for result in failures {
+                if let Outcome::Fail(message) = &result.outcome {
+                    let _ = writeln!(out, "| {} | {} | {} |", result.suite, result.name, message.replace('|', "\\|").replace('\n', " "));
+                }
+            }
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let results: Vec<String> = self
+            .results
+            .iter()
+            .map(|result| {
+                let (passed, message) = match &result.outcome {
+                    Outcome::Pass => (true, String::new()),
+                    Outcome::Fail(message) => (false, message.clone()),
+                };
+                format!(
+                    "{{\"suite\":{},\"name\":{},\"passed\":{},\"message\":{}}}",
+                    json_string(&result.suite),
+                    json_string(&result.name),
+                    passed,
+                    json_string(&message)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"passed\":{},\"total\":{},\"results\":[{}]}}",
+            self.passed(),
+            self.results.len(),
+            results.join(",")
+        )
+    }
+
+    /// Suite names in the order they were first run
+    fn suites(&self) -> Vec<&str> {
+        let mut suites: Vec<&str> = Vec::new();
+        for result in &self.results {
+            if !suites.contains(&result.suite.as_str()) {
+                suites.push(&result.suite);
+            }
+        }
+        suites
+    }
+}
+
+fn run_rom(image: &[u8]) -> Result<Outcome, Box<dyn Error>> {
+    let cart = Cartridge::load(image, &HeaderlessOptions::default())?;
+    let mut cpu = CPU::new();
+    cart.install(&mut cpu, false)?;
+    cpu.interrupt_reset();
+
+    for _ in 0..ROM_STEP_LIMIT {
+        if let Some(reason) = cpu.step_next() {
+            return Ok(Outcome::Fail(format!("stopped, {:?}", reason)));
+        }
+        let signed = (0..3).all(|i| cpu.read(SIGNATURE_ADDR + i) == SIGNATURE[i as usize]);
+        match cpu.read(STATUS_ADDR) {
+            _ if !signed => {}
+            STATUS_RUNNING => {}
+            STATUS_RESET => {
+                cpu.load(STATUS_ADDR, &[STATUS_RUNNING]);
+                cpu.interrupt_reset();
+            }
+            0 => return Ok(Outcome::Pass),
+            code => return Ok(Outcome::Fail(format!("result {}: {}", code, message(&cpu)))),
+        }
+    }
+    Ok(Outcome::Fail(format!("no result after {} instructions", ROM_STEP_LIMIT)))
+}
+
+/// The zero terminated text a test ROM leaves at `MESSAGE_ADDR`
+fn message(cpu: &CPU) -> String {
+    let text: String = (MESSAGE_ADDR..0x7000)
+        .map(|addr| cpu.read(addr))
+        .take_while(|&byte| byte != 0)
+        .map(|byte| byte as char)
+        .collect();
+    text.trim().to_string()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An NROM image which writes the blargg signature and a result
+    fn test_rom(result: u8, message: &[u8]) -> Vec<u8> {
+        // LDA #$80, STA $6000 before the signature, like the real ones
+        let mut program = vec![0xA9, STATUS_RUNNING, 0x8D, 0x00, 0x60];
+        for (i, &byte) in SIGNATURE.iter().chain(message).chain(&[0]).enumerate() {
+            // LDA #byte, STA $6001+i
+            let [lo, hi] = (SIGNATURE_ADDR + i as u16).to_le_bytes();
+            program.extend_from_slice(&[0xA9, byte, 0x8D, lo, hi]);
+        }
+        // LDA #result, STA $6000, JMP to itself
+        let end = 0x8000 + program.len() as u16 + 5;
+        program.extend_from_slice(&[0xA9, result, 0x8D, 0x00, 0x60, 0x4C, end as u8, (end >> 8) as u8]);
+
+        let mut prg = vec![0; 0x4000];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        let mut image = b"NES\x1A\x01\x00".to_vec();
+        image.extend_from_slice(&[0; 10]);
+        image.extend(prg);
+        image
+    }
+
+    #[test]
+    fn test_run_roms() {
+        let mut scorecard = Scorecard::new();
+        scorecard.run_rom("blargg", "pass.nes", &test_rom(0, b"Passed"));
+        scorecard.run_rom("blargg", "fail.nes", &test_rom(3, b"BIT \"wrong\"\n"));
+        scorecard.run_rom("other", "garbage.nes", b"NES\x1A");
+
+        assert_eq!(scorecard.passed(), 1);
+        assert_eq!(scorecard.results[1].outcome, Outcome::Fail("result 3: BIT \"wrong\"".to_string()));
+        assert!(matches!(scorecard.results[2].outcome, Outcome::Fail(_)));
+
+        let markdown = scorecard.to_markdown();
+        assert!(markdown.contains("| blargg | 1 | 2 |\n| other | 0 | 1 |\n| **all** | **1** | **3** |"), "{}", markdown);
+        assert!(markdown.contains("| blargg | fail.nes | result 3: BIT \"wrong\" |"), "{}", markdown);
+
+        let json = scorecard.to_json();
+        assert!(json.starts_with("{\"passed\":1,\"total\":3,\"results\":[{\"suite\":\"blargg\",\"name\":\"pass.nes\",\"passed\":true,\"message\":\"\"}"), "{}", json);
+        assert!(json.contains("\"message\":\"result 3: BIT \\\"wrong\\\"\""), "{}", json);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_generated_cases_pass() {
+        let mut scorecard = Scorecard::new();
+        scorecard.run_generated();
+        assert!(!scorecard.results.is_empty());
+        assert_eq!(scorecard.passed(), scorecard.results.len(), "{}", scorecard.to_markdown());
+    }
+}
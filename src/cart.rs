@@ -0,0 +1,675 @@
+//! Cartridge images: iNES files, and headerless PRG/CHR dumps described by the user.
+
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cpu::prog::fmt_listing_line;
+use crate::cpu::prog::instructions::Instruction;
+use crate::memory;
+use crate::patch;
+use crate::png;
+use crate::CPU;
+
+const INES_MAGIC: &[u8] = b"NES\x1A";
+const INES_HEADER_LEN: usize = 16;
+const TRAINER_LEN: usize = 512;
+pub const PRG_BANK_SIZE: usize = 0x4000;
+pub const CHR_BANK_SIZE: usize = 0x2000;
+const TILE_SIZE: usize = 16;
+/// Exported pattern tables put a bank's two 16x16 tile tables side by side
+pub const CHR_IMAGE_WIDTH: usize = 256;
+const CHR_BANK_HEIGHT: usize = 128;
+const PRG_ROM_ADDR: u16 = 0x8000;
+
+/// RGB for each of the 64 colours the PPU can output, as the 2C02 shows them
+pub const SYSTEM_PALETTE: [[u8; 3]; 64] = [
+    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
+];
+
+/// The four system palette colours a tile's colour indices 0-3 are shown
+/// in, like one of the PPU's four background and four sprite palettes
+pub type ChrPalette = [u8; 4];
+
+/// Shows colour indices 0-3 from black to white, for CHR with no palette to hand
+pub const GREY_CHR_PALETTE: ChrPalette = [0x0F, 0x00, 0x10, 0x30];
+
+/// Mappers the emulator can run
+const SUPPORTED_MAPPERS: [u8; 1] = [0];
+
+/// Common boards by iNES mapper number
+const MAPPER_NAMES: [(u8, &str); 22] = [
+    (0, "NROM"),
+    (1, "MMC1"),
+    (2, "UxROM"),
+    (3, "CNROM"),
+    (4, "MMC3"),
+    (5, "MMC5"),
+    (7, "AxROM"),
+    (9, "MMC2"),
+    (10, "MMC4"),
+    (11, "Color Dreams"),
+    (13, "CPROM"),
+    (19, "Namco 163"),
+    (21, "VRC4a/VRC4c"),
+    (22, "VRC2a"),
+    (23, "VRC2b/VRC4e"),
+    (24, "VRC6a"),
+    (25, "VRC4b/VRC4d"),
+    (26, "VRC6b"),
+    (34, "BNROM/NINA-001"),
+    (66, "GxROM"),
+    (69, "FME-7"),
+    (71, "Camerica"),
+];
+
+/// Board name for an iNES mapper number, if it's a well known one
+pub fn mapper_name(number: u8) -> Option<&'static str> {
+    MAPPER_NAMES.iter().find(|(n, _)| *n == number).map(|(_, name)| *name)
+}
+
+/// A cartridge needs a mapper that isn't emulated
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UnsupportedMapper {
+    pub number: u8,
+    pub name: Option<&'static str>,
+}
+
+impl fmt::Display for UnsupportedMapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self.name {
+            Some(name) => write!(f, "mapper {} ({}) is not supported", self.number, name),
+            None => write!(f, "mapper {} is not supported", self.number),
+        }
+    }
+}
+
+impl Error for UnsupportedMapper {}
+
+/// Nametable arrangement wired up by the cartridge
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Mirroring {
+    #[default]
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+impl FromStr for Mirroring {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h" | "horizontal" => Ok(Mirroring::Horizontal),
+            "v" | "vertical" => Ok(Mirroring::Vertical),
+            "4" | "four" | "fourscreen" => Ok(Mirroring::FourScreen),
+            _ => Err(format!("unknown mirroring `{}`", s)),
+        }
+    }
+}
+
+/// Fields of an iNES or NES 2.0 header
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InesHeader {
+    pub nes2: bool,
+    pub prg_size: usize,
+    pub chr_size: usize,
+    pub mapper: u16,
+    /// Always 0 in iNES 1.0 headers
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    /// 512 bytes between the header and PRG ROM
+    pub trainer: bool,
+}
+
+impl InesHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.len() < INES_HEADER_LEN || !data.starts_with(INES_MAGIC) {
+            return Err("not an iNES image".into());
+        }
+        let (flags6, flags7) = (data[6], data[7]);
+        let nes2 = flags7 & 0b1100 == 0b1000;
+
+        let mirroring = match (flags6 & 0b1000 != 0, flags6 & 0b1 != 0) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+        let mut mapper = (flags7 & 0xF0 | flags6 >> 4) as u16;
+        let (mut prg_banks, mut chr_banks) = (data[4] as usize, data[5] as usize);
+        let mut submapper = 0;
+        if nes2 {
+            mapper |= ((data[8] & 0x0F) as u16) << 8;
+            submapper = data[8] >> 4;
+            if data[9] & 0x0F == 0x0F || data[9] >> 4 == 0x0F {
+                return Err("NES 2.0 exponent-multiplier ROM sizes aren't supported".into());
+            }
+            prg_banks |= ((data[9] & 0x0F) as usize) << 8;
+            chr_banks |= ((data[9] >> 4) as usize) << 8;
+        }
+
+        Ok(InesHeader {
+            nes2,
+            prg_size: prg_banks * PRG_BANK_SIZE,
+            chr_size: chr_banks * CHR_BANK_SIZE,
+            mapper,
+            submapper,
+            mirroring,
+            battery: flags6 & 0b10 != 0,
+            trainer: flags6 & 0b100 != 0,
+        })
+    }
+
+    /// Where PRG ROM and CHR ROM are in an image of `len` bytes with this header
+    pub fn rom_ranges(&self, len: usize) -> Result<(Range<usize>, Range<usize>), Box<dyn Error>> {
+        let prg_start = INES_HEADER_LEN + if self.trainer { TRAINER_LEN } else { 0 };
+        let chr_start = prg_start + self.prg_size;
+        let end = chr_start + self.chr_size;
+        if len < end {
+            return Err(format!("iNES image is {} bytes but its header describes {}", len, end).into());
+        }
+        Ok((prg_start..chr_start, chr_start..end))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    /// Empty for boards using CHR RAM
+    pub chr_rom: Vec<u8>,
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    /// Battery backed PRG RAM
+    pub battery: bool,
+}
+
+/// How to lay out a dump which has no header to say so itself
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct HeaderlessOptions {
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    /// PRG ROM size in bytes, or None for the whole file. Anything after the PRG ROM is CHR ROM.
+    pub prg_size: Option<usize>,
+    /// CHR ROM size in bytes, or None for the rest of the file. Bytes past it are ignored.
+    pub chr_size: Option<usize>,
+}
+
+impl HeaderlessOptions {
+    /// Takes `--mapper N`, `--prg SIZE`, `--chr SIZE` and `--mirroring h|v|4`
+    /// from command line arguments, returning the options and the other arguments
+    pub fn from_args(args: &[String]) -> Result<(Self, Vec<String>), String> {
+        let mut options = HeaderlessOptions::default();
+        let mut rest = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = arg.as_str();
+            if !matches!(flag, "--mapper" | "--prg" | "--chr" | "--mirroring") {
+                rest.push(arg.clone());
+                continue;
+            }
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag {
+                "--mapper" => options.mapper = value.parse().map_err(|_| format!("invalid mapper `{}`", value))?,
+                "--prg" => options.prg_size = Some(parse_size(value)?),
+                "--chr" => options.chr_size = Some(parse_size(value)?),
+                _ => options.mirroring = value.parse()?,
+            }
+        }
+        Ok((options, rest))
+    }
+}
+
+impl Cartridge {
+    /// Parses an iNES image
+    pub fn from_ines(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let header = InesHeader::parse(data)?;
+        let mapper = u8::try_from(header.mapper).map_err(|_| format!("mapper {} is beyond the 8 bit range supported", header.mapper))?;
+        let (prg, chr) = header.rom_ranges(data.len())?;
+
+        Ok(Cartridge {
+            prg_rom: data[prg].to_vec(),
+            chr_rom: data[chr].to_vec(),
+            mapper,
+            mirroring: header.mirroring,
+            battery: header.battery,
+        })
+    }
+
+    /// Writes an iNES 1.0 image, the inverse of `from_ines`
+    pub fn to_ines(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if self.prg_rom.is_empty() || !self.prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+            return Err(format!("PRG ROM size must be a multiple of 16K, got {:#x}", self.prg_rom.len()).into());
+        }
+        if !self.chr_rom.len().is_multiple_of(CHR_BANK_SIZE) {
+            return Err(format!("CHR ROM size must be a multiple of 8K, got {:#x}", self.chr_rom.len()).into());
+        }
+        let (prg_banks, chr_banks) = (self.prg_rom.len() / PRG_BANK_SIZE, self.chr_rom.len() / CHR_BANK_SIZE);
+        if prg_banks > 0xFF || chr_banks > 0xFF {
+            return Err("ROM is too large for an iNES 1.0 header".into());
+        }
+
+        let mirroring = match self.mirroring {
+            Mirroring::Horizontal => 0b0000,
+            Mirroring::Vertical => 0b0001,
+            Mirroring::FourScreen => 0b1000,
+        };
+        let flags6 = (self.mapper << 4) | mirroring | if self.battery { 0b10 } else { 0 };
+
+        let mut image = INES_MAGIC.to_vec();
+        image.extend_from_slice(&[prg_banks as u8, chr_banks as u8, flags6, self.mapper & 0xF0]);
+        image.resize(INES_HEADER_LEN, 0);
+        image.extend_from_slice(&self.prg_rom);
+        image.extend_from_slice(&self.chr_rom);
+        Ok(image)
+    }
+
+    /// Builds a cartridge from a raw dump, such as a homebrew build output
+    /// that was never wrapped in an iNES header
+    pub fn headerless(data: &[u8], options: &HeaderlessOptions) -> Result<Self, Box<dyn Error>> {
+        let prg_len = options.prg_size.unwrap_or(data.len());
+        if prg_len == 0 || !prg_len.is_multiple_of(PRG_BANK_SIZE) {
+            return Err(format!("PRG ROM size must be a multiple of 16K, got {:#x}", prg_len).into());
+        }
+        if prg_len > data.len() {
+            return Err(format!("dump is {} bytes, smaller than the {} byte PRG ROM", data.len(), prg_len).into());
+        }
+
+        let chr_rom = &data[prg_len..];
+        let chr_rom = match options.chr_size {
+            Some(chr_len) => chr_rom.get(..chr_len).ok_or_else(|| {
+                format!("{} bytes left after PRG ROM, smaller than the {} byte CHR ROM", chr_rom.len(), chr_len)
+            })?,
+            None => chr_rom,
+        };
+        if !chr_rom.len().is_multiple_of(CHR_BANK_SIZE) {
+            return Err(format!("{} bytes left after PRG ROM, not a whole number of 8K CHR banks", chr_rom.len()).into());
+        }
+
+        Ok(Cartridge {
+            prg_rom: data[..prg_len].to_vec(),
+            chr_rom: chr_rom.to_vec(),
+            mapper: options.mapper,
+            mirroring: options.mirroring,
+            battery: false,
+        })
+    }
+
+    /// Loads an iNES image, falling back to the headerless options if there's no header
+    pub fn load(data: &[u8], headerless: &HeaderlessOptions) -> Result<Self, Box<dyn Error>> {
+        if data.starts_with(INES_MAGIC) {
+            Cartridge::from_ines(data)
+        } else {
+            Cartridge::headerless(data, headerless)
+        }
+    }
+
+    /// Fails unless the cartridge's mapper is emulated
+    pub fn check_mapper(&self) -> Result<(), UnsupportedMapper> {
+        match SUPPORTED_MAPPERS.contains(&self.mapper) {
+            true => Ok(()),
+            false => Err(UnsupportedMapper { number: self.mapper, name: mapper_name(self.mapper) }),
+        }
+    }
+
+    /// Maps the PRG ROM into the CPU as NROM, mirroring a single 16K bank.
+    /// Other mappers are an `UnsupportedMapper` error unless `nrom_fallback`
+    /// is set, which only gets far in games that never switch banks.
+    pub fn install(&self, cpu: &mut CPU, nrom_fallback: bool) -> Result<(), Box<dyn Error>> {
+        if let Err(unsupported) = self.check_mapper() {
+            if !nrom_fallback {
+                return Err(unsupported.into());
+            }
+        }
+
+        let prg_rom = &self.prg_rom[..self.prg_rom.len().min(2 * PRG_BANK_SIZE)];
+        cpu.load(PRG_ROM_ADDR, prg_rom);
+        if prg_rom.len() == PRG_BANK_SIZE {
+            cpu.load(PRG_ROM_ADDR + PRG_BANK_SIZE as u16, prg_rom);
+        }
+        cpu.protect(PRG_ROM_ADDR..=0xFFFF);
+        Ok(())
+    }
+
+    /// Writes the CHR ROM as a raw .chr file, as used by tile editors
+    pub fn export_chr<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        if self.chr_rom.is_empty() {
+            return Err("cartridge has no CHR ROM to export, it uses CHR RAM".into());
+        }
+        fs::write(path, &self.chr_rom)?;
+        Ok(())
+    }
+
+    /// Renders the pattern tables to a greyscale PNG, one 256x128 strip per 8K bank
+    pub fn export_chr_png<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        if self.chr_rom.is_empty() {
+            return Err("cartridge has no CHR ROM to export, it uses CHR RAM".into());
+        }
+        let pixels: Vec<u8> = self.chr_pixels().iter().map(|&colour| colour * 0x55).collect();
+        let height = pixels.len() / CHR_IMAGE_WIDTH;
+        fs::write(path, png::encode_greyscale(CHR_IMAGE_WIDTH as u32, height as u32, &pixels))?;
+        Ok(())
+    }
+
+    /// 8K banks of CHR ROM, to page through with `chr_bank_rgb`
+    pub fn chr_banks(&self) -> usize {
+        self.chr_rom.len() / CHR_BANK_SIZE
+    }
+
+    /// One bank's pattern tables as `CHR_IMAGE_WIDTH` x 128 RGB24 pixels,
+    /// coloured with `palette`
+    pub fn chr_bank_rgb(&self, bank: usize, palette: &ChrPalette) -> Result<Vec<u8>, Box<dyn Error>> {
+        if bank >= self.chr_banks() {
+            return Err(format!("no CHR bank {}, the cartridge has {}", bank, self.chr_banks()).into());
+        }
+        let bank_len = CHR_IMAGE_WIDTH * CHR_BANK_HEIGHT;
+        let pixels = self.chr_pixels();
+        Ok(pixels[bank * bank_len..(bank + 1) * bank_len]
+            .iter()
+            .flat_map(|&colour| SYSTEM_PALETTE[(palette[colour as usize] & 0x3F) as usize])
+            .collect())
+    }
+
+    /// Writes `chr_bank_rgb` as a PNG
+    pub fn export_chr_bank_png<P: AsRef<Path>>(&self, path: P, bank: usize, palette: &ChrPalette) -> Result<(), Box<dyn Error>> {
+        let rgb = self.chr_bank_rgb(bank, palette)?;
+        fs::write(path, png::encode_rgb(CHR_IMAGE_WIDTH as u32, CHR_BANK_HEIGHT as u32, &rgb))?;
+        Ok(())
+    }
+
+    /// Colour indices 0-3 of every CHR pixel, `CHR_IMAGE_WIDTH` to a row
+    pub fn chr_pixels(&self) -> Vec<u8> {
+        let banks = self.chr_rom.len() / CHR_BANK_SIZE;
+        let mut pixels = vec![0; banks * CHR_IMAGE_WIDTH * CHR_BANK_HEIGHT];
+
+        for (index, tile) in self.chr_rom.chunks_exact(TILE_SIZE).enumerate() {
+            // tiles go across each 4K table, left table then right
+            let (bank, table, row, column) = (index / 512, index / 256 % 2, index / 16 % 16, index % 16);
+            let origin_x = table * 128 + column * 8;
+            let origin_y = bank * CHR_BANK_HEIGHT + row * 8;
+
+            for y in 0..8 {
+                let (plane0, plane1) = (tile[y], tile[y + 8]);
+                for x in 0..8 {
+                    let bit = 7 - x;
+                    let colour = (plane0 >> bit & 1) | (plane1 >> bit & 1) << 1;
+                    pixels[(origin_y + y) * CHR_IMAGE_WIDTH + origin_x + x] = colour;
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// Identifies a ROM for files kept alongside it
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    memory::fnv1a(rom)
+}
+
+/// Instructions listed from the reset vector by `describe_rom`
+const RESET_LISTING_LEN: usize = 8;
+
+/// Human readable summary of an iNES image, for `nes-rs info`
+pub fn describe_rom(image: &[u8]) -> Result<String, Box<dyn Error>> {
+    // not a Cartridge, which can't have the NES 2.0 mappers past 255
+    let header = InesHeader::parse(image)?;
+    let (prg, chr) = header.rom_ranges(image.len())?;
+    let (prg_rom, chr_rom) = (&image[prg], &image[chr]);
+    let mapper_name = u8::try_from(header.mapper).ok().and_then(mapper_name).unwrap_or("unknown");
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "format:     {}", if header.nes2 { "NES 2.0" } else { "iNES" });
+    let _ = writeln!(out, "PRG ROM:    {}K", header.prg_size / 1024);
+    let _ = match header.chr_size {
+        0 => writeln!(out, "CHR ROM:    none (CHR RAM)"),
+        size => writeln!(out, "CHR ROM:    {}K", size / 1024),
+    };
+    let _ = write!(out, "mapper:     {} ({})", header.mapper, mapper_name);
+    if header.nes2 {
+        let _ = write!(out, ", submapper {}", header.submapper);
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(out, "mirroring:  {:?}", header.mirroring);
+    let _ = writeln!(out, "battery:    {}", yes_no(header.battery));
+    let _ = writeln!(out, "trainer:    {}", yes_no(header.trainer));
+    let _ = writeln!(out, "CRC32:      {:08X} (file), {:08X} (PRG+CHR)", patch::crc32(image), patch::crc32(&[prg_rom, chr_rom].concat()));
+    let _ = writeln!(out, "FNV-1a:     {:016x}", rom_hash(image));
+
+    // assume the last bank is fixed at the top of the address space, as it is on most boards
+    let top = match prg_rom.len() {
+        0 => return Ok(out),
+        len if len < 0x8000 => [prg_rom, prg_rom].concat(),
+        len => prg_rom[len - 0x8000..].to_vec(),
+    };
+    let vector = |addr: u16| {
+        let offset = (addr - PRG_ROM_ADDR) as usize;
+        u16::from_le_bytes([top[offset], top[offset + 1]])
+    };
+    let reset = vector(0xFFFC);
+    let _ = writeln!(out, "vectors:    NMI ${:04X}  RESET ${:04X}  IRQ ${:04X}", vector(0xFFFA), reset, vector(0xFFFE));
+
+    out += "\nreset:\n";
+    let mut addr = reset;
+    for _ in 0..RESET_LISTING_LEN {
+        let offset = match addr.checked_sub(PRG_ROM_ADDR) {
+            Some(offset) => offset as usize,
+            None => break,
+        };
+        match Instruction::decode(&top[offset..]) {
+            Some(instruction) => {
+                let _ = writeln!(out, "{}", fmt_listing_line(addr, &instruction));
+                addr = match addr.checked_add(instruction.size()) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+            None => {
+                let _ = writeln!(out, "{:04X}  {:02X}        ???", addr, top[offset]);
+                break;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Parses a size given on the command line: plain bytes, or with a `k` suffix
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, scale) = match s.strip_suffix(['k', 'K']) {
+        Some(digits) => (digits, 1024),
+        None => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .map(|n| n * scale)
+        .map_err(|_| format!("invalid size `{}`", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_ines() {
+        let mut data = INES_MAGIC.to_vec();
+        // 2 PRG banks, 1 CHR bank, mapper 0x21, vertical, battery
+        data.extend_from_slice(&[2, 1, 0x13, 0x20, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend(vec![0xAA; 2 * PRG_BANK_SIZE]);
+        data.extend(vec![0xBB; CHR_BANK_SIZE]);
+
+        let cart = Cartridge::from_ines(&data).unwrap();
+        assert_eq!(cart.prg_rom.len(), 0x8000);
+        assert_eq!(cart.chr_rom, vec![0xBB; CHR_BANK_SIZE]);
+        assert_eq!(cart.mapper, 0x21);
+        assert_eq!(cart.mirroring, Mirroring::Vertical);
+        assert!(cart.battery);
+
+        assert_eq!(cart.to_ines().unwrap(), data);
+
+        assert!(Cartridge::from_ines(&data[..data.len() - 1]).is_err());
+        assert!(Cartridge::from_ines(&[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_describe_rom() {
+        let mut data = INES_MAGIC.to_vec();
+        // NES 2.0, mapper 0x104 submapper 2, 1 PRG bank, CHR RAM
+        data.extend_from_slice(&[1, 0, 0x40, 0x08, 0x21, 0, 0, 0, 0, 0, 0, 0]);
+        let header = InesHeader::parse(&data).unwrap();
+        assert!(header.nes2);
+        assert_eq!((header.mapper, header.submapper), (0x104, 2));
+        assert!(Cartridge::from_ines(&data).is_err());
+
+        data[7] = 0;
+        data[8] = 0;
+        let mut prg_rom = vec![0xEA; PRG_BANK_SIZE];
+        // LDA #$01, then an illegal opcode
+        prg_rom[..3].copy_from_slice(&[0xA9, 0x01, 0x02]);
+        prg_rom[0x3FFA..].copy_from_slice(&[0x34, 0x12, 0x00, 0x80, 0xCD, 0xAB]);
+        data.extend(prg_rom);
+
+        let info = describe_rom(&data).unwrap();
+        assert!(info.contains("format:     iNES\n"), "{}", info);
+        assert!(info.contains("CHR ROM:    none"), "{}", info);
+        assert!(info.contains("mapper:     4 (MMC3)\n"), "{}", info);
+        assert!(info.contains("vectors:    NMI $1234  RESET $8000  IRQ $ABCD"), "{}", info);
+        assert!(info.ends_with("reset:\n8000  A9 01     LDA #$01\n8002  02        ???\n"), "{}", info);
+        assert!(describe_rom(b"NES").is_err());
+    }
+
+    #[test]
+    fn test_describe_nes2_rom() {
+        let mut data = INES_MAGIC.to_vec();
+        // NES 2.0, mapper 0x104 submapper 2, 1 PRG bank, 1 CHR bank
+        data.extend_from_slice(&[1, 1, 0x40, 0x08, 0x21, 0, 0, 0, 0, 0, 0, 0]);
+        let mut prg_rom = vec![0xEA; PRG_BANK_SIZE];
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        data.extend(prg_rom);
+        data.extend(vec![0; CHR_BANK_SIZE]);
+        assert!(Cartridge::from_ines(&data).is_err());
+
+        let info = describe_rom(&data).unwrap();
+        assert!(info.contains("format:     NES 2.0\n"), "{}", info);
+        assert!(info.contains("CHR ROM:    8K\n"), "{}", info);
+        assert!(info.contains("mapper:     260 (unknown), submapper 2\n"), "{}", info);
+        assert!(info.contains("RESET $C000"), "{}", info);
+        assert!(info.contains("reset:\nC000  EA        NOP\n"), "{}", info);
+
+        // cut short of the CHR ROM the header describes
+        assert!(describe_rom(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_headerless() {
+        let mut data = vec![0xAA; 0x8000];
+        data.extend(vec![0xBB; CHR_BANK_SIZE]);
+
+        let options = HeaderlessOptions { mirroring: Mirroring::Vertical, prg_size: Some(parse_size("32k").unwrap()), ..Default::default() };
+        let cart = Cartridge::load(&data, &options).unwrap();
+        assert_eq!(cart.prg_rom.len(), 0x8000);
+        assert_eq!(cart.chr_rom.len(), CHR_BANK_SIZE);
+        assert_eq!(cart.mirroring, Mirroring::Vertical);
+
+        // the whole file as PRG
+        let cart = Cartridge::headerless(&data[..0x4000], &HeaderlessOptions::default()).unwrap();
+        assert_eq!(cart.prg_rom.len(), 0x4000);
+        assert!(cart.chr_rom.is_empty());
+
+        assert!(Cartridge::headerless(&data[..100], &HeaderlessOptions::default()).is_err());
+        assert!(Cartridge::headerless(&data[..0x4100], &HeaderlessOptions { prg_size: Some(0x4000), ..Default::default() }).is_err());
+
+        // padding after the CHR ROM
+        let mut padded = data.clone();
+        padded.extend([0xFF; 0x100]);
+        let args: Vec<String> = ["disasm", "--prg", "32k", "--chr", "8k", "--mirroring", "v", "dump.bin"].map(String::from).into();
+        let (options, rest) = HeaderlessOptions::from_args(&args).unwrap();
+        assert_eq!(rest, ["disasm", "dump.bin"]);
+        assert_eq!(Cartridge::load(&padded, &options).unwrap(), Cartridge::load(&data, &options).unwrap());
+        assert!(Cartridge::load(&padded, &HeaderlessOptions { chr_size: None, ..options.clone() }).is_err());
+        assert!(Cartridge::load(&data, &HeaderlessOptions { chr_size: Some(0x4000), ..options }).is_err());
+
+        let args = |args: &[&str]| HeaderlessOptions::from_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        assert_eq!(args(&["--mapper", "4"]).unwrap().0.mapper, 4);
+        assert!(args(&["--mapper", "256"]).is_err());
+        assert!(args(&["--mirroring", "diagonal"]).is_err());
+        assert!(args(&["--prg"]).is_err());
+    }
+
+    #[test]
+    fn test_chr_pixels() {
+        let mut chr_rom = vec![0; 2 * CHR_BANK_SIZE];
+        // top row of the first tile: colours 1, 2, 3, 0, ...
+        chr_rom[0] = 0b1010_0000;
+        chr_rom[8] = 0b0110_0000;
+        // first tile of the right table in the second bank, bottom right pixel
+        chr_rom[CHR_BANK_SIZE + 0x1000 + 7] = 0b0000_0001;
+
+        let cart = Cartridge { chr_rom, ..Cartridge::headerless(&[0; PRG_BANK_SIZE], &HeaderlessOptions::default()).unwrap() };
+        let pixels = cart.chr_pixels();
+        assert_eq!(pixels.len(), CHR_IMAGE_WIDTH * 256);
+        assert_eq!(&pixels[..5], &[1, 2, 3, 0, 0]);
+        assert_eq!(pixels[(128 + 7) * CHR_IMAGE_WIDTH + 128 + 7], 1);
+        assert_eq!(pixels.iter().filter(|&&colour| colour != 0).count(), 4);
+
+        assert_eq!(cart.chr_banks(), 2);
+        let rgb = cart.chr_bank_rgb(0, &[0x0F, 0x16, 0x27, 0x18]).unwrap();
+        assert_eq!(rgb.len(), CHR_IMAGE_WIDTH * CHR_BANK_HEIGHT * 3);
+        assert_eq!(&rgb[..12], &[0xFF, 0x22, 0x00, 0xFF, 0x9C, 0x12, 0xC4, 0x62, 0x00, 0x05, 0x05, 0x05]);
+        // the palette's high bits are ignored, like the PPU's
+        let rgb = cart.chr_bank_rgb(1, &[0x4F, 0x40, 0x10, 0x30]).unwrap();
+        assert_eq!(&rgb[((7 * CHR_IMAGE_WIDTH) + 128 + 7) * 3..][..3], &SYSTEM_PALETTE[0x00]);
+        assert!(cart.chr_bank_rgb(2, &GREY_CHR_PALETTE).is_err());
+    }
+
+    #[test]
+    fn test_install() {
+        let mut prg_rom = vec![0xEA; PRG_BANK_SIZE];
+        prg_rom[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        let mut cart = Cartridge::headerless(&prg_rom, &HeaderlessOptions::default()).unwrap();
+
+        let mut cpu = CPU::new();
+        cart.install(&mut cpu, false).unwrap();
+        cpu.interrupt_reset();
+        assert_eq!(cpu.registers().pc, 0x8000);
+        // 16K of PRG ROM is mirrored into both halves
+        assert_eq!(cpu.read(0xBFFC), 0x00);
+        assert_eq!(cpu.read(0xFFFD), 0x80);
+
+        cart.mapper = 4;
+        let error = cart.install(&mut CPU::new(), false).unwrap_err();
+        assert_eq!(error.to_string(), "mapper 4 (MMC3) is not supported");
+        assert_eq!(error.downcast_ref(), Some(&UnsupportedMapper { number: 4, name: Some("MMC3") }));
+        assert!(cart.install(&mut CPU::new(), true).is_ok());
+
+        cart.mapper = 200;
+        assert_eq!(cart.check_mapper().unwrap_err().to_string(), "mapper 200 is not supported");
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse_size("16K"), Ok(0x4000));
+        assert_eq!(parse_size("8192"), Ok(0x2000));
+        assert!(parse_size("lots").is_err());
+        assert_eq!("v".parse(), Ok(Mirroring::Vertical));
+        assert!("diagonal".parse::<Mirroring>().is_err());
+    }
+}
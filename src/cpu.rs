@@ -1,14 +1,34 @@
 mod addr;
 mod ops;
-mod reg;
+pub mod builder;
+#[cfg(feature = "debugger")]
+pub mod crash;
+#[cfg(feature = "assembler")]
+pub mod cycles;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod determinism;
+pub mod lockstep;
+pub mod reg;
 pub mod prog;
+pub mod reference;
+#[cfg(feature = "assembler")]
+pub mod single_step;
+#[cfg(feature = "assembler")]
+pub mod testgen;
+pub mod watchdog;
 
-use crate::cpu::addr::AddressMode;
-use crate::cpu::ops::Opcode;
-use crate::cpu::reg::RegisterSet;
-use crate::memory::{SimpleMap, MemoryMap};
+// named by `Instruction` and `Opcode`'s public API, so downstream code needs them too
+pub use addr::{Addr, AddressMode, ZpAddr};
+pub use ops::{Mnemonic, Opcode, Penalty, Timing};
+
+use crate::cpu::addr::ResolvedOperand;
+use crate::cpu::reg::{RegisterSet, Status};
+use crate::memory::{MemoryInit, SimpleMap, MemoryMap, Peripheral, Register, RomWrite};
+use crate::savestate::{SaveState, CPU_CHUNK, RAM_CHUNK};
 
-use self::ops::Mnemonic;
 
 /// The NES CPU - Ricoh 2A03 (Modified MOS 6502)
 #[derive(Default)]
@@ -16,7 +36,32 @@ pub struct CPU {
     /// CPU Register Set
     reg: RegisterSet,
     /// CPU Memory
-    mem: SimpleMap<0xFFFF>,
+    mem: SimpleMap<0x10000>,
+    /// NMI edge detected and waiting to be serviced
+    nmi_pending: bool,
+    /// Level of the (active low on hardware) IRQ line, true when asserted
+    irq_line: bool,
+    /// Set by a JAM or unemulated opcode, with the address it was at. Only a reset recovers.
+    halted: Option<(u8, u16)>,
+}
+
+/// Why a run loop stopped
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopReason {
+    /// Reached a BRK (0x00) byte
+    Break,
+    /// Executed a KIL/JAM opcode and locked up, or an opcode that isn't emulated
+    Halted { opcode: u8, pc: u16 },
+}
+
+/// Everything a step can change other than memory
+#[cfg(feature = "debugger")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct CoreState {
+    reg: RegisterSet,
+    nmi_pending: bool,
+    irq_line: bool,
+    halted: Option<(u8, u16)>,
 }
 
 impl std::fmt::Debug for CPU {
@@ -66,7 +111,9 @@ impl CPU {
     const STACK_ADDR_MIN: u16 = 0x0100;
     const STACK_ADDR_MAX: u16 = 0x01FF;
 
+    const NMI_VECTOR_ADDR: u16 = 0xFFFA;
     const PRG_START_ADDR: u16 = 0xFFFC;
+    const IRQ_VECTOR_ADDR: u16 = 0xFFFE;
 }
 
 impl CPU {
@@ -81,12 +128,18 @@ impl CPU {
     }
 
     fn get_operand_u8(&mut self, opcode: &Opcode) -> u8 {
-        self.mem.read_u8(self.get_operand_address(&opcode.mode))
+        match self.resolve_operand(opcode) {
+            ResolvedOperand::Value(value) => value,
+            ResolvedOperand::Address(addr) => self.mem.read_u8(addr),
+            ResolvedOperand::None => {
+                panic!("ERROR: {:?} has no operand in {:?} addressing", opcode.mnemonic, opcode.mode)
+            }
+        }
     }
 
     // there is never a u16 operand, any apparent u16 operand is actually an address
     // fn get_operand_u16(&mut self, opcode: &Opcode) -> u16 {
-    //     self.mem.read_u16(self.get_operand_address(&opcode.mode))
+    //     self.mem.read_u16(self.get_operand_address(opcode))
     // }
 
     fn increment_pc(&mut self, opcode: &Opcode) {
@@ -113,7 +166,8 @@ impl CPU {
         // On the NES the decimal flag has no effect, else we'd check for it here
         match &opcode.mnemonic {
             Mnemonic::ADC => self.do_base_add(operand, 0x01 * carry_multiplier),
-            Mnemonic::SBC => self.do_base_add(!operand + 1, 0xFF * carry_multiplier),
+            // A - M - (1 - C) is A + !M + C in two's complement
+            Mnemonic::SBC => self.do_base_add(!operand, carry_multiplier),
             x => panic!("ERROR: Addition not a valid instruction for: {:?}", x),
         }
 
@@ -138,10 +192,10 @@ impl CPU {
                 self.reg.a <<= 1;
                 self.update_zn_from_accumulator();
             }
-            mode => {
-                let addr = self.get_operand_address(mode);
+            _ => {
+                let addr = self.get_operand_address(opcode);
                 let operand = self.mem.read_u8(addr);
-                self.reg.set_carry(self.reg.a & 0b1000_0000 != 0);
+                self.reg.set_carry(operand & 0b1000_0000 != 0);
                 let result = operand << 1;
                 self.mem.write_u8(addr, result);
                 self.update_zn_from_value(result);
@@ -159,10 +213,10 @@ impl CPU {
                     self.reg.a >>= 1;
                     self.update_zn_from_accumulator();
                 }
-                mode => {
-                    let addr = self.get_operand_address(mode);
+                _ => {
+                    let addr = self.get_operand_address(opcode);
                     let operand = self.mem.read_u8(addr);
-                    self.reg.set_carry(self.reg.a & 1 != 0);
+                    self.reg.set_carry(operand & 1 != 0);
                     let result = operand >> 1;
                     self.mem.write_u8(addr, result);
                     self.update_zn_from_value(result);
@@ -174,7 +228,7 @@ impl CPU {
 
     fn do_bit_test(&mut self, opcode: &Opcode) {
         let operand = self.get_operand_u8(opcode);
-        self.reg.set_zero((operand & self.reg.a) != 0);
+        self.reg.set_zero((operand & self.reg.a) == 0);
         self.reg.set_negative(operand & 0b1000_0000 != 0);
         self.reg.set_overflow(operand & 0b0100_0000 != 0);
 
@@ -195,7 +249,7 @@ impl CPU {
             Mnemonic::BEQ => self.reg.get_zero(),
             x => panic!("ERROR: Branch not a valid instruction for: {:?}", x),
         } {
-            self.reg.pc = self.reg.pc.wrapping_add(relative_offset as u16);
+            self.reg.pc = self.reg.pc.wrapping_add(relative_offset as i8 as u16);
         }
         // the relative offsets is from the end of the op
         // so we increment the pc as normal regardless
@@ -206,7 +260,7 @@ impl CPU {
         self.reg.pc += 1;
         self.push_u16(self.reg.pc);
         // BRK sets bits 4 and 5 in the word pushed to the stack (but not in the actual register)
-        self.push_u8(self.reg.p | 0b0011_0000);
+        self.push_u8(self.reg.p.with_break_set().bits());
 
         // DO INTERRUPT STUFF ================================================================================
     }
@@ -221,19 +275,19 @@ impl CPU {
             x => panic!("ERROR: Compare not a valid instruction for: {:?}", x),
         };
 
-        self.reg.set_carry(base_value > operand);
+        self.reg.set_carry(base_value >= operand);
         self.update_zn_from_value(base_value.wrapping_sub(operand));
 
         self.increment_pc(opcode);
     }
 
     fn do_crement(&mut self, opcode: &Opcode) {
-        let addr = self.get_operand_address(&opcode.mode);
+        let addr = self.get_operand_address(opcode);
         let value = self.mem.read_u8(addr);
 
         let result = match &opcode.mnemonic {
-            Mnemonic::DEC => value.wrapping_add(1),
-            Mnemonic::INC => value.wrapping_sub(1),
+            Mnemonic::DEC => value.wrapping_sub(1),
+            Mnemonic::INC => value.wrapping_add(1),
             x => panic!(
                 "ERROR: Increment/Decrement not a valid instruction for: {:?}",
                 x
@@ -275,13 +329,13 @@ impl CPU {
 
     fn do_jump(&mut self, opcode: &Opcode) {
         // jumps are absolute addressed, but the operand is the target, not the contents
-        let destination = self.get_operand_address(&opcode.mode);
+        let destination = self.get_operand_address(opcode);
         self.reg.pc = destination;
     }
 
     fn do_subroutine_jump(&mut self, opcode: &Opcode) {
         // jumps are absolute addressed, but the operand is the target, not the contents
-        let destination = self.get_operand_address(&opcode.mode);
+        let destination = self.get_operand_address(opcode);
         self.increment_pc(opcode);
         self.push_u16(self.reg.pc - 1);
         self.reg.pc = destination;
@@ -384,37 +438,41 @@ impl CPU {
     }
 
     fn do_rotate_left(&mut self, opcode: &Opcode) {
-        let new_carry = self.reg.a & 0b1000_0000 != 0;
-
-        self.reg.a = if self.reg.get_carry() {
-            self.reg.a | 0b1000_0000
-        } else {
-            self.reg.a & 0b0111_1111
-        }
-        .rotate_left(1);
-
-        self.reg.set_carry(new_carry);
-        self.update_zn_from_accumulator();
-        self.increment_pc(opcode);
+        let carry_in = self.reg.get_carry() as u8;
+        let rotate = |value: u8| (value << 1 | carry_in, value & 0b1000_0000 != 0);
+        self.do_read_modify_write(opcode, rotate);
     }
 
     fn do_rotate_right(&mut self, opcode: &Opcode) {
-        let new_carry = self.reg.a & 0b0000_0001 != 0;
+        let carry_in = (self.reg.get_carry() as u8) << 7;
+        let rotate = |value: u8| (value >> 1 | carry_in, value & 0b0000_0001 != 0);
+        self.do_read_modify_write(opcode, rotate);
+    }
 
-        self.reg.a = if self.reg.get_carry() {
-            self.reg.a | 0b0000_0001
-        } else {
-            self.reg.a & 0b1111_1110
-        }
-        .rotate_right(1);
+    /// Applies `op` to A or memory, setting carry from what it returns and N and Z from the result
+    fn do_read_modify_write(&mut self, opcode: &Opcode, op: impl Fn(u8) -> (u8, bool)) {
+        let result = match &opcode.mode {
+            AddressMode::Accumulator => {
+                let (result, carry) = op(self.reg.a);
+                self.reg.a = result;
+                self.reg.set_carry(carry);
+                result
+            }
+            _ => {
+                let addr = self.get_operand_address(opcode);
+                let (result, carry) = op(self.mem.read_u8(addr));
+                self.mem.write_u8(addr, result);
+                self.reg.set_carry(carry);
+                result
+            }
+        };
 
-        self.reg.set_carry(new_carry);
-        self.update_zn_from_accumulator();
+        self.update_zn_from_value(result);
         self.increment_pc(opcode);
     }
 
     fn do_return_from_interrupt(&mut self, _opcode: &Opcode) {
-        self.reg.p = self.pull_u8();
+        self.reg.p = Status::from_pulled(self.pull_u8());
         self.reg.pc = self.pull_u16();
     }
 
@@ -425,12 +483,18 @@ impl CPU {
     fn do_stack_transfer(&mut self, opcode: &Opcode) {
         match &opcode.mnemonic {
             Mnemonic::TXS => self.reg.sp = self.reg.x,
-            Mnemonic::TSX => self.reg.x = self.reg.sp,
+            Mnemonic::TSX => {
+                self.reg.x = self.reg.sp;
+                self.update_zn_from_value(self.reg.x);
+            }
             Mnemonic::PHA => self.push_u8(self.reg.a),
-            Mnemonic::PLA => self.reg.a = self.pull_u8(),
+            Mnemonic::PLA => {
+                self.reg.a = self.pull_u8();
+                self.update_zn_from_accumulator();
+            }
             // PHP pushes the processor word with 4 and 5 set, and PLP ignores them when pulling
-            Mnemonic::PHP => self.push_u8(self.reg.p | 0b0011_0000),
-            Mnemonic::PLP => self.reg.p = self.pull_u8() & !0b0011_0000,
+            Mnemonic::PHP => self.push_u8(self.reg.p.with_break_set().bits()),
+            Mnemonic::PLP => self.reg.p = Status::from_pulled(self.pull_u8()),
             x => panic!("ERROR: Stack transfer not a valid instruction for: {:?}", x),
         }
 
@@ -438,7 +502,7 @@ impl CPU {
     }
 
     fn do_store_register(&mut self, opcode: &Opcode) {
-        let addr = self.get_operand_address(&opcode.mode);
+        let addr = self.get_operand_address(opcode);
         self.mem.write_u8(
             addr,
             match &opcode.mnemonic {
@@ -456,9 +520,36 @@ impl CPU {
         CPU {
             reg: RegisterSet::default(),
             mem: SimpleMap::default(),
+            nmi_pending: false,
+            irq_line: false,
+            halted: None,
         }
     }
 
+    /// Starts counting reads, writes and executes of each address
+    #[cfg(feature = "heatmap")]
+    pub fn track_accesses(&mut self) {
+        self.mem.track_accesses();
+    }
+
+    /// Access counts since `track_accesses`, if it was called
+    #[cfg(feature = "heatmap")]
+    pub fn heatmap(&self) -> Option<std::cell::Ref<'_, crate::memory::heatmap::Heatmap>> {
+        self.mem.heatmap()
+    }
+
+    /// Sets up a CPU with a cartridge, peripherals and other options in one go
+    pub fn builder() -> builder::CpuBuilder {
+        builder::CpuBuilder::new()
+    }
+
+    /// Creates a CPU with internal RAM filled as it would be at power-on
+    pub fn power_on(init: MemoryInit) -> Self {
+        let mut cpu = CPU::new();
+        init.apply(&mut cpu.mem, CPU::CPU_RAM_ADDR_MIN..CPU::IO_REG_ADDR_MIN);
+        cpu
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         self.mem.read_u8(addr)
     }
@@ -467,57 +558,253 @@ impl CPU {
         self.mem.load(addr, data);
     }
 
-    fn get_operand_address(&self, mode: &AddressMode) -> u16 {
+    /// The byte stored at `addr`, without reading through a mapped peripheral
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.mem.raw(addr as usize..addr as usize + 1)[0]
+    }
+
+    /// Stores a byte at `addr` even when it's write protected ROM or under a
+    /// peripheral, for debuggers and tests to patch code and vectors
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        self.mem.load(addr, &[val]);
+    }
+
+    /// Current state of the CPU registers
+    pub fn registers(&self) -> &RegisterSet {
+        &self.reg
+    }
+
+    /// Mutable access to the CPU registers, for debuggers and scripting
+    pub fn registers_mut(&mut self) -> &mut RegisterSet {
+        &mut self.reg
+    }
+
+    /// Makes a region read-only to the running program. `load` can still change it.
+    pub fn protect(&mut self, range: std::ops::RangeInclusive<u16>) {
+        self.mem.protect(range);
+    }
+
+    /// Records writes to read-only regions for `rom_writes` instead of just dropping them
+    pub fn set_strict_rom(&mut self, strict: bool) {
+        self.mem.set_strict(strict);
+    }
+
+    /// Writes blocked since the last `clear_rom_writes`, in strict mode
+    pub fn rom_writes(&self) -> &[RomWrite] {
+        self.mem.rom_writes()
+    }
+
+    pub fn clear_rom_writes(&mut self) {
+        self.mem.clear_rom_writes();
+    }
+
+    /// Maps custom hardware onto the bus, ticked after every instruction
+    pub fn map_peripheral(&mut self, range: std::ops::RangeInclusive<u16>, peripheral: Box<dyn Peripheral>) {
+        self.mem.map_peripheral(range, peripheral);
+    }
+
+    /// Descriptions of the peripherals' registers, by address
+    pub fn io_registers(&self) -> Vec<(u16, Register)> {
+        self.mem.registers()
+    }
+
+    /// Starts or stops keeping the old value of every byte the program writes,
+    /// for `take_writes`. Writes to peripherals aren't kept.
+    pub fn journal_writes(&mut self, on: bool) {
+        self.mem.set_journal(on);
+    }
+
+    /// Address and old value of each byte written since the last call, oldest first
+    pub fn take_writes(&mut self) -> Vec<(u16, u8)> {
+        self.mem.take_journal()
+    }
+
+    #[cfg(feature = "debugger")]
+    pub(crate) fn core_state(&self) -> CoreState {
+        CoreState { reg: self.reg, nmi_pending: self.nmi_pending, irq_line: self.irq_line, halted: self.halted }
+    }
+
+    #[cfg(feature = "debugger")]
+    pub(crate) fn restore_core_state(&mut self, state: CoreState) {
+        self.reg = state.reg;
+        self.nmi_pending = state.nmi_pending;
+        self.irq_line = state.irq_line;
+        self.halted = state.halted;
+    }
+
+    /// Snapshot of the registers, interrupt lines and everything below PRG ROM
+    pub fn save_state(&self) -> SaveState {
+        let mut cpu = self.reg.pc.to_le_bytes().to_vec();
+        cpu.extend_from_slice(&[self.reg.sp, self.reg.a, self.reg.x, self.reg.y, self.reg.p.bits()]);
+        cpu.extend_from_slice(&[self.nmi_pending as u8, self.irq_line as u8]);
+        let (halted, opcode, pc) = match self.halted {
+            Some((opcode, pc)) => (1, opcode, pc),
+            None => (0, 0, 0),
+        };
+        cpu.extend_from_slice(&[halted, opcode]);
+        cpu.extend_from_slice(&pc.to_le_bytes());
+
+        let mut state = SaveState::new();
+        state.insert(CPU_CHUNK, cpu);
+        state.insert(RAM_CHUNK, self.mem.raw(0..CPU::PRG_ROM_ADDR_MIN as usize).to_vec());
+        state
+    }
+
+    /// Stable hash of the registers, interrupt lines and memory below PRG ROM
+    pub fn state_hash(&self) -> u64 {
+        self.save_state().hash()
+    }
+
+    /// Restores a `save_state` snapshot. PRG ROM and mapped peripherals are left alone.
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), Box<dyn std::error::Error>> {
+        let cpu = state.require(CPU_CHUNK)?;
+        let ram = state.require(RAM_CHUNK)?;
+        if cpu.len() < 13 {
+            return Err(format!("CPU chunk is {} bytes, expected 13", cpu.len()).into());
+        }
+        if ram.len() < CPU::PRG_ROM_ADDR_MIN as usize {
+            return Err(format!("RAM chunk is {} bytes, expected {}", ram.len(), CPU::PRG_ROM_ADDR_MIN).into());
+        }
+
+        self.reg.pc = u16::from_le_bytes([cpu[0], cpu[1]]);
+        (self.reg.sp, self.reg.a, self.reg.x, self.reg.y) = (cpu[2], cpu[3], cpu[4], cpu[5]);
+        self.reg.p = Status::from_bits(cpu[6]);
+        self.nmi_pending = cpu[7] != 0;
+        self.irq_line = cpu[8] != 0;
+        self.halted = (cpu[9] != 0).then(|| (cpu[10], u16::from_le_bytes([cpu[11], cpu[12]])));
+        self.mem.load(CPU::CPU_RAM_ADDR_MIN, &ram[..CPU::PRG_ROM_ADDR_MIN as usize]);
+        Ok(())
+    }
+
+    /// Address of the memory an instruction reads or writes. Immediate
+    /// operands have none, so a store with one is rejected rather than
+    /// writing into the instruction stream. No opcode in the table has one,
+    /// which `test_opcodes_have_operands` checks.
+    fn get_operand_address(&self, opcode: &Opcode) -> u16 {
+        match self.resolve_operand(opcode) {
+            ResolvedOperand::Address(addr) => addr,
+            _ => panic!("ERROR: {:?} needs a memory operand, {:?} addressing has none", opcode.mnemonic, opcode.mode),
+        }
+    }
+
+    /// Works out the operand, making the same reads the 6502 does on the way
+    fn resolve_operand(&self, opcode: &Opcode) -> ResolvedOperand {
         use AddressMode::*;
-        match mode {
-            Implicit => panic!("AddressMode Error: No operand for Implicit addressing."),
-            Accumulator => panic!("AddressMode Error: No operand for Accumulator addressing."), // may need to replace this
-            Immediate => self.reg.pc,
-            ZeroPage => self.mem.read_u8(self.reg.pc) as u16,
-            ZeroPageX => self.mem.read_u8(self.reg.pc).wrapping_add(self.reg.x) as u16,
-            ZeroPageY => self.mem.read_u8(self.reg.pc).wrapping_add(self.reg.y) as u16,
-            Relative => self.reg.pc, // for branch instructions, there is no operand really.
-            Absolute => self.mem.read_u16(self.reg.pc), // may also need to replace this
-            AbsoluteX => self
-                .mem
-                .read_u16(self.reg.pc)
-                .wrapping_add(self.reg.x as u16),
-            AbsoluteY => self
-                .mem
-                .read_u16(self.reg.pc)
-                .wrapping_add(self.reg.y as u16),
-            Indirect => {
-                let ptr = self.mem.read_u16(self.reg.pc);
-
-                self.mem.read_u16(ptr)
-            },
+        let zero_page = ZpAddr(self.mem.read_u8(self.reg.pc));
+        let absolute = || Addr(self.mem.read_u16(self.reg.pc));
+        let addr = match opcode.mode {
+            Implicit | Accumulator => return ResolvedOperand::None,
+            // for branches the value is the relative offset
+            Immediate | Relative => return ResolvedOperand::Value(zero_page.0),
+            ZeroPage => zero_page.into(),
+            ZeroPageX => self.index_zero_page(zero_page, self.reg.x),
+            ZeroPageY => self.index_zero_page(zero_page, self.reg.y),
+            Absolute => absolute(),
+            AbsoluteX => self.index(opcode, absolute(), self.reg.x),
+            AbsoluteY => self.index(opcode, absolute(), self.reg.y),
+            // the NMOS 6502 doesn't carry into the pointer's high byte
+            Indirect => self.read_pointer(absolute(), absolute().next_in_page()),
             IndirectX => {
-                let ptr = self.mem.read_u8(self.reg.pc).wrapping_add(self.reg.x);
-
-                u16::from_le_bytes([
-                    self.mem.read_u8(ptr as u16),
-                    self.mem.read_u8(ptr.wrapping_add(1) as u16),
-                ])
-            },
+                // the base is read while X is added, as in zero page indexing
+                self.mem.read_u8(Addr::from(zero_page).0);
+                let ptr = zero_page.index(self.reg.x);
+                self.read_pointer(ptr.into(), ptr.next().into())
+            }
             IndirectY => {
-                let ptr = self.mem.read_u8(self.reg.pc);
-
-                u16::from_le_bytes([
-                    self.mem.read_u8(ptr as u16),
-                    self.mem.read_u8(ptr.wrapping_add(1) as u16),
-                ])
-                .wrapping_add(self.reg.y as u16)
+                let ptr = self.read_pointer(zero_page.into(), zero_page.next().into());
+                self.index(opcode, ptr, self.reg.y)
             }
+        };
+        ResolvedOperand::Address(addr.0)
+    }
+
+    /// Zero page indexing, which reads the unindexed address while it adds
+    fn index_zero_page(&self, base: ZpAddr, index: u8) -> Addr {
+        self.mem.read_u8(Addr::from(base).0);
+        base.index(index).into()
+    }
+
+    /// Indexes `base`, making the read the 6502 does at the address before
+    /// the carry into the high byte is fixed up. Reads only make it when they
+    /// cross a page, stores and read-modify-writes always do. It matters for
+    /// peripherals, whose registers can change when read.
+    fn index(&self, opcode: &Opcode, base: Addr, index: u8) -> Addr {
+        let writes = matches!(
+            opcode.mnemonic,
+            Mnemonic::STA | Mnemonic::STX | Mnemonic::STY | Mnemonic::ASL | Mnemonic::LSR
+                | Mnemonic::ROL | Mnemonic::ROR | Mnemonic::INC | Mnemonic::DEC
+        );
+        if writes || base.crosses_page(base.index(index)) {
+            self.mem.read_u8(base.index_without_carry(index).0);
         }
+        base.index(index)
+    }
+
+    /// Reads a little endian pointer from its two bytes
+    fn read_pointer(&self, lo: Addr, hi: Addr) -> Addr {
+        Addr(u16::from_le_bytes([self.mem.read_u8(lo.0), self.mem.read_u8(hi.0)]))
+    }
+
+    /// Signal a falling edge on the NMI line, serviced after the current instruction
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
     }
 
+    /// Assert or release the IRQ line, serviced while asserted and not masked
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    /// Pushes PC and status (with B clear) and jumps through the given vector
+    fn service_interrupt(&mut self, vector: u16) {
+        self.push_u16(self.reg.pc);
+        self.push_u8(self.reg.p.with_break_clear().bits());
+        self.reg.set_interrupt(true);
+        self.reg.pc = self.mem.read_u16(vector);
+    }
+
+    /// Interrupt lines are polled at the end of every instruction.
+    ///
+    /// CLI, SEI and PLP change the I flag after the poll has happened, so the
+    /// mask in effect is the one from before they ran and an IRQ is only taken
+    /// (or held off) one instruction later. RTI restores the flag before the
+    /// poll so takes effect immediately. The taken-branch poll delay only
+    /// matters for lines changing mid-instruction, which can't happen while
+    /// lines are only driven between steps.
+    fn poll_interrupts(&mut self, irq_masked: bool) {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.service_interrupt(CPU::NMI_VECTOR_ADDR);
+        } else if self.irq_line && !irq_masked {
+            self.service_interrupt(CPU::IRQ_VECTOR_ADDR);
+        }
+    }
+
+    /// True once a JAM opcode has locked the CPU up
+    pub fn is_halted(&self) -> bool {
+        self.halted.is_some()
+    }
+
+    /// Executes one instruction. Does nothing while halted, not even servicing interrupts.
     pub fn step(&mut self, code: u8) {
         use ops::Mnemonic::*;
 
+        if self.halted.is_some() {
+            return;
+        }
+        // opcodes that aren't emulated lock up like JAMs, so they can be reported
+        let opcode = match Opcode::from_code(code) {
+            Some(opcode) if !ops::JAM_OPCODES.contains(&code) => opcode,
+            _ => {
+                self.halted = Some((code, self.reg.pc));
+                return;
+            }
+        };
+        #[cfg(feature = "heatmap")]
+        self.mem.record_access(crate::memory::heatmap::Access::Execute, self.reg.pc);
         self.reg.pc += 1;
-        let &opcode = ops::CPU_OPCODE_MAP
-            .get(&code)
-            .expect(&format!("ERROR: Opcode {:#x?} unimplemented\nDump:\n {:#?}", code, self));
+
+        let prior_irq_mask = self.reg.get_interrupt();
 
         match opcode.mnemonic {
             // Add or Subtract
@@ -563,32 +850,57 @@ impl CPU {
             // Stack instructions
             TXS | TSX | PHA | PLA | PHP | PLP => self.do_stack_transfer(opcode),
         }
+        self.mem.tick_peripherals();
+
+        let irq_masked = match opcode.mnemonic {
+            CLI | SEI | PLP => prior_irq_mask,
+            _ => self.reg.get_interrupt(),
+        };
+        self.poll_interrupts(irq_masked);
+    }
+
+    /// Fetches and executes the instruction at PC, as the run loop does.
+    /// Returns why execution can't continue, if it can't.
+    pub fn step_next(&mut self) -> Option<StopReason> {
+        if self.halted.is_none() {
+            match self.read(self.reg.pc) {
+                0x00 => return Some(StopReason::Break), // break (temporary manual check until we implement proper interrupts)
+                opcode => self.step(opcode),
+            }
+        }
+        self.halted.map(|(opcode, pc)| StopReason::Halted { opcode, pc })
     }
 
-    /// Continuously run program from current location until BRK
-    pub fn run(&mut self) {
-        self.run_with_callback(|_|Ok(()));
+    /// Continuously run program from current location until BRK or a JAM opcode
+    pub fn run(&mut self) -> StopReason {
+        self.run_with_callback(|_|Ok(()))
     }
 
     /// Reset register state and initialise program counter to value at 0xFFFC
     pub fn interrupt_reset(&mut self) {
+        self.halted = None;
         self.reg.reset();
         self.reg.pc = self.mem.read_u16(CPU::PRG_START_ADDR);
     }
 
-    /// Loads program into PRG_ROM and sets the reset address
+    /// Longest program `load_program` can load without running into the vectors
+    pub const MAX_PROGRAM_LEN: usize = (CPU::NMI_VECTOR_ADDR - CPU::PRG_ROM_ADDR_MIN) as usize;
+
+    /// Loads program into PRG_ROM, sets the reset address and write protects PRG_ROM.
+    /// Programs longer than `MAX_PROGRAM_LEN` overwrite the vectors.
     pub fn load_program(&mut self, program: &[u8]) {
         self.mem.load(CPU::PRG_ROM_ADDR_MIN, program);
-        self.mem.write_u16(0xFFFC, CPU::PRG_ROM_ADDR_MIN);
+        self.mem.load(CPU::PRG_START_ADDR, &CPU::PRG_ROM_ADDR_MIN.to_le_bytes());
+        self.mem.protect(CPU::PRG_ROM_ADDR_MIN..=CPU::NES_ADDR_MAX);
     }
 
     /// Loads program at 0x0600 and sets reset address (fudge code for snake testing)
     pub fn load_for_snake(&mut self, program: &[u8]) {
         self.mem.load(0x0600, program);
-        self.mem.write_u16(0xFFFC, 0x0600);
+        self.mem.load(CPU::PRG_START_ADDR, &0x0600u16.to_le_bytes());
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> StopReason
     where F: FnMut(&mut CPU) -> Result<(), Box<dyn std::error::Error>>,
     {
 
@@ -596,9 +908,8 @@ impl CPU {
             if let Err(error) = callback(self) {
                 panic!("ERROR in UI Callback: {:?}", error);
             }
-            match self.mem.read_u8(self.reg.pc) {
-                0x00 => return, // break (temporary manual check until we implement proper interrupts)
-                opcode => self.step(opcode),
+            if let Some(reason) = self.step_next() {
+                return reason;
             }
         }
     }
@@ -608,6 +919,32 @@ impl CPU {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_peek_poke() {
+        let mut cpu = CPU::new();
+        cpu.load_program(&[0xEA]);
+        cpu.map_peripheral(0x4016..=0x4016, Box::new(crate::input::ControllerPorts::new(std::rc::Rc::new(std::cell::Cell::new([0xFF, 0, 0, 0])))));
+
+        cpu.poke(0x8000, 0x60);
+        assert_eq!(cpu.peek(0x8000), 0x60);
+        cpu.poke(0x4016, 0x12);
+        assert_eq!(cpu.peek(0x4016), 0x12);
+        assert_ne!(cpu.read(0x4016), 0x12);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        let mut cpu = CPU::new();
+        // JMP ($02FF) takes its high byte from $0200, not $0300
+        cpu.load(0x0600, &[0x6C, 0xFF, 0x02]);
+        cpu.load(0x02FF, &[0x34]);
+        cpu.load(0x0200, &[0x12]);
+        cpu.load(0x0300, &[0x56]);
+        cpu.reg.pc = 0x0600;
+        cpu.step_next();
+        assert_eq!(cpu.reg.pc, 0x1234);
+    }
+
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let mut cpu = CPU::new();
@@ -615,8 +952,8 @@ mod tests {
         cpu.run();
 
         assert_eq!(cpu.reg.a, 0x05);
-        assert!(cpu.reg.p & 0b0000_0010 == 0b00);
-        assert!(cpu.reg.p & 0b1000_0000 == 0);
+        assert!(!cpu.reg.p.contains(Status::ZERO));
+        assert!(!cpu.reg.p.contains(Status::NEGATIVE));
     }
 
     #[test]
@@ -625,7 +962,7 @@ mod tests {
         cpu.load(0, &[0xA9, 0x00, 0x00]);
         cpu.run();
 
-        assert!(cpu.reg.p & 0b0000_0010 == 0b10);
+        assert!(cpu.reg.p.contains(Status::ZERO));
     }
 
     #[test]
@@ -657,6 +994,20 @@ mod tests {
         assert_eq!(cpu.reg.x, 0x01);
     }
 
+    #[test]
+    fn test_stack_instructions_are_one_byte() {
+        let mut cpu = CPU::new();
+        // TXS, PHA, PHP, PLP, PLA, TSX, then LDA #$42 to show PC lands on it
+        cpu.load_program(&[0x9A, 0x48, 0x08, 0x28, 0x68, 0xBA, 0xA9, 0x42, 0x00]);
+        cpu.interrupt_reset();
+        for pc in 0x8000..0x8006 {
+            assert_eq!(cpu.reg.pc, pc);
+            cpu.step(cpu.read(pc));
+        }
+        cpu.run();
+        assert_eq!(cpu.reg.a, 0x42);
+    }
+
     #[test]
     fn test_program_load() {
         let mut cpu = CPU::new();
@@ -749,20 +1100,21 @@ mod tests {
         cpu.run();
         assert_eq!(cpu.reg.get_overflow(), true);
 
+        // SBC subtracts an extra 1 when carry is clear, so these set it first
         // 0 - 1 = -1, returns V = 0
-        cpu.load_program(&[0xA9, 0x00, 0xE9, 0x01, 0x00]);
+        cpu.load_program(&[0xA9, 0x00, 0x38, 0xE9, 0x01, 0x00]);
         cpu.interrupt_reset();
         cpu.run();
         assert_eq!(cpu.reg.get_overflow(), false);
 
         // -128 - 1 = -129, returns V = 1
-        cpu.load_program(&[0xA9, 0x80, 0xE9, 0x01, 0x00]);
+        cpu.load_program(&[0xA9, 0x80, 0x38, 0xE9, 0x01, 0x00]);
         cpu.interrupt_reset();
         cpu.run();
         assert_eq!(cpu.reg.get_overflow(), true);
 
         // 127 - -1 = 128, returns V = 1
-        cpu.load_program(&[0xA9, 0x7F, 0xE9, 0xFF, 0x00]);
+        cpu.load_program(&[0xA9, 0x7F, 0x38, 0xE9, 0xFF, 0x00]);
         cpu.interrupt_reset();
         cpu.run();
         assert_eq!(cpu.reg.get_overflow(), true);
@@ -857,6 +1209,288 @@ mod tests {
         assert_eq!(cpu.reg.get_carry(), true);
     }
 
+    #[test]
+    fn test_power_on_fills_internal_ram_only() {
+        let cpu = CPU::power_on(MemoryInit::FF);
+        assert_eq!(cpu.read(0x0000), 0xFF);
+        assert_eq!(cpu.read(0x1FFF), 0xFF);
+        assert_eq!(cpu.read(0x2000), 0x00);
+        assert_eq!(cpu.read(0x8000), 0x00);
+
+        let a = CPU::power_on(MemoryInit::Random(0x5EED));
+        let b = CPU::power_on(MemoryInit::Random(0x5EED));
+        assert!((0x0000..0x2000).all(|addr| a.read(addr) == b.read(addr)));
+    }
+
+    /// Whether an opcode's addressing mode gives it the operand it needs, such
+    /// as an address for a store, so the operand helpers can't panic on it
+    fn has_operand(opcode: &Opcode) -> bool {
+        use AddressMode::*;
+        use ops::Mnemonic::*;
+        let address = !matches!(opcode.mode, Implicit | Accumulator | Immediate | Relative);
+        match opcode.mnemonic {
+            STA | STX | STY | INC | DEC | JMP | JSR => address,
+            ASL | LSR | ROL | ROR => address || opcode.mode == Accumulator,
+            ADC | SBC | AND | ORA | EOR | BIT | CMP | CPX | CPY | LDA | LDX | LDY => !matches!(opcode.mode, Implicit | Accumulator),
+            BPL | BMI | BVC | BVS | BCC | BCS | BNE | BEQ => opcode.mode == Relative,
+            _ => true,
+        }
+    }
+
+    #[test]
+    fn test_opcodes_have_operands() {
+        use ops::NMOS_6502_OPCODES;
+        let bad: Vec<_> = NMOS_6502_OPCODES.iter().filter(|opcode| !has_operand(opcode)).collect();
+        assert!(bad.is_empty(), "{:?}", bad);
+        assert!(!has_operand(&Opcode::new(Mnemonic::STA, 0x89, 2, 2, 0, AddressMode::Immediate)));
+        assert!(!has_operand(&Opcode::new(Mnemonic::LDA, 0xA9, 1, 2, 0, AddressMode::Implicit)));
+        assert!(!has_operand(&Opcode::new(Mnemonic::BNE, 0xD0, 3, 2, 1, AddressMode::Absolute)));
+    }
+
+    #[test]
+    fn test_jam_opcode_halts() {
+        // LDA #01, JAM, LDA #02
+        let mut cpu = CPU::new();
+        cpu.load(0x0600, &[0xA9, 0x01, 0x02, 0xA9, 0x02]);
+        cpu.reg.pc = 0x0600;
+
+        assert_eq!(cpu.run(), StopReason::Halted { opcode: 0x02, pc: 0x0602 });
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.reg.a, 0x01);
+
+        // stays jammed, ignoring interrupts
+        cpu.trigger_nmi();
+        assert_eq!(cpu.step_next(), Some(StopReason::Halted { opcode: 0x02, pc: 0x0602 }));
+        assert_eq!(cpu.reg.pc, 0x0602);
+
+        cpu.load(0xFFFC, &[0x03, 0x06]);
+        cpu.interrupt_reset();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.run(), StopReason::Break);
+        assert_eq!(cpu.reg.a, 0x02);
+    }
+
+    #[test]
+    fn test_nmi_pushes_state_and_jumps() {
+        let mut cpu = CPU::new();
+        cpu.load_program(&[0xEA, 0xEA, 0x00]);
+        cpu.load(CPU::NMI_VECTOR_ADDR, &0x9000u16.to_le_bytes());
+        cpu.interrupt_reset();
+        cpu.reg.set_interrupt(true);
+
+        // NMI ignores the I flag
+        cpu.trigger_nmi();
+        cpu.step(0xEA);
+        assert_eq!(cpu.reg.pc, 0x9000);
+        // status pushed with B clear and bit 5 set, then return address
+        assert_eq!(cpu.pull_u8(), 0b0010_0100);
+        assert_eq!(cpu.pull_u16(), 0x8001);
+
+        // the edge is only serviced once
+        cpu.step(0xEA);
+        assert_eq!(cpu.reg.pc, 0x9001);
+    }
+
+    #[test]
+    fn test_irq_masked_by_interrupt_flag() {
+        let mut cpu = CPU::new();
+        cpu.load_program(&[0xEA, 0xEA, 0x00]);
+        cpu.load(CPU::IRQ_VECTOR_ADDR, &0x9000u16.to_le_bytes());
+        cpu.interrupt_reset();
+
+        cpu.reg.set_interrupt(true);
+        cpu.set_irq(true);
+        cpu.step(0xEA);
+        assert_eq!(cpu.reg.pc, 0x8001);
+
+        cpu.reg.set_interrupt(false);
+        cpu.step(0xEA);
+        assert_eq!(cpu.reg.pc, 0x9000);
+        assert!(cpu.reg.get_interrupt());
+    }
+
+    #[test]
+    fn test_cli_delays_irq_by_one_instruction() {
+        let mut cpu = CPU::new();
+        // CLI, NOP, NOP
+        cpu.load_program(&[0x58, 0xEA, 0xEA, 0x00]);
+        cpu.load(CPU::IRQ_VECTOR_ADDR, &0x9000u16.to_le_bytes());
+        cpu.interrupt_reset();
+        cpu.reg.set_interrupt(true);
+        cpu.set_irq(true);
+
+        cpu.step(0x58);
+        assert_eq!(cpu.reg.pc, 0x8001);
+        cpu.step(0xEA);
+        assert_eq!(cpu.reg.pc, 0x9000);
+        // returns to the instruction after the NOP that ran
+        cpu.pull_u8();
+        assert_eq!(cpu.pull_u16(), 0x8002);
+    }
+
+    #[test]
+    fn test_sei_still_takes_pending_irq() {
+        let mut cpu = CPU::new();
+        // SEI, NOP
+        cpu.load_program(&[0x78, 0xEA, 0x00]);
+        cpu.load(CPU::IRQ_VECTOR_ADDR, &0x9000u16.to_le_bytes());
+        cpu.interrupt_reset();
+        cpu.set_irq(true);
+
+        cpu.step(0x78);
+        assert_eq!(cpu.reg.pc, 0x9000);
+        // the pushed status already has I set by the SEI
+        assert_eq!(cpu.pull_u8() & 0b0000_0100, 0b0000_0100);
+        assert_eq!(cpu.pull_u16(), 0x8001);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut cpu = CPU::new();
+        // LDX #$05, STX $10, JAM
+        cpu.load_program(&[0xA2, 0x05, 0x86, 0x10, 0x02]);
+        cpu.interrupt_reset();
+        cpu.run();
+        let bytes = cpu.save_state().to_bytes();
+
+        let mut restored = CPU::new();
+        restored.load_program(&[0xA2, 0x05, 0x86, 0x10, 0x02]);
+        restored.load_state(&SaveState::from_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(restored.reg, cpu.reg);
+        assert_eq!(restored.halted, Some((0x02, 0x8004)));
+        assert_eq!(restored.read(0x10), 0x05);
+
+        assert!(restored.load_state(&SaveState::new()).is_err());
+        assert_eq!(restored.state_hash(), cpu.state_hash());
+        restored.load(0x10, &[0x06]);
+        assert_ne!(restored.state_hash(), cpu.state_hash());
+    }
+
+    #[test]
+    fn test_save_state_layout_unchanged() {
+        // Version 1 is the only format so far, so there's no older state to
+        // migrate. This one was written by the current code and catches layout
+        // changes made without bumping the version. Keep it as is when the
+        // version goes up, to become the first older state that has to load.
+        let state = SaveState::from_bytes(include_bytes!("../tests/savestates/v1.nsst")).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_state(&state).unwrap();
+
+        assert_eq!((cpu.reg.pc, cpu.reg.sp, cpu.reg.a, cpu.reg.x, cpu.reg.y), (0x0605, 0xFD, 0x11, 0x22, 0x33));
+        assert_eq!(cpu.reg.p, Status::NEGATIVE | Status::CARRY);
+        assert!(cpu.nmi_pending && !cpu.irq_line && cpu.halted.is_none());
+        assert_eq!((cpu.read(0x10), cpu.read(0x01FF), cpu.read(0x6000)), (0x03, 0x06, 0x5A));
+    }
+
+    #[test]
+    fn test_serial_port_peripheral() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Collects bytes written to its data register
+        struct SerialPort(Rc<RefCell<Vec<u8>>>);
+
+        impl Peripheral for SerialPort {
+            fn read(&mut self, _offset: u16) -> u8 {
+                0
+            }
+
+            fn write(&mut self, _offset: u16, val: u8) {
+                self.0.borrow_mut().push(val);
+            }
+        }
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new();
+        cpu.map_peripheral(0x5000..=0x5000, Box::new(SerialPort(output.clone())));
+        // LDA #'h', STA $5000, LDA #'i', STA $5000
+        cpu.load_program(&[0xA9, b'h', 0x8D, 0x00, 0x50, 0xA9, b'i', 0x8D, 0x00, 0x50, 0x00]);
+        cpu.interrupt_reset();
+        cpu.run();
+
+        assert_eq!(output.borrow().as_slice(), b"hi");
+        assert_eq!(cpu.read(0x5000), 0);
+    }
+
+    #[test]
+    fn test_dummy_reads() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Counts reads of each register
+        struct ReadCounter(Rc<RefCell<Vec<u16>>>);
+
+        impl Peripheral for ReadCounter {
+            fn read(&mut self, offset: u16) -> u8 {
+                self.0.borrow_mut().push(offset);
+                0
+            }
+
+            fn write(&mut self, _offset: u16, _val: u8) {}
+        }
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new();
+        cpu.map_peripheral(0x5000..=0x51FF, Box::new(ReadCounter(reads.clone())));
+        let mut run = |program: &[u8]| {
+            reads.borrow_mut().clear();
+            cpu.load(0x0600, program);
+            cpu.reg.pc = 0x0600;
+            cpu.reg.x = 0x10;
+            cpu.step_next();
+            reads.borrow().clone()
+        };
+
+        // LDA $5000,X stays in the page, so only the real read
+        assert_eq!(run(&[0xBD, 0x00, 0x50]), vec![0x0010]);
+        // LDA $50F8,X first reads $5008, then $5108
+        assert_eq!(run(&[0xBD, 0xF8, 0x50]), vec![0x0008, 0x0108]);
+        // STA $5000,X reads before writing even within the page
+        assert_eq!(run(&[0x9D, 0x00, 0x50]), vec![0x0010]);
+        // INC $50F8,X reads $5008, then reads and writes $5108
+        assert_eq!(run(&[0xFE, 0xF8, 0x50]), vec![0x0008, 0x0108]);
+
+        // with the zero page counted too, as the pointer and zero page indexed modes read there
+        let zero_page = Rc::new(RefCell::new(Vec::new()));
+        cpu.map_peripheral(0x0000..=0x00FF, Box::new(ReadCounter(zero_page.clone())));
+        let mut run = |program: &[u8]| {
+            zero_page.borrow_mut().clear();
+            cpu.load(0x0600, program);
+            cpu.reg.pc = 0x0600;
+            (cpu.reg.x, cpu.reg.y) = (0x10, 0x10);
+            cpu.step_next();
+            zero_page.borrow().clone()
+        };
+        // LDA $20,X reads $20 while adding X, then $30
+        assert_eq!(run(&[0xB5, 0x20]), vec![0x20, 0x30]);
+        // STX $F8,Y wraps round to $08 after reading $F8
+        assert_eq!(run(&[0x96, 0xF8]), vec![0xF8]);
+        // LDA ($20,X) reads $20 while adding X, the pointer at $30, then $0000 it points to
+        assert_eq!(run(&[0xA1, 0x20]), vec![0x20, 0x30, 0x31, 0x00]);
+        // LDA ($20),Y reads the pointer once, which is $0000 here, then $0010
+        assert_eq!(run(&[0xB1, 0x20]), vec![0x20, 0x21, 0x10]);
+        // STA ($20),Y reads $0010 before writing it
+        assert_eq!(run(&[0x91, 0x20]), vec![0x20, 0x21, 0x10]);
+    }
+
+    #[test]
+    fn test_resolve_operand() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0601, &[0x10]);
+        cpu.reg.pc = 0x0601;
+        let opcode = |mnemonic, mode| Opcode::find(mnemonic, mode).unwrap();
+        assert_eq!(cpu.resolve_operand(opcode(Mnemonic::LDA, AddressMode::Immediate)), ResolvedOperand::Value(0x10));
+        assert_eq!(cpu.resolve_operand(opcode(Mnemonic::LDA, AddressMode::ZeroPage)), ResolvedOperand::Address(0x10));
+        assert_eq!(cpu.resolve_operand(opcode(Mnemonic::TAX, AddressMode::Implicit)), ResolvedOperand::None);
+    }
+
+    #[test]
+    #[should_panic(expected = "STA needs a memory operand")]
+    fn test_store_immediate_rejected() {
+        let mut cpu = CPU::new();
+        cpu.do_store_register(&Opcode::new(Mnemonic::STA, 0x85, 2, 2, 0, AddressMode::Immediate));
+    }
+
     // #[test]
     // fn test_
 }
@@ -1,4 +1,6 @@
-#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+use crate::cpu::prog::instructions::Operand;
+
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum AddressMode {
     Implicit,
     Accumulator,
@@ -14,3 +16,177 @@ pub enum AddressMode {
     IndirectX, // Indexed Indirect
     IndirectY, // Indirect Indexed
 }
+
+/// A CPU address. Indexing and stepping carry into the next page, wrapping
+/// at $FFFF, except for `next_in_page`, which is how the NMOS 6502 reads the
+/// high byte of a JMP ($xxFF) pointer.
+#[derive(Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Addr(pub u16);
+
+impl Addr {
+    pub const fn page(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    /// Absolute indexed addressing, which carries
+    pub const fn index(self, by: u8) -> Addr {
+        Addr(self.0.wrapping_add(by as u16))
+    }
+
+    pub const fn next(self) -> Addr {
+        Addr(self.0.wrapping_add(1))
+    }
+
+    /// Indexes without carrying into the high byte, which is where an indexed
+    /// access first reads before the 6502 fixes up the page
+    pub const fn index_without_carry(self, by: u8) -> Addr {
+        Addr(self.0 & 0xFF00 | (self.0 as u8).wrapping_add(by) as u16)
+    }
+
+    /// The next byte, staying in this page
+    pub const fn next_in_page(self) -> Addr {
+        Addr(self.0 & 0xFF00 | (self.0 as u8).wrapping_add(1) as u16)
+    }
+
+    pub const fn crosses_page(self, to: Addr) -> bool {
+        self.page() != to.page()
+    }
+}
+
+impl From<u16> for Addr {
+    fn from(addr: u16) -> Self {
+        Addr(addr)
+    }
+}
+
+impl From<Addr> for u16 {
+    fn from(addr: Addr) -> Self {
+        addr.0
+    }
+}
+
+impl From<ZpAddr> for Addr {
+    fn from(addr: ZpAddr) -> Self {
+        Addr(addr.0 as u16)
+    }
+}
+
+/// An address in the zero page. Indexing and stepping wrap around within it,
+/// so ($FF),Y reads its pointer from $FF and $00.
+#[derive(Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct ZpAddr(pub u8);
+
+impl ZpAddr {
+    /// Zero page indexed addressing, which doesn't carry
+    pub const fn index(self, by: u8) -> ZpAddr {
+        ZpAddr(self.0.wrapping_add(by))
+    }
+
+    pub const fn next(self) -> ZpAddr {
+        ZpAddr(self.0.wrapping_add(1))
+    }
+}
+
+impl TryFrom<Addr> for ZpAddr {
+    type Error = String;
+
+    fn try_from(addr: Addr) -> Result<Self, Self::Error> {
+        u8::try_from(addr.0).map(ZpAddr).map_err(|_| format!("${:04X} is not in the zero page", addr.0))
+    }
+}
+
+/// What an instruction's operand bytes resolve to
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum ResolvedOperand {
+    /// Immediate and relative operands are a value in the instruction itself
+    Value(u8),
+    /// Memory the instruction reads or writes
+    Address(u16),
+    /// Implicit and accumulator instructions have no operand
+    None,
+}
+
+impl AddressMode {
+    /// Formats an operand as assembly text for this addressing mode.
+    /// Returns None for modes without an operand.
+    pub fn format_operand(&self, operand: &Operand) -> Option<String> {
+        use AddressMode::*;
+        match (self, operand) {
+            (Implicit, Operand::None) => None,
+            (Accumulator, Operand::None) => Some("A".into()), // may need to replace this
+            // `$` so values like #e7 don't read back as a symbol
+            (Immediate, Operand::Word(op)) => Some(format!("#${:02x}", op)),
+            (ZeroPage, Operand::Word(op)) => Some(format!("${:02x}", op)),
+            (ZeroPageX, Operand::Word(op)) => Some(format!("${:02x},X", op)),
+            (ZeroPageY, Operand::Word(op)) => Some(format!("${:02x},Y", op)),
+            (Relative, Operand::Word(op)) => Some(format!("*{:+}", *op as i8)),
+            (Absolute, Operand::DoubleWord(op)) => Some(format!("${:04x}", op)),
+            (AbsoluteX, Operand::DoubleWord(op)) => Some(format!("${:04x},X", op)),
+            (AbsoluteY, Operand::DoubleWord(op)) => Some(format!("${:04x},Y", op)),
+            (Indirect, Operand::DoubleWord(op)) => Some(format!("(${:04x})", op)),
+            (IndirectX, Operand::Word(op)) => Some(format!("(${:02x},X)", op)),
+            (IndirectY, Operand::Word(op)) => Some(format!("(${:02x}),Y", op)),
+            (mode, op) => panic!("AddressMode Error: {:?} is not a valid operand for {:?} addressing.", op, mode),
+        }
+    }
+
+    /// Parses operand text (as produced by `format_operand`) into an operand and its mode.
+    /// An empty string is an implicit operand.
+    #[cfg(feature = "assembler")]
+    pub fn parse_operand(text: &str) -> Option<(Operand, AddressMode)> {
+        crate::cpu::prog::parse_operand(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::AddressMode::*;
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_operand_format_parse_round_trip() {
+        let cases = [
+            (Implicit, Operand::None),
+            (Accumulator, Operand::None),
+            (Immediate, Operand::Word(0x23)),
+            (ZeroPage, Operand::Word(0x10)),
+            (ZeroPageX, Operand::Word(0x10)),
+            (ZeroPageY, Operand::Word(0x10)),
+            (Relative, Operand::Word(-5_i8 as u8)),
+            (Relative, Operand::Word(0x10)),
+            (Absolute, Operand::DoubleWord(0x1234)),
+            (AbsoluteX, Operand::DoubleWord(0x1234)),
+            (AbsoluteY, Operand::DoubleWord(0x1234)),
+            (Indirect, Operand::DoubleWord(0x1234)),
+            (IndirectX, Operand::Word(0x10)),
+            (IndirectY, Operand::Word(0x10)),
+        ];
+
+        for (mode, operand) in cases {
+            let text = mode.format_operand(&operand).unwrap_or_default();
+            assert_eq!(AddressMode::parse_operand(&text), Some((operand, mode)), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_address_arithmetic() {
+        assert_eq!(Addr(0x12FF).index(0x01), Addr(0x1300));
+        assert_eq!(Addr(0xFFFF).next(), Addr(0x0000));
+        assert_eq!(Addr(0x12FF).next_in_page(), Addr(0x1200));
+        assert_eq!(Addr(0x12F8).index_without_carry(0x10), Addr(0x1208));
+        assert!(Addr(0x12FF).crosses_page(Addr(0x12FF).index(1)));
+        assert!(!Addr(0x1200).crosses_page(Addr(0x1200).index(0xFF)));
+
+        assert_eq!(ZpAddr(0xFF).next(), ZpAddr(0x00));
+        assert_eq!(ZpAddr(0x80).index(0x90), ZpAddr(0x10));
+        assert_eq!(Addr::from(ZpAddr(0x10)), Addr(0x0010));
+        assert_eq!(ZpAddr::try_from(Addr(0x00FF)), Ok(ZpAddr(0xFF)));
+        assert!(ZpAddr::try_from(Addr(0x0100)).is_err());
+    }
+
+    #[test]
+    fn test_format_relative_is_signed() {
+        assert_eq!(Relative.format_operand(&Operand::Word(0xFB)), Some("*-5".into()));
+    }
+}
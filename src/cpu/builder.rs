@@ -0,0 +1,115 @@
+//! One place to set up a CPU for embedding, instead of a sequence of setters.
+//!
+//! ```no_run
+//! # use nes_rs::CPU;
+//! # use nes_rs::memory::MemoryInit;
+//! # fn load(cart: nes_rs::cart::Cartridge) -> Result<(), Box<dyn std::error::Error>> {
+//! let cpu = CPU::builder()
+//!     .with_memory_init(MemoryInit::Random(7))
+//!     .with_strict_rom()
+//!     .with_cartridge(cart)
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error::Error;
+use std::ops::RangeInclusive;
+
+use super::CPU;
+use crate::cart::Cartridge;
+use crate::memory::{MemoryInit, Peripheral};
+
+#[derive(Default)]
+pub struct CpuBuilder {
+    memory_init: MemoryInit,
+    strict_rom: bool,
+    cartridge: Option<Cartridge>,
+    nrom_fallback: bool,
+    peripherals: Vec<(RangeInclusive<u16>, Box<dyn Peripheral>)>,
+}
+
+impl CpuBuilder {
+    pub fn new() -> Self {
+        CpuBuilder::default()
+    }
+
+    /// Power-on contents of internal RAM
+    pub fn with_memory_init(mut self, init: MemoryInit) -> Self {
+        self.memory_init = init;
+        self
+    }
+
+    /// Records writes to ROM for `CPU::rom_writes`
+    pub fn with_strict_rom(mut self) -> Self {
+        self.strict_rom = true;
+        self
+    }
+
+    /// Installs the cartridge and resets into it
+    pub fn with_cartridge(mut self, cartridge: Cartridge) -> Self {
+        self.cartridge = Some(cartridge);
+        self
+    }
+
+    /// Runs carts with unsupported mappers as NROM, see `Cartridge::install`
+    pub fn with_nrom_fallback(mut self) -> Self {
+        self.nrom_fallback = true;
+        self
+    }
+
+    pub fn with_peripheral(mut self, range: RangeInclusive<u16>, peripheral: Box<dyn Peripheral>) -> Self {
+        self.peripherals.push((range, peripheral));
+        self
+    }
+
+    /// Fails if the cartridge can't be installed
+    pub fn build(self) -> Result<CPU, Box<dyn Error>> {
+        let mut cpu = CPU::power_on(self.memory_init);
+        cpu.set_strict_rom(self.strict_rom);
+        for (range, peripheral) in self.peripherals {
+            cpu.map_peripheral(range, peripheral);
+        }
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.install(&mut cpu, self.nrom_fallback)?;
+            cpu.interrupt_reset();
+        }
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cart::{HeaderlessOptions, PRG_BANK_SIZE};
+    use crate::input::{ControllerPorts, CONTROLLER_PORTS};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_build() {
+        let mut prg_rom = vec![0xEA; PRG_BANK_SIZE];
+        // STA $8000, then the reset vector
+        prg_rom[..3].copy_from_slice(&[0x8D, 0x00, 0x80]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        let mut cart = Cartridge::headerless(&prg_rom, &HeaderlessOptions::default()).unwrap();
+        let pads = Rc::new(Cell::new([0xFF, 0, 0, 0]));
+
+        let mut cpu = CPU::builder()
+            .with_memory_init(MemoryInit::FF)
+            .with_strict_rom()
+            .with_cartridge(cart.clone())
+            .with_peripheral(CONTROLLER_PORTS, Box::new(ControllerPorts::new(pads)))
+            .build()
+            .unwrap();
+        assert_eq!(cpu.registers().pc, 0x8000);
+        assert_eq!(cpu.read(0x0010), 0xFF);
+        cpu.step_next();
+        assert_eq!(cpu.rom_writes().len(), 1);
+        assert_eq!(cpu.read(0x4016), 1);
+
+        cart.mapper = 1;
+        assert!(CPU::builder().with_cartridge(cart.clone()).build().is_err());
+        assert!(CPU::builder().with_cartridge(cart).with_nrom_fallback().build().is_ok());
+    }
+}
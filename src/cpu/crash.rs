@@ -0,0 +1,199 @@
+//! Crash reports: what the CPU was doing when a program halted or the
+//! emulator panicked, gathered into one file to attach to a bug report.
+//!
+//! Keep a `Recorder` fed from the run loop so the last instructions are on
+//! hand, then `capture` a report when `step_next` returns a halt, or wrap the
+//! run in `catch` to get one for a panic instead of a process abort.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::debugger::trace_line;
+use super::ops::JAM_OPCODES;
+use super::reg::RegisterSet;
+use super::{StopReason, CPU};
+use crate::cart::rom_hash;
+use crate::memory::hexdump;
+
+/// Instructions a `Recorder` keeps by default
+pub const RECORDER_LEN: usize = 64;
+/// Bytes dumped either side of PC
+const PC_WINDOW: u16 = 32;
+
+/// Keeps a trace of the last instructions run
+pub struct Recorder {
+    lines: VecDeque<String>,
+    len: usize,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder::new(RECORDER_LEN)
+    }
+}
+
+impl Recorder {
+    pub fn new(len: usize) -> Self {
+        Recorder { lines: VecDeque::with_capacity(len), len }
+    }
+
+    /// Notes the instruction at PC, call before stepping it
+    pub fn record(&mut self, cpu: &CPU) {
+        if self.len == 0 {
+            return;
+        }
+        if self.lines.len() == self.len {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(trace_line(cpu, &BTreeMap::new()).1);
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &str> + '_ {
+        self.lines.iter().map(String::as_str)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CrashReport {
+    pub reason: String,
+    pub registers: RegisterSet,
+    /// Oldest first, the instruction that crashed last when it was recorded
+    pub trace: Vec<String>,
+    pub zero_page: Vec<u8>,
+    pub stack: Vec<u8>,
+    /// Start address and contents of the memory around PC
+    pub around_pc: (u16, Vec<u8>),
+    pub rom_hash: Option<u64>,
+}
+
+impl CrashReport {
+    /// Snapshots the CPU, reading memory without going through peripherals
+    pub fn capture(cpu: &CPU, reason: impl Into<String>, recorder: &Recorder, rom: Option<&[u8]>) -> Self {
+        let pc = cpu.registers().pc;
+        let start = pc.saturating_sub(PC_WINDOW);
+        let end = pc.saturating_add(PC_WINDOW);
+        let dump = |range: std::ops::RangeInclusive<u16>| range.map(|addr| cpu.peek(addr)).collect::<Vec<_>>();
+        CrashReport {
+            reason: reason.into(),
+            registers: *cpu.registers(),
+            trace: recorder.lines().map(String::from).collect(),
+            zero_page: dump(0x0000..=0x00FF),
+            stack: dump(0x0100..=0x01FF),
+            around_pc: (start, dump(start..=end)),
+            rom_hash: rom.map(rom_hash),
+        }
+    }
+
+    /// Describes why `step_next` stopped, for `capture`
+    pub fn describe_stop(reason: StopReason) -> String {
+        match reason {
+            StopReason::Break => "stopped on BRK".to_string(),
+            StopReason::Halted { opcode, pc } if JAM_OPCODES.contains(&opcode) => {
+                format!("jammed by opcode {:#04x} at ${:04X}", opcode, pc)
+            }
+            StopReason::Halted { opcode, pc } => format!("opcode {:#04x} at ${:04X} isn't emulated", opcode, pc),
+        }
+    }
+
+    /// Runs `f`, turning a panic inside it into a report on the CPU as it was left
+    pub fn catch<R>(cpu: &mut CPU, recorder: &Recorder, rom: Option<&[u8]>, f: impl FnOnce(&mut CPU) -> R) -> Result<R, Box<CrashReport>> {
+        panic::catch_unwind(AssertUnwindSafe(|| f(cpu))).map_err(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Box::new(CrashReport::capture(cpu, format!("panicked: {}", message), recorder, rom))
+        })
+    }
+
+    /// Writes the report into `dir` as `crash-SECONDS.txt`, returning its path
+    pub fn write_to_dir(&self, dir: impl AsRef<Path>) -> Result<PathBuf, Box<dyn Error>> {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        fs::create_dir_all(&dir)?;
+        let path = dir.as_ref().join(format!("crash-{}.txt", seconds));
+        fs::write(&path, self.to_string())?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let reg = &self.registers;
+        writeln!(f, "nes-rs crash report")?;
+        writeln!(f, "reason: {}", self.reason)?;
+        if let Some(hash) = self.rom_hash {
+            writeln!(f, "ROM: {:016x}", hash)?;
+        }
+        writeln!(f, "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{} SP:{:02X}", reg.pc, reg.a, reg.x, reg.y, reg.p, reg.sp)?;
+
+        writeln!(f, "\nlast {} instructions:", self.trace.len())?;
+        for line in &self.trace {
+            writeln!(f, "{}", line)?;
+        }
+        let (start, around_pc) = &self.around_pc;
+        writeln!(f, "\nmemory around PC:\n{}", hexdump(around_pc, *start as usize))?;
+        writeln!(f, "\nzero page:\n{}", hexdump(&self.zero_page, 0x0000))?;
+        writeln!(f, "\nstack:\n{}", hexdump(&self.stack, 0x0100))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LDX #$05, PHA, DEX, an opcode that isn't emulated
+    const CRASHES: [u8; 5] = [0xA2, 0x05, 0x48, 0xCA, 0x80];
+
+    #[test]
+    fn test_capture_on_halt() {
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&CRASHES);
+        cpu.interrupt_reset();
+        let mut recorder = Recorder::new(2);
+
+        let reason = loop {
+            recorder.record(&cpu);
+            if let Some(reason) = cpu.step_next() {
+                break reason;
+            }
+        };
+        assert_eq!(reason, StopReason::Halted { opcode: 0x80, pc: 0x0604 });
+
+        let report = CrashReport::capture(&cpu, CrashReport::describe_stop(reason), &recorder, Some(&CRASHES));
+        assert_eq!(report.reason, "opcode 0x80 at $0604 isn't emulated");
+        assert_eq!(report.trace.len(), 2);
+        assert!(report.trace[0].starts_with("0603  DEX"));
+        assert!(report.trace[1].starts_with("0604  .byte $80"));
+        assert_eq!(report.around_pc.0, 0x0604 - PC_WINDOW);
+        assert_eq!(report.around_pc.1[PC_WINDOW as usize], 0x80);
+        assert_eq!(report.stack.len(), 0x100);
+
+        let dir = std::env::temp_dir().join(format!("nes-rs-crash-{}", std::process::id()));
+        let path = report.write_to_dir(&dir).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(text.starts_with(&format!("nes-rs crash report\nreason: opcode 0x80 at $0604 isn't emulated\nROM: {:016x}\nPC:0604 A:00 X:04", rom_hash(&CRASHES))), "{}", text);
+        assert!(text.contains("\nstack:\n"), "{}", text);
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let mut cpu = CPU::new();
+        let recorder = Recorder::default();
+        assert_eq!(CrashReport::catch(&mut cpu, &recorder, None, |_| 7), Ok(7));
+
+        let report = CrashReport::catch(&mut cpu, &recorder, None, |cpu| {
+            cpu.registers_mut().a = 0x42;
+            panic!("ERROR: oops {}", 1);
+        })
+        .unwrap_err();
+        assert_eq!(report.reason, "panicked: ERROR: oops 1");
+        assert_eq!(report.registers.a, 0x42);
+    }
+}
@@ -0,0 +1,190 @@
+//! Cycle counts of executed code, for locking in timing with `assert_cycles!`.
+//!
+//! The CPU doesn't count cycles as it runs yet, so each instruction is costed
+//! from its `Timing`, with the page crossing and branch penalties it actually
+//! hit worked out from the registers before the step and the PC after it.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use super::addr::{Addr, AddressMode, ZpAddr};
+use super::ops::{Mnemonic, Penalty};
+use super::prog::asm;
+use super::prog::instructions::{Instruction, Operand};
+use super::prog::fmt_listing_line;
+use super::reg::Status;
+use super::{StopReason, CPU};
+
+/// Where snippets are assembled and run
+pub const SNIPPET_ORIGIN: u16 = 0x0600;
+const STEP_LIMIT: usize = 100_000;
+
+/// One executed instruction and what it cost
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CycleStep {
+    pub addr: u16,
+    pub instruction: Instruction,
+    pub cycles: u32,
+}
+
+/// Every instruction a snippet executed, in order
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CycleTrace {
+    pub steps: Vec<CycleStep>,
+}
+
+impl CycleTrace {
+    pub fn total(&self) -> u32 {
+        self.steps.iter().map(|step| step.cycles).sum()
+    }
+
+    /// Per instruction cycles with a running total, and the difference from `expected`
+    pub fn report(&self, expected: u32) -> String {
+        let mut out = String::from("cycles  total  instruction\n");
+        let mut total = 0;
+        for step in &self.steps {
+            total += step.cycles;
+            let _ = writeln!(out, "{:6}  {:5}  {}", step.cycles, total, fmt_listing_line(step.addr, &step.instruction));
+        }
+        let _ = write!(out, "expected {} cycles, took {} ({:+})", expected, total, total as i64 - expected as i64);
+        out
+    }
+}
+
+/// Assembles `source` at `SNIPPET_ORIGIN` and runs it on a fresh CPU up to
+/// its end or the first BRK, which isn't counted
+pub fn run(source: &str) -> Result<CycleTrace, Box<dyn Error>> {
+    let assembly = asm::assemble(&format!(".org ${:04X}\n{}\nBRK", SNIPPET_ORIGIN, source))?;
+    let mut cpu = CPU::new();
+    cpu.load(assembly.origin, &assembly.bytes);
+    cpu.load(CPU::PRG_START_ADDR, &assembly.origin.to_le_bytes());
+    cpu.interrupt_reset();
+
+    let mut trace = CycleTrace::default();
+    for _ in 0..STEP_LIMIT {
+        let addr = cpu.registers().pc;
+        let cost = PendingCost::at_pc(&cpu);
+
+        match cpu.step_next() {
+            Some(StopReason::Break) => return Ok(trace),
+            Some(reason) => return Err(format!("stopped at ${:04X}, {:?}", addr, reason).into()),
+            None => {}
+        }
+        let cost = cost.ok_or_else(|| format!("no timing for opcode {:#04x} at ${:04X}", cpu.read(addr), addr))?;
+        let cycles = cost.cycles(cpu.registers().pc);
+        trace.steps.push(CycleStep { addr, instruction: cost.instruction, cycles });
+    }
+    Err(format!("still running after {} instructions", STEP_LIMIT).into())
+}
+
+/// The cost of the instruction at PC. The penalties it hits are worked out
+/// before it's stepped and finished off with the PC it leaves behind.
+pub(crate) struct PendingCost {
+    pub instruction: Instruction,
+    next: u16,
+    page_crossed: bool,
+    taken: bool,
+}
+
+impl PendingCost {
+    /// None when the opcode at PC has no timing
+    pub fn at_pc(cpu: &CPU) -> Option<Self> {
+        let addr = cpu.registers().pc;
+        let bytes: Vec<u8> = (0..3).map(|i| cpu.read(addr.wrapping_add(i))).collect();
+        let instruction = Instruction::decode(&bytes)?;
+        Some(PendingCost {
+            next: addr.wrapping_add(instruction.size()),
+            page_crossed: crosses_page(cpu, &instruction),
+            taken: branch_taken(cpu.registers().p, &instruction),
+            instruction,
+        })
+    }
+
+    pub fn cycles(&self, pc_after: u16) -> u32 {
+        let timing = self.instruction.opcode().timing();
+        let penalty = match timing.penalty {
+            Penalty::None => 0,
+            Penalty::PageCross => self.page_crossed as u32,
+            Penalty::Branch if !self.taken => 0,
+            Penalty::Branch => 1 + (pc_after >> 8 != self.next >> 8) as u32,
+        };
+        timing.base as u32 + penalty
+    }
+}
+
+/// Whether a branch instruction's condition holds for the flags in `p`
+fn branch_taken(p: Status, instruction: &Instruction) -> bool {
+    let (flag, set) = match instruction.opcode().mnemonic {
+        Mnemonic::BPL => (Status::NEGATIVE, false),
+        Mnemonic::BMI => (Status::NEGATIVE, true),
+        Mnemonic::BVC => (Status::OVERFLOW, false),
+        Mnemonic::BVS => (Status::OVERFLOW, true),
+        Mnemonic::BCC => (Status::CARRY, false),
+        Mnemonic::BCS => (Status::CARRY, true),
+        Mnemonic::BNE => (Status::ZERO, false),
+        Mnemonic::BEQ => (Status::ZERO, true),
+        _ => return false,
+    };
+    p.contains(flag) == set
+}
+
+/// Whether indexing the instruction's operand carries into the next page
+fn crosses_page(cpu: &CPU, instruction: &Instruction) -> bool {
+    let reg = cpu.registers();
+    let (base, index) = match (instruction.opcode().mode, instruction.operand()) {
+        (AddressMode::AbsoluteX, &Operand::DoubleWord(addr)) => (Addr(addr), reg.x),
+        (AddressMode::AbsoluteY, &Operand::DoubleWord(addr)) => (Addr(addr), reg.y),
+        (AddressMode::IndirectY, &Operand::Word(zp)) => {
+            let zp = ZpAddr(zp);
+            let pointer = u16::from_le_bytes([cpu.read(Addr::from(zp).0), cpu.read(Addr::from(zp.next()).0)]);
+            (Addr(pointer), reg.y)
+        }
+        _ => return false,
+    };
+    base.crosses_page(base.index(index))
+}
+
+/// Runs an assembly snippet and asserts how many cycles it took, printing
+/// the cost of each instruction when it doesn't match
+///
+/// ```
+/// nes_rs::assert_cycles!("LDX #$02\nloop:\nDEX\nBNE loop", 2 + 2 * 2 + 3 + 2);
+/// ```
+#[macro_export]
+macro_rules! assert_cycles {
+    ($source:expr, $expected:expr) => {{
+        let trace = $crate::cpu::cycles::run($source).unwrap_or_else(|e| panic!("ERROR: snippet failed to run: {}", e));
+        let expected: u32 = $expected;
+        assert!(trace.total() == expected, "\n{}", trace.report(expected));
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_penalties() {
+        // LDA $02F0,X with X = $20 crosses into page 3, STA abs,X always costs 5
+        assert_cycles!("LDX #$20\nLDA $02F0,X\nSTA $02F0,X", 2 + 5 + 5);
+        assert_cycles!("LDX #$01\nLDA $0200,X", 2 + 4);
+        // ($10),Y through $02FF
+        assert_cycles!("LDA #$FF\nSTA $10\nLDA #$02\nSTA $11\nLDY #$01\nLDA ($10),Y", 2 + 3 + 2 + 3 + 2 + 6);
+        // not taken, then taken within the page
+        assert_cycles!("LDA #$01\nBEQ skip\nBNE skip\nskip:\nNOP", 2 + 2 + 3 + 2);
+
+        // a taken branch at $06FC, so from $06FE, over the page boundary
+        let trace = run(".org $06FB\nSEC\nBCS far\nNOP\n.org $0710\nfar:\nNOP").unwrap();
+        assert_eq!(trace.steps.iter().map(|step| step.cycles).collect::<Vec<_>>(), vec![2, 4, 2]);
+    }
+
+    #[test]
+    fn test_report() {
+        let trace = run("LDA #$01\nNOP").unwrap();
+        assert_eq!(
+            trace.report(5),
+            "cycles  total  instruction\n     2      2  0600  A9 01     LDA #$01\n     2      4  0602  EA        NOP\nexpected 5 cycles, took 4 (-1)"
+        );
+        assert!(run("JMP $0600").is_err());
+    }
+}
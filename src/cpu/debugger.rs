@@ -0,0 +1,1065 @@
+//! Debugger driven by text commands, for a console pane or a startup script.
+//!
+//! | command             | effect                                                  |
+//! |---------------------|---------------------------------------------------------|
+//! | `b ADDR`            | set a breakpoint, `d ADDR` deletes it                   |
+//! | `w ADDR`            | stop when the byte at ADDR changes, `uw` removes it     |
+//! | `step [N]`          | run N instructions, default 1                           |
+//! | `c`                 | run until a breakpoint, watchpoint or the CPU stops     |
+//! | `trace on FILE`     | log every instruction to FILE, `trace off` stops        |
+//! | `trace only WHAT`   | log only some instructions, `trace all` logs every one  |
+//! | `trace ring N`      | keep the last N traced instructions in memory           |
+//! | `trace dump [FILE]` | show the kept instructions, or write them to FILE       |
+//! | `sym NAME ADDR`     | define a symbol                                         |
+//! | `symfile FILE`      | define the labels and constants in an assembly file     |
+//! | `vectors`           | list the NMI, reset and IRQ vectors                     |
+//! | `stack [all]`       | show the stack in use, or the whole of page 1           |
+//! | `vector NAME ADDR`  | point a vector at ADDR, `vector NAME restore` undoes it |
+//! | `smc on`            | stop on writes to code that has run, `smc off` stops    |
+//! | `rewind N`          | keep the last N instructions run, to step back through  |
+//! | `back [N]`          | undo the last N instructions, default 1                 |
+//! | `io [REG [VALUE]]`  | list device registers, or decode one's fields           |
+//! | `heatmap on`        | count accesses to each address, with `heatmap`          |
+//! | `heatmap FILE`      | write the counts as CSV, or as a PNG for a `.png` FILE  |
+//!
+//! Addresses are `$8000`, `0x8000`, decimal, symbol names, or `ram[...]`
+//! around any of those, which also checks it's in internal RAM. Symbols in
+//! the zero page name the operands of instructions in the trace log, so
+//! `LDA $10` is logged as `LDA snake_head_lo` after `sym snake_head_lo $10`.
+//!
+//! Breakpoints, watchpoints and symbols can be kept between sessions in a
+//! sidecar file named after a hash of the ROM, written as a script of the
+//! commands above.
+//!
+//! `trace only` takes any mix of address ranges like `$8000-$80FF`,
+//! mnemonics, `branches` for anything that can jump, and `writes` for stores
+//! and read-modify-write instructions on memory. An instruction is traced if
+//! it's in one of the ranges, when there are any, and matches one of the
+//! others, when there are any. The filter applies to the ring buffer too,
+//! which is cheap enough to leave on and dump when a breakpoint or crash hits.
+//!
+//! The stack view marks return addresses which follow a JSR, and guesses at
+//! status bytes pushed by interrupts, which have bit 5 set and a return
+//! address above them.
+//!
+//! `smc on` marks the bytes of each instruction as it runs. A write to a
+//! marked byte stops with what wrote it, and unmarks it, so running the new
+//! code and changing it again stops again. Only instructions run by the
+//! debugger are marked, and writes to peripherals aren't seen.
+//!
+//! Stepping back restores the registers, the interrupt lines and every byte
+//! the program wrote. Peripherals aren't rewound, and neither are changes
+//! made from outside the program, such as `vector` or `CPU::poke`.
+//!
+//! `io` takes a register's address or name, like `$4016` or `JOY1`, and
+//! shows the fields of what reads return and what writes do, as the
+//! peripheral describes them. Reading a register can change it, so there's
+//! no current value to show, but one given as VALUE is split into fields.
+//!
+//! The `heatmap` commands are only there with the `heatmap` feature.
+//!
+//! Stepping and continuing also stop when the watchdog spots the program
+//! stuck or running away, such as jumping to itself with interrupts masked
+//! or executing from the stack, rather than spinning until the limit.
+//!
+//! Vector overrides write straight over ROM with `CPU::poke` for trying out
+//! handlers. They aren't saved with the session.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use super::addr::AddressMode;
+use super::ops::{Mnemonic, Opcode};
+use super::prog::asm::{Assembler, VECTOR_LABELS};
+use super::prog::instructions::Instruction;
+use super::reg::Status;
+use super::watchdog::{Diagnostic, Watchdog};
+use super::{CoreState, StopReason, CPU};
+use crate::cart::rom_hash;
+use crate::memory::Field;
+
+const JSR: u8 = 0x20;
+
+/// Instructions `c` runs before giving control back, in case nothing ever stops it
+pub const CONTINUE_LIMIT: u64 = 10_000_000;
+
+/// Why stepping or continuing ended
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop {
+    /// About to execute a breakpointed address
+    Breakpoint(u16),
+    /// The last instruction changed a watched byte
+    Watchpoint { addr: u16, old: u8, new: u8 },
+    /// The last instruction, at `pc`, wrote over code that had run
+    SelfModifying { addr: u16, pc: u16, old: u8, new: u8 },
+    Cpu(StopReason),
+    /// The watchdog caught the program stuck or running away
+    Watchdog(Diagnostic),
+    /// Ran the requested number of instructions
+    Stepped(u64),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Stop::Breakpoint(addr) => write!(f, "breakpoint at ${:04X}", addr),
+            Stop::Watchpoint { addr, old, new } => write!(f, "${:04X} changed from ${:02X} to ${:02X}", addr, old, new),
+            Stop::SelfModifying { addr, pc, old, new } => {
+                write!(f, "instruction at ${:04X} changed code at ${:04X} from ${:02X} to ${:02X}", pc, addr, old, new)
+            }
+            Stop::Cpu(StopReason::Break) => write!(f, "stopped on BRK"),
+            Stop::Cpu(StopReason::Halted { opcode, pc }) => write!(f, "halted by opcode {:#04x} at ${:04X}", opcode, pc),
+            Stop::Watchdog(diagnostic) => write!(f, "watchdog: {}", diagnostic),
+            Stop::Stepped(1) => write!(f, "stepped 1 instruction"),
+            Stop::Stepped(n) => write!(f, "stepped {} instructions", n),
+        }
+    }
+}
+
+/// Which instructions are traced, set by `trace only`
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct TraceFilter {
+    pub ranges: Vec<RangeInclusive<u16>>,
+    pub mnemonics: Vec<Mnemonic>,
+    /// Branches, jumps, calls and returns
+    pub branches: bool,
+    /// Stores and read-modify-write instructions on memory
+    pub writes: bool,
+}
+
+impl TraceFilter {
+    /// Whether the instruction at `addr` is traced. Undecodable bytes only pass address ranges.
+    pub fn matches(&self, addr: u16, instruction: Option<&Instruction>) -> bool {
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|range| range.contains(&addr)) {
+            return false;
+        }
+        if self.mnemonics.is_empty() && !self.branches && !self.writes {
+            return true;
+        }
+        let Some(opcode) = instruction.map(Instruction::opcode) else {
+            return false;
+        };
+        use Mnemonic::*;
+        let branch = opcode.mode == AddressMode::Relative || matches!(opcode.mnemonic, JMP | JSR | RTS | RTI | BRK);
+        let write = matches!(opcode.mnemonic, STA | STX | STY | INC | DEC)
+            || matches!(opcode.mnemonic, ASL | LSR | ROL | ROR) && opcode.mode != AddressMode::Accumulator;
+        self.mnemonics.contains(&opcode.mnemonic) || self.branches && branch || self.writes && write
+    }
+
+    /// Adds one `trace only` argument
+    fn add(&mut self, word: &str, parse_addr: impl Fn(&str) -> Result<u16, String>) -> Result<(), String> {
+        match word {
+            "branches" => self.branches = true,
+            "writes" => self.writes = true,
+            _ => match word.split_once('-') {
+                Some((start, end)) => self.ranges.push(parse_addr(start)?..=parse_addr(end)?),
+                None => self.mnemonics.push(word.to_ascii_uppercase().parse()?),
+            },
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    /// Watched addresses with the value last seen there
+    watchpoints: BTreeMap<u16, u8>,
+    symbols: BTreeMap<String, u16>,
+    history: Vec<String>,
+    trace: Option<BufWriter<File>>,
+    trace_filter: TraceFilter,
+    /// The last traced instructions, up to `ring_len` of them
+    ring: VecDeque<String>,
+    ring_len: usize,
+    /// Vectors changed by `vector`, with the target they had before
+    vector_overrides: BTreeMap<u16, u16>,
+    /// How to undo the last instructions run, up to `rewind_len` of them
+    rewind: VecDeque<StepRecord>,
+    rewind_len: usize,
+    /// Which addresses have been run as code, while `smc` is on
+    executed: Option<Vec<bool>>,
+    watchdog: Watchdog,
+}
+
+/// The state before an instruction, and the old value of each byte it wrote
+struct StepRecord {
+    state: CoreState,
+    writes: Vec<(u16, u8)>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    pub fn symbols(&self) -> &BTreeMap<String, u16> {
+        &self.symbols
+    }
+
+    /// Adds symbols, such as an assembler's labels, replacing any of the same name
+    pub fn load_symbols(&mut self, symbols: &BTreeMap<String, u16>) {
+        self.symbols.extend(symbols.iter().map(|(name, &addr)| (name.clone(), addr)));
+    }
+
+    /// Defines every label and constant in an assembly source file, such as
+    /// a file of `name = $10` lines written while reverse engineering a game
+    pub fn load_symbol_file(&mut self, path: impl AsRef<Path>) -> Result<usize, Box<dyn Error>> {
+        let path = path.as_ref();
+        let assembly = Assembler::new().assemble_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.load_symbols(&assembly.symbols);
+        Ok(assembly.symbols.len())
+    }
+
+    /// Names for zero page addresses, the first symbol in name order for each
+    pub fn zero_page_names(&self) -> BTreeMap<u16, String> {
+        let mut names = BTreeMap::new();
+        for (name, &addr) in self.symbols.iter().filter(|(_, &addr)| addr < 0x100) {
+            names.entry(addr).or_insert_with(|| name.clone());
+        }
+        names
+    }
+
+    /// Instructions kept by `trace ring`, oldest first
+    pub fn ring(&self) -> impl Iterator<Item = &str> + '_ {
+        self.ring.iter().map(String::as_str)
+    }
+
+    /// Undoes the last instruction run, if `rewind` kept it. False when
+    /// there's nothing left to undo.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let Some(record) = self.rewind.pop_back() else {
+            return false;
+        };
+        for &(addr, old) in record.writes.iter().rev() {
+            cpu.poke(addr, old);
+        }
+        cpu.restore_core_state(record.state);
+        for (&addr, seen) in self.watchpoints.iter_mut() {
+            *seen = cpu.read(addr);
+        }
+        true
+    }
+
+    /// Commands entered through `execute`, oldest first
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Symbol names starting with `prefix`, for completing a word being typed
+    pub fn complete(&self, prefix: &str) -> Vec<&str> {
+        self.symbols.keys().map(String::as_str).filter(|name| name.starts_with(prefix)).collect()
+    }
+
+    /// Runs one command typed by the user and records it in the history.
+    /// Returns text to show in the console.
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<String, Box<dyn Error>> {
+        let line = line.trim();
+        if !line.is_empty() && self.history.last().map(String::as_str) != Some(line) {
+            self.history.push(line.to_string());
+        }
+        self.command(cpu, line)
+    }
+
+    /// Runs every command in a script, one per line. Blank lines and lines
+    /// starting with `#` are skipped. Stops at the first failing command.
+    pub fn run_script(&mut self, cpu: &mut CPU, source: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut output = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = self.command(cpu, line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            output.push(result);
+        }
+        Ok(output)
+    }
+
+    /// Runs a script file, e.g. one given on the command line at startup
+    pub fn run_script_file(&mut self, cpu: &mut CPU, path: impl AsRef<Path>) -> Result<Vec<String>, Box<dyn Error>> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.run_script(cpu, &source).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Where `save_session` keeps the state for `rom`, inside `dir`
+    pub fn session_path(dir: impl AsRef<Path>, rom: &[u8]) -> PathBuf {
+        dir.as_ref().join(format!("{:016x}.dbg", rom_hash(rom)))
+    }
+
+    /// Writes breakpoints, watchpoints and symbols as a script that `load_session` replays
+    pub fn save_session(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut script = String::from("# nes-rs debugger session\n");
+        for (name, addr) in &self.symbols {
+            script += &format!("sym {} ${:04X}\n", name, addr);
+        }
+        for addr in &self.breakpoints {
+            script += &format!("b ${:04X}\n", addr);
+        }
+        for addr in self.watchpoints.keys() {
+            script += &format!("w ${:04X}\n", addr);
+        }
+        fs::write(path, script)?;
+        Ok(())
+    }
+
+    /// Replays a session saved by `save_session`. Returns false if there isn't one yet.
+    pub fn load_session(&mut self, cpu: &mut CPU, path: impl AsRef<Path>) -> Result<bool, Box<dyn Error>> {
+        if !path.as_ref().exists() {
+            return Ok(false);
+        }
+        self.run_script_file(cpu, path)?;
+        Ok(true)
+    }
+
+    fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<String, Box<dyn Error>> {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(String::new());
+        };
+        let args: Vec<&str> = words.collect();
+
+        match (name, args.as_slice()) {
+            ("b", [addr]) => {
+                let addr = self.parse_addr(addr)?;
+                self.breakpoints.insert(addr);
+                Ok(format!("breakpoint at ${:04X}", addr))
+            }
+            ("d", [addr]) => {
+                let addr = self.parse_addr(addr)?;
+                match self.breakpoints.remove(&addr) {
+                    true => Ok(format!("deleted breakpoint at ${:04X}", addr)),
+                    false => Err(format!("no breakpoint at ${:04X}", addr).into()),
+                }
+            }
+            ("w", [addr]) => {
+                let addr = self.parse_addr(addr)?;
+                self.watchpoints.insert(addr, cpu.read(addr));
+                Ok(format!("watching ${:04X}", addr))
+            }
+            ("uw", [addr]) => {
+                let addr = self.parse_addr(addr)?;
+                match self.watchpoints.remove(&addr) {
+                    Some(_) => Ok(format!("stopped watching ${:04X}", addr)),
+                    None => Err(format!("${:04X} isn't watched", addr).into()),
+                }
+            }
+            ("step", []) => Ok(self.run(cpu, 1)?.to_string()),
+            ("step", [count]) => {
+                let count = count.parse().map_err(|_| format!("invalid step count `{}`", count))?;
+                Ok(self.run(cpu, count)?.to_string())
+            }
+            ("c", []) => Ok(self.run(cpu, CONTINUE_LIMIT)?.to_string()),
+            ("trace", ["on", path]) => {
+                self.trace = Some(BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?));
+                Ok(format!("tracing to {}", path))
+            }
+            ("trace", ["off"]) => {
+                if let Some(mut trace) = self.trace.take() {
+                    trace.flush()?;
+                }
+                Ok("tracing off".to_string())
+            }
+            ("trace", ["only", what @ ..]) if !what.is_empty() => {
+                let mut filter = self.trace_filter.clone();
+                for word in what {
+                    filter.add(word, |text| self.parse_addr(text))?;
+                }
+                self.trace_filter = filter;
+                Ok(format!("tracing only {}", what.join(" ")))
+            }
+            ("trace", ["all"]) => {
+                self.trace_filter = TraceFilter::default();
+                Ok("tracing every instruction".to_string())
+            }
+            ("trace", ["ring", len]) => {
+                self.ring_len = len.parse().map_err(|_| format!("invalid ring buffer size `{}`", len))?;
+                while self.ring.len() > self.ring_len {
+                    self.ring.pop_front();
+                }
+                Ok(format!("keeping the last {} instructions", self.ring_len))
+            }
+            ("trace", ["dump"]) => Ok(self.ring().collect::<Vec<_>>().join("\n")),
+            ("trace", ["dump", path]) => {
+                let text: String = self.ring().map(|line| format!("{}\n", line)).collect();
+                fs::write(path, text).map_err(|e| format!("{}: {}", path, e))?;
+                Ok(format!("wrote {} instructions to {}", self.ring.len(), path))
+            }
+            ("rewind", [len]) => {
+                self.rewind_len = len.parse().map_err(|_| format!("invalid rewind size `{}`", len))?;
+                while self.rewind.len() > self.rewind_len {
+                    self.rewind.pop_front();
+                }
+                self.update_journal(cpu);
+                Ok(format!("keeping the last {} instructions to step back through", self.rewind_len))
+            }
+            ("back", []) => self.back(cpu, 1),
+            ("back", [count]) => {
+                let count = count.parse().map_err(|_| format!("invalid step count `{}`", count))?;
+                self.back(cpu, count)
+            }
+            ("smc", ["on"]) => {
+                self.executed = Some(vec![false; 0x10000]);
+                self.update_journal(cpu);
+                Ok("stopping on writes to code that has run".to_string())
+            }
+            ("smc", ["off"]) => {
+                self.executed = None;
+                self.update_journal(cpu);
+                Ok("not watching for self-modifying code".to_string())
+            }
+            ("sym", [name, addr]) => {
+                let addr = self.parse_addr(addr)?;
+                self.symbols.insert(name.to_string(), addr);
+                Ok(format!("{} = ${:04X}", name, addr))
+            }
+            ("symfile", [path]) => Ok(format!("{} symbols from {}", self.load_symbol_file(path)?, path)),
+            ("io", []) => {
+                let registers = cpu.io_registers();
+                if registers.is_empty() {
+                    return Err("no peripherals describe their registers".into());
+                }
+                let lines: Vec<String> = registers.iter().map(|(addr, register)| format!("${:04X} {:6} {}", addr, register.name, register.description)).collect();
+                Ok(lines.join("\n"))
+            }
+            ("io", [register]) => self.io(cpu, register, None),
+            ("io", [register, value]) => {
+                let value = parse_byte(value).ok_or_else(|| format!("invalid value `{}`", value))?;
+                self.io(cpu, register, Some(value))
+            }
+            ("vectors", []) => Ok(self.vectors(cpu)),
+            ("stack", []) => Ok(self.stack(cpu, false)),
+            ("stack", ["all"]) => Ok(self.stack(cpu, true)),
+            ("vector", [name, "restore"]) => {
+                let addr = vector_addr(name)?;
+                let original = self.vector_overrides.remove(&addr).ok_or_else(|| format!("{} vector isn't overridden", name))?;
+                write_vector(cpu, addr, original);
+                Ok(format!("{} vector restored to ${:04X}", name, original))
+            }
+            ("vector", [name, target]) => {
+                let addr = vector_addr(name)?;
+                let target = self.parse_addr(target)?;
+                self.vector_overrides.entry(addr).or_insert_with(|| read_vector(cpu, addr));
+                write_vector(cpu, addr, target);
+                Ok(format!("{} vector -> ${:04X}", name, target))
+            }
+            #[cfg(feature = "heatmap")]
+            ("heatmap", ["on"]) => {
+                cpu.track_accesses();
+                Ok("counting accesses".to_string())
+            }
+            #[cfg(feature = "heatmap")]
+            ("heatmap", [path]) => {
+                let heatmap = cpu.heatmap().ok_or("not counting accesses, use `heatmap on` first")?;
+                heatmap.save(path)?;
+                Ok(format!("wrote access counts to {}", path))
+            }
+            _ => Err(format!("unknown command `{}`", line).into()),
+        }
+    }
+
+    /// Steps back up to `count` instructions
+    fn back(&mut self, cpu: &mut CPU, count: u64) -> Result<String, Box<dyn Error>> {
+        if self.rewind_len == 0 {
+            return Err("not keeping instructions to step back through, use `rewind N` first".into());
+        }
+        let undone = (0..count).take_while(|_| self.step_back(cpu)).count();
+        match undone {
+            0 => Err("no instructions left to step back through".into()),
+            1 => Ok("stepped back 1 instruction".to_string()),
+            n => Ok(format!("stepped back {} instructions", n)),
+        }
+    }
+
+    /// The fields of a register, split out of `value` if there is one
+    fn io(&self, cpu: &CPU, text: &str, value: Option<u8>) -> Result<String, Box<dyn Error>> {
+        let registers = cpu.io_registers();
+        let found = match registers.iter().find(|(_, register)| register.name.eq_ignore_ascii_case(text)) {
+            Some(found) => found,
+            None => {
+                let addr = self.parse_addr(text)?;
+                registers.iter().find(|&&(at, _)| at == addr).ok_or_else(|| format!("no register described at ${:04X}", addr))?
+            }
+        };
+        let (addr, register) = found;
+        let mut lines = vec![format!("${:04X} {} {}", addr, register.name, register.description)];
+        if let Some(value) = value {
+            lines[0] += &format!(" = ${:02X}", value);
+        }
+        let fields = |access: &str, fields: &[Field]| -> Vec<String> {
+            fields.iter().map(|field| format!("  {:5} {}", access, describe_field(field, value))).collect()
+        };
+        lines.extend(fields("read", &register.read));
+        lines.extend(fields("write", &register.write));
+        Ok(lines.join("\n"))
+    }
+
+    /// Page 1 from SP, or all of it, with notes on what was pushed
+    fn stack(&self, cpu: &CPU, all: bool) -> String {
+        let sp = cpu.registers().sp as u16;
+        let byte = |offset: u16| cpu.peek(CPU::STACK_ADDR_MIN + offset);
+        let word = |offset: u16| u16::from_le_bytes([byte(offset), byte(offset + 1)]);
+        // the JSR a return address pushed at `offset` would have come from
+        let jsr_at = |offset: u16| Some(word(offset).wrapping_sub(2)).filter(|&jsr| offset < 0xFF && cpu.peek(jsr) == JSR);
+
+        // only the part in use, above SP, holds anything pushed
+        let mut notes = BTreeMap::new();
+        let mut offset = sp + 1;
+        while offset < 0x100 {
+            if let Some(jsr) = jsr_at(offset) {
+                let callee = u16::from_le_bytes([cpu.peek(jsr.wrapping_add(1)), cpu.peek(jsr.wrapping_add(2))]);
+                notes.insert(offset, format!("return to ${:04X}, JSR ${:04X}{}", jsr.wrapping_add(3), callee, self.symbol_suffix(callee)));
+                offset += 2;
+                continue;
+            }
+            let status = Status::from_bits(byte(offset));
+            if offset < 0xFE && status.contains(Status::B5) && jsr_at(offset + 1).is_none() {
+                notes.insert(offset, format!("pushed status {}?", status));
+                notes.insert(offset + 1, format!("interrupted at ${:04X}?", word(offset + 1)));
+                offset += 3;
+                continue;
+            }
+            offset += 1;
+        }
+
+        let first = if all { 0 } else { sp };
+        (first..0x100)
+            .map(|offset| {
+                let marker = if offset == sp { "SP ->" } else { "     " };
+                let mut line = format!("{} {:04X}  {:02X}", marker, CPU::STACK_ADDR_MIN + offset, byte(offset));
+                if let Some(note) = notes.get(&offset) {
+                    line += &format!("  {}", note);
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// ` name` for the first symbol at `addr`, if there is one
+    fn symbol_suffix(&self, addr: u16) -> String {
+        match self.symbols.iter().find(|(_, &value)| value == addr) {
+            Some((symbol, _)) => format!(" {}", symbol),
+            None => String::new(),
+        }
+    }
+
+    /// One line per vector: where it is, its target and the target's symbol
+    fn vectors(&self, cpu: &CPU) -> String {
+        let mut lines = Vec::new();
+        for (name, addr) in VECTOR_LABELS {
+            let target = read_vector(cpu, addr);
+            let mut line = format!("{:5} ${:04X} -> ${:04X}{}", name, addr, target, self.symbol_suffix(target));
+            if let Some(original) = self.vector_overrides.get(&addr) {
+                line += &format!(" (overridden, was ${:04X})", original);
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+
+    fn parse_addr(&self, text: &str) -> Result<u16, String> {
+        if let Some(inner) = text.strip_prefix("ram[").and_then(|rest| rest.strip_suffix(']')) {
+            let addr = self.parse_addr(inner)?;
+            return match addr < CPU::IO_REG_ADDR_MIN {
+                true => Ok(addr),
+                false => Err(format!("${:04X} is outside internal RAM", addr)),
+            };
+        }
+
+        let parsed = match text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None if text.starts_with(|c: char| c.is_ascii_digit()) => text.parse().ok(),
+            None => return self.symbols.get(text).copied().ok_or_else(|| format!("unknown symbol `{}`", text)),
+        };
+        parsed.ok_or_else(|| format!("invalid address `{}`", text))
+    }
+
+    /// Steps up to `limit` instructions. A breakpoint at the starting PC is
+    /// stepped over, so continuing from one doesn't stop straight away.
+    fn run(&mut self, cpu: &mut CPU, limit: u64) -> Result<Stop, Box<dyn Error>> {
+        let names = self.zero_page_names();
+        // writes since the last run were made outside the debugger, so the
+        // instructions kept from before them can't be undone
+        if !cpu.take_writes().is_empty() {
+            self.rewind.clear();
+        }
+        for count in 0..limit {
+            let pc = cpu.registers().pc;
+            if count > 0 && self.breakpoints.contains(&pc) {
+                return Ok(Stop::Breakpoint(pc));
+            }
+            self.trace_instruction(cpu, &names)?;
+            let (before, halted) = (cpu.core_state(), cpu.is_halted());
+            self.mark_executed(cpu);
+            let stop = cpu.step_next();
+            let writes = cpu.take_writes();
+            let modified = self.code_write(cpu, pc, &writes);
+            if !halted && stop != Some(StopReason::Break) {
+                self.record_step(before, writes);
+            }
+            if let Some(reason) = stop {
+                return Ok(Stop::Cpu(reason));
+            }
+            if let Some(modified) = modified {
+                return Ok(modified);
+            }
+            for (&addr, seen) in self.watchpoints.iter_mut() {
+                let (old, new) = (*seen, cpu.read(addr));
+                if old != new {
+                    *seen = new;
+                    return Ok(Stop::Watchpoint { addr, old, new });
+                }
+            }
+            // checked before each instruction after the first, like breakpoints
+            if count + 1 < limit {
+                if let Some(diagnostic) = self.watchdog.check(cpu) {
+                    return Ok(Stop::Watchdog(diagnostic));
+                }
+            }
+        }
+        Ok(Stop::Stepped(limit))
+    }
+
+    /// Writes are journaled for as long as `rewind` or `smc` needs them
+    fn update_journal(&self, cpu: &mut CPU) {
+        cpu.journal_writes(self.rewind_len > 0 || self.executed.is_some());
+    }
+
+    /// Marks the bytes of the instruction at PC as code, while `smc` is on
+    fn mark_executed(&mut self, cpu: &CPU) {
+        let Some(executed) = &mut self.executed else {
+            return;
+        };
+        let pc = cpu.registers().pc;
+        let size = Opcode::from_code(cpu.peek(pc)).map_or(1, |opcode| opcode.bytes);
+        for offset in 0..size {
+            executed[pc.wrapping_add(offset) as usize] = true;
+        }
+    }
+
+    /// The first of the instruction at `pc`'s writes over code that has run,
+    /// unmarking every byte it wrote
+    fn code_write(&mut self, cpu: &CPU, pc: u16, writes: &[(u16, u8)]) -> Option<Stop> {
+        let executed = self.executed.as_mut()?;
+        let mut first = None;
+        for &(addr, old) in writes {
+            if std::mem::take(&mut executed[addr as usize]) && first.is_none() {
+                first = Some(Stop::SelfModifying { addr, pc, old, new: cpu.peek(addr) });
+            }
+        }
+        first
+    }
+
+    /// Keeps what's needed to undo the instruction just run, while rewinding
+    fn record_step(&mut self, before: CoreState, writes: Vec<(u16, u8)>) {
+        if self.rewind_len == 0 {
+            return;
+        }
+        if self.rewind.len() == self.rewind_len {
+            self.rewind.pop_front();
+        }
+        self.rewind.push_back(StepRecord { state: before, writes });
+    }
+
+    fn trace_instruction(&mut self, cpu: &CPU, names: &BTreeMap<u16, String>) -> Result<(), Box<dyn Error>> {
+        if self.trace.is_none() && self.ring_len == 0 {
+            return Ok(());
+        }
+        let (instruction, line) = trace_line(cpu, names);
+        if !self.trace_filter.matches(cpu.registers().pc, instruction.as_ref()) {
+            return Ok(());
+        }
+        if let Some(trace) = &mut self.trace {
+            writeln!(trace, "{}", line)?;
+        }
+        if self.ring_len > 0 {
+            if self.ring.len() == self.ring_len {
+                self.ring.pop_front();
+            }
+            self.ring.push_back(line);
+        }
+        Ok(())
+    }
+}
+
+/// The instruction at PC and a trace log line for it, with the registers before it runs
+pub(crate) fn trace_line(cpu: &CPU, names: &BTreeMap<u16, String>) -> (Option<Instruction>, String) {
+    let reg = cpu.registers();
+    let bytes = [cpu.read(reg.pc), cpu.read(reg.pc.wrapping_add(1)), cpu.read(reg.pc.wrapping_add(2))];
+    let instruction = Instruction::decode(&bytes);
+    let text = match &instruction {
+        Some(instruction) => instruction.to_string_with_names(names),
+        None => format!(".byte ${:02X}", bytes[0]),
+    };
+    let line = format!(
+        "{:04X}  {:16}  A:{:02X} X:{:02X} Y:{:02X} P:{} SP:{:02X}",
+        reg.pc, text, reg.a, reg.x, reg.y, reg.p, reg.sp
+    );
+    (instruction, line)
+}
+
+/// Where the vector called `name` (nmi, reset or irq) is stored
+fn vector_addr(name: &str) -> Result<u16, String> {
+    VECTOR_LABELS
+        .iter()
+        .find(|(label, _)| label.eq_ignore_ascii_case(name))
+        .map(|&(_, addr)| addr)
+        .ok_or_else(|| format!("unknown vector `{}`, expected nmi, reset or irq", name))
+}
+
+fn read_vector(cpu: &CPU, addr: u16) -> u16 {
+    u16::from_le_bytes([cpu.peek(addr), cpu.peek(addr + 1)])
+}
+
+fn write_vector(cpu: &mut CPU, addr: u16, target: u16) {
+    let [lo, hi] = target.to_le_bytes();
+    cpu.poke(addr, lo);
+    cpu.poke(addr + 1, hi);
+}
+
+/// A byte as `$81`, `0x81`, `%10000001` or decimal
+fn parse_byte(text: &str) -> Option<u8> {
+    match text.chars().next()? {
+        '$' => u8::from_str_radix(&text[1..], 16).ok(),
+        '%' => u8::from_str_radix(&text[1..], 2).ok(),
+        _ => match text.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        },
+    }
+}
+
+/// One line for a field: its bits, name, value if known, and meaning
+fn describe_field(field: &Field, value: Option<u8>) -> String {
+    let bits = match field.width {
+        1 => format!("bit {}", field.shift),
+        width => format!("bits {}-{}", field.shift, field.shift + width - 1),
+    };
+    let value = match value.map(|value| field.get(value)) {
+        Some(bits) if field.width == 8 => format!("${:02X} ", bits),
+        Some(bits) => format!("%{:0width$b} ", bits, width = field.width as usize),
+        None => String::new(),
+    };
+    format!("{:9} {:7} {}{}", bits, field.name, value, field.description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LDX #$00, loop: INX, STX $10, CPX #$03, BNE loop, BRK
+    const COUNT_TO_THREE: [u8; 10] = [0xA2, 0x00, 0xE8, 0x86, 0x10, 0xE0, 0x03, 0xD0, 0xF9, 0x00];
+
+    fn count_to_three() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&COUNT_TO_THREE);
+        cpu.interrupt_reset();
+        cpu
+    }
+
+    #[test]
+    fn test_breakpoints_and_steps() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.execute(&mut cpu, "b $0605").unwrap(), "breakpoint at $0605");
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "breakpoint at $0605");
+        assert_eq!(cpu.read(0x10), 1);
+        // continuing steps off the breakpoint before checking again
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "breakpoint at $0605");
+        assert_eq!(cpu.read(0x10), 2);
+
+        debugger.execute(&mut cpu, "d $0605").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "step 2").unwrap(), "stepped 2 instructions");
+        assert_eq!(cpu.registers().pc, 0x0602);
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "stopped on BRK");
+
+        assert!(debugger.execute(&mut cpu, "d $0605").is_err());
+        assert!(debugger.execute(&mut cpu, "jump").is_err());
+        assert_eq!(debugger.history(), &["b $0605", "c", "d $0605", "step 2", "c", "d $0605", "jump"]);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut cpu, "w ram[$10]").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "$0010 changed from $00 to $01");
+        assert_eq!(cpu.registers().pc, 0x0605);
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "$0010 changed from $01 to $02");
+
+        assert!(debugger.execute(&mut cpu, "w ram[$8000]").is_err());
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+        assert!(debugger.execute(&mut cpu, "back").is_err());
+
+        debugger.run_script(&mut cpu, "rewind 100
+w $10
+step 3").unwrap();
+        let after_store = *cpu.registers();
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "$0010 changed from $01 to $02");
+        assert_eq!(debugger.execute(&mut cpu, "back").unwrap(), "stepped back 1 instruction");
+        assert_eq!((cpu.registers().pc, cpu.read(0x10)), (0x0603, 1));
+        assert_eq!(debugger.execute(&mut cpu, "back 3").unwrap(), "stepped back 3 instructions");
+        assert_eq!(cpu.registers(), &after_store);
+        // the watchpoint sees the byte as it is after stepping back
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "$0010 changed from $01 to $02");
+
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "$0010 changed from $02 to $03");
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "stopped on BRK");
+        assert_eq!(debugger.execute(&mut cpu, "back 100").unwrap(), "stepped back 13 instructions");
+        assert_eq!((cpu.registers().pc, cpu.read(0x10)), (0x0600, 0));
+        assert_eq!(debugger.execute(&mut cpu, "back").unwrap_err().to_string(), "no instructions left to step back through");
+
+        // only the last 2 are kept, and none once the program writes outside the debugger
+        debugger.execute(&mut cpu, "rewind 2").unwrap();
+        debugger.execute(&mut cpu, "step 3").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "back 3").unwrap(), "stepped back 2 instructions");
+        debugger.execute(&mut cpu, "step").unwrap();
+        // STX $10
+        cpu.step_next();
+        debugger.execute(&mut cpu, "step").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "back 3").unwrap(), "stepped back 1 instruction");
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // INX, STX $10, TXA, STA $0601 over the STX, BRK
+        let program = [0xE8, 0x86, 0x10, 0x8A, 0x8D, 0x01, 0x06, 0x00];
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&program);
+        cpu.interrupt_reset();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut cpu, "smc on").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "instruction at $0604 changed code at $0601 from $86 to $01");
+        assert_eq!(cpu.registers().pc, 0x0607);
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "stopped on BRK");
+
+        cpu.interrupt_reset();
+        debugger.execute(&mut cpu, "smc off").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "c").unwrap(), "stopped on BRK");
+    }
+
+    #[test]
+    fn test_watchdog() {
+        // SEI, JMP $0601
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&[0x78, 0x4C, 0x01, 0x06]);
+        cpu.interrupt_reset();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute(&mut cpu, "c").unwrap(),
+            "watchdog: stuck jumping to $0601 with interrupts disabled"
+        );
+        // stepping off it runs the jump, then the watchdog stops it again
+        assert_eq!(debugger.execute(&mut cpu, "step").unwrap(), "stepped 1 instruction");
+        assert!(debugger.execute(&mut cpu, "step 5").unwrap().starts_with("watchdog: stuck jumping"));
+        assert_eq!(cpu.registers().pc, 0x0601);
+    }
+
+    #[test]
+    fn test_script_symbols_and_trace() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+        let trace = std::env::temp_dir().join(format!("nes-rs-debugger-trace-{}.log", std::process::id()));
+
+        let script = format!("# startup\nsym counter $10\nsym count_loop $0602\n\nw counter\ntrace on {}\nstep 3\ntrace off\n", trace.display());
+        let output = debugger.run_script(&mut cpu, &script).unwrap();
+        assert_eq!(output[0], "counter = $0010");
+        assert_eq!(output[4], "$0010 changed from $00 to $01");
+        assert!(debugger.history().is_empty());
+        assert_eq!(debugger.complete("co"), vec!["count_loop", "counter"]);
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), vec![0x10]);
+
+        let log = fs::read_to_string(&trace).unwrap();
+        let _ = fs::remove_file(&trace);
+        assert_eq!(log.lines().count(), 3);
+        assert!(log.starts_with("0600  LDX #$00          A:00 X:00 Y:00 P:nv-bdizc SP:FF\n"), "{}", log);
+        assert!(log.contains("0603  STX counter       A:00"), "{}", log);
+
+        let symbols = std::env::temp_dir().join(format!("nes-rs-debugger-symbols-{}.s", std::process::id()));
+        fs::write(&symbols, "snake_head_lo = $10\nscreen = $0200\n").unwrap();
+        let output = debugger.execute(&mut cpu, &format!("symfile {}", symbols.display()));
+        let _ = fs::remove_file(&symbols);
+        assert_eq!(output.unwrap(), format!("2 symbols from {}", symbols.display()));
+        assert_eq!(debugger.zero_page_names().into_iter().collect::<Vec<_>>(), vec![(0x10, "counter".to_string())]);
+        assert_eq!(debugger.symbols()["screen"], 0x0200);
+
+        let err = debugger.run_script(&mut cpu, "b $8000\nb nowhere\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown symbol `nowhere`");
+    }
+
+    #[test]
+    fn test_trace_filter_and_ring() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+
+        debugger.run_script(&mut cpu, "trace ring 3\ntrace only $0602-$0608 branches writes\nc").unwrap();
+        // INX is in range but neither a branch nor a write, only the last pass is kept
+        let ring: Vec<_> = debugger.ring().map(|line| line[..22].trim_end()).collect();
+        assert_eq!(ring, vec!["0607  BNE *-7", "0603  STX $10", "0607  BNE *-7"]);
+
+        let dump = std::env::temp_dir().join(format!("nes-rs-debugger-ring-{}.log", std::process::id()));
+        let output = debugger.execute(&mut cpu, &format!("trace dump {}", dump.display())).unwrap();
+        let text = fs::read_to_string(&dump).unwrap();
+        let _ = fs::remove_file(&dump);
+        assert_eq!(output, format!("wrote 3 instructions to {}", dump.display()));
+        assert_eq!(text.lines().count(), 3);
+        assert_eq!(debugger.execute(&mut cpu, "trace dump").unwrap().lines().count(), 3);
+
+        let mut cpu = count_to_three();
+        debugger.run_script(&mut cpu, "trace all\ntrace only cpx\ntrace ring 10\nc").unwrap();
+        // the 3 from before are still kept
+        assert_eq!(debugger.ring().count(), 6);
+        assert!(debugger.ring().skip(3).all(|line| line.contains("CPX")));
+
+        debugger.execute(&mut cpu, "trace ring 1").unwrap();
+        assert_eq!(debugger.ring().count(), 1);
+        assert!(debugger.execute(&mut cpu, "trace only FOO").is_err());
+        assert!(debugger.execute(&mut cpu, "trace only $0600-nowhere").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "heatmap")]
+    fn test_heatmap() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+        let path = std::env::temp_dir().join(format!("nes-rs-debugger-heatmap-{}.csv", std::process::id()));
+        let save = format!("heatmap {}", path.display());
+
+        assert!(debugger.execute(&mut cpu, &save).is_err());
+        debugger.run_script(&mut cpu, "heatmap on\nc").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, &save).unwrap(), format!("wrote access counts to {}", path.display()));
+        let csv = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        // STX $10 three times
+        assert!(csv.lines().any(|line| line.starts_with("$0010,") && line.ends_with(",3,0")), "{}", csv);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+        let dir = std::env::temp_dir();
+        let path = Debugger::session_path(&dir, &COUNT_TO_THREE);
+        assert_ne!(path, Debugger::session_path(&dir, &COUNT_TO_THREE[1..]));
+        let _ = fs::remove_file(&path);
+
+        assert!(!debugger.load_session(&mut cpu, &path).unwrap());
+        debugger.run_script(&mut cpu, "sym count_loop $0602\nb count_loop\nb $0607\nw ram[$10]").unwrap();
+        debugger.save_session(&path).unwrap();
+
+        let mut restored = Debugger::new();
+        assert!(restored.load_session(&mut cpu, &path).unwrap());
+        let _ = fs::remove_file(&path);
+        assert_eq!(restored.symbols(), debugger.symbols());
+        assert_eq!(restored.breakpoints().collect::<Vec<_>>(), vec![0x0602, 0x0607]);
+        assert_eq!(restored.watchpoints().collect::<Vec<_>>(), vec![0x10]);
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut cpu = count_to_three();
+        cpu.protect(0xFFFA..=0xFFFF);
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, "sym start $0600").unwrap();
+
+        assert_eq!(
+            debugger.execute(&mut cpu, "vectors").unwrap(),
+            "nmi   $FFFA -> $0000\nreset $FFFC -> $0600 start\nirq   $FFFE -> $0000"
+        );
+        assert_eq!(debugger.execute(&mut cpu, "vector NMI $0605").unwrap(), "NMI vector -> $0605");
+        debugger.execute(&mut cpu, "vector nmi start").unwrap();
+        assert_eq!((cpu.peek(0xFFFA), cpu.peek(0xFFFB)), (0x00, 0x06));
+        assert!(debugger.execute(&mut cpu, "vectors").unwrap().starts_with("nmi   $FFFA -> $0600 start (overridden, was $0000)\n"));
+
+        assert_eq!(debugger.execute(&mut cpu, "vector nmi restore").unwrap(), "nmi vector restored to $0000");
+        assert_eq!(cpu.peek(0xFFFB), 0x00);
+        assert!(debugger.execute(&mut cpu, "vector nmi restore").is_err());
+        assert!(debugger.execute(&mut cpu, "vector brk $0600").is_err());
+    }
+
+    #[test]
+    fn test_io_registers() {
+        use crate::input::keyboard::{FamilyKeyboard, KeyboardState};
+        use crate::input::{ControllerPorts, CONTROLLER_PORTS};
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut cpu = count_to_three();
+        let mut debugger = Debugger::new();
+        assert!(debugger.execute(&mut cpu, "io").is_err());
+
+        let keyboard = FamilyKeyboard::new(Rc::new(Cell::new(KeyboardState::new())));
+        let ports = ControllerPorts::new(Rc::new(Cell::new([0; 4]))).with_expansion(Box::new(keyboard));
+        cpu.map_peripheral(CONTROLLER_PORTS, Box::new(ports));
+        assert_eq!(
+            debugger.execute(&mut cpu, "io").unwrap(),
+            "$4016 JOY1   controller port 1, which strobes every controller\n$4017 JOY2   controller port 2"
+        );
+        assert_eq!(
+            debugger.execute(&mut cpu, "io joy1 %110").unwrap(),
+            [
+                "$4016 JOY1 controller port 1, which strobes every controller = $06",
+                "  read  bit 0     data    %0 next bit of the controller's report",
+                "  write bit 0     strobe  %0 reload every controller's report while set",
+                "  write bit 0     reset   %0 keyboard: back to row 0",
+                "  write bit 1     column  %1 keyboard: column to read, the row advances going from 1 to 0",
+                "  write bit 2     enable  %1 keyboard: on",
+            ]
+            .join("\n")
+        );
+        assert!(debugger.execute(&mut cpu, "io $4017").unwrap().ends_with("  read  bits 1-4  keys    keyboard: 4 keys of the row and column, clear when held"));
+        assert_eq!(debugger.execute(&mut cpu, "io $2000").unwrap_err().to_string(), "no register described at $2000");
+        assert!(debugger.execute(&mut cpu, "io $4016 $100").is_err());
+    }
+
+    #[test]
+    fn test_stack() {
+        // JSR sub, BRK, then sub: PHP, LDA #$55, PHA, BRK
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&[0x20, 0x04, 0x06, 0x00, 0x08, 0xA9, 0x55, 0x48, 0x00]);
+        cpu.interrupt_reset();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, "sym sub $0604").unwrap();
+        debugger.execute(&mut cpu, "c").unwrap();
+
+        let status = cpu.peek(0x01FD);
+        assert_eq!(
+            debugger.execute(&mut cpu, "stack").unwrap(),
+            format!(
+                "SP -> 01FB  00\n      01FC  55\n      01FD  {:02X}\n      01FE  02  return to $0603, JSR $0604 sub\n      01FF  06",
+                status
+            )
+        );
+
+        // an interrupt frame below it: status, then the interrupted PC
+        cpu.registers_mut().sp = 0xF8;
+        cpu.load(0x01F9, &[0x24, 0x34, 0x12]);
+        let view = debugger.execute(&mut cpu, "stack all").unwrap();
+        assert_eq!(view.lines().count(), 0x100);
+        assert!(view.contains("SP -> 01F8  00\n      01F9  24  pushed status nv-bdIzc?\n      01FA  34  interrupted at $1234?\n"), "{}", view);
+    }
+}
@@ -0,0 +1,177 @@
+//! Determinism audit: runs the same program twice side by side and reports
+//! the first point where the runs differ.
+//!
+//! The two CPUs power on with RAM cleared in one and filled with $FF in the
+//! other, so a program that depends on uninitialised memory shows up. The
+//! `update` callback that feeds input is called for both before each
+//! instruction, so one reaching for the host's RNG or clock shows up too.
+//!
+//! Registers are compared after every instruction, and memory at the end of
+//! each frame. Bytes still at their power-on value in both runs are expected
+//! to differ and aren't reported. Until there's a PPU, a frame is a fixed
+//! number of instructions.
+
+use std::fmt;
+
+use super::reg::RegisterSet;
+use super::{StopReason, CPU};
+use crate::memory::MemoryInit;
+
+/// Power-on RAM of the two runs
+const POWER_ON: [MemoryInit; 2] = [MemoryInit::Zero, MemoryInit::FF];
+const POWER_ON_BYTES: [u8; 2] = [0x00, 0xFF];
+/// Roughly the instructions in an NTSC frame's 29780 cycles
+pub const FRAME_INSTRUCTIONS: u64 = 10_000;
+
+/// The first point where the two runs differed
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Nondeterminism {
+    /// Instructions both runs had executed
+    pub step: u64,
+    /// Address of the last instruction the first run executed
+    pub pc: u16,
+    pub registers: [RegisterSet; 2],
+    pub stops: [Option<StopReason>; 2],
+    /// Addresses where memory differs, with the value in each run
+    pub memory: Vec<(u16, u8, u8)>,
+}
+
+impl fmt::Display for Nondeterminism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(f, "runs diverged after {} instructions, the last at ${:04X}", self.step, self.pc)?;
+        for (run, (reg, stop)) in self.registers.iter().zip(&self.stops).enumerate() {
+            write!(
+                f,
+                "  run {}  PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                run + 1, reg.pc, reg.a, reg.x, reg.y, reg.p.bits(), reg.sp
+            )?;
+            match stop {
+                Some(stop) => writeln!(f, " stopped, {:?}", stop)?,
+                None => writeln!(f)?,
+            }
+        }
+        for (addr, first, second) in &self.memory {
+            writeln!(f, "  ${:04X}: {:02X} then {:02X}", addr, first, second)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Nondeterminism {}
+
+pub struct Audit {
+    runs: [CPU; 2],
+    steps: u64,
+}
+
+impl Audit {
+    /// Sets up both runs with `build`, which gets the power-on RAM to use
+    /// and should otherwise do the same thing each time it's called
+    pub fn new(build: impl Fn(MemoryInit) -> CPU) -> Self {
+        Audit { runs: POWER_ON.map(build), steps: 0 }
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.runs[0]
+    }
+
+    /// Calls `update` on each run and steps both, comparing registers
+    pub fn step(&mut self, mut update: impl FnMut(&mut CPU)) -> Result<Option<StopReason>, Nondeterminism> {
+        let pc = self.runs[0].registers().pc;
+        let stops = self.runs.each_mut().map(|cpu| {
+            update(cpu);
+            cpu.step_next()
+        });
+        self.steps += 1;
+
+        if stops[0] != stops[1] || self.runs[0].registers() != self.runs[1].registers() {
+            return Err(self.divergence(pc, stops, Vec::new()));
+        }
+        Ok(stops[0])
+    }
+
+    /// Compares memory, which is too slow to do after every instruction
+    pub fn check_memory(&self) -> Result<(), Nondeterminism> {
+        let memory: Vec<(u16, u8, u8)> = (0..=0xFFFF)
+            .map(|addr| (addr, self.runs[0].peek(addr), self.runs[1].peek(addr)))
+            .filter(|&(_, first, second)| first != second && [first, second] != POWER_ON_BYTES)
+            .collect();
+        match memory.is_empty() {
+            true => Ok(()),
+            false => Err(self.divergence(self.runs[0].registers().pc, [None; 2], memory)),
+        }
+    }
+
+    /// Runs `frames` frames of `frame_len` instructions, checking memory
+    /// after each one, until either the frames are done or the CPUs stop
+    pub fn run(&mut self, frames: u64, frame_len: u64, mut update: impl FnMut(&mut CPU)) -> Result<Option<StopReason>, Nondeterminism> {
+        for _ in 0..frames {
+            for _ in 0..frame_len {
+                if let Some(reason) = self.step(&mut update)? {
+                    self.check_memory()?;
+                    return Ok(Some(reason));
+                }
+            }
+            self.check_memory()?;
+        }
+        Ok(None)
+    }
+
+    fn divergence(&self, pc: u16, stops: [Option<StopReason>; 2], memory: Vec<(u16, u8, u8)>) -> Nondeterminism {
+        Nondeterminism { step: self.steps, pc, registers: [*self.runs[0].registers(), *self.runs[1].registers()], stops, memory }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "assembler")]
+    use crate::demo::ColorBarsDemo;
+    use crate::demo::Sandbox;
+
+    fn sandbox(program: &[u8]) -> impl Fn(MemoryInit) -> CPU + '_ {
+        move |init| {
+            let mut cpu = CPU::power_on(init);
+            Sandbox::load(&mut cpu, program);
+            cpu
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_deterministic() {
+        let mut audit = Audit::new(sandbox(&ColorBarsDemo::program()));
+        assert_eq!(audit.run(100, 100, |_| {}), Ok(Some(StopReason::Break)));
+        assert_eq!(audit.cpu().read(0x0205), 2);
+    }
+
+    #[test]
+    fn test_uninitialised_memory() {
+        // LDA $10, STA $0200
+        let mut audit = Audit::new(sandbox(&[0xA5, 0x10, 0x8D, 0x00, 0x02, 0x00]));
+        let found = audit.run(1, 10, |_| {}).unwrap_err();
+        assert_eq!((found.step, found.pc), (1, 0x0600));
+        assert_eq!((found.registers[0].a, found.registers[1].a), (0x00, 0xFF));
+        assert!(found.to_string().starts_with("runs diverged after 1 instructions, the last at $0600\n  run 1  PC:0602 A:00"), "{}", found);
+    }
+
+    #[test]
+    fn test_host_input() {
+        // LDA $FE, STA $10, then loop
+        let program = [0xA5, 0xFE, 0x85, 0x10, 0x4C, 0x00, 0x06];
+        let mut random = 0u8;
+        let mut audit = Audit::new(sandbox(&program));
+        let found = audit
+            .run(1, 10, |cpu| {
+                random = random.wrapping_add(1);
+                Sandbox::update(cpu, random, None);
+            })
+            .unwrap_err();
+        assert_eq!(found.step, 1);
+
+        // the same byte for both runs is fine
+        let mut audit = Audit::new(sandbox(&program));
+        assert_eq!(audit.run(3, 10, |cpu| Sandbox::update(cpu, 7, None)), Ok(None));
+        assert_eq!(audit.check_memory(), Ok(()));
+    }
+}
@@ -0,0 +1,739 @@
+//! Experimental translation of hot code into native code with Cranelift,
+//! to find out what a dynarec would buy. Needs the `jit` feature.
+//!
+//! `Jit::step` stands in for `CPU::step_next`. It counts how often each
+//! address starts an instruction, and once one reaches `HOT_THRESHOLD` the
+//! instructions from there are translated as a block, up to the first one
+//! that can't be and stopping after a branch or `JMP`. Until then, and for
+//! anything that isn't translated, the interpreter runs as usual.
+//!
+//! Only loads, stores, arithmetic, compares, register and flag instructions
+//! are translated, with operands that aren't indirect. The guard for
+//! memory-mapped I/O is made when a block is compiled: every address an
+//! instruction can touch, whatever the index, has to be plain memory with
+//! no peripheral and, for writes, no write protection. `clear` throws the
+//! blocks away after mapping a peripheral. A block's bytes are checked
+//! before each run, and a block ends after a store that can land in its own
+//! bytes, so self-modifying code is translated again.
+//!
+//! Blocks run as a unit. Interrupts are only polled after them, so they're
+//! not run while one is waiting, and CLI, SEI and PLP aren't translated.
+//! Peripherals are ticked once for each instruction afterwards. Nothing is
+//! translated while access counting or the write journal is on.
+
+use std::error::Error;
+use std::ops::RangeInclusive;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use super::addr::AddressMode;
+use super::ops::{Mnemonic, Opcode, JAM_OPCODES};
+use super::reg::Status;
+use super::{StopReason, CPU};
+
+/// Times an address starts an instruction before a block is compiled from it
+pub const HOT_THRESHOLD: u32 = 64;
+/// Most instructions in one block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// The registers a block works on, other than PC
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct BlockRegs {
+    a: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    p: u8,
+}
+
+/// Runs a block over the registers and the 64K of memory, returning the next PC
+type BlockFn = unsafe extern "C" fn(*mut BlockRegs, *mut u8) -> u32;
+
+struct Block {
+    /// The bytes translated, compared with memory before each run
+    code: Vec<u8>,
+    /// None when the first instruction couldn't be translated
+    native: Option<(BlockFn, u32)>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct JitStats {
+    pub blocks_compiled: u64,
+    /// Instructions run in native blocks
+    pub native: u64,
+    /// Instructions left to the interpreter
+    pub interpreted: u64,
+}
+
+pub struct Jit {
+    module: JITModule,
+    builder_context: FunctionBuilderContext,
+    counts: Vec<u32>,
+    /// By the address they start at
+    blocks: Vec<Option<Block>>,
+    stats: JitStats,
+}
+
+impl Jit {
+    /// Sets up Cranelift for the host, which fails on hosts it doesn't support
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false")?;
+        flags.set("is_pic", "false")?;
+        flags.set("opt_level", "speed")?;
+        let isa = cranelift_native::builder()?.finish(settings::Flags::new(flags))?;
+        Ok(Jit {
+            module: JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())),
+            builder_context: FunctionBuilderContext::new(),
+            counts: vec![0; 0x10000],
+            blocks: (0..0x10000).map(|_| None).collect(),
+            stats: JitStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> JitStats {
+        self.stats
+    }
+
+    /// Forgets every block and count, as needed after mapping a peripheral
+    /// over memory a block uses. The compiled code stays allocated.
+    pub fn clear(&mut self) {
+        self.blocks.iter_mut().for_each(|block| *block = None);
+        self.counts.fill(0);
+    }
+
+    /// Runs a block from PC if one is compiled or hot enough to compile,
+    /// otherwise steps the interpreter
+    pub fn step(&mut self, cpu: &mut CPU) -> Option<StopReason> {
+        let interrupt_waiting = cpu.nmi_pending || cpu.irq_line && !cpu.reg.get_interrupt();
+        if cpu.halted.is_none() && !interrupt_waiting && !cpu.mem.is_observed() {
+            if let Some(instructions) = self.run_block(cpu) {
+                for _ in 0..instructions {
+                    cpu.mem.tick_peripherals();
+                }
+                self.stats.native += instructions as u64;
+                return None;
+            }
+        }
+        self.stats.interpreted += 1;
+        cpu.step_next()
+    }
+
+    /// Runs until BRK or a halt, like `CPU::run`
+    pub fn run(&mut self, cpu: &mut CPU) -> StopReason {
+        loop {
+            if let Some(reason) = self.step(cpu) {
+                return reason;
+            }
+        }
+    }
+
+    /// The number of instructions run, if a block was
+    fn run_block(&mut self, cpu: &mut CPU) -> Option<u32> {
+        let pc = cpu.reg.pc;
+        let slot = &mut self.blocks[pc as usize];
+        if slot.as_ref().is_some_and(|block| !block_matches(cpu, pc, &block.code)) {
+            *slot = None;
+            self.counts[pc as usize] = 0;
+        }
+        if slot.is_none() {
+            let count = &mut self.counts[pc as usize];
+            *count += 1;
+            if *count < HOT_THRESHOLD {
+                return None;
+            }
+            let block = self.compile(cpu, pc);
+            self.blocks[pc as usize] = Some(block);
+        }
+
+        let (run, instructions) = self.blocks[pc as usize].as_ref()?.native?;
+        let reg = &mut cpu.reg;
+        let mut regs = BlockRegs { a: reg.a, x: reg.x, y: reg.y, sp: reg.sp, p: reg.p.bits() };
+        // SAFETY: the block was compiled for 64K of memory, which `data_mut` is
+        // for the CPU's map, and only touches the addresses checked in `compile`
+        let next = unsafe { run(&mut regs, cpu.mem.data_mut().as_mut_ptr()) };
+        (reg.a, reg.x, reg.y, reg.sp, reg.p) = (regs.a, regs.x, regs.y, regs.sp, Status::from_bits(regs.p));
+        reg.pc = next as u16;
+        Some(instructions)
+    }
+
+    /// Translates as many instructions from `pc` as it can
+    fn compile(&mut self, cpu: &CPU, pc: u16) -> Block {
+        let mut instructions = Vec::new();
+        let mut addr = pc;
+        while instructions.len() < MAX_BLOCK_LEN {
+            let Some(instruction) = Translated::decode(cpu, addr) else {
+                break;
+            };
+            addr += instruction.opcode.bytes;
+            let ends = instruction.ends_block();
+            instructions.push(instruction);
+            if ends {
+                break;
+            }
+        }
+        // a store into the block has to be seen by the check before the next run
+        let stores_into_block = |instruction: &Translated| {
+            instruction.writes.as_ref().is_some_and(|range| *range.start() < addr && *range.end() >= pc)
+        };
+        if let Some(store) = instructions.iter().position(stores_into_block) {
+            instructions.truncate(store + 1);
+            addr = instructions[store].addr + instructions[store].opcode.bytes;
+        }
+        if instructions.is_empty() {
+            // keep the opcode, so changing it gives it another chance
+            return Block { code: vec![cpu.peek(pc)], native: None };
+        }
+        let code: Vec<u8> = (pc..addr).map(|addr| cpu.peek(addr)).collect();
+
+        match self.define(&instructions, addr) {
+            Ok(run) => {
+                self.stats.blocks_compiled += 1;
+                Block { code, native: Some((run, instructions.len() as u32)) }
+            }
+            Err(_) => Block { code, native: None },
+        }
+    }
+
+    fn define(&mut self, instructions: &[Translated], end: u16) -> Result<BlockFn, Box<dyn Error>> {
+        let mut context = self.module.make_context();
+        let pointer = self.module.target_config().pointer_type();
+        context.func.signature.params.push(AbiParam::new(pointer));
+        context.func.signature.params.push(AbiParam::new(pointer));
+        context.func.signature.returns.push(AbiParam::new(types::I32));
+
+        let mut b = FunctionBuilder::new(&mut context.func, &mut self.builder_context);
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let (regs, mem) = (b.block_params(entry)[0], b.block_params(entry)[1]);
+
+        let mut emitter = Emitter::new(b, regs, mem, pointer);
+        let mut next = None;
+        for instruction in instructions {
+            next = emitter.instruction(instruction);
+        }
+        let next = next.unwrap_or_else(|| emitter.b.ins().iconst(types::I32, end as i64));
+        emitter.finish(next);
+
+        let id = self.module.declare_anonymous_function(&context.func.signature)?;
+        self.module.define_function(id, &mut context)?;
+        self.module.clear_context(&mut context);
+        self.module.finalize_definitions()?;
+        let code = self.module.get_finalized_function(id);
+        // SAFETY: the function was declared with BlockFn's signature
+        Ok(unsafe { std::mem::transmute::<*const u8, BlockFn>(code) })
+    }
+}
+
+fn block_matches(cpu: &CPU, pc: u16, code: &[u8]) -> bool {
+    code.iter().zip(pc..).all(|(&byte, addr)| cpu.peek(addr) == byte)
+}
+
+/// An instruction that can be translated, with its operand
+struct Translated {
+    addr: u16,
+    opcode: &'static Opcode,
+    operand: u16,
+    /// Every address it can write
+    writes: Option<RangeInclusive<u16>>,
+}
+
+impl Translated {
+    fn decode(cpu: &CPU, addr: u16) -> Option<Self> {
+        use AddressMode::*;
+        use Mnemonic::*;
+        let code = cpu.peek(addr);
+        let opcode = Opcode::from_code(code).filter(|_| !JAM_OPCODES.contains(&code))?;
+        // the interpreter's PC doesn't wrap
+        if addr as u32 + opcode.bytes as u32 > 0xFFFF {
+            return None;
+        }
+        let operand = u16::from_le_bytes([cpu.peek(addr.wrapping_add(1)), cpu.peek(addr.wrapping_add(2))]);
+        let (reads, writes) = match (opcode.mnemonic, opcode.mode) {
+            (BPL | BMI | BVC | BVS | BCC | BCS | BNE | BEQ, _) | (JMP, Absolute) => (false, false),
+            (TAX | TAY | TXA | TYA | TSX | TXS | INX | INY | DEX | DEY | NOP | CLC | SEC | CLV | CLD | SED, _) => {
+                (false, false)
+            }
+            (ASL | LSR | ROL | ROR, Accumulator) => (false, false),
+            (_, Immediate) if matches!(opcode.mnemonic, LDA | LDX | LDY | ADC | SBC | AND | ORA | EOR | CMP | CPX | CPY) => {
+                (false, false)
+            }
+            (LDA | LDX | LDY | ADC | SBC | AND | ORA | EOR | CMP | CPX | CPY | BIT, _) => (true, false),
+            (STA | STX | STY, _) => (false, true),
+            (INC | DEC | ASL | LSR | ROL | ROR, _) => (true, true),
+            _ => return None,
+        };
+        if (reads || writes) && !operand_is_plain(cpu, opcode.mode, operand, writes) {
+            return None;
+        }
+        let writes = writes.then(|| operand_range(opcode.mode, operand)).flatten();
+        Some(Translated { addr, opcode, operand, writes })
+    }
+
+    fn ends_block(&self) -> bool {
+        self.opcode.mode == AddressMode::Relative || self.opcode.mnemonic == Mnemonic::JMP
+    }
+}
+
+/// Every address the operand can refer to, whatever the index, for the
+/// modes that are translated
+fn operand_range(mode: AddressMode, operand: u16) -> Option<RangeInclusive<u16>> {
+    use AddressMode::*;
+    match mode {
+        ZeroPage => Some(operand & 0xFF..=operand & 0xFF),
+        ZeroPageX | ZeroPageY => Some(0x00..=0xFF),
+        Absolute => Some(operand..=operand),
+        AbsoluteX | AbsoluteY if operand <= 0xFF00 => Some(operand..=operand + 0xFF),
+        _ => None,
+    }
+}
+
+/// Whether every address the operand can refer to is plain memory
+fn operand_is_plain(cpu: &CPU, mode: AddressMode, operand: u16, write: bool) -> bool {
+    operand_range(mode, operand).is_some_and(|mut range| range.all(|addr| cpu.mem.is_plain(addr, write)))
+}
+
+/// Builds a block's function, keeping the registers in variables
+struct Emitter<'a> {
+    b: FunctionBuilder<'a>,
+    regs: Value,
+    mem: Value,
+    pointer: types::Type,
+    /// A, X, Y, SP and P in `BlockRegs` order
+    vars: [Variable; 5],
+}
+
+const A: usize = 0;
+const X: usize = 1;
+const Y: usize = 2;
+const SP: usize = 3;
+const P: usize = 4;
+
+impl<'a> Emitter<'a> {
+    fn new(mut b: FunctionBuilder<'a>, regs: Value, mem: Value, pointer: types::Type) -> Self {
+        let vars = [0, 1, 2, 3, 4].map(Variable::from_u32);
+        for (offset, &var) in vars.iter().enumerate() {
+            b.declare_var(var, types::I8);
+            let value = b.ins().load(types::I8, MemFlags::trusted(), regs, offset as i32);
+            b.def_var(var, value);
+        }
+        Emitter { b, regs, mem, pointer, vars }
+    }
+
+    /// Stores the registers back and returns `next` as the PC
+    fn finish(mut self, next: Value) {
+        for (offset, &var) in self.vars.iter().enumerate() {
+            let value = self.b.use_var(var);
+            self.b.ins().store(MemFlags::trusted(), value, self.regs, offset as i32);
+        }
+        self.b.ins().return_(&[next]);
+        self.b.finalize();
+    }
+
+    fn get(&mut self, reg: usize) -> Value {
+        self.b.use_var(self.vars[reg])
+    }
+
+    fn set(&mut self, reg: usize, value: Value) {
+        self.b.def_var(self.vars[reg], value);
+    }
+
+    /// Sets or clears the flags in `mask` from the bits of `value` under it
+    fn set_flags(&mut self, mask: Status, value: Value) {
+        let p = self.get(P);
+        let kept = self.b.ins().band_imm(p, !mask.bits() as i64);
+        let value = self.b.ins().band_imm(value, mask.bits() as i64);
+        let p = self.b.ins().bor(kept, value);
+        self.set(P, p);
+    }
+
+    /// Sets or clears one flag from a 0 or 1
+    fn set_flag(&mut self, flag: Status, bit: Value) {
+        let shifted = self.b.ins().ishl_imm(bit, flag.bits().trailing_zeros() as i64);
+        self.set_flags(flag, shifted);
+    }
+
+    fn update_zn(&mut self, value: Value) {
+        let zero = self.b.ins().icmp_imm(IntCC::Equal, value, 0);
+        self.set_flag(Status::ZERO, zero);
+        self.set_flags(Status::NEGATIVE, value);
+    }
+
+    /// A 0 or 1 for one flag
+    fn flag(&mut self, flag: Status) -> Value {
+        let p = self.get(P);
+        let bit = self.b.ins().band_imm(p, flag.bits() as i64);
+        self.b.ins().icmp_imm(IntCC::NotEqual, bit, 0)
+    }
+
+    /// Where the operand is in memory, as a base and an offset
+    fn address(&mut self, instruction: &Translated) -> (Value, i32) {
+        use AddressMode::*;
+        let operand = instruction.operand;
+        let indexed = |emitter: &mut Self, index: usize| {
+            let index = emitter.get(index);
+            emitter.b.ins().uextend(emitter.pointer, index)
+        };
+        match instruction.opcode.mode {
+            ZeroPage => (self.mem, (operand & 0xFF) as i32),
+            ZeroPageX | ZeroPageY => {
+                let index = if instruction.opcode.mode == ZeroPageX { X } else { Y };
+                let index = self.get(index);
+                // wraps within the zero page
+                let addr = self.b.ins().iadd_imm(index, (operand & 0xFF) as i64);
+                let addr = self.b.ins().uextend(self.pointer, addr);
+                (self.b.ins().iadd(self.mem, addr), 0)
+            }
+            Absolute => (self.mem, operand as i32),
+            AbsoluteX | AbsoluteY => {
+                let index = indexed(self, if instruction.opcode.mode == AbsoluteX { X } else { Y });
+                (self.b.ins().iadd(self.mem, index), operand as i32)
+            }
+            mode => unreachable!("{:?} isn't translated", mode),
+        }
+    }
+
+    fn operand(&mut self, instruction: &Translated) -> Value {
+        if instruction.opcode.mode == AddressMode::Immediate {
+            return self.b.ins().iconst(types::I8, (instruction.operand & 0xFF) as i64);
+        }
+        let (base, offset) = self.address(instruction);
+        self.b.ins().load(types::I8, MemFlags::trusted(), base, offset)
+    }
+
+    /// A + value + C, setting the flags
+    fn add(&mut self, value: Value) {
+        let a = self.get(A);
+        let carry = self.flag(Status::CARRY);
+        let wide = [a, value, carry].map(|v| self.b.ins().uextend(types::I16, v));
+        let sum = self.b.ins().iadd(wide[0], wide[1]);
+        let sum = self.b.ins().iadd(sum, wide[2]);
+        let result = self.b.ins().ireduce(types::I8, sum);
+        let carry = self.b.ins().ushr_imm(sum, 8);
+        let carry = self.b.ins().ireduce(types::I8, carry);
+        // overflow when both inputs have a different sign to the result
+        let a_sign = self.b.ins().bxor(a, result);
+        let value_sign = self.b.ins().bxor(value, result);
+        let overflow = self.b.ins().band(a_sign, value_sign);
+        let overflow = self.b.ins().ushr_imm(overflow, 1);
+        self.set_flags(Status::OVERFLOW, overflow);
+        self.set_flag(Status::CARRY, carry);
+        self.update_zn(result);
+        self.set(A, result);
+    }
+
+    fn compare(&mut self, reg: usize, value: Value) {
+        let reg = self.get(reg);
+        let carry = self.b.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, reg, value);
+        self.set_flag(Status::CARRY, carry);
+        let result = self.b.ins().isub(reg, value);
+        self.update_zn(result);
+    }
+
+    /// Emits one instruction, returning the next PC if it ends the block
+    fn instruction(&mut self, instruction: &Translated) -> Option<Value> {
+        use Mnemonic::*;
+        let next = instruction.addr + instruction.opcode.bytes;
+        let transfer = |emitter: &mut Self, from: usize, to: usize, flags: bool| {
+            let value = emitter.get(from);
+            emitter.set(to, value);
+            if flags {
+                emitter.update_zn(value);
+            }
+        };
+        let step = |emitter: &mut Self, reg: usize, by: i64| {
+            let value = emitter.get(reg);
+            let value = emitter.b.ins().iadd_imm(value, by);
+            emitter.set(reg, value);
+            emitter.update_zn(value);
+        };
+
+        match instruction.opcode.mnemonic {
+            LDA | LDX | LDY => {
+                let value = self.operand(instruction);
+                let reg = match instruction.opcode.mnemonic {
+                    LDA => A,
+                    LDX => X,
+                    _ => Y,
+                };
+                self.set(reg, value);
+                self.update_zn(value);
+            }
+            STA | STX | STY => {
+                let reg = match instruction.opcode.mnemonic {
+                    STA => A,
+                    STX => X,
+                    _ => Y,
+                };
+                let value = self.get(reg);
+                let (base, offset) = self.address(instruction);
+                self.b.ins().store(MemFlags::trusted(), value, base, offset);
+            }
+            ADC => {
+                let value = self.operand(instruction);
+                self.add(value);
+            }
+            SBC => {
+                // A - M - (1 - C) is A + !M + C
+                let value = self.operand(instruction);
+                let value = self.b.ins().bnot(value);
+                self.add(value);
+            }
+            AND | ORA | EOR => {
+                let value = self.operand(instruction);
+                let a = self.get(A);
+                let result = match instruction.opcode.mnemonic {
+                    AND => self.b.ins().band(a, value),
+                    ORA => self.b.ins().bor(a, value),
+                    _ => self.b.ins().bxor(a, value),
+                };
+                self.set(A, result);
+                self.update_zn(result);
+            }
+            CMP | CPX | CPY => {
+                let value = self.operand(instruction);
+                let reg = match instruction.opcode.mnemonic {
+                    CMP => A,
+                    CPX => X,
+                    _ => Y,
+                };
+                self.compare(reg, value);
+            }
+            BIT => {
+                let value = self.operand(instruction);
+                let a = self.get(A);
+                let masked = self.b.ins().band(a, value);
+                let zero = self.b.ins().icmp_imm(IntCC::Equal, masked, 0);
+                self.set_flag(Status::ZERO, zero);
+                self.set_flags(Status::from_bits(Status::NEGATIVE.bits() | Status::OVERFLOW.bits()), value);
+            }
+            INC | DEC => {
+                let (base, offset) = self.address(instruction);
+                let value = self.b.ins().load(types::I8, MemFlags::trusted(), base, offset);
+                let by = if instruction.opcode.mnemonic == INC { 1 } else { -1 };
+                let value = self.b.ins().iadd_imm(value, by);
+                self.b.ins().store(MemFlags::trusted(), value, base, offset);
+                self.update_zn(value);
+            }
+            ASL | LSR | ROL | ROR => {
+                let address = match instruction.opcode.mode {
+                    AddressMode::Accumulator => None,
+                    _ => Some(self.address(instruction)),
+                };
+                let value = match address {
+                    Some((base, offset)) => self.b.ins().load(types::I8, MemFlags::trusted(), base, offset),
+                    None => self.get(A),
+                };
+                let carry_in = self.flag(Status::CARRY);
+                let (carry, result) = match instruction.opcode.mnemonic {
+                    ASL | ROL => (self.b.ins().ushr_imm(value, 7), self.b.ins().ishl_imm(value, 1)),
+                    _ => (self.b.ins().band_imm(value, 1), self.b.ins().ushr_imm(value, 1)),
+                };
+                let result = match instruction.opcode.mnemonic {
+                    ROL => self.b.ins().bor(result, carry_in),
+                    ROR => {
+                        let top = self.b.ins().ishl_imm(carry_in, 7);
+                        self.b.ins().bor(result, top)
+                    }
+                    _ => result,
+                };
+                self.set_flag(Status::CARRY, carry);
+                match address {
+                    Some((base, offset)) => {
+                        self.b.ins().store(MemFlags::trusted(), result, base, offset);
+                    }
+                    None => self.set(A, result),
+                }
+                self.update_zn(result);
+            }
+            TAX => transfer(self, A, X, true),
+            TAY => transfer(self, A, Y, true),
+            TXA => transfer(self, X, A, true),
+            TYA => transfer(self, Y, A, true),
+            TSX => transfer(self, SP, X, true),
+            TXS => transfer(self, X, SP, false),
+            INX => step(self, X, 1),
+            INY => step(self, Y, 1),
+            DEX => step(self, X, -1),
+            DEY => step(self, Y, -1),
+            CLC | SEC | CLV | CLD | SED => {
+                let (flag, set) = match instruction.opcode.mnemonic {
+                    CLC => (Status::CARRY, false),
+                    SEC => (Status::CARRY, true),
+                    CLV => (Status::OVERFLOW, false),
+                    CLD => (Status::DECIMAL, false),
+                    _ => (Status::DECIMAL, true),
+                };
+                let value = self.b.ins().iconst(types::I8, if set { 0xFF } else { 0 });
+                self.set_flags(flag, value);
+            }
+            NOP => {}
+            JMP => return Some(self.b.ins().iconst(types::I32, instruction.operand as i64)),
+            BPL | BMI | BVC | BVS | BCC | BCS | BNE | BEQ => {
+                let (flag, set) = match instruction.opcode.mnemonic {
+                    BPL => (Status::NEGATIVE, false),
+                    BMI => (Status::NEGATIVE, true),
+                    BVC => (Status::OVERFLOW, false),
+                    BVS => (Status::OVERFLOW, true),
+                    BCC => (Status::CARRY, false),
+                    BCS => (Status::CARRY, true),
+                    BNE => (Status::ZERO, false),
+                    _ => (Status::ZERO, true),
+                };
+                let target = next.wrapping_add(instruction.operand as u8 as i8 as u16);
+                let bit = self.flag(flag);
+                let taken = self.b.ins().iconst(types::I32, target as i64);
+                let not_taken = self.b.ins().iconst(types::I32, next as i64);
+                return Some(match set {
+                    true => self.b.ins().select(bit, taken, not_taken),
+                    false => self.b.ins().select(bit, not_taken, taken),
+                });
+            }
+            mnemonic => unreachable!("{:?} isn't translated", mnemonic),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Peripheral;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Sums a table built in a loop, with carries, compares and shifts along the way
+    const HOT_LOOP: [u8; 34] = [
+        0xA0, 0x08, // LDY #$08
+        0xA2, 0x00, // outer: LDX #$00
+        0x8A, //       inner: TXA
+        0x18, //       CLC
+        0x65, 0x10, // ADC $10
+        0x9D, 0x00, 0x03, // STA $0300,X
+        0x5D, 0x00, 0x02, // EOR $0200,X
+        0x4A, //       LSR A
+        0x85, 0x10, // STA $10
+        0x26, 0x11, // ROL $11
+        0xC9, 0x40, // CMP #$40
+        0x2A, //       ROL A
+        0x95, 0x20, // STA $20,X
+        0xE8, //       INX
+        0xD0, 0xE9, // BNE inner
+        0x88, //       DEY
+        0xD0, 0xE4, // BNE outer
+        0x38, //       SEC
+        0xE9, 0x01, // SBC #$01
+        0x00, //       BRK
+    ];
+
+    fn hot_loop() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&HOT_LOOP);
+        cpu.load(0x0200, &(0..=0xFF).map(|i: u8| i.wrapping_mul(7)).collect::<Vec<_>>());
+        cpu.interrupt_reset();
+        cpu
+    }
+
+    fn ram(cpu: &CPU) -> Vec<u8> {
+        (0..0x0800).map(|addr| cpu.peek(addr)).collect()
+    }
+
+    #[test]
+    fn test_matches_interpreter() {
+        let mut interpreted = hot_loop();
+        assert_eq!(interpreted.run(), StopReason::Break);
+
+        let mut cpu = hot_loop();
+        let mut jit = Jit::new().unwrap();
+        assert_eq!(jit.run(&mut cpu), StopReason::Break);
+        assert_eq!(cpu.registers(), interpreted.registers());
+        assert_eq!(ram(&cpu), ram(&interpreted));
+
+        let stats = jit.stats();
+        // the inner loop, as the outer one only runs 8 times
+        assert_eq!(stats.blocks_compiled, 1);
+        assert!(stats.native > stats.interpreted * 10, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // LDA #$00, INC $0601 counting up LDA's operand, BNE -7
+        let program = [0xA9, 0x00, 0xEE, 0x01, 0x06, 0xD0, 0xF9, 0x00];
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&program);
+        cpu.interrupt_reset();
+        let mut jit = Jit::new().unwrap();
+        assert_eq!(jit.run(&mut cpu), StopReason::Break);
+        assert_eq!((cpu.registers().a, cpu.peek(0x0601)), (0xFF, 0x00));
+        // the block from $0600 ends at the INC, as it writes into the block,
+        // and goes stale after every run to be compiled again once hot. The
+        // ones from the INC and the BNE stay valid.
+        assert_eq!(jit.stats().blocks_compiled, 256 / HOT_THRESHOLD as u64 + 2);
+    }
+
+    #[test]
+    fn test_block_patches_itself() {
+        let program = [
+            0xA2, 0x00, //       LDX #$00
+            0xE8, //             loop: INX
+            0x8E, 0x07, 0x06, // STX $0607, the operand of the LDA after it
+            0xA9, 0x00, //       LDA #$00
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xE0, 0x80, //       CPX #$80
+            0xD0, 0xF3, //       BNE loop
+            0x00, //             BRK
+        ];
+        let mut interpreted = CPU::new();
+        interpreted.load_for_snake(&program);
+        interpreted.interrupt_reset();
+        assert_eq!(interpreted.run(), StopReason::Break);
+
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&program);
+        cpu.interrupt_reset();
+        let mut jit = Jit::new().unwrap();
+        assert_eq!(jit.run(&mut cpu), StopReason::Break);
+        assert!((0x01..=0x80).all(|x| cpu.peek(0x0200 + x) == x as u8));
+        assert_eq!(cpu.registers(), interpreted.registers());
+        assert_eq!(ram(&cpu), ram(&interpreted));
+        // the block from the loop ends at the STX, so the LDA is checked before it runs
+        assert!(jit.stats().blocks_compiled >= 1);
+    }
+
+    struct Counter(Rc<Cell<u8>>);
+
+    impl Peripheral for Counter {
+        fn read(&mut self, _offset: u16) -> u8 {
+            self.0.set(self.0.get().wrapping_add(1));
+            self.0.get()
+        }
+
+        fn write(&mut self, _offset: u16, _val: u8) {}
+    }
+
+    #[test]
+    fn test_peripherals_are_interpreted() {
+        // LDA $4000, TAX, LDY #$01, BNE -7 reading the peripheral each time
+        let reads = Rc::new(Cell::new(0));
+        let mut cpu = CPU::new();
+        cpu.map_peripheral(0x4000..=0x4000, Box::new(Counter(Rc::clone(&reads))));
+        cpu.load_for_snake(&[0xAD, 0x00, 0x40, 0xAA, 0xA0, 0x01, 0xD0, 0xF8]);
+        cpu.interrupt_reset();
+        let mut jit = Jit::new().unwrap();
+        for _ in 0..HOT_THRESHOLD * 8 {
+            jit.step(&mut cpu);
+        }
+        assert!(reads.get() > HOT_THRESHOLD as u8);
+        assert_eq!(cpu.registers().x, reads.get());
+        // the loop from TAX on is translated
+        assert_eq!(jit.stats().blocks_compiled, 1);
+    }
+}
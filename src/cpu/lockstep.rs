@@ -0,0 +1,132 @@
+//! Self-check mode running `CPU` and the `Reference` interpreter side by side.
+//!
+//! After every instruction the registers and any memory the reference wrote
+//! are compared, and the first difference is reported with the instruction
+//! that caused it. Writes only the main core made aren't caught.
+
+use std::fmt;
+
+use super::reference::Reference;
+use super::reg::RegisterSet;
+use super::{StopReason, CPU};
+
+/// The first point where the two cores disagreed
+#[derive(Debug)]
+pub struct Divergence {
+    /// Address of the instruction that was just executed
+    pub pc: u16,
+    pub opcode: u8,
+    pub cpu: RegisterSet,
+    pub reference: RegisterSet,
+    /// Addresses where memory differs, with the CPU's then the reference's value
+    pub memory: Vec<(u16, u8, u8)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(f, "cores diverged after opcode {:#04x} at ${:04X}", self.opcode, self.pc)?;
+        for (name, reg) in [("cpu", &self.cpu), ("reference", &self.reference)] {
+            writeln!(
+                f,
+                "  {:9}  PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                name, reg.pc, reg.a, reg.x, reg.y, reg.p.bits(), reg.sp
+            )?;
+        }
+        for (addr, cpu, reference) in &self.memory {
+            writeln!(f, "  ${:04X}: cpu {:02X}, reference {:02X}", addr, cpu, reference)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+pub struct Lockstep {
+    cpu: CPU,
+    reference: Reference,
+}
+
+impl Lockstep {
+    /// Starts the reference from the CPU's current registers and memory.
+    /// Interrupts can't be raised while running in lockstep.
+    pub fn new(cpu: CPU) -> Self {
+        let reference = Reference::from_cpu(&cpu);
+        Lockstep { cpu, reference }
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+
+    /// Steps both cores one instruction, as `CPU::step_next` does
+    pub fn step(&mut self) -> Result<Option<StopReason>, Divergence> {
+        let pc = self.cpu.registers().pc;
+        let opcode = self.cpu.read(pc);
+        if let Some(reason) = self.cpu.step_next() {
+            return Ok(Some(reason));
+        }
+
+        let stepped = self.reference.step();
+        let memory: Vec<(u16, u8, u8)> = self
+            .reference
+            .writes()
+            .iter()
+            .map(|&addr| (addr, self.cpu.read(addr), self.reference.read(addr)))
+            .filter(|(_, cpu, reference)| cpu != reference)
+            .collect();
+
+        if stepped.is_err() || *self.cpu.registers() != self.reference.reg || !memory.is_empty() {
+            return Err(Divergence { pc, opcode, cpu: *self.cpu.registers(), reference: self.reference.reg, memory });
+        }
+        Ok(None)
+    }
+
+    /// Runs until the CPU stops or the cores diverge
+    pub fn run(&mut self) -> Result<StopReason, Divergence> {
+        loop {
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "assembler")]
+    use crate::demo::ColorBarsDemo;
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_lockstep_agrees() {
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&ColorBarsDemo::program());
+        cpu.interrupt_reset();
+
+        let mut lockstep = Lockstep::new(cpu);
+        assert_eq!(lockstep.run().unwrap(), StopReason::Break);
+        assert_eq!(lockstep.cpu().read(0x0205), 2);
+    }
+
+    #[test]
+    fn test_lockstep_divergence() {
+        // LDA #$01, then a change behind the reference's back
+        let mut cpu = CPU::new();
+        cpu.load_for_snake(&[0xA9, 0x01, 0xEA, 0x00]);
+        cpu.interrupt_reset();
+
+        let mut lockstep = Lockstep::new(cpu);
+        lockstep.step().unwrap();
+        lockstep.cpu.registers_mut().x = 5;
+
+        let divergence = lockstep.step().unwrap_err();
+        assert_eq!((divergence.pc, divergence.opcode), (0x0602, 0xEA));
+        assert_eq!((divergence.cpu.x, divergence.reference.x), (5, 0));
+        assert!(divergence.to_string().contains("X:05"));
+    }
+}
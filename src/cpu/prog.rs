@@ -1,14 +1,23 @@
-mod instructions;
+#[cfg(feature = "assembler")]
+pub mod asm;
+pub mod flow;
+pub mod graph;
+pub mod instructions;
+pub mod optimize;
+#[cfg(feature = "assembler")]
 mod parse;
 
-use std::{fmt::Display, str::FromStr};
-
-use nom::IResult;
-use nom::{bytes::complete::take, };
+use std::fmt::Display;
+#[cfg(feature = "assembler")]
+use std::str::FromStr;
 
 use instructions::Instruction;
+use crate::cpu::Penalty;
+#[cfg(feature = "assembler")]
+pub(crate) use parse::parse_operand;
 use crate::cpu::ops::Mnemonic;
 
+#[derive(Clone)]
 pub struct Program {
     start: u16,
     code: Vec<Instruction>
@@ -19,13 +28,106 @@ impl Program {
     pub fn new() -> Self {
         Program { start: 0, code: Vec::new() }
     }
+
+    /// Sets the address the first instruction is loaded at
+    pub fn with_start(mut self, start: u16) -> Self {
+        self.start = start;
+        self
+    }
+
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    /// Address of each instruction, in program order
+    pub fn addresses(&self) -> Vec<u16> {
+        self.code
+            .iter()
+            .scan(self.start, |addr, instruction| {
+                let this = *addr;
+                *addr = addr.wrapping_add(instruction.size());
+                Some(this)
+            })
+            .collect()
+    }
+
+    /// Machine code for the whole program
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.code.iter().flat_map(|instruction| instruction.to_bytes()).collect()
+    }
+
+    /// Fewest and most cycles the instructions starting in `range` can take,
+    /// run once each from first to last. Branches count as not taken for
+    /// the fewest, and indexed reads as crossing a page for the most.
+    pub fn cycle_estimate(&self, range: std::ops::Range<u16>) -> std::ops::RangeInclusive<u32> {
+        let (mut min, mut max) = (0, 0);
+        for (instruction, addr) in self.code.iter().zip(self.addresses()) {
+            if !range.contains(&addr) {
+                continue;
+            }
+            let timing = instruction.opcode().timing();
+            min += timing.base as u32;
+            max += timing.base as u32
+                + match timing.penalty {
+                    Penalty::None => 0,
+                    Penalty::PageCross => 1,
+                    Penalty::Branch => match instruction.branch_target(addr) {
+                        Some(target) if target >> 8 != addr.wrapping_add(2) >> 8 => 2,
+                        _ => 1,
+                    },
+                };
+        }
+        min..=max
+    }
+
+    /// Listing with the address and encoded bytes alongside each instruction
+    pub fn listing(&self) -> String {
+        self.code
+            .iter()
+            .zip(self.addresses())
+            .map(|(instruction, addr)| fmt_listing_line(addr, instruction) + "\n")
+            .collect()
+    }
+}
+
+/// Formats one line of a listing: address, encoded bytes, then the instruction
+pub(crate) fn fmt_listing_line(addr: u16, instruction: &Instruction) -> String {
+    fmt_named_listing_line(addr, instruction, &std::collections::BTreeMap::new())
 }
 
+/// `fmt_listing_line` with zero page operands named, see `Instruction::to_string_with_names`
+pub(crate) fn fmt_named_listing_line(addr: u16, instruction: &Instruction, names: &std::collections::BTreeMap<u16, String>) -> String {
+    let bytes = instruction
+        .to_bytes()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{:04X}  {:8}  {}", addr, bytes, instruction.to_string_with_names(names))
+}
+
+#[cfg(feature = "assembler")]
 impl FromStr for Program {
     type Err = Box<dyn std::error::Error>;
 
-    fn from_str(_s: &str) -> Result<Self, Self::Err> {
-        Ok(Program::new())
+    /// Assembles the source, see `asm` for the syntax.
+    /// Data directives are decoded as instructions like any other bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let assembly = asm::assemble(s)?;
+
+        let mut code = Vec::new();
+        let mut rest = &assembly.bytes[..];
+        while !rest.is_empty() {
+            let instruction = Instruction::decode(rest).ok_or("assembled bytes don't decode as instructions")?;
+            rest = &rest[instruction.size() as usize..];
+            code.push(instruction);
+        }
+
+        Ok(Program { start: assembly.origin, code })
     }
 }
 
@@ -34,9 +136,13 @@ impl TryFrom<&[u8]> for Program {
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         let mut instructions = Vec::new();
-        
-        let mut cursor = value.iter();
-        while let Some(instruction) = Instruction::from_iter(&mut cursor) {
+
+        let mut offset = 0;
+        while offset < value.len() {
+            let instruction = Instruction::decode(&value[offset..]).ok_or_else(|| {
+                format!("unknown opcode or truncated operand at offset {:#06x}: {:#04x}", offset, value[offset])
+            })?;
+            offset += instruction.size() as usize;
             instructions.push(instruction);
         }
 
@@ -112,7 +218,43 @@ mod tests {
         // LDA #23
         // BRK
         let program = Program::try_from(&[0xA9, 0x23, 0x00][..]).unwrap();
-        assert_eq!(format!("{}", program), "LDA #23\nBRK\n");
+        assert_eq!(format!("{}", program), "LDA #$23\nBRK\n");
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_assemble_round_trip() {
+        let program = Program::try_from(&[0xAD, 0x10, 0x00, 0xF0, 0xFB][..]).unwrap();
+        let reassembled: Program = program.to_string().parse().unwrap();
+        assert_eq!(reassembled.to_bytes(), program.to_bytes());
+
+        // immediates that start with a letter aren't symbols
+        let program = Program::try_from(&[0xA9, 0xE7][..]).unwrap();
+        let reassembled: Program = program.to_string().parse().unwrap();
+        assert_eq!(reassembled.to_bytes(), vec![0xA9, 0xE7]);
+
+        let program = Program::try_from(SNAKE_BYTES).unwrap();
+        let reassembled: Program = program.to_string().parse().unwrap();
+        assert_eq!(reassembled.to_bytes(), SNAKE_BYTES);
+
+        let program: Program = ".org $0600\nloop: DEX\nBNE loop".parse().unwrap();
+        assert_eq!(program.start(), 0x0600);
+        assert_eq!(program.to_bytes(), vec![0xCA, 0xD0, 0xFD]);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_cycle_estimate() {
+        let program: Program = ".org $06F0\nLDX #$08\nloop: LDA $0300,X\nSTA $0400,X\nDEX\nBNE loop\nBEQ far\n.org $0700\nfar: RTS"
+            .parse()
+            .unwrap();
+        // LDA 4-5, STA 5, DEX 2, BNE 2-3
+        assert_eq!(program.cycle_estimate(0x06F2..0x06FB), 13..=15);
+        // the BEQ at $06FB lands on the next page
+        assert_eq!(program.cycle_estimate(0x06FB..0x06FD), 2..=4);
+        // with LDX 2, up to the padding before `far`
+        assert_eq!(program.cycle_estimate(0x0000..0x06FD), 17..=21);
+        assert_eq!(program.cycle_estimate(0x8000..0x9000), 0..=0);
     }
 
     #[test]
@@ -121,4 +263,12 @@ mod tests {
         // may later try to compare it against the source
         let _: Program = SNAKE_BYTES.try_into().unwrap();
     }
+
+    #[test]
+    fn test_disassemble_errors() {
+        // JAM opcode, then an absolute operand cut short
+        let err = Program::try_from(&[0xEA, 0x02][..]).err().unwrap();
+        assert!(err.to_string().contains("offset 0x0001"), "{}", err);
+        assert!(Program::try_from(&[0xAD, 0x10][..]).is_err());
+    }
 }
\ No newline at end of file
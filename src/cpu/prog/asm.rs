@@ -0,0 +1,1085 @@
+//! Two pass assembler with labels and constant expressions.
+//!
+//! The first pass records label addresses and picks an addressing mode for
+//! every instruction; the second evaluates operands against the finished
+//! symbol table and encodes them. Operands referring to symbols which are
+//! only defined further down are assumed to be absolute, since their value
+//! isn't known when the instruction's size is decided.
+//!
+//! Syntax follows the rest of the crate: `$` prefixes hex, bare numbers are
+//! hex as well (`LDA #0f`), `%` prefixes binary, and `*+n`/`*-n` on a branch
+//! is a raw offset. Expressions may use labels, `*` for the current address,
+//! `+ - * /`, brackets, and `<`/`>` for the low and high byte.
+//!
+//! `.include "file.s"` splices in another file, searched for next to the
+//! including file and then in each configured include path.
+//!
+//! Source written for ca65 or nesasm can be assembled by picking that
+//! `Dialect`, which switches bare numbers to decimal, the data directive
+//! names, local label syntax, and for nesasm `[ ]` brackets for indirection.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nom::{Err as NomErr, IResult};
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, tag_no_case, take_while, take_while1};
+use nom::character::complete::{alpha1, char, digit1, one_of, satisfy, space0, space1};
+use nom::combinator::{all_consuming, eof, map, map_res, opt, recognize};
+use nom::error::{make_error, ErrorKind};
+use nom::multi::separated_list1;
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+
+use crate::cart::{Cartridge, Mirroring};
+use crate::cpu::addr::{Addr, AddressMode, ZpAddr};
+use crate::cpu::ops::{Mnemonic, Opcode};
+
+use super::instructions::{Instruction, Operand};
+
+/// Output of the assembler: a contiguous image and the symbols defined
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Assembly {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub symbols: BTreeMap<String, u16>,
+}
+
+/// Labels whose addresses `to_ines` writes into the NMI, reset and IRQ vectors
+pub(crate) const VECTOR_LABELS: [(&str, u16); 3] = [("nmi", 0xFFFA), ("reset", 0xFFFC), ("irq", 0xFFFE)];
+
+impl Assembly {
+    /// Wraps the code in an iNES image, NROM-128 if it fits in $C000-$FFFF
+    /// and NROM-256 otherwise. The vectors are filled from the `nmi`, `reset`
+    /// and `irq` labels, with NMI and IRQ going to reset when their label is
+    /// missing. `chr_rom` can be empty for CHR RAM.
+    pub fn to_ines(&self, mapper: u8, mirroring: Mirroring, chr_rom: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let end = self.origin as usize + self.bytes.len();
+        if self.origin < 0x8000 || end > 0x10000 {
+            return Err(format!("code at ${:04X}-${:04X} is outside PRG ROM at $8000-$FFFF", self.origin, end - 1).into());
+        }
+        let reset = *self.symbols.get("reset").ok_or("no `reset` label to start at")?;
+        if end > 0xFFFA {
+            return Err(format!("code at ${:04X}-${:04X} overlaps the vectors at $FFFA", self.origin, end - 1).into());
+        }
+
+        let base = if self.origin >= 0xC000 { 0xC000 } else { 0x8000 };
+        let mut prg_rom = vec![0; 0x10000 - base];
+        let start = self.origin as usize - base;
+        prg_rom[start..start + self.bytes.len()].copy_from_slice(&self.bytes);
+        for (label, vector) in VECTOR_LABELS {
+            let target = self.symbols.get(label).copied().unwrap_or(reset);
+            let offset = vector as usize - base;
+            prg_rom[offset..offset + 2].copy_from_slice(&target.to_le_bytes());
+        }
+
+        let cart = Cartridge { prg_rom, chr_rom: chr_rom.to_vec(), mapper, mirroring, battery: false };
+        cart.to_ines()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Expr {
+    Number(i32),
+    /// Hex literal written with more than two digits, e.g. `$0010`,
+    /// which keeps absolute addressing even for zero page values
+    WideNumber(i32),
+    Symbol(String),
+    /// Address of the current statement
+    Pc,
+    LowByte(Box<Expr>),
+    HighByte(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Index {
+    None,
+    X,
+    Y,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum OperandExpr {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr, Index),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+    /// `*+n` branch offset, taken as is
+    RawOffset(i8),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum DataItem {
+    Expr(Expr),
+    Text(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Statement {
+    Instruction(Mnemonic, OperandExpr),
+    Bytes(Vec<DataItem>),
+    Words(Vec<Expr>),
+    Org(Expr),
+    Constant(String, Expr),
+    /// Replaced by the included file's lines while reading the source
+    Include(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Line {
+    /// `line N`, or `file:N` for source read from a file
+    location: String,
+    label: Option<String>,
+    statement: Option<Statement>,
+}
+
+/// Result of the first pass
+struct Layout {
+    symbols: BTreeMap<String, u16>,
+    /// Addressing mode chosen for each line holding an instruction
+    modes: Vec<Option<AddressMode>>,
+}
+
+/// Source syntax accepted by the assembler
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Dialect {
+    /// The syntax the disassembler prints: bare numbers are hex, `*+n` branch offsets
+    #[default]
+    Native,
+    /// ca65: decimal numbers, `.byte`/`.word`/`.addr`, `@local` labels
+    Ca65,
+    /// nesasm: decimal numbers, `.db`/`.dw`, `.local` labels, `[ ]` for indirection
+    Nesasm,
+}
+
+impl Dialect {
+    fn bare_radix(self) -> u32 {
+        match self {
+            Dialect::Native => 16,
+            Dialect::Ca65 | Dialect::Nesasm => 10,
+        }
+    }
+
+    fn local_prefix(self) -> Option<char> {
+        match self {
+            Dialect::Native => None,
+            Dialect::Ca65 => Some('@'),
+            Dialect::Nesasm => Some('.'),
+        }
+    }
+
+    fn indirect_brackets(self) -> (char, char) {
+        match self {
+            Dialect::Native | Dialect::Ca65 => ('(', ')'),
+            Dialect::Nesasm => ('[', ']'),
+        }
+    }
+
+    fn byte_directives(self) -> &'static [&'static str] {
+        match self {
+            Dialect::Native | Dialect::Ca65 => &[".byte", ".byt"],
+            Dialect::Nesasm => &[".db", "db", ".byte"],
+        }
+    }
+
+    fn word_directives(self) -> &'static [&'static str] {
+        match self {
+            Dialect::Native => &[".word"],
+            Dialect::Ca65 => &[".word", ".addr"],
+            Dialect::Nesasm => &[".dw", "dw", ".word"],
+        }
+    }
+}
+
+/// Assembles source text into machine code
+#[derive(Debug, Default, Clone)]
+pub struct Assembler {
+    include_paths: Vec<PathBuf>,
+    dialect: Dialect,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler { include_paths: Vec::new(), dialect: Dialect::Native }
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Adds a directory to search for included files
+    pub fn with_include_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Assembles source text. Includes are looked up in the current
+    /// directory, then the include paths.
+    pub fn assemble(&self, source: &str) -> Result<Assembly, Box<dyn Error>> {
+        let mut lines = Vec::new();
+        self.read_source(source, None, &mut Vec::new(), &mut lines)?;
+        self.finish(lines)
+    }
+
+    pub fn assemble_file(&self, path: impl AsRef<Path>) -> Result<Assembly, Box<dyn Error>> {
+        let mut lines = Vec::new();
+        self.read_file(path.as_ref(), &mut Vec::new(), &mut lines)?;
+        self.finish(lines)
+    }
+
+    fn finish(&self, mut lines: Vec<Line>) -> Result<Assembly, Box<dyn Error>> {
+        if let Some(prefix) = self.dialect.local_prefix() {
+            qualify_locals(&mut lines, prefix);
+        }
+        let layout = layout(&lines)?;
+        encode(&lines, layout)
+    }
+
+    /// Reads `path` into `lines`. `stack` holds the files currently being
+    /// included, so a file including itself is caught rather than recursing forever.
+    fn read_file(&self, path: &Path, stack: &mut Vec<PathBuf>, lines: &mut Vec<Line>) -> Result<(), Box<dyn Error>> {
+        let canonical = path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?;
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack.iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+            return Err(format!("include cycle: {}", chain.join(" -> ")).into());
+        }
+
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        stack.push(canonical);
+        self.read_source(&source, Some(path), stack, lines)?;
+        stack.pop();
+        Ok(())
+    }
+
+    fn read_source(
+        &self,
+        source: &str,
+        file: Option<&Path>,
+        stack: &mut Vec<PathBuf>,
+        lines: &mut Vec<Line>,
+    ) -> Result<(), Box<dyn Error>> {
+        // editors on Windows may start the file with a byte order mark
+        let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+        for (i, text) in source.lines().enumerate() {
+            let location = match file {
+                Some(path) => format!("{}:{}", path.display(), i + 1),
+                None => format!("line {}", i + 1),
+            };
+            let line = parse_line(self.dialect, location, text)?;
+
+            match &line.statement {
+                Some(Statement::Include(name)) => {
+                    let path = self
+                        .resolve_include(name, file)
+                        .ok_or_else(|| line_error(&line, format!("can't find include `{}`", name)))?;
+                    // keep any label, which then points at the start of the included code
+                    lines.push(Line { statement: None, ..line });
+                    self.read_file(&path, stack, lines)?;
+                }
+                _ => lines.push(line),
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_include(&self, name: &str, from: Option<&Path>) -> Option<PathBuf> {
+        let dir = from.and_then(Path::parent).map(Path::to_path_buf).unwrap_or_default();
+        std::iter::once(dir)
+            .chain(self.include_paths.iter().cloned())
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+}
+
+/// Assembles `source` with the default settings
+pub fn assemble(source: &str) -> Result<Assembly, Box<dyn Error>> {
+    Assembler::new().assemble(source)
+}
+
+fn line_error(line: &Line, message: impl std::fmt::Display) -> Box<dyn Error> {
+    format!("{}: {}", line.location, message).into()
+}
+
+/*
+ * Parsing
+ */
+
+/// Deepest bracket and unary operator nesting accepted in a line. The
+/// expression parser is recursive, so this keeps hostile input from
+/// overflowing the stack.
+const MAX_NESTING: usize = 32;
+
+/// How deeply brackets and chains of unary operators nest in a line. Ends
+/// of unary chains are only found roughly, so this may overestimate.
+fn nesting_depth(text: &str) -> usize {
+    let (mut enclosing, mut base, mut depth, mut max) = (Vec::new(), 0, 0, 0);
+    let mut previous = None;
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        match c {
+            '-' | '<' | '>' if previous.is_none_or(|p| "#([,=+-*/<>".contains(p)) => depth += 1,
+            '(' | '[' => {
+                enclosing.push(base);
+                base = depth + 1;
+                depth = base;
+            }
+            ')' | ']' => {
+                base = enclosing.pop().unwrap_or(0);
+                depth = base;
+            }
+            _ => depth = base,
+        }
+        max = max.max(depth);
+        previous = Some(c);
+    }
+    max
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => (),
+        }
+    }
+    text
+}
+
+fn symbol(d: Dialect, s: &str) -> IResult<&str, &str> {
+    let local = d.local_prefix();
+    recognize(pair(
+        satisfy(move |c| c.is_ascii_alphabetic() || c == '_' || Some(c) == local),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(s)
+}
+
+fn number(d: Dialect, s: &str) -> IResult<&str, Expr> {
+    alt((
+        map_res(preceded(char('$'), take_while1(|c: char| c.is_ascii_hexdigit())), |digits: &str| {
+            let value = i32::from_str_radix(digits, 16)?;
+            Ok::<_, std::num::ParseIntError>(if digits.len() > 2 { Expr::WideNumber(value) } else { Expr::Number(value) })
+        }),
+        map_res(preceded(char('%'), take_while1(|c| c == '0' || c == '1')), |digits| {
+            i32::from_str_radix(digits, 2).map(Expr::Number)
+        }),
+        map_res(recognize(pair(digit1, take_while(|c: char| c.is_ascii_hexdigit()))), move |digits| {
+            i32::from_str_radix(digits, d.bare_radix()).map(Expr::Number)
+        }),
+    ))(s)
+}
+
+fn primary(d: Dialect, s: &str) -> IResult<&str, Expr> {
+    alt((
+        |s| number(d, s),
+        map(|s| symbol(d, s), |name| Expr::Symbol(name.to_string())),
+        map(char('*'), |_| Expr::Pc),
+        delimited(pair(char('('), space0), |s| expr(d, s), pair(space0, char(')'))),
+    ))(s)
+}
+
+fn unary(d: Dialect, s: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded(pair(char('<'), space0), |s| unary(d, s)), |e| Expr::LowByte(Box::new(e))),
+        map(preceded(pair(char('>'), space0), |s| unary(d, s)), |e| Expr::HighByte(Box::new(e))),
+        map(preceded(pair(char('-'), space0), |s| unary(d, s)), |e| Expr::Negate(Box::new(e))),
+        |s| primary(d, s),
+    ))(s)
+}
+
+/// Left associative chain of `operand (op operand)*`
+fn binary_chain<'a>(
+    d: Dialect,
+    s: &'a str,
+    ops: &'static str,
+    operand: fn(Dialect, &'a str) -> IResult<&'a str, Expr>,
+) -> IResult<&'a str, Expr> {
+    let (mut rem, mut lhs) = operand(d, s)?;
+    while let Ok((after, (op, rhs))) = pair(delimited(space0, one_of(ops), space0), |s| operand(d, s))(rem) {
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        rem = after;
+    }
+    Ok((rem, lhs))
+}
+
+fn term(d: Dialect, s: &str) -> IResult<&str, Expr> {
+    binary_chain(d, s, "*/", unary)
+}
+
+fn expr(d: Dialect, s: &str) -> IResult<&str, Expr> {
+    binary_chain(d, s, "+-", term)
+}
+
+fn index(s: &str) -> IResult<&str, Index> {
+    preceded(
+        tuple((space0, char(','), space0)),
+        alt((map(tag_no_case("X"), |_| Index::X), map(tag_no_case("Y"), |_| Index::Y))),
+    )(s)
+}
+
+fn raw_offset(s: &str) -> IResult<&str, OperandExpr> {
+    map_res(recognize(preceded(char('*'), pair(one_of("+-"), digit1))), |text: &str| {
+        text[1..].parse::<i8>().map(OperandExpr::RawOffset)
+    })(s)
+}
+
+fn operand_expr(d: Dialect, mnemonic: Mnemonic, s: &str) -> IResult<&str, OperandExpr> {
+    let (open, close) = d.indirect_brackets();
+    let e = |s| expr(d, s);
+
+    // anywhere else `*` is just the current address
+    if d == Dialect::Native && Opcode::find(mnemonic, AddressMode::Relative).is_some() {
+        if let Ok((rem, offset)) = terminated(raw_offset, eof)(s) {
+            return Ok((rem, offset));
+        }
+    }
+
+    all_consuming(alt((
+        map(eof, |_| OperandExpr::None),
+        map(terminated(tag_no_case("A"), eof), |_| OperandExpr::Accumulator),
+        map(preceded(pair(char('#'), space0), e), OperandExpr::Immediate),
+        map(
+            delimited(pair(char(open), space0), e, tuple((space0, char(','), space0, tag_no_case("X"), space0, char(close)))),
+            OperandExpr::IndirectX,
+        ),
+        map(
+            delimited(pair(char(open), space0), e, tuple((space0, char(close), space0, char(','), space0, tag_no_case("Y")))),
+            OperandExpr::IndirectY,
+        ),
+        map(terminated(delimited(pair(char(open), space0), e, pair(space0, char(close))), eof), OperandExpr::Indirect),
+        map(pair(e, opt(index)), |(e, i)| OperandExpr::Direct(e, i.unwrap_or(Index::None))),
+    )))(s)
+}
+
+fn mnemonic(s: &str) -> IResult<&str, Mnemonic> {
+    map_res(alpha1, |word: &str| word.to_uppercase().parse::<Mnemonic>())(s)
+}
+
+fn data_item(d: Dialect, s: &str) -> IResult<&str, DataItem> {
+    alt((
+        map(delimited(char('"'), opt(is_not("\"")), char('"')), |text: Option<&str>| {
+            DataItem::Text(text.unwrap_or_default().to_string())
+        }),
+        map(|s| expr(d, s), DataItem::Expr),
+    ))(s)
+}
+
+fn list<'a, T>(
+    d: Dialect,
+    item: fn(Dialect, &'a str) -> IResult<&'a str, T>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<T>> {
+    separated_list1(tuple((space0, char(','), space0)), move |s| item(d, s))
+}
+
+/// Matches any of a dialect's spellings of a directive, followed by a space
+fn directive_name<'a>(names: &'static [&'static str]) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |s: &'a str| {
+        for name in names {
+            if let Ok(result) = terminated(tag_no_case::<_, _, nom::error::Error<&str>>(*name), space1)(s) {
+                return Ok(result);
+            }
+        }
+        Err(NomErr::Error(make_error(s, ErrorKind::Tag)))
+    }
+}
+
+fn directive(d: Dialect, s: &str) -> IResult<&str, Statement> {
+    alt((
+        map(preceded(directive_name(d.byte_directives()), list(d, data_item)), Statement::Bytes),
+        map(preceded(directive_name(d.word_directives()), list(d, expr)), Statement::Words),
+        map(preceded(directive_name(&[".org"]), |s| expr(d, s)), Statement::Org),
+        map(
+            preceded(directive_name(&[".include"]), delimited(char('"'), is_not("\""), char('"'))),
+            |name: &str| Statement::Include(name.to_string()),
+        ),
+    ))(s)
+}
+
+fn constant(d: Dialect, s: &str) -> IResult<&str, Statement> {
+    map(
+        pair(|s| symbol(d, s), preceded(tuple((space0, char('='), space0)), |s| expr(d, s))),
+        |(name, value)| Statement::Constant(name.to_string(), value),
+    )(s)
+}
+
+fn instruction(d: Dialect, s: &str) -> IResult<&str, Statement> {
+    let (rem, mnem) = terminated(mnemonic, space0)(s)?;
+    let (rem, operand) = operand_expr(d, mnem, rem.trim_end())?;
+    Ok((rem, Statement::Instruction(mnem, operand)))
+}
+
+fn label(d: Dialect, s: &str) -> IResult<&str, &str> {
+    terminated(|s| symbol(d, s), pair(char(':'), space0))(s)
+}
+
+fn parse_line(d: Dialect, location: String, text: &str) -> Result<Line, Box<dyn Error>> {
+    let text = strip_comment(text).trim();
+    let (rest, label) = opt(|s| label(d, s))(text).map_err(|_| format!("{}: bad label", location))?;
+
+    let statement = if rest.is_empty() {
+        None
+    } else if nesting_depth(rest) > MAX_NESTING {
+        return Err(format!("{}: expression nested more than {} deep", location, MAX_NESTING).into());
+    } else {
+        let statement = alt((|s| directive(d, s), |s| constant(d, s), |s| instruction(d, s)));
+        match all_consuming(statement)(rest.trim_end()) {
+            Ok((_, statement)) => Some(statement),
+            Err(_) => return Err(format!("{}: can't parse `{}`", location, rest).into()),
+        }
+    };
+
+    Ok(Line { location, label: label.map(str::to_string), statement })
+}
+
+fn qualify(expr: &mut Expr, scope: &str, prefix: char) {
+    match expr {
+        Expr::Symbol(name) if name.starts_with(prefix) => *name = format!("{}{}", scope, name),
+        Expr::LowByte(e) | Expr::HighByte(e) | Expr::Negate(e) => qualify(e, scope, prefix),
+        Expr::Binary(_, lhs, rhs) => {
+            qualify(lhs, scope, prefix);
+            qualify(rhs, scope, prefix);
+        }
+        _ => (),
+    }
+}
+
+/// Renames local labels after the global label they follow, so `@loop`
+/// under `reset:` becomes `reset@loop` and can be reused under other labels
+fn qualify_locals(lines: &mut [Line], prefix: char) {
+    let mut scope = String::new();
+    for line in lines.iter_mut() {
+        match &mut line.label {
+            Some(label) if label.starts_with(prefix) => *label = format!("{}{}", scope, label),
+            Some(label) => scope = label.clone(),
+            None => (),
+        }
+
+        let exprs: Vec<&mut Expr> = match &mut line.statement {
+            Some(Statement::Instruction(_, operand)) => match operand {
+                OperandExpr::Immediate(e)
+                | OperandExpr::Direct(e, _)
+                | OperandExpr::Indirect(e)
+                | OperandExpr::IndirectX(e)
+                | OperandExpr::IndirectY(e) => vec![e],
+                _ => vec![],
+            },
+            Some(Statement::Bytes(items)) => items
+                .iter_mut()
+                .filter_map(|item| match item {
+                    DataItem::Expr(e) => Some(e),
+                    DataItem::Text(_) => None,
+                })
+                .collect(),
+            Some(Statement::Words(items)) => items.iter_mut().collect(),
+            Some(Statement::Org(e)) | Some(Statement::Constant(_, e)) => vec![e],
+            Some(Statement::Include(_)) | None => vec![],
+        };
+        for e in exprs {
+            qualify(e, &scope, prefix);
+        }
+    }
+}
+
+/*
+ * Evaluation
+ */
+
+fn eval(expr: &Expr, symbols: &BTreeMap<String, u16>, pc: u16) -> Result<i32, String> {
+    Ok(match expr {
+        Expr::Number(n) | Expr::WideNumber(n) => *n,
+        Expr::Symbol(name) => match symbols.get(name) {
+            Some(&value) => value as i32,
+            None => return Err(format!("undefined symbol `{}`", name)),
+        },
+        Expr::Pc => pc as i32,
+        Expr::LowByte(e) => eval(e, symbols, pc)? & 0xFF,
+        Expr::HighByte(e) => (eval(e, symbols, pc)? >> 8) & 0xFF,
+        Expr::Negate(e) => eval(e, symbols, pc)?.checked_neg().ok_or("arithmetic overflow")?,
+        Expr::Binary(op, lhs, rhs) => {
+            let (lhs, rhs) = (eval(lhs, symbols, pc)?, eval(rhs, symbols, pc)?);
+            let result = match op {
+                '+' => lhs.checked_add(rhs),
+                '-' => lhs.checked_sub(rhs),
+                '*' => lhs.checked_mul(rhs),
+                '/' if rhs == 0 => return Err("division by zero".into()),
+                '/' => lhs.checked_div(rhs),
+                _ => unreachable!(),
+            };
+            result.ok_or("arithmetic overflow")?
+        }
+    })
+}
+
+fn to_byte(value: i32) -> Result<u8, String> {
+    match value {
+        -0x80..=0xFF => Ok(value as u8),
+        _ => Err(format!("value {} doesn't fit in a byte", value)),
+    }
+}
+
+/// Zero page addresses and pointers, which unlike immediates can't be negative
+fn to_zero_page(value: i32) -> Result<ZpAddr, String> {
+    let addr = Addr(to_word(value)?);
+    match value {
+        0.. => ZpAddr::try_from(addr),
+        _ => Err(format!("{} is not a zero page address", value)),
+    }
+}
+
+fn to_word(value: i32) -> Result<u16, String> {
+    match value {
+        -0x8000..=0xFFFF => Ok(value as u16),
+        _ => Err(format!("value {} doesn't fit in a word", value)),
+    }
+}
+
+/// Chooses an addressing mode for an instruction. `hint` is the operand's
+/// value if it could be worked out on the first pass.
+fn select_mode(mnemonic: Mnemonic, operand: &OperandExpr, hint: Option<i32>) -> Result<AddressMode, String> {
+    use AddressMode::*;
+    let has = |mode| Opcode::find(mnemonic, mode).is_some();
+
+    let candidates: Vec<AddressMode> = match operand {
+        OperandExpr::None => vec![Implicit, Accumulator],
+        OperandExpr::Accumulator => vec![Accumulator],
+        OperandExpr::Immediate(_) => vec![Immediate],
+        OperandExpr::RawOffset(_) => vec![Relative],
+        OperandExpr::IndirectX(_) => vec![IndirectX],
+        OperandExpr::IndirectY(_) => vec![IndirectY],
+        // brackets round a plain operand are only an indirect jump for JMP
+        OperandExpr::Indirect(_) if has(Indirect) => vec![Indirect],
+        OperandExpr::Indirect(_) | OperandExpr::Direct(_, Index::None) if has(Relative) => vec![Relative],
+        OperandExpr::Indirect(_) | OperandExpr::Direct(_, _) => {
+            let index = match operand {
+                OperandExpr::Direct(_, index) => *index,
+                _ => Index::None,
+            };
+            let (zp, abs) = match index {
+                Index::None => (ZeroPage, Absolute),
+                Index::X => (ZeroPageX, AbsoluteX),
+                Index::Y => (ZeroPageY, AbsoluteY),
+            };
+            match hint {
+                Some(0..=0xFF) => vec![zp, abs],
+                _ => vec![abs, zp],
+            }
+        }
+    };
+
+    candidates
+        .into_iter()
+        .find(|&mode| has(mode))
+        .ok_or_else(|| format!("{} doesn't support this addressing mode", mnemonic))
+}
+
+fn operand_value(operand: &OperandExpr) -> Option<&Expr> {
+    match operand {
+        OperandExpr::Immediate(e)
+        | OperandExpr::Direct(e, _)
+        | OperandExpr::Indirect(e)
+        | OperandExpr::IndirectX(e)
+        | OperandExpr::IndirectY(e) => Some(e),
+        _ => None,
+    }
+}
+
+fn data_size(items: &[DataItem]) -> u16 {
+    items
+        .iter()
+        .map(|item| match item {
+            DataItem::Expr(_) => 1,
+            DataItem::Text(text) => text.len() as u16,
+        })
+        .sum()
+}
+
+/// First pass: defines labels and constants, and picks each instruction's mode
+fn layout(lines: &[Line]) -> Result<Layout, Box<dyn Error>> {
+    let mut symbols = BTreeMap::new();
+    let mut modes = Vec::with_capacity(lines.len());
+    let mut pc: u16 = 0;
+
+    for line in lines {
+        if let Some(Statement::Org(e)) = &line.statement {
+            pc = eval(e, &symbols, pc).and_then(to_word).map_err(|e| line_error(line, e))?;
+        }
+
+        if let Some(name) = &line.label {
+            if symbols.insert(name.clone(), pc).is_some() {
+                return Err(line_error(line, format!("`{}` is already defined", name)));
+            }
+        }
+
+        let mut mode = None;
+        match &line.statement {
+            Some(Statement::Instruction(mnemonic, operand)) => {
+                let hint = operand_value(operand)
+                    .filter(|e| !matches!(e, Expr::WideNumber(_)))
+                    .and_then(|e| eval(e, &symbols, pc).ok());
+                let selected = select_mode(*mnemonic, operand, hint).map_err(|e| line_error(line, e))?;
+                pc = pc.wrapping_add(Opcode::from_mnemonic_mode(*mnemonic, selected).bytes);
+                mode = Some(selected);
+            }
+            Some(Statement::Bytes(items)) => pc = pc.wrapping_add(data_size(items)),
+            Some(Statement::Words(items)) => pc = pc.wrapping_add(2 * items.len() as u16),
+            Some(Statement::Constant(name, e)) => {
+                let value = eval(e, &symbols, pc).and_then(to_word).map_err(|e| line_error(line, e))?;
+                if symbols.insert(name.clone(), value).is_some() {
+                    return Err(line_error(line, format!("`{}` is already defined", name)));
+                }
+            }
+            Some(Statement::Org(_)) | Some(Statement::Include(_)) | None => (),
+        }
+        modes.push(mode);
+    }
+
+    Ok(Layout { symbols, modes })
+}
+
+fn encode_instruction(
+    mnemonic: Mnemonic,
+    mode: AddressMode,
+    operand: &OperandExpr,
+    symbols: &BTreeMap<String, u16>,
+    pc: u16,
+) -> Result<Instruction, String> {
+    let value = match operand_value(operand) {
+        Some(e) => Some(eval(e, symbols, pc)?),
+        None => None,
+    };
+
+    let operand = match (mode, operand, value) {
+        (_, OperandExpr::RawOffset(offset), _) => Operand::Word(*offset as u8),
+        (AddressMode::Relative, _, Some(target)) => {
+            let offset = target - (pc as i32 + 2);
+            if !(-128..=127).contains(&offset) {
+                return Err(format!("branch to ${:04X} is out of range", target));
+            }
+            Operand::Word(offset as u8)
+        }
+        (_, _, None) => Operand::None,
+        (
+            AddressMode::ZeroPage
+            | AddressMode::ZeroPageX
+            | AddressMode::ZeroPageY
+            | AddressMode::IndirectX
+            | AddressMode::IndirectY,
+            _,
+            Some(addr),
+        ) => Operand::Word(to_zero_page(addr)?.0),
+        (AddressMode::Immediate, _, Some(value)) => Operand::Word(to_byte(value)?),
+        (_, _, Some(value)) => Operand::DoubleWord(to_word(value)?),
+    };
+
+    Ok(Instruction::new(mnemonic, operand, mode))
+}
+
+/// Second pass: evaluates every operand and emits the image
+fn encode(lines: &[Line], layout: Layout) -> Result<Assembly, Box<dyn Error>> {
+    let Layout { symbols, modes } = layout;
+    let mut origin = None;
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut pc: u16 = 0;
+
+    for (line, mode) in lines.iter().zip(modes) {
+        let mut out = Vec::new();
+        match &line.statement {
+            Some(Statement::Org(e)) => {
+                pc = eval(e, &symbols, pc).and_then(to_word).map_err(|e| line_error(line, e))?;
+                if let Some(origin) = origin {
+                    // fill the gap so the image stays contiguous
+                    let offset = pc.checked_sub(origin).filter(|&o| o as usize >= bytes.len());
+                    match offset {
+                        Some(offset) => bytes.resize(offset as usize, 0),
+                        None => return Err(line_error(line, format!(".org ${:04X} moves backwards", pc))),
+                    }
+                }
+                continue;
+            }
+            Some(Statement::Instruction(mnemonic, operand)) => {
+                let instruction = encode_instruction(*mnemonic, mode.unwrap(), operand, &symbols, pc)
+                    .map_err(|e| line_error(line, e))?;
+                out = instruction.to_bytes();
+            }
+            Some(Statement::Bytes(items)) => {
+                for item in items {
+                    match item {
+                        DataItem::Expr(e) => {
+                            out.push(eval(e, &symbols, pc).and_then(to_byte).map_err(|e| line_error(line, e))?)
+                        }
+                        DataItem::Text(text) => out.extend_from_slice(text.as_bytes()),
+                    }
+                }
+            }
+            Some(Statement::Words(items)) => {
+                for e in items {
+                    let word = eval(e, &symbols, pc).and_then(to_word).map_err(|e| line_error(line, e))?;
+                    out.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            Some(Statement::Constant(..)) | Some(Statement::Include(_)) | None => (),
+        }
+
+        if !out.is_empty() {
+            origin.get_or_insert(pc);
+            pc = pc.wrapping_add(out.len() as u16);
+            bytes.extend(out);
+        }
+    }
+
+    Ok(Assembly { origin: origin.unwrap_or(pc), bytes, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().bytes
+    }
+
+    #[test]
+    fn test_assemble_plain_instructions() {
+        assert_eq!(
+            bytes("LDA #02\nDEC $FF23,X ;comment\n\nTAX\nBPL *-5\nBRK\n"),
+            vec![0xA9, 0x02, 0xDE, 0x23, 0xFF, 0xAA, 0x10, 0xFB, 0x00],
+        );
+    }
+
+    #[test]
+    fn test_current_address_outside_branches() {
+        let assembly = assemble(".org $8000\nJMP *+3\nLDA *+2\nBNE *+2\nLDX #<*\n").unwrap();
+        assert_eq!(assembly.bytes, vec![0x4C, 0x03, 0x80, 0xAD, 0x05, 0x80, 0xD0, 0x02, 0xA2, 0x08]);
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let assembly = assemble(
+            ".org $8000
+            reset:  LDX #00
+            loop:   INX
+                    BNE loop
+                    JMP reset
+                    JSR sub
+            sub:    RTS",
+        )
+        .unwrap();
+
+        assert_eq!(assembly.origin, 0x8000);
+        assert_eq!(assembly.bytes, vec![0xA2, 0x00, 0xE8, 0xD0, 0xFD, 0x4C, 0x00, 0x80, 0x20, 0x0B, 0x80, 0x60]);
+        assert_eq!(assembly.symbols["sub"], 0x800B);
+    }
+
+    #[test]
+    fn test_assemble_expressions() {
+        let assembly = assemble(
+            "ptr = $10
+            .org $C000
+                LDA #<table
+                STA ptr
+                LDA #>table
+                STA ptr+1
+                LDA table+2*2,X
+                JMP (vectors)
+            table: .byte 1, %101, \"hi\"
+            vectors: .word table, *",
+        )
+        .unwrap();
+
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xA9, 0x0E, 0x85, 0x10, 0xA9, 0xC0, 0x85, 0x11, 0xBD, 0x12, 0xC0, 0x6C, 0x12, 0xC0,
+                0x01, 0x05, b'h', b'i', 0x0E, 0xC0, 0x12, 0xC0,
+            ]
+        );
+        // the operands for table and vectors were read before either was defined
+        assert_eq!(assembly.symbols["table"], 0xC00E);
+    }
+
+    #[test]
+    fn test_assemble_zero_page_selection() {
+        // known zero page values shrink, forward references stay absolute
+        assert_eq!(
+            bytes("zp = $20\nLDA zp\nLDA later\nlater = $20"),
+            vec![0xA5, 0x20, 0xAD, 0x20, 0x00],
+        );
+        // a four digit address is kept absolute
+        assert_eq!(bytes("LDA $0020"), vec![0xAD, 0x20, 0x00]);
+        // STX has no absolute,Y so stays in the zero page
+        assert_eq!(bytes("STX $10,Y"), vec![0x96, 0x10]);
+        // indexed indirect operands are always zero page
+        assert_eq!(bytes("ADC ($10,X)\nADC ($10),Y"), vec![0x61, 0x10, 0x71, 0x10]);
+        // but unlike immediates they can't be negative or past the zero page
+        assert_eq!(bytes("LDA #-1"), vec![0xA9, 0xFF]);
+        assert!(assemble("LDA (-1),Y").unwrap_err().to_string().contains("not a zero page address"));
+        assert!(assemble("LDA ($100,X)").unwrap_err().to_string().contains("not in the zero page"));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert!(assemble("LDA undefined").unwrap_err().to_string().contains("undefined symbol"));
+        assert!(assemble("a: NOP\na: NOP").unwrap_err().to_string().starts_with("line 2"));
+        assert!(assemble("BNE far\n.org $0200\nfar: RTS").is_err());
+        assert!(assemble("LDA (10,Y)").is_err());
+        assert!(assemble("STA #10").is_err());
+        assert!(assemble("LDA #$7FFFFFFF*2").unwrap_err().to_string().contains("overflow"));
+    }
+
+    #[test]
+    fn test_assemble_layouts() {
+        // saved on Windows: BOM, CRLF, tab indents and a blank line of stray whitespace
+        let source = "\u{feff}; clear the screen\r\n\tLDX #$00\r\nloop:\tSTA $0200,X ; pixel\r\n \t \r\n\tINX\t\r\n\tBNE loop\r\n";
+        assert_eq!(assemble(source).unwrap().bytes, vec![0xA2, 0x00, 0x9D, 0x00, 0x02, 0xE8, 0xD0, 0xFA]);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        assert_eq!(nesting_depth("LDA #<(-(1 - -2))"), 5);
+        assert_eq!(assemble("LDA #-(-<(1))").unwrap().bytes, vec![0xA9, 0x01]);
+
+        let deep = format!("LDA #{}1{}", "(".repeat(1000), ")".repeat(1000));
+        assert!(assemble(&deep).unwrap_err().to_string().contains("nested"));
+        assert!(assemble(&format!("LDA #{}1", "-".repeat(100_000))).is_err());
+    }
+
+    /// Fresh scratch directory for include tests
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nes-rs-asm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_assemble_include() {
+        let dir = scratch_dir("include");
+        fs::write(dir.join("main.s"), ".org $8000\n.include \"consts.s\"\nLDA #value\nmath: .include \"math.s\"\n").unwrap();
+        fs::write(dir.join("consts.s"), "value = $42\n").unwrap();
+        fs::write(dir.join("lib/math.s"), "ASL A\nRTS\n").unwrap();
+
+        let assembly = Assembler::new().with_include_path(dir.join("lib")).assemble_file(dir.join("main.s")).unwrap();
+        assert_eq!(assembly.bytes, vec![0xA9, 0x42, 0x0A, 0x60]);
+        assert_eq!(assembly.symbols["math"], 0x8002);
+
+        // without the include path math.s can't be found
+        let err = Assembler::new().assemble_file(dir.join("main.s")).unwrap_err().to_string();
+        assert!(err.ends_with("main.s:4: can't find include `math.s`"), "{}", err);
+
+        // errors inside included files are reported against that file
+        fs::write(dir.join("consts.s"), "value = \n").unwrap();
+        let err = Assembler::new().with_include_path(dir.join("lib")).assemble_file(dir.join("main.s")).unwrap_err();
+        assert!(err.to_string().contains("consts.s:1:"), "{}", err);
+    }
+
+    #[test]
+    fn test_assemble_include_cycle() {
+        let dir = scratch_dir("cycle");
+        fs::write(dir.join("a.s"), ".include \"b.s\"\n").unwrap();
+        fs::write(dir.join("b.s"), "NOP\n.include \"a.s\"\n").unwrap();
+
+        let err = Assembler::new().assemble_file(dir.join("a.s")).unwrap_err().to_string();
+        assert!(err.starts_with("include cycle:"), "{}", err);
+    }
+
+    #[test]
+    fn test_assemble_ca65_dialect() {
+        let source = "
+            .org $8000
+            reset:  ldx #10         ; decimal
+            @loop:  dex
+                    bne @loop
+            nmi:    ldy #$10
+            @loop:  dey
+                    bne @loop
+                    jmp (vectors)
+            vectors: .addr reset, nmi
+                    .byte 255, %11";
+
+        let assembly = Assembler::new().with_dialect(Dialect::Ca65).assemble(source).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xA2, 0x0A, 0xCA, 0xD0, 0xFD, 0xA0, 0x10, 0x88, 0xD0, 0xFD, 0x6C, 0x0D, 0x80, 0x00, 0x80, 0x05,
+                0x80, 0xFF, 0x03,
+            ]
+        );
+        assert_eq!(assembly.symbols["reset@loop"], 0x8002);
+        assert_eq!(assembly.symbols["nmi@loop"], 0x8007);
+
+        // the same source isn't valid in the native syntax
+        assert!(assemble(source).is_err());
+    }
+
+    #[test]
+    fn test_assemble_nesasm_dialect() {
+        let source = "
+            .org $C000
+            copy:   ldy #0
+            .next:  lda [src],y
+                    sta $0200,y
+                    iny
+                    bne .next
+                    jmp [src]
+            src = $20
+            table:  .db 1, 2
+                    dw table";
+
+        let assembly = Assembler::new().with_dialect(Dialect::Nesasm).assemble(source).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xA0, 0x00, 0xB1, 0x20, 0x99, 0x00, 0x02, 0xC8, 0xD0, 0xF8, 0x6C, 0x20, 0x00, 0x01, 0x02, 0x0D, 0xC0,
+            ]
+        );
+        assert_eq!(assembly.symbols["copy.next"], 0xC002);
+    }
+
+    #[test]
+    fn test_to_ines() {
+        use crate::cart::{HeaderlessOptions, PRG_BANK_SIZE};
+        use crate::CPU;
+
+        let source = "
+            .org $C000
+            reset:  LDA #$2A
+                    STA $10
+            loop:   JMP loop
+            nmi:    RTI";
+        let image = assemble(source).unwrap().to_ines(0, Mirroring::Vertical, &[]).unwrap();
+        assert_eq!(image.len(), 16 + PRG_BANK_SIZE);
+
+        let cart = Cartridge::load(&image, &HeaderlessOptions::default()).unwrap();
+        assert_eq!(cart.mirroring, Mirroring::Vertical);
+        let mut cpu = CPU::builder().with_cartridge(cart).build().unwrap();
+        for _ in 0..3 {
+            cpu.step_next();
+        }
+        assert_eq!(cpu.read(0x10), 0x2A);
+        // NMI from its label, IRQ falls back to reset
+        assert_eq!((cpu.read(0xFFFA), cpu.read(0xFFFB)), (0x07, 0xC0));
+        assert_eq!((cpu.read(0xFFFE), cpu.read(0xFFFF)), (0x00, 0xC0));
+
+        // below $C000 needs 32K
+        let image = assemble(".org $8000\nreset: JMP reset").unwrap().to_ines(0, Mirroring::Horizontal, &[0; 0x2000]).unwrap();
+        assert_eq!(&image[4..6], &[2, 1]);
+
+        let errors = [
+            (assemble("NOP").unwrap().to_ines(0, Mirroring::Horizontal, &[]), "outside PRG ROM"),
+            (assemble(".org $C000\nNOP").unwrap().to_ines(0, Mirroring::Horizontal, &[]), "no `reset` label"),
+            (assemble(".org $FFF9\nreset: NOP\nNOP").unwrap().to_ines(0, Mirroring::Horizontal, &[]), "overlaps the vectors"),
+            (assemble(".org $C000\nreset: NOP").unwrap().to_ines(0, Mirroring::Horizontal, &[0; 100]), "multiple of 8K"),
+        ];
+        for (result, message) in errors {
+            let error = result.unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+}
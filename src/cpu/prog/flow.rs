@@ -0,0 +1,292 @@
+//! Recursive descent disassembly.
+//!
+//! Rather than sweeping linearly through memory, instructions are decoded by
+//! following branches, jumps and subroutine calls out from a set of entry
+//! points (normally the interrupt vectors). Bytes never reached as code are
+//! kept as data, so tables embedded in PRG ROM don't turn into garbage code.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use crate::cpu::addr::AddressMode;
+use crate::cpu::ops::Mnemonic;
+
+use super::fmt_named_listing_line;
+use super::instructions::{Instruction, Operand};
+
+/// A run of memory classified by the disassembler
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Segment {
+    Code { addr: u16, instruction: Instruction },
+    Data { addr: u16, bytes: Vec<u8> },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Disassembly {
+    pub segments: Vec<Segment>,
+}
+
+/// Addresses execution may continue at after the instruction at `addr`.
+/// Indirect jumps and returns have no statically known successor.
+pub fn successors(addr: u16, instruction: &Instruction) -> Vec<u16> {
+    let opcode = instruction.opcode();
+    let next = addr.wrapping_add(instruction.size());
+
+    match (opcode.mnemonic, opcode.mode, instruction.operand()) {
+        (Mnemonic::JMP, AddressMode::Absolute, &Operand::DoubleWord(dest)) => vec![dest],
+        (Mnemonic::JSR, AddressMode::Absolute, &Operand::DoubleWord(dest)) => vec![dest, next],
+        (Mnemonic::JMP, _, _) | (Mnemonic::RTS, _, _) | (Mnemonic::RTI, _, _) | (Mnemonic::BRK, _, _) => vec![],
+        _ => match instruction.branch_target(addr) {
+            Some(dest) => vec![dest, next],
+            None => vec![next],
+        },
+    }
+}
+
+/// Disassembles `image` loaded at `base` by following control flow from `entry_points`
+pub fn trace(image: &[u8], base: u16, entry_points: &[u16]) -> Disassembly {
+    let mut code: BTreeMap<usize, Instruction> = BTreeMap::new();
+    let mut claimed = vec![false; image.len()];
+    let mut pending = entry_points.to_vec();
+
+    while let Some(addr) = pending.pop() {
+        let offset = match addr.checked_sub(base) {
+            Some(offset) if (offset as usize) < image.len() => offset as usize,
+            _ => continue,
+        };
+        if code.contains_key(&offset) {
+            continue;
+        }
+
+        let instruction = match Instruction::decode(&image[offset..]) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        // don't decode jumps into the middle of an instruction already seen
+        let span = offset..offset + instruction.size() as usize;
+        if claimed[span.clone()].iter().any(|&c| c) {
+            continue;
+        }
+        claimed[span].iter_mut().for_each(|c| *c = true);
+
+        pending.extend(successors(addr, &instruction));
+        code.insert(offset, instruction);
+    }
+
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while offset < image.len() {
+        let addr = base.wrapping_add(offset as u16);
+        match code.remove(&offset) {
+            Some(instruction) => {
+                offset += instruction.size() as usize;
+                segments.push(Segment::Code { addr, instruction });
+            }
+            None => {
+                let end = (offset..image.len()).find(|&i| claimed[i]).unwrap_or(image.len());
+                segments.push(Segment::Data { addr, bytes: image[offset..end].to_vec() });
+                offset = end;
+            }
+        }
+    }
+
+    Disassembly { segments }
+}
+
+/// Disassembles a 16K or 32K NROM PRG image, starting from its NMI, reset and IRQ vectors
+pub fn disassemble_rom(prg: &[u8]) -> Result<Disassembly, Box<dyn std::error::Error>> {
+    if prg.len() != 0x4000 && prg.len() != 0x8000 {
+        return Err(format!("PRG ROM must be 16K or 32K, got {:#x} bytes", prg.len()).into());
+    }
+
+    let base = (0x10000 - prg.len()) as u16;
+    let vector = |addr: u16| {
+        let offset = (addr - base) as usize;
+        u16::from_le_bytes([prg[offset], prg[offset + 1]])
+    };
+
+    Ok(trace(prg, base, &[vector(0xFFFA), vector(0xFFFC), vector(0xFFFE)]))
+}
+
+impl Disassembly {
+    /// `L` and the address for every jump, call or branch target decoded as code
+    pub fn labels(&self) -> BTreeMap<u16, String> {
+        let code: Vec<u16> = self
+            .segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Code { addr, .. } => Some(*addr),
+                Segment::Data { .. } => None,
+            })
+            .collect();
+        let mut labels = BTreeMap::new();
+        for segment in &self.segments {
+            if let Segment::Code { addr, instruction } = segment {
+                let next = addr.wrapping_add(instruction.size());
+                for dest in successors(*addr, instruction).into_iter().filter(|&dest| dest != next) {
+                    if code.binary_search(&dest).is_ok() {
+                        labels.insert(dest, format!("L{:04X}", dest));
+                    }
+                }
+            }
+        }
+        labels
+    }
+
+    /// One JSON record per instruction or data run, for tools outside the crate.
+    /// Code records have `address`, `bytes`, `mnemonic`, `mode`, `operand` and
+    /// `label`; data records have `address` and `bytes`.
+    pub fn to_json(&self) -> String {
+        let labels = self.labels();
+        let bytes_json = |bytes: &[u8]| bytes.iter().map(u8::to_string).collect::<Vec<_>>().join(",");
+        let mut records = Vec::new();
+        for segment in &self.segments {
+            let mut record = String::new();
+            match segment {
+                Segment::Code { addr, instruction } => {
+                    let opcode = instruction.opcode();
+                    let _ = write!(
+                        record,
+                        "{{\"kind\":\"code\",\"address\":{},\"bytes\":[{}],\"mnemonic\":\"{}\",\"mode\":\"{:?}\",\"operand\":{},\"label\":{}}}",
+                        addr,
+                        bytes_json(&instruction.to_bytes()),
+                        opcode.mnemonic,
+                        opcode.mode,
+                        opcode.mode.format_operand(instruction.operand()).map_or("null".to_string(), |op| format!("\"{}\"", op)),
+                        labels.get(addr).map_or("null".to_string(), |label| format!("\"{}\"", label)),
+                    );
+                }
+                Segment::Data { addr, bytes } => {
+                    let _ = write!(record, "{{\"kind\":\"data\",\"address\":{},\"bytes\":[{}]}}", addr, bytes_json(bytes));
+                }
+            }
+            records.push(record);
+        }
+        format!("[{}]", records.join(","))
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        self.write_listing(f, &BTreeMap::new())
+    }
+}
+
+impl Disassembly {
+    /// The listing with zero page operands named from `names`, e.g. symbols loaded from a file
+    pub fn to_string_with_names(&self, names: &BTreeMap<u16, String>) -> String {
+        let mut listing = String::new();
+        let _ = self.write_listing(&mut listing, names);
+        listing
+    }
+
+    fn write_listing(&self, f: &mut impl Write, names: &BTreeMap<u16, String>) -> Result<(), fmt::Error> {
+        for segment in &self.segments {
+            match segment {
+                Segment::Code { addr, instruction } => writeln!(f, "{}", fmt_named_listing_line(*addr, instruction, names))?,
+                Segment::Data { addr, bytes } => {
+                    for (i, chunk) in bytes.chunks(8).enumerate() {
+                        let values = chunk.iter().map(|b| format!("${:02X}", b)).collect::<Vec<_>>().join(", ");
+                        writeln!(f, "{:04X}  .byte {}", addr.wrapping_add(i as u16 * 8), values)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::prog::SNAKE_BYTES;
+
+    #[test]
+    fn test_trace_separates_code_and_data() {
+        // 0600 LDA #01
+        // 0602 BEQ $0607
+        // 0604 JMP $0609
+        // 0607 (data) DE AD
+        // 0609 RTS
+        let image = [0xA9, 0x01, 0xF0, 0x03, 0x4C, 0x09, 0x06, 0xDE, 0xAD, 0x60, 0xFF];
+        let disassembly = trace(&image, 0x0600, &[0x0600]);
+
+        let kinds: Vec<_> = disassembly
+            .segments
+            .iter()
+            .map(|s| match s {
+                Segment::Code { addr, .. } => (*addr, true),
+                Segment::Data { addr, .. } => (*addr, false),
+            })
+            .collect();
+        // decoding the branch target at 0607 would give DEC $60AD,X which
+        // overlaps the RTS, so those bytes are left as data
+        assert_eq!(
+            kinds,
+            vec![(0x0600, true), (0x0602, true), (0x0604, true), (0x0607, false), (0x0609, true), (0x060A, false)]
+        );
+    }
+
+    #[test]
+    fn test_trace_unreachable_bytes_are_data() {
+        // 8000 JMP $8005, 8003 (data) 01 02, 8005 RTS
+        let image = [0x4C, 0x05, 0x80, 0x01, 0x02, 0x60];
+        let disassembly = trace(&image, 0x8000, &[0x8000]);
+        assert_eq!(
+            disassembly.segments[1],
+            Segment::Data { addr: 0x8003, bytes: vec![0x01, 0x02] }
+        );
+        assert_eq!(
+            format!("{}", disassembly),
+            "8000  4C 05 80  JMP $8005\n8003  .byte $01, $02\n8005  60        RTS\n"
+        );
+        let names = BTreeMap::from([(0x10, "unused".to_string())]);
+        assert_eq!(disassembly.to_string_with_names(&names), disassembly.to_string());
+        assert_eq!(disassembly.labels().into_iter().collect::<Vec<_>>(), vec![(0x8005, "L8005".to_string())]);
+        assert_eq!(
+            disassembly.to_json(),
+            concat!(
+                "[{\"kind\":\"code\",\"address\":32768,\"bytes\":[76,5,128],\"mnemonic\":\"JMP\",\"mode\":\"Absolute\",\"operand\":\"$8005\",\"label\":null},",
+                "{\"kind\":\"data\",\"address\":32771,\"bytes\":[1,2]},",
+                "{\"kind\":\"code\",\"address\":32773,\"bytes\":[96],\"mnemonic\":\"RTS\",\"mode\":\"Implicit\",\"operand\":null,\"label\":\"L8005\"}]"
+            )
+        );
+    }
+
+    #[test]
+    fn test_disassemble_rom_from_vectors() {
+        let mut prg = vec![0xFF; 0x4000];
+        // reset at C000: LDX #00, RTI ; NMI at C010: RTI
+        prg[0x0000..0x0003].copy_from_slice(&[0xA2, 0x00, 0x40]);
+        prg[0x0010] = 0x40;
+        prg[0x3FFA..].copy_from_slice(&[0x10, 0xC0, 0x00, 0xC0, 0x10, 0xC0]);
+
+        let disassembly = disassemble_rom(&prg).unwrap();
+        let code: Vec<u16> = disassembly
+            .segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Code { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(code, vec![0xC000, 0xC002, 0xC010]);
+
+        assert!(disassemble_rom(&[0u8; 100]).is_err());
+    }
+
+    #[test]
+    fn test_trace_snake() {
+        let disassembly = trace(SNAKE_BYTES, 0x0600, &[0x0600]);
+        assert!(matches!(disassembly.segments[0], Segment::Code { addr: 0x0600, .. }));
+
+        let names = BTreeMap::from([(0x10, "snake_head_lo".to_string()), (0x11, "snake_head_hi".to_string())]);
+        let listing = disassembly.to_string_with_names(&names);
+        assert!(listing.contains("STA snake_head_lo\n"), "{}", listing);
+        assert!(listing.contains("LDA snake_head_lo,X\n"), "{}", listing);
+        assert!(listing.contains("STA (snake_head_lo,X)\n"), "{}", listing);
+        // immediates aren't addresses
+        assert!(listing.contains("LDA #$10\n"), "{}", listing);
+    }
+}
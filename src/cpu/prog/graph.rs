@@ -0,0 +1,232 @@
+//! Basic blocks and call graphs over a Program.
+//!
+//! Both graphs can be exported as Graphviz DOT, e.g.
+//! `dot -Tsvg blocks.dot > blocks.svg`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::cpu::addr::AddressMode;
+use crate::cpu::ops::Mnemonic;
+
+use super::flow::successors;
+use super::instructions::{Instruction, Operand};
+use super::Program;
+
+/// A straight run of instructions only entered at the top and left at the bottom
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    /// Start addresses of the blocks control may pass to next.
+    /// Subroutine calls fall through here, the callee is in the call graph.
+    pub successors: Vec<u16>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockGraph {
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// Subroutine entry points mapped to the subroutines they call
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CallGraph {
+    pub calls: BTreeMap<u16, BTreeSet<u16>>,
+}
+
+fn call_target(instruction: &Instruction) -> Option<u16> {
+    let opcode = instruction.opcode();
+    match (opcode.mnemonic, opcode.mode, instruction.operand()) {
+        (Mnemonic::JSR, AddressMode::Absolute, &Operand::DoubleWord(dest)) => Some(dest),
+        _ => None,
+    }
+}
+
+/// Successors within the calling subroutine, stepping over any call
+fn local_successors(addr: u16, instruction: &Instruction) -> Vec<u16> {
+    match call_target(instruction) {
+        Some(_) => vec![addr.wrapping_add(instruction.size())],
+        None => successors(addr, instruction),
+    }
+}
+
+impl BlockGraph {
+    pub fn block_at(&self, addr: u16) -> Option<&BasicBlock> {
+        self.blocks.iter().find(|block| block.start == addr)
+    }
+
+    /// Graphviz DOT with one node per block, labelled with its listing
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph blocks {\n    node [shape=box, fontname=monospace];\n");
+        for block in &self.blocks {
+            let label: String = block
+                .instructions
+                .iter()
+                .map(|(addr, instruction)| format!("{:04X}  {}\\l", addr, instruction))
+                .collect();
+            writeln!(dot, "    \"{:04X}\" [label=\"{}\"];", block.start, label).unwrap();
+            for successor in &block.successors {
+                writeln!(dot, "    \"{:04X}\" -> \"{:04X}\";", block.start, successor).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl CallGraph {
+    /// Graphviz DOT with one node per subroutine
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n");
+        for (caller, callees) in &self.calls {
+            writeln!(dot, "    \"{:04X}\";", caller).unwrap();
+            for callee in callees {
+                writeln!(dot, "    \"{:04X}\" -> \"{:04X}\";", caller, callee).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl Program {
+    /// Splits the program into basic blocks. Only targets inside the program
+    /// start new blocks; edges to outside addresses are kept as successors.
+    pub fn basic_blocks(&self) -> BlockGraph {
+        let addresses = self.addresses();
+        let index: HashMap<u16, usize> = addresses.iter().enumerate().map(|(i, &addr)| (addr, i)).collect();
+
+        // the first instruction, every target, and whatever follows a transfer of control
+        let mut leaders = BTreeSet::new();
+        leaders.extend(addresses.first());
+        for (instruction, &addr) in self.code.iter().zip(&addresses) {
+            leaders.extend(call_target(instruction).filter(|dest| index.contains_key(dest)));
+            let next = addr.wrapping_add(instruction.size());
+            let targets = local_successors(addr, instruction);
+            if targets != [next] {
+                leaders.extend(targets.iter().filter(|dest| index.contains_key(dest)));
+                if index.contains_key(&next) {
+                    leaders.insert(next);
+                }
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for (instruction, &addr) in self.code.iter().zip(&addresses) {
+            if leaders.contains(&addr) || blocks.is_empty() {
+                blocks.push(BasicBlock { start: addr, instructions: Vec::new(), successors: Vec::new() });
+            }
+            blocks.last_mut().unwrap().instructions.push((addr, instruction.clone()));
+        }
+
+        for block in blocks.iter_mut() {
+            let (addr, instruction) = block.instructions.last().unwrap();
+            let mut successors = local_successors(*addr, instruction);
+            // the end of the program has nowhere to fall through to
+            let next = addr.wrapping_add(instruction.size());
+            if !index.contains_key(&next) {
+                successors.retain(|&dest| dest != next);
+            }
+            successors.dedup();
+            block.successors = successors;
+        }
+
+        BlockGraph { blocks }
+    }
+
+    /// Subroutines reachable from the program start and the calls they make.
+    /// Computed jumps can't be followed, so code only reached through them is missing.
+    pub fn call_graph(&self) -> CallGraph {
+        let graph = self.basic_blocks();
+        let mut calls = BTreeMap::new();
+        let mut pending: Vec<u16> = graph.blocks.first().map(|b| b.start).into_iter().collect();
+
+        while let Some(entry) = pending.pop() {
+            if calls.contains_key(&entry) {
+                continue;
+            }
+
+            let mut callees = BTreeSet::new();
+            let mut seen = BTreeSet::new();
+            let mut blocks = vec![entry];
+            while let Some(start) = blocks.pop() {
+                if !seen.insert(start) {
+                    continue;
+                }
+                if let Some(block) = graph.block_at(start) {
+                    callees.extend(block.instructions.iter().filter_map(|(_, i)| call_target(i)));
+                    blocks.extend(&block.successors);
+                }
+            }
+
+            pending.extend(&callees);
+            calls.insert(entry, callees);
+        }
+
+        CallGraph { calls }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::prog::SNAKE_BYTES;
+
+    // 8000 LDX #03
+    // 8002 JSR $800A
+    // 8005 DEX
+    // 8006 BNE $8002
+    // 8008 BRK
+    // 8009 NOP
+    // 800A RTS
+    const LOOP: &[u8] = &[0xA2, 0x03, 0x20, 0x0A, 0x80, 0xCA, 0xD0, 0xFA, 0x00, 0xEA, 0x60];
+
+    #[test]
+    fn test_basic_blocks() {
+        let program = Program::try_from(LOOP).unwrap().with_start(0x8000);
+        let graph = program.basic_blocks();
+
+        let shape: Vec<_> = graph
+            .blocks
+            .iter()
+            .map(|b| (b.start, b.instructions.len(), b.successors.clone()))
+            .collect();
+        assert_eq!(
+            shape,
+            vec![
+                (0x8000, 1, vec![0x8002]),
+                (0x8002, 3, vec![0x8002, 0x8008]),
+                (0x8008, 1, vec![]),
+                (0x8009, 1, vec![0x800A]),
+                (0x800A, 1, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let program = Program::try_from(LOOP).unwrap().with_start(0x8000);
+        let graph = program.call_graph();
+        assert_eq!(graph.calls.len(), 2);
+        assert_eq!(graph.calls[&0x8000], BTreeSet::from([0x800A]));
+        assert!(graph.calls[&0x800A].is_empty());
+
+        assert_eq!(
+            graph.to_dot(),
+            "digraph calls {\n    \"8000\";\n    \"8000\" -> \"800A\";\n    \"800A\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_snake_graphs() {
+        let program = Program::try_from(SNAKE_BYTES).unwrap().with_start(0x0600);
+        let blocks = program.basic_blocks();
+        assert!(blocks.to_dot().contains("\"0600\" -> \"0606\";"));
+
+        // the entry calls init and then falls through into init's body,
+        // so init's own calls are reachable from it as well
+        let calls = program.call_graph();
+        assert_eq!(calls.calls[&0x0600], BTreeSet::from([0x0606, 0x060D, 0x062A, 0x0638]));
+        assert!(calls.calls[&0x0638].contains(&0x064D));
+    }
+}
@@ -1,17 +1,18 @@
+use std::collections::BTreeMap;
 use std::ops::Add;
 
-use crate::cpu::ops::{Mnemonic, Opcode, CPU_OPCODE_MAP};
+use crate::cpu::ops::{Mnemonic, Opcode};
 use crate::cpu::addr::AddressMode;
 
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum Operand {
     None,
     Word(u8),
     DoubleWord(u16),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct Instruction {
     opcode: &'static Opcode,
     operand: Operand,
@@ -23,7 +24,7 @@ impl Instruction {
         I: Iterator<Item = &'a u8>
     {
         if let Some(byte) = program.next() {
-            let opcode = CPU_OPCODE_MAP.get(&byte).expect("Error, opcode not found in map while decompiling.").clone();
+            let opcode = Opcode::from_code(*byte).expect("Error, opcode not found in map while decompiling.");
             
             let operand = match opcode.mode {
                 AddressMode::Implicit |
@@ -47,33 +48,90 @@ impl Instruction {
         }
     }
 
+    /// Decodes the instruction at the start of `bytes`, returning None for
+    /// unknown opcodes or a truncated operand
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let opcode = Opcode::from_code(*bytes.first()?)?;
+        let operand = match opcode.mode {
+            AddressMode::Implicit |
+            AddressMode::Accumulator => Operand::None,
+            AddressMode::Immediate |
+            AddressMode::ZeroPage |
+            AddressMode::ZeroPageX |
+            AddressMode::ZeroPageY |
+            AddressMode::Relative |
+            AddressMode::IndirectX |
+            AddressMode::IndirectY => Operand::Word(*bytes.get(1)?),
+            AddressMode::Absolute |
+            AddressMode::AbsoluteX |
+            AddressMode::AbsoluteY |
+            AddressMode::Indirect => Operand::DoubleWord(u16::from_le_bytes([*bytes.get(1)?, *bytes.get(2)?])),
+        };
+        Some(Instruction { opcode, operand })
+    }
+
     pub fn new(mnemonic: Mnemonic, operand: Operand, mode: AddressMode) -> Self {
         Instruction {
             opcode: Opcode::from_mnemonic_mode(mnemonic, mode),
             operand: operand,
         }
     }
+
+    pub fn opcode(&self) -> &'static Opcode {
+        self.opcode
+    }
+
+    pub fn operand(&self) -> &Operand {
+        &self.operand
+    }
+
+    /// Encoded length of the instruction in bytes
+    pub fn size(&self) -> u16 {
+        self.opcode.bytes
+    }
+
+    /// Destination of a relative branch located at `addr`
+    pub fn branch_target(&self, addr: u16) -> Option<u16> {
+        match (self.opcode.mode, &self.operand) {
+            (AddressMode::Relative, &Operand::Word(offset)) => {
+                Some(addr.wrapping_add(2).wrapping_add(offset as i8 as u16))
+            }
+            _ => Option::None,
+        }
+    }
+
+    /// Machine code for the instruction, opcode first then the little endian operand
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode.code];
+        match self.operand {
+            Operand::None => (),
+            Operand::Word(op) => bytes.push(op),
+            Operand::DoubleWord(op) => bytes.extend_from_slice(&op.to_le_bytes()),
+        }
+        bytes
+    }
+
+    /// Like `to_string`, but a zero page operand is written as its name in
+    /// `names` when it has one, e.g. `LDA snake_head_lo` for `LDA $10`
+    pub fn to_string_with_names(&self, names: &BTreeMap<u16, String>) -> String {
+        use AddressMode::*;
+        match (self.opcode.mode, &self.operand) {
+            (ZeroPage | ZeroPageX | ZeroPageY | IndirectX | IndirectY, &Operand::Word(op)) => match names.get(&(op as u16)) {
+                Some(name) => {
+                    let operand = self.opcode.mode.format_operand(&self.operand).unwrap_or_default();
+                    format!("{} {}", self.opcode.mnemonic, operand.replacen(&format!("${:02x}", op), name, 1))
+                }
+                None => self.to_string(),
+            },
+            _ => self.to_string(),
+        }
+    }
 }
 
 impl std::fmt::Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         let mnem = self.opcode.mnemonic.to_string();
-        let oper = match (&self.opcode.mode, &self.operand) {
-            (AddressMode::Implicit, Operand::None) => None,
-            (AddressMode::Accumulator, Operand::None) => Some("A".into()), // may need to replace this
-            (AddressMode::Immediate, Operand::Word(op)) => Some(format!("#{:02x}", op)),
-            (AddressMode::ZeroPage, Operand::Word(op))  => Some(format!("${:02x}", op)),
-            (AddressMode::ZeroPageX, Operand::Word(op))  => Some(format!("${:02x},X", op)),
-            (AddressMode::ZeroPageY, Operand::Word(op))  => Some(format!("${:02x},Y", op)),
-            (AddressMode::Relative, Operand::Word(op))  => Some(format!("*{:+}", op)),
-            (AddressMode::Absolute, Operand::DoubleWord(op))  => Some(format!("${:04x}", op)),
-            (AddressMode::AbsoluteX, Operand::DoubleWord(op)) => Some(format!("${:04x},X", op)),
-            (AddressMode::AbsoluteY, Operand::DoubleWord(op)) => Some(format!("${:04x},Y", op)),
-            (AddressMode::Indirect, Operand::DoubleWord(op)) => Some(format!("(${:04x})", op)),
-            (AddressMode::IndirectX, Operand::Word(op))  => Some(format!("(${:02x},X)", op)),
-            (AddressMode::IndirectY, Operand::Word(op))  => Some(format!("(${:02x}),Y", op)),
-            _ => todo!()
-        };
+        let oper = self.opcode.mode.format_operand(&self.operand);
         if let Some(op) = oper {
             write!(f, "{} {}", mnem, op)
         } else {
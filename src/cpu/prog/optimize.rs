@@ -0,0 +1,300 @@
+//! Peephole optimisation passes over a Program.
+//!
+//! Branch and jump targets inside the program are tracked by instruction, so
+//! they are relocated when earlier instructions shrink or are removed. Other
+//! absolute operands pointing back into the program (data tables, self
+//! modifying code) are not relocated, so only optimise programs which don't
+//! address their own bytes.
+
+use std::collections::HashMap;
+
+use crate::cpu::addr::AddressMode;
+use crate::cpu::ops::{Mnemonic, Opcode};
+
+use super::instructions::{Instruction, Operand};
+use super::Program;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Target {
+    /// Not a branch or jump
+    None,
+    /// Index of the target instruction within the program
+    Internal(usize),
+    /// Target address outside of the program
+    External(u16),
+}
+
+struct Node {
+    instruction: Instruction,
+    target: Target,
+}
+
+/// The result of optimising a program, with listings to compare
+pub struct Optimization {
+    pub program: Program,
+    pub before: String,
+    pub after: String,
+}
+
+fn is_jump(instruction: &Instruction) -> bool {
+    let opcode = instruction.opcode();
+    matches!(opcode.mnemonic, Mnemonic::JMP | Mnemonic::JSR) && opcode.mode == AddressMode::Absolute
+}
+
+fn is_branch(instruction: &Instruction) -> bool {
+    instruction.opcode().mode == AddressMode::Relative
+}
+
+/// Flag instructions which override each other when run back to back.
+/// CLI/SEI are left out since CLI;SEI deliberately opens a one instruction
+/// window for a pending IRQ.
+fn flag_group(instruction: &Instruction) -> Option<u8> {
+    use Mnemonic::*;
+    match instruction.opcode().mnemonic {
+        CLC | SEC => Some(0),
+        CLD | SED => Some(1),
+        CLV => Some(2),
+        _ => None,
+    }
+}
+
+fn build_nodes(program: &Program) -> Vec<Node> {
+    let addresses = program.addresses();
+    let index: HashMap<u16, usize> = addresses.iter().enumerate().map(|(i, &addr)| (addr, i)).collect();
+
+    program
+        .code
+        .iter()
+        .zip(&addresses)
+        .map(|(instruction, &addr)| {
+            let target = match (instruction.operand(), is_jump(instruction)) {
+                (&Operand::DoubleWord(dest), true) => Some(dest),
+                _ => instruction.branch_target(addr),
+            };
+            let target = match target {
+                Some(dest) => index.get(&dest).map_or(Target::External(dest), |&i| Target::Internal(i)),
+                None => Target::None,
+            };
+            Node { instruction: instruction.clone(), target }
+        })
+        .collect()
+}
+
+/// Removes flag operations immediately overridden by the next instruction
+fn remove_redundant_flags(nodes: &mut Vec<Node>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < nodes.len() {
+        let group = flag_group(&nodes[i].instruction);
+        if group.is_some() && group == flag_group(&nodes[i + 1].instruction) {
+            nodes.remove(i);
+            // anything which jumped to the removed op now lands on its replacement
+            for node in nodes.iter_mut() {
+                if let Target::Internal(t) = node.target {
+                    if t > i {
+                        node.target = Target::Internal(t - 1);
+                    }
+                }
+            }
+            changed = true;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// Shrinks absolute addressing of the zero page to zero page addressing.
+/// Indexed modes are left alone, as zero page indexing wraps within the page.
+fn promote_zero_page(nodes: &mut [Node]) -> bool {
+    let mut changed = false;
+    for node in nodes.iter_mut() {
+        let opcode = node.instruction.opcode();
+        if let (AddressMode::Absolute, &Operand::DoubleWord(addr)) = (opcode.mode, node.instruction.operand()) {
+            if addr < 0x100 && node.target == Target::None {
+                if let Some(zp) = Opcode::find(opcode.mnemonic, AddressMode::ZeroPage) {
+                    node.instruction = Instruction::new(zp.mnemonic, Operand::Word(addr as u8), zp.mode);
+                    changed = true;
+                }
+            }
+        }
+    }
+    changed
+}
+
+fn layout(start: u16, nodes: &[Node]) -> Vec<u16> {
+    nodes
+        .iter()
+        .scan(start, |addr, node| {
+            let this = *addr;
+            *addr = addr.wrapping_add(node.instruction.size());
+            Some(this)
+        })
+        .collect()
+}
+
+fn resolve(target: Target, addresses: &[u16]) -> Option<u16> {
+    match target {
+        Target::Internal(i) => Some(addresses[i]),
+        Target::External(addr) => Some(addr),
+        Target::None => None,
+    }
+}
+
+fn fits_branch(from: u16, to: u16) -> bool {
+    let offset = to as i32 - (from as i32 + 2);
+    (-128..=127).contains(&offset)
+}
+
+/// Points branches and jumps which land on an equivalent branch or jump
+/// straight at the final destination
+fn thread_jumps(start: u16, nodes: &mut [Node]) -> bool {
+    let addresses = layout(start, nodes);
+    let mut changed = false;
+
+    for i in 0..nodes.len() {
+        let mnemonic = nodes[i].instruction.opcode().mnemonic;
+        let threadable = is_branch(&nodes[i].instruction) || (mnemonic == Mnemonic::JMP && is_jump(&nodes[i].instruction));
+        if !threadable {
+            continue;
+        }
+
+        let mut target = nodes[i].target;
+        // bounded walk so jump cycles can't hang the optimiser
+        for _ in 0..nodes.len() {
+            match target {
+                Target::Internal(j) if j != i && nodes[j].instruction.opcode() == nodes[i].instruction.opcode() => {
+                    target = nodes[j].target;
+                }
+                _ => break,
+            }
+        }
+
+        if target != nodes[i].target {
+            let reachable = match (is_branch(&nodes[i].instruction), resolve(target, &addresses)) {
+                (true, Some(dest)) => fits_branch(addresses[i], dest),
+                (false, Some(_)) => true,
+                (_, None) => false,
+            };
+            if reachable {
+                nodes[i].target = target;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Re-encodes branch offsets and jump addresses for the final layout.
+/// Returns None if a branch can no longer reach its target.
+fn encode(start: u16, nodes: Vec<Node>) -> Option<Program> {
+    let addresses = layout(start, &nodes);
+    let mut code = Vec::with_capacity(nodes.len());
+
+    for (node, &addr) in nodes.into_iter().zip(&addresses) {
+        let opcode = node.instruction.opcode();
+        let instruction = match resolve(node.target, &addresses) {
+            Some(dest) if is_branch(&node.instruction) => {
+                if !fits_branch(addr, dest) {
+                    return None;
+                }
+                let offset = dest.wrapping_sub(addr.wrapping_add(2)) as u8;
+                Instruction::new(opcode.mnemonic, Operand::Word(offset), opcode.mode)
+            }
+            Some(dest) => Instruction::new(opcode.mnemonic, Operand::DoubleWord(dest), opcode.mode),
+            None => node.instruction,
+        };
+        code.push(instruction);
+    }
+
+    Some(Program { start, code })
+}
+
+impl Program {
+    /// Runs the peephole passes: redundant flag op removal, zero page
+    /// promotion of absolute operands, and branch/jump threading.
+    /// If the result can't be encoded the program is returned unchanged.
+    pub fn optimize(&self) -> Optimization {
+        let mut nodes = build_nodes(self);
+        while remove_redundant_flags(&mut nodes) | promote_zero_page(&mut nodes) {}
+        while thread_jumps(self.start, &mut nodes) {}
+
+        let program = encode(self.start, nodes).unwrap_or_else(|| self.clone());
+
+        Optimization {
+            before: self.listing(),
+            after: program.listing(),
+            program,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize_bytes(code: &[u8]) -> Vec<u8> {
+        Program::try_from(code).unwrap().with_start(0x8000).optimize().program.to_bytes()
+    }
+
+    #[test]
+    fn test_promote_zero_page() {
+        // LDA $0010, STA $0200, LDA $0010,X
+        assert_eq!(
+            optimize_bytes(&[0xAD, 0x10, 0x00, 0x8D, 0x00, 0x02, 0xBD, 0x10, 0x00]),
+            vec![0xA5, 0x10, 0x8D, 0x00, 0x02, 0xBD, 0x10, 0x00],
+        );
+    }
+
+    #[test]
+    fn test_remove_redundant_flags() {
+        // CLC, SEC, CLI, SEI, CLD, CLD
+        assert_eq!(
+            optimize_bytes(&[0x18, 0x38, 0x58, 0x78, 0xD8, 0xD8]),
+            vec![0x38, 0x58, 0x78, 0xD8],
+        );
+    }
+
+    #[test]
+    fn test_thread_branch_to_branch() {
+        // 8000 BEQ $8004
+        // 8002 NOP
+        // 8003 NOP
+        // 8004 BEQ $8008
+        // 8006 NOP
+        // 8007 NOP
+        // 8008 BRK
+        assert_eq!(
+            optimize_bytes(&[0xF0, 0x02, 0xEA, 0xEA, 0xF0, 0x02, 0xEA, 0xEA, 0x00]),
+            vec![0xF0, 0x06, 0xEA, 0xEA, 0xF0, 0x02, 0xEA, 0xEA, 0x00],
+        );
+
+        // a different condition must not be threaded through
+        assert_eq!(
+            optimize_bytes(&[0xF0, 0x02, 0xEA, 0xEA, 0xD0, 0x02, 0xEA, 0xEA, 0x00]),
+            vec![0xF0, 0x02, 0xEA, 0xEA, 0xD0, 0x02, 0xEA, 0xEA, 0x00],
+        );
+    }
+
+    #[test]
+    fn test_targets_relocated_after_shrinking() {
+        // 8000 LDA $0010
+        // 8003 JMP $8007
+        // 8006 NOP
+        // 8007 BNE $8000
+        let optimized = optimize_bytes(&[0xAD, 0x10, 0x00, 0x4C, 0x07, 0x80, 0xEA, 0xD0, 0xF7]);
+        // 8000 LDA $10
+        // 8002 JMP $8006
+        // 8005 NOP
+        // 8006 BNE $8000
+        assert_eq!(optimized, vec![0xA5, 0x10, 0x4C, 0x06, 0x80, 0xEA, 0xD0, 0xF8]);
+    }
+
+    #[test]
+    fn test_optimization_listings() {
+        let program = Program::try_from(&[0xAD, 0x10, 0x00][..]).unwrap().with_start(0x8000);
+        let optimization = program.optimize();
+        assert_eq!(optimization.before, "8000  AD 10 00  LDA $0010\n");
+        assert_eq!(optimization.after, "8000  A5 10     LDA $10\n");
+    }
+}
@@ -1,11 +1,11 @@
 use nom::IResult;
 use nom::branch::alt;
-use nom::bytes::complete::{tag_no_case, take};
-use nom::combinator::{eof, opt, peek, success};
+use nom::bytes::complete::{tag_no_case, take, take_while};
+use nom::combinator::{eof, opt, peek, recognize, success};
 // use nom::combinator::{not, peek};
 use nom::error::{ErrorKind, make_error};
-use nom::sequence::{pair, preceded, terminated};
-use nom::character::complete::{digit1, hex_digit1, line_ending, not_line_ending, one_of, space0, space1};
+use nom::sequence::{pair, preceded, terminated, tuple};
+use nom::character::complete::{digit1, hex_digit1, line_ending, not_line_ending, one_of, satisfy, space0, space1};
 use nom::multi::{many0, many_till};
 
 use nom::Err as NomErr; // typedef to make error handling less confusing
@@ -40,24 +40,39 @@ fn mnemonic(s: &str) -> IResult<&str, Mnemonic> {
     }
 }
 
-/// Combinator to read to the next line ending
+/// Combinator to read to the next line ending.
+/// Labels are accepted and skipped, as instructions here don't refer to them.
 fn line(s: &str) -> IResult<&str, Option<Instruction>> {
+    let label = terminated(
+        recognize(
+            pair(
+                satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+                take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            )
+        ),
+        tag_no_case(":"),
+    );
+
     preceded(
-        space0,
+        pair(
+            space0,
+            opt(
+                pair(
+                    label,
+                    space0,
+                )
+            ),
+        ),
         terminated(
             opt(instruction),
-            pair(
-                opt(
-                    pair(
-                        space0,
-                        comment
-                    ),
-                ),
+            tuple((
+                space0,
+                opt(comment),
                 alt((
                     line_ending,
                     eof,
                 ))
-            ),
+            )),
         ),
     )(s)
 }
@@ -111,7 +126,9 @@ fn accumulator(s: &str) -> IResult<&str, OperandMode> {
 fn hex_byte(s: &str) -> IResult<&str, u8> {
     let (rem, res) = hex_digit1(s)?;
     match res.len() {
-        2 => Ok((rem, u8::from_str_radix(res, 16).unwrap())),
+        2 => u8::from_str_radix(res, 16)
+            .map(|byte| (rem, byte))
+            .map_err(|_| NomErr::Error(make_error(s, ErrorKind::HexDigit))),
         _ => Err(NomErr::Error(make_error(s, ErrorKind::HexDigit))),
     }
 }
@@ -119,14 +136,16 @@ fn hex_byte(s: &str) -> IResult<&str, u8> {
 fn hex_double_byte(s: &str) -> IResult<&str, u16> {
     let (rem, res) = hex_digit1(s)?;
     match res.len() {
-        4 => Ok((rem, u16::from_str_radix(res, 16).unwrap())),
+        4 => u16::from_str_radix(res, 16)
+            .map(|word| (rem, word))
+            .map_err(|_| NomErr::Error(make_error(s, ErrorKind::HexDigit))),
         _ => Err(NomErr::Error(make_error(s, ErrorKind::HexDigit))),
     }
 }
 
 fn immediate(s: &str) -> IResult<&str, OperandMode> {
     preceded(
-        tag_no_case("#"),
+        pair(tag_no_case("#"), opt(tag_no_case("$"))),
         hex_byte,
     )(s)
         .map(|(rem, res)| {
@@ -182,16 +201,16 @@ fn zeropage_y(s: &str) -> IResult<&str, OperandMode> {
 }
 
 fn relative(s: &str) -> IResult<&str, OperandMode> {
-    preceded(
+    let (rem, (sign, digits)) = preceded(
         tag_no_case("*"),
         pair(one_of("+-"), digit1),
-    )(s)
-        .map(|(rem,res)| {
-            (rem, OperandMode::new(
-                Word(i8::from_str_radix(&(res.0.to_string() + res.1), 10).unwrap() as u8),
-                Relative
-            ))
-        })
+    )(s)?;
+
+    // offsets outside -128..=127 can't be encoded
+    match i8::from_str_radix(&(sign.to_string() + digits), 10) {
+        Ok(offset) => Ok((rem, OperandMode::new(Word(offset as u8), Relative))),
+        Err(_) => Err(NomErr::Error(make_error(s, ErrorKind::TooLarge))),
+    }
 }
 
 fn absolute(s: &str) -> IResult<&str, OperandMode> {
@@ -231,12 +250,12 @@ fn indirect(s: &str) -> IResult<&str, OperandMode> {
     preceded(
         tag_no_case("("),
         terminated(
-            zp_addr,
+            abs_addr,
             tag_no_case(")"),
         ),
     )(s)
         .map(|(rem, res)| {
-            (rem, OperandMode::new(Word(res), Indirect))
+            (rem, OperandMode::new(DoubleWord(res), Indirect))
         })
 }
 
@@ -266,6 +285,14 @@ fn indirect_y(s: &str) -> IResult<&str, OperandMode> {
         })
 }
 
+/// Parses a complete operand string, failing if any text is left over
+pub(crate) fn parse_operand(s: &str) -> Option<(Operand, AddressMode)> {
+    match operand(s) {
+        Ok(("", OperandMode { operand, mode })) => Some((operand, mode)),
+        _ => Option::None,
+    }
+}
+
 fn instruction(s: &str) -> IResult<&str, Instruction> {
     pair(
         mnemonic,
@@ -346,6 +373,13 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_relative_out_of_range() {
+        assert!(relative("*+127").is_ok());
+        assert!(relative("*+128").is_err());
+        assert!(relative("*-999").is_err());
+    }
+
     #[test]
     fn test_parse_operand() {
         assert_eq!(
@@ -491,6 +525,23 @@ mod tests {
         "#;
 
         program(code_chunk).unwrap();
+    }
+
+    #[test]
+    fn test_parse_program_layouts() {
+        // labels sharing a line, trailing tabs, a blank line of stray whitespace and CRLF endings
+        let code_chunk = "init: LDA #02 ; direction\r\n\t \t\r\ndrawLoop:\r\n\tSTA $0200,X\t\r\nRTS   ; done\r\n";
+        assert_eq!(
+            program(code_chunk),
+            Ok((
+                "",
+                vec![
+                    Instruction::new(Mnemonic::LDA, Operand::Word(0x02), AddressMode::Immediate),
+                    Instruction::new(Mnemonic::STA, Operand::DoubleWord(0x0200), AddressMode::AbsoluteX),
+                    Instruction::new(Mnemonic::RTS, Operand::None, AddressMode::Implicit),
+                ]
+            ))
+        );
         // assert_eq!(
         //     program(code_chunk),
         //     Ok((
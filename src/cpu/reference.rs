@@ -0,0 +1,289 @@
+//! A second, deliberately plain 6502 interpreter for cross-checking `CPU`.
+//!
+//! Each instruction is executed in one place straight from its mnemonic and
+//! addressing mode, sharing nothing with the main core but the opcode table
+//! and register layout, so a bug in one shows up as a difference rather than
+//! being reproduced in both. Interrupt lines aren't modelled.
+
+use super::addr::AddressMode::*;
+use super::ops::{Mnemonic, Opcode};
+use super::reg::{RegisterSet, Status};
+use super::CPU;
+
+const CARRY: u8 = 0b0000_0001;
+const ZERO: u8 = 0b0000_0010;
+const INTERRUPT: u8 = 0b0000_0100;
+const DECIMAL: u8 = 0b0000_1000;
+const BREAK_BITS: u8 = 0b0011_0000;
+const OVERFLOW: u8 = 0b0100_0000;
+const NEGATIVE: u8 = 0b1000_0000;
+
+const IRQ_VECTOR_ADDR: u16 = 0xFFFE;
+
+pub struct Reference {
+    pub reg: RegisterSet,
+    memory: Vec<u8>,
+    /// Addresses written by the last instruction
+    writes: Vec<u16>,
+}
+
+impl Default for Reference {
+    fn default() -> Self {
+        Reference { reg: RegisterSet::default(), memory: vec![0; 0x10000], writes: Vec::new() }
+    }
+}
+
+impl Reference {
+    pub fn new() -> Self {
+        Reference::default()
+    }
+
+    /// Copies the registers and all of memory from a CPU
+    pub fn from_cpu(cpu: &CPU) -> Self {
+        let mut reference = Reference::new();
+        reference.reg = *cpu.registers();
+        for addr in 0..=0xFFFF {
+            reference.memory[addr as usize] = cpu.read(addr);
+        }
+        reference
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        for (offset, &byte) in data.iter().enumerate() {
+            self.memory[addr.wrapping_add(offset as u16) as usize] = byte;
+        }
+    }
+
+    /// Addresses the last instruction wrote to, in order
+    pub fn writes(&self) -> &[u16] {
+        &self.writes
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+        self.writes.push(addr);
+    }
+
+    fn read_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    /// Pointers in the zero page wrap around within it
+    fn read_zero_page_u16(&self, addr: u8) -> u16 {
+        u16::from_le_bytes([self.read(addr as u16), self.read(addr.wrapping_add(1) as u16)])
+    }
+
+    fn push(&mut self, value: u8) {
+        self.write(0x0100 | self.reg.sp as u16, value);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        self.read(0x0100 | self.reg.sp as u16)
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        self.reg.p.set(Status::from_bits(flag), value);
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.reg.p.bits() & flag != 0
+    }
+
+    fn set_zn(&mut self, value: u8) -> u8 {
+        self.set_flag(ZERO, value == 0);
+        self.set_flag(NEGATIVE, value & 0x80 != 0);
+        value
+    }
+
+    fn add(&mut self, operand: u8) {
+        let sum = self.reg.a as u16 + operand as u16 + self.flag(CARRY) as u16;
+        let result = sum as u8;
+        self.set_flag(CARRY, sum > 0xFF);
+        self.set_flag(OVERFLOW, (self.reg.a ^ result) & (operand ^ result) & 0x80 != 0);
+        self.reg.a = self.set_zn(result);
+    }
+
+    fn compare(&mut self, register: u8, operand: u8) {
+        self.set_flag(CARRY, register >= operand);
+        self.set_zn(register.wrapping_sub(operand));
+    }
+
+    /// Executes one instruction. Returns the opcode byte if it isn't a documented instruction.
+    pub fn step(&mut self) -> Result<(), u8> {
+        use Mnemonic::*;
+
+        let pc = self.reg.pc;
+        let code = self.read(pc);
+        let opcode = Opcode::from_code(code).ok_or(code)?;
+        self.writes.clear();
+
+        let operand_addr = pc.wrapping_add(1);
+        self.reg.pc = pc.wrapping_add(opcode.bytes);
+        let (x, y) = (self.reg.x as u16, self.reg.y as u16);
+
+        let ea = match opcode.mode {
+            Implicit | Accumulator => None,
+            Immediate | Relative => Some(operand_addr),
+            ZeroPage => Some(self.read(operand_addr) as u16),
+            ZeroPageX => Some(self.read(operand_addr).wrapping_add(self.reg.x) as u16),
+            ZeroPageY => Some(self.read(operand_addr).wrapping_add(self.reg.y) as u16),
+            Absolute => Some(self.read_u16(operand_addr)),
+            AbsoluteX => Some(self.read_u16(operand_addr).wrapping_add(x)),
+            AbsoluteY => Some(self.read_u16(operand_addr).wrapping_add(y)),
+            Indirect => {
+                // the NMOS 6502 doesn't carry into the pointer's high byte
+                let ptr = self.read_u16(operand_addr);
+                let hi = (ptr & 0xFF00) | (ptr as u8).wrapping_add(1) as u16;
+                Some(u16::from_le_bytes([self.read(ptr), self.read(hi)]))
+            }
+            IndirectX => Some(self.read_zero_page_u16(self.read(operand_addr).wrapping_add(self.reg.x))),
+            IndirectY => Some(self.read_zero_page_u16(self.read(operand_addr)).wrapping_add(y)),
+        };
+        let operand = ea.map_or(self.reg.a, |addr| self.read(addr));
+
+        // shifts and rotates work on A or memory, carry comes from the bit shifted out
+        let modify = |reference: &mut Reference, result: u8, carry: bool| {
+            reference.set_flag(CARRY, carry);
+            reference.set_zn(result);
+            match ea {
+                Some(addr) => reference.write(addr, result),
+                None => reference.reg.a = result,
+            }
+        };
+        let branch = |reference: &mut Reference, taken: bool| {
+            if taken {
+                reference.reg.pc = reference.reg.pc.wrapping_add(operand as i8 as u16);
+            }
+        };
+
+        match opcode.mnemonic {
+            ADC => self.add(operand),
+            SBC => self.add(!operand),
+            AND => self.reg.a = self.set_zn(self.reg.a & operand),
+            ORA => self.reg.a = self.set_zn(self.reg.a | operand),
+            EOR => self.reg.a = self.set_zn(self.reg.a ^ operand),
+            CMP => self.compare(self.reg.a, operand),
+            CPX => self.compare(self.reg.x, operand),
+            CPY => self.compare(self.reg.y, operand),
+            BIT => {
+                self.set_flag(ZERO, self.reg.a & operand == 0);
+                self.set_flag(NEGATIVE, operand & 0x80 != 0);
+                self.set_flag(OVERFLOW, operand & 0x40 != 0);
+            }
+            ASL => modify(self, operand << 1, operand & 0x80 != 0),
+            LSR => modify(self, operand >> 1, operand & 1 != 0),
+            ROL => modify(self, operand << 1 | self.flag(CARRY) as u8, operand & 0x80 != 0),
+            ROR => modify(self, operand >> 1 | (self.flag(CARRY) as u8) << 7, operand & 1 != 0),
+            INC | DEC => {
+                let result = if opcode.mnemonic == INC { operand.wrapping_add(1) } else { operand.wrapping_sub(1) };
+                self.set_zn(result);
+                self.write(ea.expect("INC and DEC always address memory"), result);
+            }
+            INX => self.reg.x = self.set_zn(self.reg.x.wrapping_add(1)),
+            INY => self.reg.y = self.set_zn(self.reg.y.wrapping_add(1)),
+            DEX => self.reg.x = self.set_zn(self.reg.x.wrapping_sub(1)),
+            DEY => self.reg.y = self.set_zn(self.reg.y.wrapping_sub(1)),
+            LDA => self.reg.a = self.set_zn(operand),
+            LDX => self.reg.x = self.set_zn(operand),
+            LDY => self.reg.y = self.set_zn(operand),
+            STA => self.write(ea.expect("stores always address memory"), self.reg.a),
+            STX => self.write(ea.expect("stores always address memory"), self.reg.x),
+            STY => self.write(ea.expect("stores always address memory"), self.reg.y),
+            TAX => self.reg.x = self.set_zn(self.reg.a),
+            TAY => self.reg.y = self.set_zn(self.reg.a),
+            TXA => self.reg.a = self.set_zn(self.reg.x),
+            TYA => self.reg.a = self.set_zn(self.reg.y),
+            TSX => self.reg.x = self.set_zn(self.reg.sp),
+            TXS => self.reg.sp = self.reg.x,
+            CLC => self.set_flag(CARRY, false),
+            SEC => self.set_flag(CARRY, true),
+            CLI => self.set_flag(INTERRUPT, false),
+            SEI => self.set_flag(INTERRUPT, true),
+            CLV => self.set_flag(OVERFLOW, false),
+            CLD => self.set_flag(DECIMAL, false),
+            SED => self.set_flag(DECIMAL, true),
+            PHA => self.push(self.reg.a),
+            PHP => self.push(self.reg.p.bits() | BREAK_BITS),
+            PLA => {
+                let value = self.pull();
+                self.reg.a = self.set_zn(value);
+            }
+            PLP => self.reg.p = Status::from(self.pull() & !BREAK_BITS),
+            BPL => branch(self, !self.flag(NEGATIVE)),
+            BMI => branch(self, self.flag(NEGATIVE)),
+            BVC => branch(self, !self.flag(OVERFLOW)),
+            BVS => branch(self, self.flag(OVERFLOW)),
+            BCC => branch(self, !self.flag(CARRY)),
+            BCS => branch(self, self.flag(CARRY)),
+            BNE => branch(self, !self.flag(ZERO)),
+            BEQ => branch(self, self.flag(ZERO)),
+            JMP => self.reg.pc = ea.expect("jumps always have a target"),
+            JSR => {
+                let [lo, hi] = pc.wrapping_add(2).to_le_bytes();
+                self.push(hi);
+                self.push(lo);
+                self.reg.pc = ea.expect("jumps always have a target");
+            }
+            RTS => {
+                let lo = self.pull();
+                let hi = self.pull();
+                self.reg.pc = u16::from_le_bytes([lo, hi]).wrapping_add(1);
+            }
+            RTI => {
+                self.reg.p = Status::from(self.pull() & !BREAK_BITS);
+                let lo = self.pull();
+                let hi = self.pull();
+                self.reg.pc = u16::from_le_bytes([lo, hi]);
+            }
+            BRK => {
+                // the byte after BRK is padding, skipped on return
+                let [lo, hi] = pc.wrapping_add(2).to_le_bytes();
+                self.push(hi);
+                self.push(lo);
+                self.push(self.reg.p.bits() | BREAK_BITS);
+                self.set_flag(INTERRUPT, true);
+                self.reg.pc = self.read_u16(IRQ_VECTOR_ADDR);
+            }
+            NOP => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_step() {
+        // LDA #$50, ADC #$50, STA $10, JMP ($02FF)
+        let mut reference = Reference::new();
+        reference.load(0x0600, &[0xA9, 0x50, 0x69, 0x50, 0x85, 0x10, 0x6C, 0xFF, 0x02]);
+        reference.load(0x02FF, &[0x34, 0x56]);
+        reference.load(0x0200, &[0x12]);
+        reference.reg.pc = 0x0600;
+
+        reference.step().unwrap();
+        reference.step().unwrap();
+        assert_eq!(reference.reg.a, 0xA0);
+        assert_eq!(reference.reg.p.bits(), NEGATIVE | OVERFLOW);
+
+        reference.step().unwrap();
+        assert_eq!(reference.read(0x10), 0xA0);
+        assert_eq!(reference.writes(), &[0x10]);
+
+        // the pointer's high byte comes from the start of the same page
+        reference.step().unwrap();
+        assert_eq!(reference.reg.pc, 0x1234);
+
+        reference.load(0x1234, &[0x02]);
+        assert_eq!(reference.step(), Err(0x02));
+    }
+}
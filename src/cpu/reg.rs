@@ -1,4 +1,131 @@
-#[derive(Debug)]
+use std::fmt;
+use std::ops::{BitAnd, BitOr, Not};
+
+/// Processor status flags \[ N V B5 B4 D I Z C \]
+///
+/// Bits 4 and 5 don't exist in the register itself, they only appear in the
+/// copy pushed to the stack. Use `with_break_set`/`with_break_clear` when
+/// pushing and `from_pulled` when pulling rather than masking by hand.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Status(u8);
+
+impl Status {
+    pub const NEGATIVE: Status = Status(0b1000_0000);
+    pub const OVERFLOW: Status = Status(0b0100_0000);
+    /// Unused, always set when pushed
+    pub const B5: Status = Status(0b0010_0000);
+    /// Set when pushed by BRK or PHP, clear when pushed by an interrupt
+    pub const B4: Status = Status(0b0001_0000);
+    pub const DECIMAL: Status = Status(0b0000_1000);
+    pub const INTERRUPT: Status = Status(0b0000_0100);
+    pub const ZERO: Status = Status(0b0000_0010);
+    pub const CARRY: Status = Status(0b0000_0001);
+
+    /// Flags in display order, with their letters
+    const NAMES: [(Status, char); 8] = [
+        (Status::NEGATIVE, 'N'),
+        (Status::OVERFLOW, 'V'),
+        (Status::B5, '-'),
+        (Status::B4, 'B'),
+        (Status::DECIMAL, 'D'),
+        (Status::INTERRUPT, 'I'),
+        (Status::ZERO, 'Z'),
+        (Status::CARRY, 'C'),
+    ];
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Status(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// True if every flag in `flags` is set
+    pub const fn contains(self, flags: Status) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn insert(&mut self, flags: Status) {
+        self.0 |= flags.0;
+    }
+
+    pub fn remove(&mut self, flags: Status) {
+        self.0 &= !flags.0;
+    }
+
+    pub fn set(&mut self, flags: Status, value: bool) {
+        if value {
+            self.insert(flags);
+        } else {
+            self.remove(flags);
+        }
+    }
+
+    /// As pushed by PHP and BRK
+    pub const fn with_break_set(self) -> Status {
+        Status(self.0 | Status::B4.0 | Status::B5.0)
+    }
+
+    /// As pushed by NMI and IRQ
+    pub const fn with_break_clear(self) -> Status {
+        Status((self.0 & !Status::B4.0) | Status::B5.0)
+    }
+
+    /// Register value from a byte pulled by PLP or RTI, which ignore bits 4 and 5
+    pub const fn from_pulled(bits: u8) -> Status {
+        Status(bits & !(Status::B4.0 | Status::B5.0))
+    }
+}
+
+impl From<u8> for Status {
+    fn from(bits: u8) -> Self {
+        Status(bits)
+    }
+}
+
+impl From<Status> for u8 {
+    fn from(status: Status) -> Self {
+        status.0
+    }
+}
+
+impl BitOr for Status {
+    type Output = Status;
+
+    fn bitor(self, rhs: Status) -> Status {
+        Status(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Status {
+    type Output = Status;
+
+    fn bitand(self, rhs: Status) -> Status {
+        Status(self.0 & rhs.0)
+    }
+}
+
+impl Not for Status {
+    type Output = Status;
+
+    fn not(self) -> Status {
+        Status(!self.0)
+    }
+}
+
+/// Flags as NV-BDIZC, upper case when set and lower case when clear
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        for (flag, name) in Status::NAMES {
+            let name = if self.contains(flag) { name } else { name.to_ascii_lowercase() };
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RegisterSet {
     /// Program Counter
     pub pc: u16,
@@ -10,8 +137,8 @@ pub struct RegisterSet {
     pub x: u8,
     /// Index Register Y
     pub y: u8,
-    /// Processor Status Word \[ N V B5 B4 D I Z C \]
-    pub p: u8,
+    /// Processor Status Word
+    pub p: Status,
 }
 
 impl Default for RegisterSet {
@@ -22,95 +149,100 @@ impl Default for RegisterSet {
             a: u8::MIN,
             x: u8::MIN,
             y: u8::MIN,
-            p: u8::MIN,
+            p: Status::default(),
         }
     }
 }
 
 impl RegisterSet {
-    const BITMASK_NEGATIVE: u8 = 0b1000_0000;
-    const BITMASK_OVERFLOW: u8 = 0b0100_0000;
-    // 3rd status bit unused
-    const BITMASK_B5: u8 = 0b0010_0000;
-    const BITMASK_B4: u8 = 0b0001_0000;
-    const BITMASK_DECIMAL: u8 = 0b0000_1000;
-    const BITMASK_INTERRUPT: u8 = 0b0000_0100;
-    const BITMASK_ZERO: u8 = 0b0000_0010;
-    const BITMASK_CARRY: u8 = 0b0000_0001;
-
-    fn set_flag(&mut self, bitmask: u8, value: bool) {
-        if value {
-            self.p |= bitmask;
-        } else {
-            self.p &= !bitmask;
-        }
-    }
-
-    pub fn get_negative(&mut self) -> bool {
-        (self.p & Self::BITMASK_NEGATIVE) != 0
+    pub fn get_negative(&self) -> bool {
+        self.p.contains(Status::NEGATIVE)
     }
 
     pub fn set_negative(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_NEGATIVE, value);
+        self.p.set(Status::NEGATIVE, value);
     }
 
-    pub fn get_overflow(&mut self) -> bool {
-        (self.p & Self::BITMASK_OVERFLOW) != 0
+    pub fn get_overflow(&self) -> bool {
+        self.p.contains(Status::OVERFLOW)
     }
 
     pub fn set_overflow(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_OVERFLOW, value);
+        self.p.set(Status::OVERFLOW, value);
     }
 
-    pub fn get_b5(&mut self) -> bool {
-        (self.p & Self::BITMASK_B5) != 0
+    pub fn get_b5(&self) -> bool {
+        self.p.contains(Status::B5)
     }
 
     pub fn set_b5(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_B5, value);
+        self.p.set(Status::B5, value);
     }
 
-    pub fn get_b4(&mut self) -> bool {
-        (self.p & Self::BITMASK_B4) != 0
+    pub fn get_b4(&self) -> bool {
+        self.p.contains(Status::B4)
     }
 
     pub fn set_b4(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_B4, value);
+        self.p.set(Status::B4, value);
     }
 
-    pub fn get_decimal(&mut self) -> bool {
-        (self.p & Self::BITMASK_DECIMAL) != 0
+    pub fn get_decimal(&self) -> bool {
+        self.p.contains(Status::DECIMAL)
     }
 
     pub fn set_decimal(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_DECIMAL, value);
+        self.p.set(Status::DECIMAL, value);
     }
 
-    pub fn get_interrupt(&mut self) -> bool {
-        (self.p & Self::BITMASK_INTERRUPT) != 0
+    pub fn get_interrupt(&self) -> bool {
+        self.p.contains(Status::INTERRUPT)
     }
 
     pub fn set_interrupt(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_INTERRUPT, value);
+        self.p.set(Status::INTERRUPT, value);
     }
 
-    pub fn get_zero(&mut self) -> bool {
-        (self.p & Self::BITMASK_ZERO) != 0
+    pub fn get_zero(&self) -> bool {
+        self.p.contains(Status::ZERO)
     }
 
     pub fn set_zero(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_ZERO, value);
+        self.p.set(Status::ZERO, value);
     }
 
-    pub fn get_carry(&mut self) -> bool {
-        (self.p & Self::BITMASK_CARRY) != 0
+    pub fn get_carry(&self) -> bool {
+        self.p.contains(Status::CARRY)
     }
 
     pub fn set_carry(&mut self, value: bool) {
-        self.set_flag(Self::BITMASK_CARRY, value);
+        self.p.set(Status::CARRY, value);
     }
 
     pub fn reset(&mut self) {
         *self = RegisterSet::default();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let status = Status::NEGATIVE | Status::ZERO | Status::CARRY;
+        assert_eq!(u8::from(status), 0b1000_0011);
+        assert_eq!(status.to_string(), "Nv-bdiZC");
+        assert_eq!(Status::from(0xFF).to_string(), "NV-BDIZC");
+
+        assert_eq!(status.with_break_set().bits(), 0b1011_0011);
+        assert_eq!(Status::from(0xFF).with_break_clear().bits(), 0b1110_1111);
+        assert_eq!(Status::from_pulled(0xFF).bits(), 0b1100_1111);
+
+        let mut reg = RegisterSet::default();
+        reg.set_overflow(true);
+        assert!(reg.p.contains(Status::OVERFLOW));
+        reg.p.remove(Status::OVERFLOW);
+        assert!(!reg.get_overflow());
+    }
+}
@@ -0,0 +1,402 @@
+//! Conformance against the SingleStepTests/65x02 per-opcode vectors.
+//!
+//! The vectors aren't shipped with the crate. Each file in the `nes6502` set
+//! (the 2A03, so no decimal mode) is a JSON array of cases for one opcode,
+//! giving the registers and RAM before and after a single instruction and
+//! every bus access it made, one per cycle.
+//!
+//! The interpreter doesn't run cycle by cycle, so bus activity is checked
+//! loosely: every address it reads or writes must be one the real CPU
+//! accessed the same way, and every address the real CPU wrote must have been
+//! written. Cycles are the instruction's costed `Timing`, as `cycles` works
+//! them out, against the length of the access list. Bits 4 and 5 of P aren't
+//! compared, as they don't exist in the register.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use super::cycles::PendingCost;
+use super::ops::{Opcode, JAM_OPCODES};
+use super::reg::{RegisterSet, Status};
+use super::CPU;
+use crate::memory::Peripheral;
+
+/// Bits of P that are compared
+const STATUS_MASK: u8 = !(Status::B4.bits() | Status::B5.bits());
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BusOp {
+    Read,
+    Write,
+}
+
+/// Registers and the RAM a case sets up or expects
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct State {
+    pub registers: RegisterSet,
+    pub ram: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Case {
+    pub name: String,
+    pub initial: State,
+    pub expected: State,
+    /// Every bus access in order, one per cycle
+    pub cycles: Vec<(u16, u8, BusOp)>,
+}
+
+/// Whether the interpreter runs `code`, as cases for other opcodes only
+/// show it halting
+pub fn emulated(code: u8) -> bool {
+    Opcode::from_code(code).is_some() && !JAM_OPCODES.contains(&code)
+}
+
+/// Reads a vector file, such as `nes6502/v1/a9.json`
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Case>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+pub fn parse(text: &str) -> Result<Vec<Case>, Box<dyn Error>> {
+    let mut parser = Parser { bytes: text.as_bytes(), pos: 0 };
+    let json = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        return Err(format!("trailing data at byte {}", parser.pos).into());
+    }
+    json.array()?.iter().map(Case::from_json).collect()
+}
+
+impl Case {
+    fn from_json(json: &Json) -> Result<Self, Box<dyn Error>> {
+        let name = json.field("name")?.string()?.to_string();
+        let initial = State::from_json(json.field("initial")?)?;
+        let expected = State::from_json(json.field("final")?)?;
+        let cycles = json
+            .field("cycles")?
+            .array()?
+            .iter()
+            .map(|cycle| match cycle.array()? {
+                [addr, value, op] => {
+                    let op = match op.string()? {
+                        "read" => BusOp::Read,
+                        "write" => BusOp::Write,
+                        other => return Err(format!("unknown bus access `{}`", other).into()),
+                    };
+                    Ok((addr.number()?, value.number()?, op))
+                }
+                _ => Err("a cycle should be [address, value, access]".into()),
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        Ok(Case { name, initial, expected, cycles })
+    }
+
+    /// Steps the instruction from the initial state, listing every way the
+    /// result differs from the vector
+    pub fn run(&self) -> Result<(), String> {
+        let bus = Rc::new(RefCell::new(Bus { ram: vec![0; 0x10000], log: Vec::new() }));
+        for &(addr, value) in &self.initial.ram {
+            bus.borrow_mut().ram[addr as usize] = value;
+        }
+        let mut cpu = CPU::new();
+        cpu.map_peripheral(0x0000..=0xFFFF, Box::new(Probe(Rc::clone(&bus))));
+        *cpu.registers_mut() = self.initial.registers;
+
+        let cost = PendingCost::at_pc(&cpu);
+        bus.borrow_mut().log.clear();
+        let opcode = cpu.read(cpu.registers().pc);
+        cpu.step(opcode);
+
+        let mut errors = Vec::new();
+        let (reg, expected) = (cpu.registers(), &self.expected.registers);
+        let mut compare = |name: &str, got: u16, expected: u16| {
+            if got != expected {
+                errors.push(format!("{}: expected ${:02X}, got ${:02X}", name, expected, got));
+            }
+        };
+        compare("PC", reg.pc, expected.pc);
+        compare("SP", reg.sp as u16, expected.sp as u16);
+        compare("A", reg.a as u16, expected.a as u16);
+        compare("X", reg.x as u16, expected.x as u16);
+        compare("Y", reg.y as u16, expected.y as u16);
+        compare("P", (reg.p.bits() & STATUS_MASK) as u16, (expected.p.bits() & STATUS_MASK) as u16);
+
+        let bus = bus.borrow();
+        for &(addr, value) in &self.expected.ram {
+            let got = bus.ram[addr as usize];
+            if got != value {
+                errors.push(format!("${:04X}: expected ${:02X}, got ${:02X}", addr, value, got));
+            }
+        }
+
+        match cost {
+            Some(cost) if cost.cycles(reg.pc) as usize != self.cycles.len() => {
+                errors.push(format!("cycles: expected {}, took {}", self.cycles.len(), cost.cycles(reg.pc)));
+            }
+            Some(_) => {}
+            None => errors.push(format!("cycles: no timing for opcode {:#04x}", opcode)),
+        }
+        for &(addr, _, op) in &bus.log {
+            if !self.cycles.iter().any(|&(real, _, real_op)| (real, real_op) == (addr, op)) {
+                errors.push(format!("bus: {:?} of ${:04X} the 6502 doesn't make", op, addr));
+            }
+        }
+        for &(addr, _, op) in self.cycles.iter().filter(|&&(_, _, op)| op == BusOp::Write) {
+            if !bus.log.iter().any(|&(made, _, made_op)| (made, made_op) == (addr, op)) {
+                errors.push(format!("bus: no write to ${:04X}", addr));
+            }
+        }
+
+        errors.dedup();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("; ")),
+        }
+    }
+}
+
+impl State {
+    fn from_json(json: &Json) -> Result<Self, Box<dyn Error>> {
+        let registers = RegisterSet {
+            pc: json.field("pc")?.number()?,
+            sp: json.field("s")?.number()?,
+            a: json.field("a")?.number()?,
+            x: json.field("x")?.number()?,
+            y: json.field("y")?.number()?,
+            p: Status::from_bits(json.field("p")?.number()?),
+        };
+        let ram = json
+            .field("ram")?
+            .array()?
+            .iter()
+            .map(|byte| match byte.array()? {
+                [addr, value] => Ok((addr.number()?, value.number()?)),
+                _ => Err("a RAM entry should be [address, value]".into()),
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        Ok(State { registers, ram })
+    }
+}
+
+/// Flat 64K of RAM standing in for the whole bus, logging every access
+struct Bus {
+    ram: Vec<u8>,
+    log: Vec<(u16, u8, BusOp)>,
+}
+
+struct Probe(Rc<RefCell<Bus>>);
+
+impl Peripheral for Probe {
+    fn read(&mut self, offset: u16) -> u8 {
+        let mut bus = self.0.borrow_mut();
+        let value = bus.ram[offset as usize];
+        bus.log.push((offset, value, BusOp::Read));
+        value
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        let mut bus = self.0.borrow_mut();
+        bus.ram[offset as usize] = val;
+        bus.log.push((offset, val, BusOp::Write));
+    }
+}
+
+/// The subset of JSON the vectors use: no floats, negatives, booleans or nulls
+#[derive(Debug, PartialEq)]
+enum Json {
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn field(&self, name: &str) -> Result<&Json, Box<dyn Error>> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("missing field `{}`", name).into()),
+            _ => Err(format!("expected an object with `{}`", name).into()),
+        }
+    }
+
+    fn array(&self) -> Result<&[Json], Box<dyn Error>> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => Err("expected an array".into()),
+        }
+    }
+
+    fn string(&self) -> Result<&str, Box<dyn Error>> {
+        match self {
+            Json::String(s) => Ok(s),
+            _ => Err("expected a string".into()),
+        }
+    }
+
+    fn number<T: TryFrom<u64>>(&self) -> Result<T, Box<dyn Error>> {
+        match self {
+            Json::Number(n) => T::try_from(*n).map_err(|_| format!("{} is out of range", n).into()),
+            _ => Err("expected a number".into()),
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.bytes.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips whitespace and consumes `byte` if it's next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.bytes.get(self.pos) == Some(&byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Box<dyn Error>> {
+        match self.eat(byte) {
+            true => Ok(()),
+            false => Err(format!("expected `{}` at byte {}", byte as char, self.pos).into()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, Box<dyn Error>> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'0'..=b'9') => {
+                let start = self.pos;
+                while self.bytes.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+                let digits = std::str::from_utf8(&self.bytes[start..self.pos])?;
+                Ok(Json::Number(digits.parse()?))
+            }
+            _ => Err(format!("unexpected input at byte {}", self.pos).into()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.bytes.get(self.pos) {
+                        Some(&c @ (b'"' | b'\\' | b'/')) => out.push(c),
+                        _ => return Err(format!("unsupported escape at byte {}", self.pos).into()),
+                    }
+                }
+                Some(&c) => out.push(c),
+                None => return Err("unterminated string".into()),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LDA ($10),Y with the pointer at $02FF and Y = 1, so the read crosses
+    /// into page 3 after a dummy read of $0200
+    const LDA_INDIRECT_Y: &str = r#"[{
+        "name": "b1 10 a0",
+        "initial": {"pc": 1536, "s": 253, "a": 0, "x": 0, "y": 1, "p": 36,
+            "ram": [[1536, 177], [1537, 16], [16, 255], [17, 2], [512, 17], [768, 66]]},
+        "final": {"pc": 1538, "s": 253, "a": 66, "x": 0, "y": 1, "p": 36,
+            "ram": [[1536, 177], [1537, 16], [16, 255], [17, 2], [512, 17], [768, 66]]},
+        "cycles": [[1536, 177, "read"], [1537, 16, "read"], [16, 255, "read"],
+            [17, 2, "read"], [512, 17, "read"], [768, 66, "read"]]
+    }]"#;
+
+    #[test]
+    fn test_run() {
+        let cases = parse(LDA_INDIRECT_Y).unwrap();
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].name, "b1 10 a0");
+        assert_eq!(cases[0].cycles[4], (0x0200, 0x11, BusOp::Read));
+        assert_eq!(cases[0].run(), Ok(()));
+
+        let mut wrong = cases[0].clone();
+        wrong.expected.registers.a = 0x43;
+        wrong.expected.registers.p = Status::from_bits(0x34 | Status::CARRY.bits());
+        wrong.expected.ram.push((0x0300, 0x43));
+        wrong.cycles.pop();
+        wrong.cycles.push((0x0300, 0x66, BusOp::Write));
+        assert_eq!(
+            wrong.run(),
+            Err("A: expected $43, got $42; P: expected $05, got $04; $0300: expected $43, got $42; \
+                 bus: Read of $0300 the 6502 doesn't make; bus: no write to $0300"
+                .to_string())
+        );
+
+        wrong = cases[0].clone();
+        wrong.cycles.push((0x0600, 0xB1, BusOp::Read));
+        assert_eq!(wrong.run(), Err("cycles: expected 7, took 6".to_string()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("[]").unwrap(), vec![]);
+        assert_eq!(parse("[1] x").unwrap_err().to_string(), "trailing data at byte 4");
+        assert_eq!(parse("[{\"name\": \"a\"}]").unwrap_err().to_string(), "missing field `initial`");
+        assert_eq!(parse("[-1]").unwrap_err().to_string(), "unexpected input at byte 1");
+        assert_eq!(parse("{\"a\" 1}").unwrap_err().to_string(), "expected `:` at byte 5");
+        assert!(emulated(0xA9));
+        assert!(!emulated(0x02));
+        assert!(!emulated(0x80));
+    }
+}
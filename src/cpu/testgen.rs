@@ -0,0 +1,341 @@
+//! Generator for small self-verifying CPU test programs.
+//!
+//! Each `TestCase` sets up registers, flags and memory, runs one instruction,
+//! saves the resulting state and compares it against what a simple reference
+//! model of the instruction predicts. The program reports its own result in
+//! blargg's test ROM convention, a status byte at 0x6000 which is 0 for a pass,
+//! so `to_ines` images can be cross-checked on other emulators.
+//!
+//! BRK isn't covered, as the programs use it to finish.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+
+use super::addr::AddressMode::*;
+use super::ops::{Mnemonic, Opcode, NMOS_6502_OPCODES};
+use super::prog::asm;
+use super::reference::Reference;
+use super::{StopReason, CPU};
+use crate::cart::PRG_BANK_SIZE;
+
+/// Status byte: 0x80 while running, 0x00 passed, 0x01 failed
+pub use crate::accuracy::STATUS_ADDR;
+/// A, X, Y, P and SP straight after the instruction under test are saved here
+pub const RESULT_ADDR: u16 = 0x00F0;
+
+const ZERO_PAGE_ADDR: u8 = 0x10;
+const ABSOLUTE_ADDR: u16 = 0x0300;
+/// Base of the pointer for (zp,X)
+const INDIRECT_X_ADDR: u8 = 0x20;
+const INDIRECT_X_TARGET: u16 = 0x0320;
+/// Pointer for (zp),Y
+const INDIRECT_Y_ADDR: u8 = 0x30;
+const INDIRECT_Y_TARGET: u16 = 0x0330;
+/// Pointer for JMP (abs)
+const JUMP_VECTOR_ADDR: u16 = 0x0340;
+const INITIAL_SP: u8 = 0xFD;
+/// Loaded by control flow tests when they don't jump
+const NOT_TAKEN: u8 = 0xEE;
+const STEP_LIMIT: usize = 10_000;
+
+const CARRY: u8 = 0b0000_0001;
+const ZERO: u8 = 0b0000_0010;
+const BREAK_BITS: u8 = 0b0011_0000;
+const OVERFLOW: u8 = 0b0100_0000;
+const NEGATIVE: u8 = 0b1000_0000;
+
+/// Machine state before the instruction under test; `m` is its memory or immediate operand
+#[derive(Debug, Clone, Copy)]
+struct Setup {
+    a: u8,
+    x: u8,
+    y: u8,
+    m: u8,
+    p: u8,
+}
+
+/// Chosen to hit carries, overflows, zero results and zero page wrapping between them
+const SETUPS: [Setup; 3] = [
+    Setup { a: 0x50, x: 0x04, y: 0x06, m: 0x50, p: 0x00 },
+    Setup { a: 0xFF, x: 0xFF, y: 0x80, m: 0x01, p: NEGATIVE | OVERFLOW | ZERO | CARRY },
+    Setup { a: 0x00, x: 0x80, y: 0xFF, m: 0x80, p: CARRY },
+];
+
+/// Expected state after the instruction under test
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct EndState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// As pushed by PHP, so with bits 4 and 5 set
+    pub p: u8,
+    pub sp: u8,
+    /// Memory the instruction reads or writes
+    pub memory: Vec<(u16, u8)>,
+}
+
+impl EndState {
+    /// Every address the program checks, with the value it should hold
+    fn checks(&self) -> Vec<(u16, u8)> {
+        let registers = [self.a, self.x, self.y, self.p, self.sp];
+        (RESULT_ADDR..).zip(registers).chain(self.memory.iter().copied()).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    /// The opcode under test
+    pub opcode: u8,
+    pub origin: u16,
+    pub program: Vec<u8>,
+    pub expected: EndState,
+}
+
+impl TestCase {
+    /// Runs the program on a fresh CPU and checks the result
+    pub fn run(&self) -> Result<(), String> {
+        let mut cpu = CPU::new();
+        cpu.load(self.origin, &self.program);
+        cpu.load(CPU::PRG_START_ADDR, &self.origin.to_le_bytes());
+        cpu.interrupt_reset();
+
+        for _ in 0..STEP_LIMIT {
+            match cpu.step_next() {
+                Some(StopReason::Break) => return self.check(&cpu),
+                Some(reason) => return Err(format!("{}: stopped early, {:?}", self.name, reason)),
+                None => {}
+            }
+        }
+        Err(format!("{}: still running after {} instructions", self.name, STEP_LIMIT))
+    }
+
+    /// Compares a finished run's memory against the expected state
+    pub fn check(&self, cpu: &CPU) -> Result<(), String> {
+        let mismatches: Vec<String> = self
+            .expected
+            .checks()
+            .into_iter()
+            .chain([(STATUS_ADDR, 0)])
+            .filter(|&(addr, value)| cpu.read(addr) != value)
+            .map(|(addr, value)| format!("${:04X} is {:02X}, expected {:02X}", addr, cpu.read(addr), value))
+            .collect();
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{}: {}", self.name, mismatches.join(", ")))
+        }
+    }
+
+    /// Wraps the program in an NROM iNES image. BRK and NMIs land on an endless loop.
+    pub fn to_ines(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let prg_offset = |addr: u16| (addr as usize - 0x8000) % PRG_BANK_SIZE;
+        if self.origin < 0x8000 || prg_offset(self.origin) + self.program.len() > PRG_BANK_SIZE - 0x10 {
+            return Err(format!("{}: program at ${:04X} doesn't fit in PRG ROM", self.name, self.origin).into());
+        }
+
+        let mut prg = vec![0; PRG_BANK_SIZE];
+        let start = prg_offset(self.origin);
+        prg[start..start + self.program.len()].copy_from_slice(&self.program);
+
+        // JMP to itself at 0xFFF0, and the vectors
+        let idle: u16 = 0xFFF0;
+        let [idle_lo, idle_hi] = idle.to_le_bytes();
+        let [origin_lo, origin_hi] = self.origin.to_le_bytes();
+        prg[prg_offset(idle)..].copy_from_slice(&[
+            0x4C, idle_lo, idle_hi, 0, 0, 0, 0, 0, 0, 0, idle_lo, idle_hi, origin_lo, origin_hi, idle_lo, idle_hi,
+        ]);
+
+        let mut image = b"NES\x1A".to_vec();
+        image.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        image.extend(prg);
+        Ok(image)
+    }
+}
+
+/// Test programs for every documented opcode except BRK, assembled at `origin`
+pub fn generate(origin: u16) -> Vec<TestCase> {
+    NMOS_6502_OPCODES
+        .iter()
+        .filter(|opcode| opcode.mnemonic != Mnemonic::BRK)
+        .flat_map(|opcode| SETUPS.iter().enumerate().map(move |(i, setup)| build(opcode, setup, i, origin)))
+        .collect()
+}
+
+fn build(opcode: &Opcode, setup: &Setup, variant: usize, origin: u16) -> TestCase {
+    let name = format!("{} {:?} ({:#04x}) #{}", opcode.mnemonic, opcode.mode, opcode.code, variant);
+    let assemble = |expected: &EndState| {
+        asm::assemble(&source(opcode, setup, origin, expected))
+            .unwrap_or_else(|e| panic!("ERROR: test program for {} failed to assemble: {}", name, e))
+    };
+
+    // the checks come after everything the reference runs, so don't change its layout
+    let expected = expected_state(&name, &assemble(&EndState::default()), effective_address(opcode, setup));
+    let program = assemble(&expected).bytes;
+
+    TestCase { name, opcode: opcode.code, origin, program, expected }
+}
+
+/// Runs the setup and the instruction under test on the reference interpreter
+fn expected_state(name: &str, assembly: &asm::Assembly, effective_address: Option<u16>) -> EndState {
+    let (test, target) = (assembly.symbols["test"], assembly.symbols["target"]);
+    let mut reference = Reference::new();
+    reference.load(assembly.origin, &assembly.bytes);
+    reference.reg.pc = assembly.origin;
+
+    let mut memory: BTreeSet<u16> = effective_address.into_iter().collect();
+    for _ in 0..STEP_LIMIT {
+        if reference.reg.pc == target {
+            let reg = reference.reg;
+            let memory = memory.into_iter().map(|addr| (addr, reference.read(addr))).collect();
+            return EndState { a: reg.a, x: reg.x, y: reg.y, p: reg.p.bits() | BREAK_BITS, sp: reg.sp, memory };
+        }
+
+        let at_test = reference.reg.pc == test;
+        if let Err(code) = reference.step() {
+            panic!("ERROR: reference hit opcode {:#04x} running {}", code, name);
+        }
+        if at_test {
+            memory.extend(reference.writes());
+        }
+    }
+    panic!("ERROR: reference never reached the end of {}", name);
+}
+
+/// Address the instruction under test reads or writes, if any
+fn effective_address(opcode: &Opcode, setup: &Setup) -> Option<u16> {
+    if is_control_flow(opcode) {
+        return None;
+    }
+    match opcode.mode {
+        ZeroPage => Some(ZERO_PAGE_ADDR as u16),
+        ZeroPageX => Some(ZERO_PAGE_ADDR.wrapping_add(setup.x) as u16),
+        ZeroPageY => Some(ZERO_PAGE_ADDR.wrapping_add(setup.y) as u16),
+        Absolute => Some(ABSOLUTE_ADDR),
+        AbsoluteX => Some(ABSOLUTE_ADDR + setup.x as u16),
+        AbsoluteY => Some(ABSOLUTE_ADDR + setup.y as u16),
+        IndirectX => Some(INDIRECT_X_TARGET),
+        IndirectY => Some(INDIRECT_Y_TARGET + setup.y as u16),
+        Implicit | Accumulator | Immediate | Relative | Indirect => None,
+    }
+}
+
+fn is_control_flow(opcode: &Opcode) -> bool {
+    use Mnemonic::*;
+    opcode.mode == Relative || matches!(opcode.mnemonic, JMP | JSR | RTS | RTI)
+}
+
+fn source(opcode: &Opcode, setup: &Setup, origin: u16, expected: &EndState) -> String {
+    use Mnemonic::*;
+    let mut lines = vec![
+        format!(".org ${:04X}", origin),
+        format!("LDA #$80\nSTA ${:04X}", STATUS_ADDR),
+        // blargg's signature, so other emulators know to watch the status byte
+        format!("LDA #$DE\nSTA ${:04X}\nLDA #$B0\nSTA ${:04X}\nLDA #$61\nSTA ${:04X}", STATUS_ADDR + 1, STATUS_ADDR + 2, STATUS_ADDR + 3),
+        format!("LDX #${:02X}\nTXS", INITIAL_SP),
+    ];
+
+    let store = |addr: u16, value: &str| format!("LDA #{}\nSTA ${:04X}", value, addr);
+    match opcode.mode {
+        IndirectX => {
+            let pointer = INDIRECT_X_ADDR.wrapping_add(setup.x);
+            lines.push(store(pointer as u16, &format!("${:02X}", INDIRECT_X_TARGET as u8)));
+            lines.push(store(pointer.wrapping_add(1) as u16, &format!("${:02X}", INDIRECT_X_TARGET >> 8)));
+        }
+        IndirectY => {
+            lines.push(store(INDIRECT_Y_ADDR as u16, &format!("${:02X}", INDIRECT_Y_TARGET as u8)));
+            lines.push(store(INDIRECT_Y_ADDR as u16 + 1, &format!("${:02X}", INDIRECT_Y_TARGET >> 8)));
+        }
+        Indirect => {
+            lines.push(store(JUMP_VECTOR_ADDR, "<target"));
+            lines.push(store(JUMP_VECTOR_ADDR + 1, ">target"));
+        }
+        _ => {}
+    }
+    if let Some(addr) = effective_address(opcode, setup) {
+        lines.push(store(addr, &format!("${:02X}", setup.m)));
+    }
+
+    let push = |value: &str| format!("LDA #{}\nPHA", value);
+    match opcode.mnemonic {
+        PLA | PLP => lines.push(push(&format!("${:02X}", setup.m))),
+        RTS => lines.extend([push(">ret"), push("<ret")]),
+        RTI => lines.extend([push(">target"), push("<target"), push(&format!("${:02X}", setup.m))]),
+        _ => {}
+    }
+
+    lines.push(push(&format!("${:02X}", setup.p)));
+    lines.push(format!("LDX #${:02X}\nLDY #${:02X}\nLDA #${:02X}\nPLP", setup.x, setup.y, setup.a));
+
+    let operand = match opcode.mode {
+        Implicit | Accumulator => vec![],
+        Immediate => vec![format!("${:02X}", setup.m)],
+        ZeroPage | ZeroPageX | ZeroPageY => vec![format!("${:02X}", ZERO_PAGE_ADDR)],
+        Absolute if is_control_flow(opcode) => vec!["<target".to_string(), ">target".to_string()],
+        Absolute | AbsoluteX | AbsoluteY => vec![format!("${:02X}", ABSOLUTE_ADDR as u8), format!("${:02X}", ABSOLUTE_ADDR >> 8)],
+        IndirectX => vec![format!("${:02X}", INDIRECT_X_ADDR)],
+        IndirectY => vec![format!("${:02X}", INDIRECT_Y_ADDR)],
+        Indirect => vec![format!("${:02X}", JUMP_VECTOR_ADDR as u8), format!("${:02X}", JUMP_VECTOR_ADDR >> 8)],
+        // over the not taken load
+        Relative => vec!["$02".to_string()],
+    };
+    let bytes: Vec<String> = std::iter::once(format!("${:02X}", opcode.code)).chain(operand).collect();
+    lines.push(format!("test: .byte {}", bytes.join(", ")));
+    if is_control_flow(opcode) {
+        lines.push(format!("LDA #${:02X}", NOT_TAKEN));
+    }
+
+    lines.push(format!(
+        "target: PHP\nSTA ${:02X}\nSTX ${:02X}\nSTY ${:02X}\nPLA\nSTA ${:02X}\nTSX\nSTX ${:02X}",
+        RESULT_ADDR,
+        RESULT_ADDR + 1,
+        RESULT_ADDR + 2,
+        RESULT_ADDR + 3,
+        RESULT_ADDR + 4
+    ));
+    for (addr, value) in expected.checks() {
+        lines.push(format!("LDA ${:04X}\nCMP #${:02X}\nBNE fail", addr, value));
+    }
+    lines.push(format!("LDA #$00\nSTA ${:04X}\nBRK", STATUS_ADDR));
+    lines.push(format!("fail: LDA #$01\nSTA ${:04X}\nBRK", STATUS_ADDR));
+    lines.push("ret = target - 1".to_string());
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expected_state() {
+        // 0x50 + 0x50 overflows into the sign bit
+        let cases = generate(0x0600);
+        let adc = cases.iter().find(|case| case.opcode == 0x69).unwrap();
+        assert_eq!(adc.expected.a, 0xA0);
+        assert_eq!(adc.expected.p, NEGATIVE | OVERFLOW | BREAK_BITS);
+        assert!(adc.expected.memory.is_empty());
+
+        let inc = cases.iter().find(|case| case.opcode == 0xEE).unwrap();
+        assert_eq!(inc.expected.memory, vec![(ABSOLUTE_ADDR, 0x51)]);
+    }
+
+    #[test]
+    fn test_generate() {
+        let cases = generate(0xC000);
+        assert_eq!(cases.len(), (NMOS_6502_OPCODES.len() - 1) * SETUPS.len());
+
+        let jsr = cases.iter().find(|case| case.opcode == 0x20).unwrap();
+        assert_eq!(jsr.expected.sp, INITIAL_SP - 2);
+        assert_eq!(jsr.expected.memory.len(), 2);
+
+        let image = jsr.to_ines().unwrap();
+        assert_eq!(image.len(), 16 + PRG_BANK_SIZE);
+        assert_eq!(&image[16..16 + jsr.program.len()], &jsr.program[..]);
+        // reset vector
+        assert_eq!(&image[image.len() - 4..image.len() - 2], &[0x00, 0xC0]);
+
+        assert!(generate(0x0600)[0].to_ines().is_err());
+    }
+}
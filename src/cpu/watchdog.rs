@@ -0,0 +1,185 @@
+//! Detection of programs which have run away or stopped making progress.
+//!
+//! Call `Watchdog::check` before each instruction, e.g. from the
+//! `run_with_callback` callback, and stop or break into the debugger when it
+//! reports a diagnostic. The debugger's `step` and `c` check with their own.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::CPU;
+
+const JMP_ABSOLUTE: u8 = 0x4C;
+/// Branch offset pointing back at the branch itself
+const BRANCH_TO_SELF: u8 = 0xFE;
+
+/// A failure pattern spotted in the running program
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Diagnostic {
+    /// PC is in the stack page, normally from returning through a corrupt stack
+    StackExecution { pc: u16 },
+    /// Jumping or branching to itself with IRQs masked, so only an NMI can end it
+    JumpToSelf { pc: u16 },
+    /// The stack pointer has wrapped around repeatedly, e.g. unbalanced pushes in a loop
+    StackWrapStorm { pc: u16, wraps: usize },
+    /// A write to a read-only region was blocked, seen with the CPU in strict ROM mode
+    RomWrite { pc: u16, addr: u16, value: u8 },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Diagnostic::StackExecution { pc } => write!(f, "executing from the stack page at ${:04X}", pc),
+            Diagnostic::JumpToSelf { pc } => write!(f, "stuck jumping to ${:04X} with interrupts disabled", pc),
+            Diagnostic::StackWrapStorm { pc, wraps } => {
+                write!(f, "stack pointer wrapped {} times recently, now at ${:04X}", wraps, pc)
+            }
+            Diagnostic::RomWrite { pc, addr, value } => {
+                write!(f, "write of ${:02X} to ROM at ${:04X}, before ${:04X}", value, addr, pc)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    /// Stack wraps tolerated within `wrap_window` instructions
+    pub wrap_limit: usize,
+    pub wrap_window: u64,
+    instructions: u64,
+    last_sp: Option<u8>,
+    /// Instruction counts at which the stack pointer wrapped
+    wraps: VecDeque<u64>,
+    /// Blocked ROM writes already reported
+    rom_writes: usize,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            wrap_limit: 4,
+            wrap_window: 8192,
+            instructions: 0,
+            last_sp: None,
+            wraps: VecDeque::new(),
+            rom_writes: 0,
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog::default()
+    }
+
+    /// Inspects the CPU before its next instruction runs
+    pub fn check(&mut self, cpu: &CPU) -> Option<Diagnostic> {
+        let reg = cpu.registers();
+        let pc = reg.pc;
+        self.instructions += 1;
+
+        // a push or pull moves SP at most 3, so a big jump between steps is a wrap
+        if let Some(last_sp) = self.last_sp {
+            if (last_sp as i16 - reg.sp as i16).abs() > 0x80 {
+                self.wraps.push_back(self.instructions);
+            }
+        }
+        self.last_sp = Some(reg.sp);
+        while matches!(self.wraps.front(), Some(&at) if at + self.wrap_window < self.instructions) {
+            self.wraps.pop_front();
+        }
+
+        // the log may have been cleared since the last check
+        let rom_writes = cpu.rom_writes();
+        self.rom_writes = self.rom_writes.min(rom_writes.len());
+        if let Some(write) = rom_writes.get(self.rom_writes) {
+            self.rom_writes += 1;
+            return Some(Diagnostic::RomWrite { pc, addr: write.addr, value: write.value });
+        }
+
+        if (CPU::STACK_ADDR_MIN..=CPU::STACK_ADDR_MAX).contains(&pc) {
+            return Some(Diagnostic::StackExecution { pc });
+        }
+
+        if reg.get_interrupt() {
+            let to_self = match cpu.read(pc) {
+                JMP_ABSOLUTE => cpu.read(pc.wrapping_add(1)) as u16 | (cpu.read(pc.wrapping_add(2)) as u16) << 8 == pc,
+                // conditional branches only, whose flags can't change while looping
+                code if code & 0x1F == 0x10 => cpu.read(pc.wrapping_add(1)) == BRANCH_TO_SELF,
+                _ => false,
+            };
+            if to_self {
+                return Some(Diagnostic::JumpToSelf { pc });
+            }
+        }
+
+        if self.wraps.len() >= self.wrap_limit {
+            return Some(Diagnostic::StackWrapStorm { pc, wraps: self.wraps.len() });
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(cpu: &mut CPU, steps: usize) -> Option<Diagnostic> {
+        let mut watchdog = Watchdog::new();
+        for _ in 0..steps {
+            if let Some(diagnostic) = watchdog.check(cpu) {
+                return Some(diagnostic);
+            }
+            let code = cpu.read(cpu.registers().pc);
+            cpu.step(code);
+        }
+        None
+    }
+
+    #[test]
+    fn test_jump_to_self() {
+        // SEI, JMP $0601
+        let mut cpu = CPU::new();
+        cpu.load(0x0600, &[0x78, 0x4C, 0x01, 0x06]);
+        cpu.registers_mut().pc = 0x0600;
+        assert_eq!(watch(&mut cpu, 10), Some(Diagnostic::JumpToSelf { pc: 0x0601 }));
+
+        // with interrupts enabled it's just waiting for one
+        let mut cpu = CPU::new();
+        cpu.load(0x0600, &[0x58, 0x4C, 0x01, 0x06]);
+        cpu.registers_mut().pc = 0x0600;
+        assert_eq!(watch(&mut cpu, 10), None);
+    }
+
+    #[test]
+    fn test_stack_execution() {
+        // RTS through a stack that was never pushed to returns into page one
+        let mut cpu = CPU::new();
+        cpu.load(0x0600, &[0x60]);
+        cpu.load(0x01FE, &[0x10, 0x01]);
+        cpu.registers_mut().pc = 0x0600;
+        cpu.registers_mut().sp = 0xFD;
+        assert_eq!(watch(&mut cpu, 10), Some(Diagnostic::StackExecution { pc: 0x0111 }));
+    }
+
+    #[test]
+    fn test_rom_write() {
+        // STA $8010 from a program in PRG ROM
+        let mut cpu = CPU::new();
+        cpu.load_program(&[0xA9, 0x42, 0x8D, 0x10, 0x80, 0xEA]);
+        cpu.interrupt_reset();
+        cpu.set_strict_rom(true);
+        assert_eq!(watch(&mut cpu, 10), Some(Diagnostic::RomWrite { pc: 0x8005, addr: 0x8010, value: 0x42 }));
+        assert_eq!(cpu.read(0x8010), 0);
+    }
+
+    #[test]
+    fn test_stack_wrap_storm() {
+        // loop: PHA, JMP loop
+        let mut cpu = CPU::new();
+        cpu.load(0x0600, &[0x48, 0x4C, 0x00, 0x06]);
+        cpu.registers_mut().pc = 0x0600;
+        assert!(matches!(watch(&mut cpu, 10_000), Some(Diagnostic::StackWrapStorm { wraps: 4, .. })));
+    }
+}
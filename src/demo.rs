@@ -0,0 +1,168 @@
+//! Built-in demo programs and the easy6502 sandbox machine they run on.
+//!
+//! The sandbox is the environment from the easy6502 tutorial: a 32x32 screen
+//! of palette indices at 0x0200-0x05FF, a random byte at 0xFE and the ASCII
+//! code of the last key pressed at 0xFF. Programs from the tutorial run on it
+//! unmodified when loaded at 0x0600.
+
+use std::ops::Range;
+
+use crate::cpu::prog;
+#[cfg(feature = "assembler")]
+use crate::cpu::prog::asm;
+use crate::input::Button;
+use crate::CPU;
+
+/// The easy6502 machine: memory mapped screen, random number and keyboard
+pub struct Sandbox;
+
+impl Sandbox {
+    /// Where programs are loaded and started
+    pub const LOAD_ADDR: u16 = 0x0600;
+    /// One byte per pixel, rows left to right, top to bottom
+    pub const SCREEN_RANGE: Range<u16> = 0x0200..0x0600;
+    pub const SCREEN_WIDTH: usize = 32;
+    pub const SCREEN_HEIGHT: usize = 32;
+    /// Written with a new random byte before each instruction
+    pub const RANDOM_ADDR: u16 = 0xFE;
+    /// ASCII code of the last key pressed
+    pub const LAST_KEY_ADDR: u16 = 0xFF;
+
+    pub const KEY_UP: u8 = b'w';
+    pub const KEY_DOWN: u8 = b's';
+    pub const KEY_LEFT: u8 = b'a';
+    pub const KEY_RIGHT: u8 = b'd';
+
+    /// easy6502's 16 colours, the C64 palette
+    const PALETTE: [[u8; 3]; 16] = [
+        [0x00, 0x00, 0x00],
+        [0xFF, 0xFF, 0xFF],
+        [0x88, 0x00, 0x00],
+        [0xAA, 0xFF, 0xEE],
+        [0xCC, 0x44, 0xCC],
+        [0x00, 0xCC, 0x55],
+        [0x00, 0x00, 0xAA],
+        [0xEE, 0xEE, 0x77],
+        [0xDD, 0x88, 0x55],
+        [0x66, 0x44, 0x00],
+        [0xFF, 0x77, 0x77],
+        [0x33, 0x33, 0x33],
+        [0x77, 0x77, 0x77],
+        [0xAA, 0xFF, 0x66],
+        [0x00, 0x88, 0xFF],
+        [0xBB, 0xBB, 0xBB],
+    ];
+
+    /// Loads a program at `LOAD_ADDR` and resets into it
+    pub fn load(cpu: &mut CPU, program: &[u8]) {
+        cpu.load_for_snake(program);
+        cpu.interrupt_reset();
+    }
+
+    /// Sets the random byte, and the last key if one was pressed. Call before each instruction.
+    pub fn update(cpu: &mut CPU, random: u8, key: Option<u8>) {
+        cpu.load(Sandbox::RANDOM_ADDR, &[random]);
+        if let Some(key) = key {
+            cpu.load(Sandbox::LAST_KEY_ADDR, &[key]);
+        }
+    }
+
+    /// The WASD key code for a direction on the controller
+    pub fn key_for(button: Button) -> Option<u8> {
+        match button {
+            Button::Up => Some(Sandbox::KEY_UP),
+            Button::Down => Some(Sandbox::KEY_DOWN),
+            Button::Left => Some(Sandbox::KEY_LEFT),
+            Button::Right => Some(Sandbox::KEY_RIGHT),
+            _ => None,
+        }
+    }
+
+    /// RGB for a screen byte, of which only the low 4 bits count
+    pub fn colour(index: u8) -> [u8; 3] {
+        Sandbox::PALETTE[index as usize & 0x0F]
+    }
+
+    /// The whole screen as RGB24
+    pub fn screen_rgb(cpu: &CPU) -> Vec<u8> {
+        Sandbox::SCREEN_RANGE.flat_map(|addr| Sandbox::colour(cpu.read(addr))).collect()
+    }
+}
+
+/// The snake game from the easy6502 tutorial
+pub struct SnakeDemo;
+
+impl SnakeDemo {
+    pub fn program() -> &'static [u8] {
+        prog::SNAKE_BYTES
+    }
+}
+
+/// Vertical colour bars across the whole screen, assembled from source at startup
+#[cfg(feature = "assembler")]
+pub struct ColorBarsDemo;
+
+#[cfg(feature = "assembler")]
+impl ColorBarsDemo {
+    pub const LOAD_ADDR: u16 = Sandbox::LOAD_ADDR;
+
+    pub const SOURCE: &'static str = "
+        .org $0600
+        ; each page of the screen is 8 rows, so the same colour for a column
+        ; can be stored into all four pages at once
+                LDX #$00
+        fill:   TXA
+                AND #$1F
+                LSR
+                STA $0200,X
+                STA $0300,X
+                STA $0400,X
+                STA $0500,X
+                INX
+                BNE fill
+                BRK
+    ";
+
+    pub fn program() -> Vec<u8> {
+        asm::assemble(ColorBarsDemo::SOURCE)
+            .expect("ERROR: built-in colour bars demo failed to assemble")
+            .bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "assembler")]
+    use crate::cpu::StopReason;
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn test_color_bars() {
+        let mut cpu = CPU::new();
+        Sandbox::load(&mut cpu, &ColorBarsDemo::program());
+        assert_eq!(cpu.run(), StopReason::Break);
+
+        for addr in Sandbox::SCREEN_RANGE {
+            let column = (addr - Sandbox::SCREEN_RANGE.start) as usize % Sandbox::SCREEN_WIDTH;
+            assert_eq!(cpu.read(addr) as usize, column / 2, "pixel at {:#06x}", addr);
+        }
+
+        let screen = Sandbox::screen_rgb(&cpu);
+        assert_eq!(screen.len(), Sandbox::SCREEN_WIDTH * Sandbox::SCREEN_HEIGHT * 3);
+        // columns 2 and 3 are colour 1, white
+        assert_eq!(&screen[6..12], &[0xFF; 6]);
+    }
+
+    #[test]
+    fn test_sandbox_io() {
+        assert_eq!(Sandbox::key_for(Button::Left), Some(0x61));
+        assert_eq!(Sandbox::key_for(Button::Start), None);
+        assert_eq!(Sandbox::colour(0x12), Sandbox::colour(0x02));
+
+        let mut cpu = CPU::new();
+        Sandbox::update(&mut cpu, 7, Some(Sandbox::KEY_UP));
+        Sandbox::update(&mut cpu, 9, None);
+        assert_eq!((cpu.read(Sandbox::RANDOM_ADDR), cpu.read(Sandbox::LAST_KEY_ADDR)), (9, b'w'));
+    }
+}
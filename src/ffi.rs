@@ -0,0 +1,179 @@
+//! C interface for embedding the emulator in non-Rust frontends.
+//!
+//! The matching header lives in `include/nes_rs.h` and can be regenerated with
+//! `cbindgen --config cbindgen.toml --output include/nes_rs.h`.
+//!
+//! The crate builds as an rlib, so a shared library for C has to be asked for:
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+//!
+//! Every function taking a `*mut CPU` expects a handle returned by
+//! [`nes_create`] that has not yet been passed to [`nes_destroy`].
+
+use crate::cpu::StopReason;
+use crate::CPU;
+
+/// Result of [`nes_step`]: the instruction was executed
+pub const NES_STEP_OK: i32 = 0;
+/// Result of [`nes_step`]: the CPU is sitting on a BRK and did not advance
+pub const NES_STEP_BREAK: i32 = 1;
+/// Result of [`nes_step`]: the CPU hit a JAM opcode and is locked up until reset
+pub const NES_STEP_HALTED: i32 = 2;
+/// Result of any call given a null handle or buffer
+pub const NES_ERR_NULL: i32 = -1;
+/// Result of [`nes_load_program`]: the program would run into the vectors at $FFFA
+pub const NES_ERR_TOO_LONG: i32 = -2;
+
+/// Allocates a new CPU, to be freed with [`nes_destroy`]
+#[no_mangle]
+pub extern "C" fn nes_create() -> *mut CPU {
+    Box::into_raw(Box::new(CPU::new()))
+}
+
+/// Frees a CPU created by [`nes_create`]. Null is ignored.
+///
+/// # Safety
+/// `cpu` must be null or a live handle from [`nes_create`].
+#[no_mangle]
+pub unsafe extern "C" fn nes_destroy(cpu: *mut CPU) {
+    if !cpu.is_null() {
+        drop(Box::from_raw(cpu));
+    }
+}
+
+/// Loads `len` bytes of program code into PRG ROM and points the reset vector at it.
+/// Nothing is loaded if it's longer than `CPU::MAX_PROGRAM_LEN`.
+///
+/// # Safety
+/// `cpu` must be a live handle and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nes_load_program(cpu: *mut CPU, data: *const u8, len: usize) -> i32 {
+    match (cpu.as_mut(), data.is_null()) {
+        (Some(_), false) if len > CPU::MAX_PROGRAM_LEN => NES_ERR_TOO_LONG,
+        (Some(cpu), false) => {
+            cpu.load_program(std::slice::from_raw_parts(data, len));
+            0
+        }
+        _ => NES_ERR_NULL,
+    }
+}
+
+/// Resets the CPU, jumping to the reset vector
+///
+/// # Safety
+/// `cpu` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn nes_reset(cpu: *mut CPU) -> i32 {
+    match cpu.as_mut() {
+        Some(cpu) => {
+            cpu.interrupt_reset();
+            0
+        }
+        None => NES_ERR_NULL,
+    }
+}
+
+/// Executes a single instruction
+///
+/// # Safety
+/// `cpu` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn nes_step(cpu: *mut CPU) -> i32 {
+    match cpu.as_mut() {
+        Some(cpu) => match cpu.step_next() {
+            None => NES_STEP_OK,
+            Some(StopReason::Break) => NES_STEP_BREAK,
+            Some(StopReason::Halted { .. }) => NES_STEP_HALTED,
+        },
+        None => NES_ERR_NULL,
+    }
+}
+
+/// Reads a byte of CPU memory, returning 0 for a null handle
+///
+/// # Safety
+/// `cpu` must be null or a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn nes_read(cpu: *const CPU, addr: u16) -> u8 {
+    cpu.as_ref().map_or(0, |cpu| cpu.read(addr))
+}
+
+/// Writes a byte of CPU memory
+///
+/// # Safety
+/// `cpu` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn nes_write(cpu: *mut CPU, addr: u16, value: u8) -> i32 {
+    match cpu.as_mut() {
+        Some(cpu) => {
+            cpu.load(addr, &[value]);
+            0
+        }
+        None => NES_ERR_NULL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffi_program_round_trip() {
+        unsafe {
+            let cpu = nes_create();
+            // LDA #C0, TAX, INX, BRK
+            let code = [0xA9, 0xC0, 0xAA, 0xE8, 0x00];
+            assert_eq!(nes_load_program(cpu, code.as_ptr(), code.len()), 0);
+            assert_eq!(nes_reset(cpu), 0);
+
+            while nes_step(cpu) == NES_STEP_OK {}
+            assert_eq!((*cpu).registers().x, 0xC1);
+
+            assert_eq!(nes_write(cpu, 0x10, 0x42), 0);
+            assert_eq!(nes_read(cpu, 0x10), 0x42);
+
+            nes_destroy(cpu);
+        }
+    }
+
+    #[test]
+    fn test_ffi_step_halted() {
+        unsafe {
+            let cpu = nes_create();
+            let code = [0xEA, 0x02];
+            nes_load_program(cpu, code.as_ptr(), code.len());
+            nes_reset(cpu);
+
+            assert_eq!(nes_step(cpu), NES_STEP_OK);
+            assert_eq!(nes_step(cpu), NES_STEP_HALTED);
+            assert_eq!(nes_step(cpu), NES_STEP_HALTED);
+
+            nes_destroy(cpu);
+        }
+    }
+
+    #[test]
+    fn test_ffi_null_handles() {
+        unsafe {
+            assert_eq!(nes_step(std::ptr::null_mut()), NES_ERR_NULL);
+            assert_eq!(nes_load_program(std::ptr::null_mut(), std::ptr::null(), 0), NES_ERR_NULL);
+            assert_eq!(nes_read(std::ptr::null(), 0), 0);
+            nes_destroy(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_ffi_program_too_long() {
+        unsafe {
+            let cpu = nes_create();
+            let code = vec![0xEA; 0x10000];
+            assert_eq!(nes_load_program(cpu, code.as_ptr(), code.len()), NES_ERR_TOO_LONG);
+            assert_eq!(nes_load_program(cpu, code.as_ptr(), CPU::MAX_PROGRAM_LEN + 1), NES_ERR_TOO_LONG);
+            assert_eq!(nes_read(cpu, 0x8000), 0x00);
+
+            assert_eq!(nes_load_program(cpu, code.as_ptr(), CPU::MAX_PROGRAM_LEN), 0);
+            assert_eq!((nes_read(cpu, 0xFFF9), nes_read(cpu, 0xFFFC), nes_read(cpu, 0xFFFD)), (0xEA, 0x00, 0x80));
+
+            nes_destroy(cpu);
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! Controller input, independent of where the events come from.
+//!
+//! Frontends (SDL, the terminal UI, scripts) translate their own key events
+//! into `InputEvent`s and apply them to an `InputState`. Calling `end_frame`
+//! once per frame gives press and release edges, so held keys and OS key
+//! repeat don't register as fresh presses.
+//!
+//! `ControllerPorts` puts the buttons on the bus at $4016/$4017 the way the
+//! hardware shifts them out, with or without a Four Score adapter.
+
+pub mod keyboard;
+
+use std::cell::Cell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use crate::memory::{Field, Peripheral, Register};
+
+/// Standard controller buttons, in the order the controller shifts them out
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+    ];
+
+    /// Bit for the button in the controller's report byte
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InputEvent {
+    Press(Button),
+    Release(Button),
+}
+
+/// Buttons held on one controller, with the previous frame kept for edge detection
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct InputState {
+    held: u8,
+    previous: u8,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        InputState::default()
+    }
+
+    pub fn apply(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Press(button) => self.held |= button.mask(),
+            InputEvent::Release(button) => self.held &= !button.mask(),
+        }
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held & button.mask() != 0
+    }
+
+    /// Held now but not at the end of the last frame
+    pub fn just_pressed(&self, button: Button) -> bool {
+        self.is_held(button) && self.previous & button.mask() == 0
+    }
+
+    /// Held at the end of the last frame but not now
+    pub fn just_released(&self, button: Button) -> bool {
+        !self.is_held(button) && self.previous & button.mask() != 0
+    }
+
+    /// Buttons pressed since the last frame
+    pub fn pressed(&self) -> impl Iterator<Item = Button> + '_ {
+        Button::ALL.into_iter().filter(|&button| self.just_pressed(button))
+    }
+
+    /// Latches the current buttons as the previous frame's
+    pub fn end_frame(&mut self) {
+        self.previous = self.held;
+    }
+
+    /// All held buttons as the controller reports them, A in bit 0
+    pub fn bits(&self) -> u8 {
+        self.held
+    }
+}
+
+/// Addresses of the two controller ports. Writes to $4016 strobe every controller.
+pub const CONTROLLER_PORTS: RangeInclusive<u16> = 0x4016..=0x4017;
+
+/// Button bit read from either port
+const DATA: Field = Field::new("data", 0, 1, "next bit of the controller's report");
+/// Written to $4016
+const STROBE: Field = Field::new("strobe", 0, 1, "reload every controller's report while set");
+
+/// Bits 17-24 of each port identify a Four Score, one bit in a different place per port
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0b0000_1000, 0b0000_0100];
+
+/// Polls a second that turbo rates are measured against. Games read the
+/// controllers once a frame, so counting polls keeps turbo in step with the
+/// game, and the same in every frontend and movie, without a clock.
+const POLLS_PER_SECOND: u32 = 60;
+
+/// Autofire for some buttons of one controller, applied as the shift register latches
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Turbo {
+    buttons: u8,
+    pulses_per_second: u32,
+}
+
+impl Turbo {
+    /// A press and release every poll is as fast as the game can see
+    pub const MAX_RATE: u32 = POLLS_PER_SECOND / 2;
+
+    pub fn new(pulses_per_second: u32) -> Self {
+        Turbo { buttons: 0, pulses_per_second: pulses_per_second.clamp(1, Turbo::MAX_RATE) }
+    }
+
+    pub fn with_button(mut self, button: Button) -> Self {
+        self.buttons |= button.mask();
+        self
+    }
+
+    pub fn is_enabled(&self, button: Button) -> bool {
+        self.buttons & button.mask() != 0
+    }
+
+    /// Releases held turbo buttons for the second half of each pulse
+    fn apply(&self, bits: u8, poll: u32) -> u8 {
+        let phase = poll as u64 * self.pulses_per_second as u64 * 2 / POLLS_PER_SECOND as u64;
+        match phase % 2 {
+            0 => bits,
+            _ => bits & !self.buttons,
+        }
+    }
+}
+
+/// The controller shift registers, readable by the program through `CONTROLLER_PORTS`
+pub struct ControllerPorts {
+    /// Report bytes of controllers 1-4, updated by the frontend
+    pads: Rc<Cell<[u8; 4]>>,
+    four_score: bool,
+    strobe: bool,
+    /// Bits not yet read from each port, next one in bit 0
+    shift: [u32; 2],
+    /// Device on the Famicom expansion port, sharing both registers
+    expansion: Option<Box<dyn Peripheral>>,
+    turbo: [Turbo; 4],
+    /// Times the strobe has been released, the clock for turbo
+    polls: u32,
+}
+
+impl ControllerPorts {
+    pub fn new(pads: Rc<Cell<[u8; 4]>>) -> Self {
+        ControllerPorts {
+            pads,
+            four_score: false,
+            strobe: false,
+            shift: [u32::MAX; 2],
+            expansion: None,
+            turbo: [Turbo::default(); 4],
+            polls: 0,
+        }
+    }
+
+    /// Adds a Four Score, so controllers 3 and 4 follow 1 and 2 on each port
+    pub fn with_four_score(mut self) -> Self {
+        self.four_score = true;
+        self
+    }
+
+    /// Connects a device such as `keyboard::FamilyKeyboard` to the expansion port.
+    /// It sees every write and its reads are combined with the controller bit.
+    pub fn with_expansion(mut self, device: Box<dyn Peripheral>) -> Self {
+        self.expansion = Some(device);
+        self
+    }
+
+    /// Sets turbo for controller `pad`, 0-3
+    pub fn with_turbo(mut self, pad: usize, turbo: Turbo) -> Self {
+        self.turbo[pad] = turbo;
+        self
+    }
+
+    fn latch(&mut self) {
+        let mut pads = self.pads.get();
+        for (bits, turbo) in pads.iter_mut().zip(&self.turbo) {
+            *bits = turbo.apply(*bits, self.polls);
+        }
+        for port in 0..2 {
+            // official controllers shift out 1s once their 8 buttons are read
+            self.shift[port] = match self.four_score {
+                true => u32::from_le_bytes([pads[port], pads[port + 2], FOUR_SCORE_SIGNATURES[port], 0xFF]),
+                false => u32::from_le_bytes([pads[port], 0xFF, 0xFF, 0xFF]),
+            };
+        }
+    }
+}
+
+impl Peripheral for ControllerPorts {
+    fn read(&mut self, offset: u16) -> u8 {
+        let port = offset as usize;
+        let expansion = self.expansion.as_mut().map_or(0, |device| device.read(offset));
+        if self.strobe {
+            self.latch();
+            return self.shift[port] as u8 & DATA.mask() | expansion;
+        }
+        let bit = self.shift[port] as u8 & DATA.mask();
+        self.shift[port] = self.shift[port] >> 1 | 1 << 31;
+        bit | expansion
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        if let Some(device) = &mut self.expansion {
+            device.write(offset, val);
+        }
+        // $4017 writes go to the APU frame counter
+        if offset != 0 {
+            return;
+        }
+        // the registers reload for as long as the strobe is high, so the
+        // buttons at the falling edge are the ones read out
+        let strobe = STROBE.get(val) != 0;
+        if self.strobe || strobe {
+            self.latch();
+        }
+        if self.strobe && !strobe {
+            self.polls = self.polls.wrapping_add(1);
+        }
+        self.strobe = strobe;
+    }
+
+    fn tick(&mut self) {
+        if let Some(device) = &mut self.expansion {
+            device.tick();
+        }
+    }
+
+    /// The expansion device's fields are added to the registers it shares
+    fn registers(&self) -> Vec<Register> {
+        let mut registers = vec![
+            Register {
+                offset: 0,
+                name: "JOY1",
+                description: "controller port 1, which strobes every controller",
+                read: vec![DATA],
+                write: vec![STROBE],
+            },
+            // writes go to the APU frame counter, which isn't emulated
+            Register { offset: 1, name: "JOY2", description: "controller port 2", read: vec![DATA], write: Vec::new() },
+        ];
+        let expansion = self.expansion.as_ref().map_or(Vec::new(), |device| device.registers());
+        for shared in expansion {
+            if let Some(register) = registers.iter_mut().find(|register| register.offset == shared.offset) {
+                register.read.extend(shared.read);
+                register.write.extend(shared.write);
+            }
+        }
+        registers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_press_release_edges() {
+        let mut input = InputState::new();
+        input.apply(InputEvent::Press(Button::Up));
+        assert!(input.is_held(Button::Up));
+        assert!(input.just_pressed(Button::Up));
+        assert_eq!(input.pressed().collect::<Vec<_>>(), vec![Button::Up]);
+
+        // a repeated press while held is not a new edge
+        input.end_frame();
+        input.apply(InputEvent::Press(Button::Up));
+        assert!(input.is_held(Button::Up));
+        assert!(!input.just_pressed(Button::Up));
+
+        input.apply(InputEvent::Release(Button::Up));
+        assert!(input.just_released(Button::Up));
+        input.end_frame();
+        assert!(!input.just_released(Button::Up));
+    }
+
+    #[test]
+    fn test_bits_in_controller_order() {
+        let mut input = InputState::new();
+        input.apply(InputEvent::Press(Button::A));
+        input.apply(InputEvent::Press(Button::Start));
+        input.apply(InputEvent::Press(Button::Right));
+        assert_eq!(input.bits(), 0b1000_1001);
+    }
+
+    fn read_port(ports: &mut ControllerPorts, offset: u16, count: usize) -> Vec<u8> {
+        (0..count).map(|_| ports.read(offset)).collect()
+    }
+
+    #[test]
+    fn test_controller_ports() {
+        let pads = Rc::new(Cell::new([0b1000_1001, 0b0000_0010, 0, 0]));
+        let mut ports = ControllerPorts::new(pads.clone());
+
+        ports.write(0, 1);
+        // while strobed every read is button A
+        assert_eq!(read_port(&mut ports, 0, 3), vec![1, 1, 1]);
+        ports.write(0, 0);
+        pads.set([0; 4]);
+        assert_eq!(read_port(&mut ports, 0, 10), vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        assert_eq!(read_port(&mut ports, 1, 9), vec![0, 1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_four_score() {
+        let pads = Rc::new(Cell::new([Button::A.mask(), Button::B.mask(), Button::Start.mask(), Button::Right.mask()]));
+        let mut ports = ControllerPorts::new(pads).with_four_score();
+        ports.write(0, 1);
+        ports.write(0, 0);
+
+        let port1 = read_port(&mut ports, 0, 25);
+        let port2 = read_port(&mut ports, 1, 25);
+        let set_bits = |bits: &[u8]| bits.iter().enumerate().filter(|(_, &bit)| bit == 1).map(|(i, _)| i + 1).collect::<Vec<_>>();
+        // controller 1 A, controller 3 Start, the signature on the 20th read, then 1s
+        assert_eq!(set_bits(&port1), vec![1, 12, 20, 25]);
+        // controller 2 B, controller 4 Right, the signature on the 19th read, then 1s
+        assert_eq!(set_bits(&port2), vec![2, 16, 19, 25]);
+    }
+
+    #[test]
+    fn test_turbo() {
+        let pads = Rc::new(Cell::new([Button::A.mask() | Button::B.mask(), 0, 0, 0]));
+        let turbo = Turbo::new(15).with_button(Button::A);
+        let mut ports = ControllerPorts::new(pads).with_turbo(0, turbo);
+        assert!(turbo.is_enabled(Button::A) && !turbo.is_enabled(Button::B));
+
+        let mut polls = Vec::new();
+        for _ in 0..8 {
+            ports.write(0, 1);
+            ports.write(0, 0);
+            polls.push(read_port(&mut ports, 0, 2));
+        }
+        // 15 pulses a second at 60 polls: A held for 2 polls, released for 2, B always held
+        let (on, off) = (vec![1, 1], vec![0, 1]);
+        assert_eq!(polls, vec![on.clone(), on.clone(), off.clone(), off.clone(), on.clone(), on, off.clone(), off]);
+
+        assert_eq!(Turbo::new(1000), Turbo::new(Turbo::MAX_RATE));
+    }
+
+    #[test]
+    fn test_expansion_port() {
+        use keyboard::{FamilyKeyboard, Key, KeyboardState};
+
+        let keys = Rc::new(Cell::new(KeyboardState::new()));
+        let pads = Rc::new(Cell::new([Button::A.mask(), 0, 0, 0]));
+        let mut ports = ControllerPorts::new(pads).with_expansion(Box::new(FamilyKeyboard::new(keys.clone())));
+        let mut state = KeyboardState::new();
+        state.press(Key::RightBracket);
+        keys.set(state);
+
+        // strobes the controllers and resets the keyboard to row 0 in one write
+        ports.write(0, 0b101);
+        ports.write(0, 0b100);
+        assert_eq!(ports.read(0), 1);
+        assert_eq!(ports.read(1), 0b0001_1100);
+
+        let registers = ports.registers();
+        let names = |fields: &[Field]| fields.iter().map(|field| field.name).collect::<Vec<_>>();
+        assert_eq!((registers[0].name, names(&registers[0].write)), ("JOY1", vec!["strobe", "reset", "column", "enable"]));
+        assert_eq!((registers[1].name, names(&registers[1].read)), ("JOY2", vec!["data", "keys"]));
+    }
+}
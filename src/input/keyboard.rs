@@ -0,0 +1,154 @@
+//! Family BASIC keyboard, read through the Famicom expansion port.
+//!
+//! The program writes $4016 to pick one of 9 rows and 2 columns of the key
+//! matrix, then reads 4 keys from bits 1-4 of $4017, low when pressed.
+//! Writing bit 0 resets to row 0, bit 1 selects the column, and the row
+//! advances when the column goes from 1 back to 0. Bit 2 enables the keyboard.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::memory::{Field, Peripheral, Register};
+
+const ROWS: usize = 9;
+
+/// Written to $4016
+const RESET: Field = Field::new("reset", 0, 1, "keyboard: back to row 0");
+const COLUMN: Field = Field::new("column", 1, 1, "keyboard: column to read, the row advances going from 1 to 0");
+const ENABLE: Field = Field::new("enable", 2, 1, "keyboard: on");
+/// Read from $4017, low when pressed
+const KEYS: Field = Field::new("keys", 1, 4, "keyboard: 4 keys of the row and column, clear when held");
+
+/// Keys in matrix order: 8 per row, column 0 then 1, $4017 bit 1 first
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[repr(u8)]
+pub enum Key {
+    RightBracket, LeftBracket, Return, F8, Stop, Yen, RightShift, Kana,
+    Semicolon, Colon, At, F7, Caret, Minus, Slash, Underscore,
+    K, L, O, F6, Num0, P, Comma, Period,
+    J, U, I, F5, Num8, Num9, N, M,
+    H, G, Y, F4, Num6, Num7, V, B,
+    D, R, T, F3, Num4, Num5, C, F,
+    A, S, W, F2, Num3, E, Z, X,
+    Ctr, Q, Escape, F1, Num2, Num1, Grph, LeftShift,
+    Left, Right, Up, ClrHome, Insert, Delete, Space, Down,
+}
+
+impl Key {
+    fn mask(self) -> u128 {
+        1 << self as u8
+    }
+}
+
+/// Keys held on the keyboard, fed by the frontend from host key events
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct KeyboardState {
+    held: u128,
+}
+
+impl KeyboardState {
+    pub fn new() -> Self {
+        KeyboardState::default()
+    }
+
+    pub fn press(&mut self, key: Key) {
+        self.held |= key.mask();
+    }
+
+    pub fn release(&mut self, key: Key) {
+        self.held &= !key.mask();
+    }
+
+    pub fn is_held(&self, key: Key) -> bool {
+        self.held & key.mask() != 0
+    }
+
+    /// The 4 keys of one row and column, bit 0 for the first, set when held
+    fn matrix_bits(&self, row: usize, column: usize) -> u8 {
+        (self.held >> (row * 8 + column * 4)) as u8 & 0x0F
+    }
+}
+
+/// The keyboard as an expansion port device, see `ControllerPorts::with_expansion`
+pub struct FamilyKeyboard {
+    keys: Rc<Cell<KeyboardState>>,
+    enabled: bool,
+    row: usize,
+    column: usize,
+}
+
+impl FamilyKeyboard {
+    pub fn new(keys: Rc<Cell<KeyboardState>>) -> Self {
+        FamilyKeyboard { keys, enabled: false, row: 0, column: 0 }
+    }
+}
+
+impl Peripheral for FamilyKeyboard {
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset != 1 || !self.enabled {
+            return 0;
+        }
+        // past the last row nothing is pressed
+        let held = match self.row < ROWS {
+            true => self.keys.get().matrix_bits(self.row, self.column),
+            false => 0,
+        };
+        !held << KEYS.shift & KEYS.mask()
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        if offset != 0 {
+            return;
+        }
+        self.enabled = ENABLE.get(val) != 0;
+        let column = COLUMN.get(val) as usize;
+        if RESET.get(val) != 0 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 {
+            self.row = (self.row + 1).min(ROWS);
+        }
+        self.column = column;
+    }
+
+    fn registers(&self) -> Vec<Register> {
+        vec![
+            Register { offset: 0, name: "KBDOUT", description: "keyboard row and column", read: Vec::new(), write: vec![RESET, COLUMN, ENABLE] },
+            Register { offset: 1, name: "KBDIN", description: "keyboard keys", read: vec![KEYS], write: Vec::new() },
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_matrix() {
+        let keys = Rc::new(Cell::new(KeyboardState::new()));
+        let mut keyboard = FamilyKeyboard::new(keys.clone());
+        let mut state = KeyboardState::new();
+        state.press(Key::Return);
+        state.press(Key::M);
+        state.press(Key::Space);
+        keys.set(state);
+
+        // a full scan the way Family BASIC does it: reset, then both columns of each row
+        keyboard.write(0, 0b101);
+        let mut scan = Vec::new();
+        for _ in 0..ROWS + 1 {
+            keyboard.write(0, 0b100);
+            scan.push(keyboard.read(1));
+            keyboard.write(0, 0b110);
+            scan.push(keyboard.read(1));
+        }
+
+        let mut expected = vec![0b0001_1110; 2 * (ROWS + 1)];
+        expected[0] = 0b0001_0110;
+        expected[7] = 0b0000_1110;
+        expected[17] = 0b0001_0110;
+        assert_eq!(scan, expected);
+
+        keyboard.write(0, 0);
+        assert_eq!(keyboard.read(1), 0);
+    }
+}
@@ -1,4 +1,56 @@
+//! A NES emulator, so far a 6502 CPU core with cartridge loading, input,
+//! save states and tooling around them.
+//!
+//! # Features and stability
+//!
+//! The CPU core, `cart`, `memory`, `input`, `savestate` and the `prelude`
+//! are always built and follow semver. Everything behind a feature is an
+//! experimental subsystem, whose API can change in any minor release. A
+//! subsystem becomes stable by losing its feature and joining the list above.
+//!
+//! | feature     | default | what it adds                                                                    |
+//! |-------------|---------|---------------------------------------------------------------------------------|
+//! | `assembler` | yes     | `cpu::prog::asm`, `cpu::cycles`, `cpu::testgen` and `cpu::single_step`, via nom |
+//! | `debugger`  | yes     | `cpu::debugger` and `cpu::crash`, implies `assembler`                           |
+//! | `scripting` | no      | Rhai scripts driving the CPU, in `script`                                       |
+//! | `ffi`       | no      | the C API in `ffi`                                                              |
+//! | `heatmap`   | no      | per-address access counts in `memory::heatmap`                                  |
+//! | `jit`       | no      | translating hot loops to native code with Cranelift, in `cpu::jit`              |
+//! | `frontend`  | yes     | the SDL `nes-rs` binary, nothing in the library                                 |
+//!
+//! Embedders who only want the core can use `default-features = false`,
+//! which also leaves out SDL, so it doesn't need to be installed. The
+//! example below is built by every `cargo test`, with or without features,
+//! so the stable API can't quietly end up behind one:
+//!
+//! ```
+//! use nes_rs::cart::{Cartridge, HeaderlessOptions};
+//! use nes_rs::memory::MemoryInit;
+//! use nes_rs::savestate::SaveState;
+//! use nes_rs::cpu::{StopReason, CPU};
+//!
+//! let mut prg = vec![0xEA; 0x4000];
+//! prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+//! prg[0] = 0x02;
+//! let cart = Cartridge::headerless(&prg, &HeaderlessOptions::default()).unwrap();
+//! let mut cpu = CPU::builder().with_memory_init(MemoryInit::FF).with_cartridge(cart).build().unwrap();
+//! assert_eq!(cpu.run(), StopReason::Halted { opcode: 0x02, pc: 0x8000 });
+//! assert!(SaveState::from_bytes(&cpu.save_state().to_bytes()).is_ok());
+//! ```
+
+pub mod accuracy;
+pub mod cart;
 pub mod cpu;
+pub mod demo;
+pub mod input;
 pub mod memory;
+pub mod patch;
+mod png;
+pub mod prelude;
+pub mod savestate;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "scripting")]
+pub mod script;
 
 pub use cpu::CPU;
@@ -1,21 +1,49 @@
-use nes_rs::{cpu::prog, CPU};
+use nes_rs::input::{Button, InputEvent, InputState};
+use nes_rs::accuracy::Scorecard;
+use nes_rs::cart;
+use nes_rs::memory;
+use nes_rs::cpu::prog::{asm, flow};
+use nes_rs::demo::{Sandbox, SnakeDemo};
+use nes_rs::savestate::{SaveSlots, SaveState, Thumbnail};
+use nes_rs::cpu::crash::{CrashReport, Recorder};
+use nes_rs::cpu::determinism::{Audit, FRAME_INSTRUCTIONS};
+use nes_rs::cpu::StopReason;
+use nes_rs::CPU;
 
 use sdl2::event::Event;
 use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::PixelFormatEnum;
 
 use rand::Rng;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("accuracy") => return accuracy(&args[1..]),
+        Some("info" | "disasm" | "hexdump" | "diff") => {
+            let (headerless, args) = cart::HeaderlessOptions::from_args(&args)?;
+            return inspect(&args, &headerless);
+        }
+        Some("audit") => {
+            let (headerless, args) = cart::HeaderlessOptions::from_args(&args[1..])?;
+            return audit(&args, &headerless);
+        }
+        _ => {}
+    }
+    // `nes-rs sandbox PROGRAM` runs an easy6502 program, the snake game without one
+    let (title, program) = match args.as_slice() {
+        [command, path] if command == "sandbox" => (path.clone(), read_sandbox_program(path)?),
+        _ => ("Snake game".to_string(), SnakeDemo::program().to_vec()),
+    };
+
     let sdl_context = sdl2::init()?;
     let video_subsystem =
         sdl_context
             .video()?;
     let window =
         video_subsystem
-            .window("Snake game", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
+            .window(&title, (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
             .position_centered()
             .build()?;
 
@@ -40,17 +68,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             )?;
 
     let mut cpu = CPU::new();
-    cpu.load_for_snake(
-        prog::SNAKE_BYTES
-    );
-    cpu.interrupt_reset();
+    Sandbox::load(&mut cpu, &program);
 
     let mut screen_state = [0 as u8; 32 * 3 * 32];
     let mut rng = rand::thread_rng();
-    
-    cpu.run_with_callback(move |cpu| {
-        handle_user_input(cpu, &mut event_pump);
-        cpu.load(0xfe, &[rng.gen_range(1..16)]);
+    let mut input = InputState::new();
+    let slots = SaveSlots::for_rom("saves", &program);
+    let mut slot = 0;
+    let mut recorder = Recorder::default();
+    let recording = &mut recorder;
+
+    let reason = cpu.run_with_callback(move |cpu| {
+        recording.record(cpu);
+        let mut typed = None;
+        for key in handle_user_input(&mut input, &mut typed, &mut event_pump) {
+            let result = match key {
+                SlotKey::Select(number) => {
+                    slot = number;
+                    Ok(())
+                }
+                SlotKey::Save => {
+                    let mut state = cpu.save_state();
+                    state.set_thumbnail(&Thumbnail { width: 32, height: 32, rgb: screen_state.to_vec() });
+                    slots.save(slot, &state)
+                }
+                SlotKey::Load => slots.load(slot).and_then(|state| cpu.load_state(&state)),
+            };
+            if let Err(error) = result {
+                eprintln!("slot {}: {}", slot, error);
+            }
+        }
+        // the arrow keys and controllers type WASD, as the tutorial's snake expects
+        let key = typed.or_else(|| input.pressed().find_map(Sandbox::key_for));
+        input.end_frame();
+        Sandbox::update(cpu, rng.gen::<u8>(), key);
 
         if read_screen_state(cpu, &mut screen_state) {
             texture.update(None, &screen_state, 32 * 3)?;
@@ -63,54 +114,203 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok(())
     });
 
+    if let StopReason::Halted { .. } = reason {
+        let report = CrashReport::capture(&cpu, CrashReport::describe_stop(reason), &recorder, Some(&program));
+        let path = report.write_to_dir("crashes")?;
+        eprintln!("{}, crash report written to {}", report.reason, path.display());
+    }
+    Ok(())
+}
+
+/// Assembles .s and .asm files, anything else is taken as machine code
+fn read_sandbox_program(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let path = std::path::Path::new(path);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("s" | "asm") => Ok(asm::Assembler::new().assemble_file(path)?.bytes),
+        _ => Ok(std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+    }
+}
+
+/// `nes-rs accuracy [--json] [DIR...]`: scores the generated CPU tests and
+/// every .nes test ROM in each DIR, one suite per directory
+fn accuracy(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut scorecard = Scorecard::new();
+    scorecard.run_generated();
+
+    for dir in args.iter().filter(|arg| *arg != "--json") {
+        let dir = std::path::Path::new(dir);
+        let suite = dir.file_name().map_or(dir.display().to_string(), |name| name.to_string_lossy().into_owned());
+        let mut roms: Vec<_> = std::fs::read_dir(dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")))
+            .collect();
+        roms.sort();
+        for rom in roms {
+            let name = rom.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            scorecard.run_rom(&suite, &name, &std::fs::read(&rom)?);
+        }
+    }
+
+    match args.iter().any(|arg| arg == "--json") {
+        true => println!("{}", scorecard.to_json()),
+        false => print!("{}", scorecard.to_markdown()),
+    }
     Ok(())
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+/// Flags describing a ROM dump without an iNES header, taken by `audit` and `disasm`
+const HEADERLESS_USAGE: &str = "[--mapper N] [--prg SIZE] [--chr SIZE] [--mirroring h|v|4]";
+
+/// `nes-rs audit [--frames N] ROM`: runs a ROM twice without input and
+/// reports the first difference between the runs
+fn audit(args: &[String], headerless: &cart::HeaderlessOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let (frames, path) = match args {
+        [path] => (600, path),
+        [flag, frames, path] if flag == "--frames" => (frames.parse().map_err(|_| format!("invalid frame count `{}`", frames))?, path),
+        _ => return Err(format!("usage: nes-rs audit [--frames N] {} ROM", HEADERLESS_USAGE).into()),
+    };
+    let image = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let cart = cart::Cartridge::load(&image, headerless)?;
+    // fail here rather than in the audit if the cart can't be installed
+    CPU::builder().with_cartridge(cart.clone()).build()?;
+
+    let mut audit = Audit::new(|init| {
+        let builder = CPU::builder().with_memory_init(init).with_cartridge(cart.clone());
+        builder.build().unwrap_or_else(|e| panic!("ERROR: {}", e))
+    });
+    match audit.run(frames, FRAME_INSTRUCTIONS, |_| {})? {
+        Some(reason) => println!("deterministic until the CPU stopped, {:?}", reason),
+        None => println!("deterministic for {} frames", frames),
+    }
+    Ok(())
+}
+
+/// Subcommands printing what's in a file:
+/// `nes-rs info ROM`, `nes-rs disasm [--format text|json] [--symbols FILE] ROM`, `nes-rs hexdump FILE`
+/// and `nes-rs diff BEFORE AFTER`, for two save states or two memory dumps.
+/// `disasm` also takes `HEADERLESS_USAGE` for dumps without a header.
+fn inspect(args: &[String], headerless: &cart::HeaderlessOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let read = |path: &String| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    match args {
+        [command, path] if command == "info" => print!("{}", cart::describe_rom(&read(path)?)?),
+        [command, path] if command == "hexdump" => println!("{}", memory::hexdump(&read(path)?, 0)),
+        [command, before, after] if command == "diff" => {
+            let (before, after) = (read(before)?, read(after)?);
+            match (SaveState::from_bytes(&before), SaveState::from_bytes(&after)) {
+                (Ok(before), Ok(after)) => println!("{}", before.diff_report(&after)),
+                _ => println!("{}", memory::diff_report(&before, &after, 0)),
+            }
+        }
+        [command, rest @ ..] if command == "disasm" => {
+            let usage = format!("usage: nes-rs disasm [--format text|json] [--symbols FILE] {} ROM", HEADERLESS_USAGE);
+            let (mut format, mut names) = ("text", std::collections::BTreeMap::new());
+            let mut rest = rest;
+            while let [flag, value, tail @ ..] = rest {
+                match flag.as_str() {
+                    "--format" => format = value.as_str(),
+                    // an assembly file of `name = $10` lines naming zero page addresses
+                    "--symbols" => {
+                        let symbols = asm::Assembler::new().assemble_file(value)?.symbols;
+                        for (name, addr) in symbols.into_iter().filter(|&(_, addr)| addr < 0x100) {
+                            names.entry(addr).or_insert(name);
+                        }
+                    }
+                    _ => return Err(usage.into()),
+                }
+                rest = tail;
+            }
+            let [path] = rest else {
+                return Err(usage.into());
+            };
+            let cart = cart::Cartridge::load(&read(path)?, headerless)?;
+            let disassembly = flow::disassemble_rom(&cart.prg_rom)?;
+            match format {
+                "text" => print!("{}", disassembly.to_string_with_names(&names)),
+                "json" => println!("{}", disassembly.to_json()),
+                _ => return Err(format!("unknown format `{}`, expected text or json", format).into()),
+            }
+        }
+        [command, ..] if command == "diff" => return Err("usage: nes-rs diff BEFORE AFTER".into()),
+        _ => return Err(format!("usage: nes-rs {} FILE", args[0]).into()),
+    }
+    Ok(())
+}
+
+/// Save slot hotkeys: Shift+0-9 pick a slot, F5 saves to it and F9 loads it.
+/// Digits on their own are typed into the program.
+enum SlotKey {
+    Select(usize),
+    Save,
+    Load,
+}
+
+/// Applies key events to `input`, setting `typed` to the ASCII code of the
+/// last key pressed that has one, and returns the slot hotkeys pressed
+fn handle_user_input(input: &mut InputState, typed: &mut Option<u8>, event_pump: &mut EventPump) -> Vec<SlotKey> {
+    let mut slot_keys = Vec::new();
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => std::process::exit(0),
-            Event::KeyDown { keycode: Some(Keycode::W), .. } => cpu.load(MMAP_DPAD_UP as u16, &[0xFF]),
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => cpu.load(MMAP_DPAD_DOWN as u16, &[0xFF]),
-            Event::KeyDown { keycode: Some(Keycode::A), .. } => cpu.load(MMAP_DPAD_LEFT as u16, &[0xFF]),
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => cpu.load(MMAP_DPAD_RIGHT as u16, &[0xFF]),
+            Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => slot_keys.push(SlotKey::Save),
+            Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => slot_keys.push(SlotKey::Load),
+            Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } => {
+                let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                if let Some(slot) = slot_for_key(keycode).filter(|_| shift) {
+                    slot_keys.push(SlotKey::Select(slot));
+                    continue;
+                }
+                *typed = ascii_for_key(keycode).or(*typed);
+                if let Some(button) = button_for_key(keycode) {
+                    input.apply(InputEvent::Press(button));
+                }
+            }
+            Event::KeyUp { keycode: Some(keycode), .. } => {
+                if let Some(button) = button_for_key(keycode) {
+                    input.apply(InputEvent::Release(button));
+                }
+            }
             _ => {}
         }
     }
+    slot_keys
 }
 
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in 0x0200..0x0600 {
-        let color_idx = cpu.read(i);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
-        }
-        frame_idx += 3
+fn slot_for_key(keycode: Keycode) -> Option<usize> {
+    match keycode.name().as_bytes() {
+        [digit @ b'0'..=b'9'] => Some((digit - b'0') as usize),
+        _ => None,
+    }
+}
+
+/// The code easy6502 programs read at $FF for a key, lower case for letters
+fn ascii_for_key(keycode: Keycode) -> Option<u8> {
+    match keycode.name().as_bytes() {
+        [byte] if byte.is_ascii_graphic() => Some(byte.to_ascii_lowercase()),
+        b"Space" => Some(b' '),
+        b"Return" => Some(b'\r'),
+        b"Backspace" => Some(0x08),
+        _ => None,
     }
-    update
 }
 
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
+fn button_for_key(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::W | Keycode::Up => Some(Button::Up),
+        Keycode::S | Keycode::Down => Some(Button::Down),
+        Keycode::A | Keycode::Left => Some(Button::Left),
+        Keycode::D | Keycode::Right => Some(Button::Right),
+        Keycode::K => Some(Button::A),
+        Keycode::J => Some(Button::B),
+        Keycode::Return => Some(Button::Start),
+        Keycode::RShift => Some(Button::Select),
+        _ => None,
     }
 }
 
-pub const MMAP_DPAD_UP: u8 = 0x77;
-pub const MMAP_DPAD_DOWN: u8 = 0x73;
-pub const MMAP_DPAD_LEFT: u8 = 0x61;
-pub const MMAP_DPAD_RIGHT: u8 = 0x64;
\ No newline at end of file
+fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
+    let screen = Sandbox::screen_rgb(cpu);
+    let update = frame[..] != screen[..];
+    frame.copy_from_slice(&screen);
+    update
+}
@@ -1,4 +1,13 @@
+use std::cell::RefCell;
 use std::fmt;
+use std::ops::{Range, RangeInclusive};
+
+use crate::cpu::Addr;
+
+#[cfg(feature = "heatmap")]
+pub mod heatmap;
+#[cfg(feature = "heatmap")]
+use heatmap::{Access, Heatmap};
 
 pub trait MemoryMap {
     fn read_u8(&self, addr: u16) -> u8;
@@ -9,46 +18,319 @@ pub trait MemoryMap {
 
     fn read_u16(&self, addr: u16) -> u16 {
         let lo = self.read_u8(addr);
-        let hi = self.read_u8(addr + 1);
+        let hi = self.read_u8(Addr(addr).next().0);
         u16::from_le_bytes([lo, hi])
     }
 
     fn write_u16(&mut self, addr: u16, val: u16) {
         let [lo, hi] = val.to_le_bytes();
         self.write_u8(addr, lo);
-        self.write_u8(addr + 1, hi);
+        self.write_u8(Addr(addr).next().0, hi);
+    }
+}
+
+/// Power-on contents of RAM, which is left undefined by the hardware
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum MemoryInit {
+    /// Every byte cleared
+    #[default]
+    Zero,
+    /// Every byte set to 0xFF
+    FF,
+    /// Every byte set to the given value
+    Pattern(u8),
+    /// Pseudo-random bytes, reproducible for a given seed
+    Random(u64),
+}
+
+impl MemoryInit {
+    /// Writes the initial pattern over a range of a memory map
+    pub fn apply<M: MemoryMap>(&self, mem: &mut M, range: Range<u16>) {
+        let mut state = match self {
+            MemoryInit::Random(seed) => *seed,
+            _ => 0,
+        };
+
+        for addr in range {
+            let value = match self {
+                MemoryInit::Zero => 0x00,
+                MemoryInit::FF => 0xFF,
+                MemoryInit::Pattern(value) => *value,
+                MemoryInit::Random(_) => splitmix64(&mut state) as u8,
+            };
+            mem.write_u8(addr, value);
+        }
+    }
+}
+
+/// Small PRNG so seeded RAM contents stay identical across platforms and crate versions
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// 64 bit FNV-1a, stable between builds and platforms unlike std's hasher
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// Custom hardware mapped onto a range of the bus, such as a serial port or
+/// a board-specific register. Addresses are offsets from the start of the range.
+pub trait Peripheral {
+    fn read(&mut self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, val: u8);
+
+    /// Called once after every instruction, as the CPU doesn't count cycles yet
+    fn tick(&mut self) {}
+
+    /// What each register means, for debuggers to show decoded values
+    fn registers(&self) -> Vec<Register> {
+        Vec::new()
     }
 }
 
+/// Bits of a register with one meaning
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    /// Lowest bit
+    pub shift: u8,
+    pub width: u8,
+    pub description: &'static str,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, shift: u8, width: u8, description: &'static str) -> Self {
+        Field { name, shift, width, description }
+    }
+
+    pub const fn mask(&self) -> u8 {
+        (((1u16 << self.width) - 1) as u8) << self.shift
+    }
+
+    /// The field's bits in `val`, shifted down to bit 0
+    pub const fn get(&self, val: u8) -> u8 {
+        (val & self.mask()) >> self.shift
+    }
+}
+
+/// A peripheral's register, with separate fields for what reads return
+/// and what writes do, as they often mean different things
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Register {
+    /// From the start of the peripheral's range
+    pub offset: u16,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub read: Vec<Field>,
+    pub write: Vec<Field>,
+}
+
+struct MappedPeripheral {
+    range: RangeInclusive<u16>,
+    /// Reads go through `&self`, but reading a device register can change it
+    device: RefCell<Box<dyn Peripheral>>,
+}
+
+/// A blocked write to a read-only region
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RomWrite {
+    pub addr: u16,
+    pub value: u8,
+}
+
 /// A MemoryMap with only a flat address space and no shared regions
-pub struct SimpleMap<const S: usize>([u8; S]);
+pub struct SimpleMap<const S: usize> {
+    data: [u8; S],
+    /// Regions writes can't change, such as PRG ROM. There are no mappers to
+    /// route them to yet, so they are dropped. `load` ignores this.
+    read_only: Vec<RangeInclusive<u16>>,
+    /// Keep a record of blocked writes
+    strict: bool,
+    rom_writes: Vec<RomWrite>,
+    /// Take priority over memory and read-only regions, first mapped wins
+    peripherals: Vec<MappedPeripheral>,
+    /// Access counts, once tracking is turned on
+    #[cfg(feature = "heatmap")]
+    heatmap: Option<RefCell<Heatmap>>,
+    /// Address and old value of each byte written, while journaling
+    journal: Option<Vec<(u16, u8)>>,
+}
 
 impl<const S: usize> Default for SimpleMap<S> {
     fn default() -> SimpleMap<S> {
-        SimpleMap([0u8; S])
+        SimpleMap {
+            data: [0u8; S],
+            read_only: Vec::new(),
+            strict: false,
+            rom_writes: Vec::new(),
+            peripherals: Vec::new(),
+            #[cfg(feature = "heatmap")]
+            heatmap: None,
+            journal: None,
+        }
+    }
+}
+
+impl<const S: usize> SimpleMap<S> {
+    /// Makes a region read-only to writes through the MemoryMap
+    pub fn protect(&mut self, range: RangeInclusive<u16>) {
+        self.read_only.push(range);
+    }
+
+    pub fn is_read_only(&self, addr: u16) -> bool {
+        self.read_only.iter().any(|range| range.contains(&addr))
+    }
+
+    /// In strict mode blocked writes are recorded for `rom_writes`
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn rom_writes(&self) -> &[RomWrite] {
+        &self.rom_writes
+    }
+
+    pub fn clear_rom_writes(&mut self) {
+        self.rom_writes.clear();
+    }
+
+    /// Routes reads and writes in a range to a peripheral. `load` bypasses it.
+    pub fn map_peripheral(&mut self, range: RangeInclusive<u16>, peripheral: Box<dyn Peripheral>) {
+        self.peripherals.push(MappedPeripheral { range, device: RefCell::new(peripheral) });
+    }
+
+    pub fn tick_peripherals(&mut self) {
+        for mapped in &mut self.peripherals {
+            mapped.device.get_mut().tick();
+        }
+    }
+
+    /// Contents of a range as stored, without going through peripherals
+    pub fn raw(&self, range: std::ops::Range<usize>) -> &[u8] {
+        &self.data[range]
+    }
+
+    /// Starts counting accesses through the MemoryMap, from zero
+    #[cfg(feature = "heatmap")]
+    pub fn track_accesses(&mut self) {
+        self.heatmap = Some(RefCell::new(Heatmap::new()));
+    }
+
+    #[cfg(feature = "heatmap")]
+    pub fn heatmap(&self) -> Option<std::cell::Ref<'_, Heatmap>> {
+        self.heatmap.as_ref().map(RefCell::borrow)
+    }
+
+    /// Counts an access if tracking is on
+    #[cfg(feature = "heatmap")]
+    pub fn record_access(&self, access: Access, addr: u16) {
+        if let Some(heatmap) = &self.heatmap {
+            heatmap.borrow_mut().record(access, addr);
+        }
+    }
+
+    /// Starts or stops keeping the old value of every byte written,
+    /// so the writes can be undone. Peripherals and `load` aren't journaled.
+    pub fn set_journal(&mut self, on: bool) {
+        self.journal = on.then(Vec::new);
+    }
+
+    /// The journal since it was last taken, oldest write first
+    pub fn take_journal(&mut self) -> Vec<(u16, u8)> {
+        self.journal.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Whether a read, or a write, at `addr` only touches memory, with no
+    /// peripheral or, for a write, write protection in the way
+    pub fn is_plain(&self, addr: u16, write: bool) -> bool {
+        self.peripheral_at(addr).is_none() && !(write && self.is_read_only(addr))
+    }
+
+    /// Whether accesses are being counted or journaled
+    pub fn is_observed(&self) -> bool {
+        #[cfg(feature = "heatmap")]
+        if self.heatmap.is_some() {
+            return true;
+        }
+        self.journal.is_some()
+    }
+
+    /// The memory itself, for code that has checked `is_plain` and
+    /// `is_observed` so can skip the MemoryMap
+    #[cfg(feature = "jit")]
+    pub(crate) fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Registers of every peripheral, by address. Registers past the end
+    /// of a peripheral's range aren't on the bus, so aren't listed.
+    pub fn registers(&self) -> Vec<(u16, Register)> {
+        let mut registers = Vec::new();
+        for mapped in &self.peripherals {
+            for register in mapped.device.borrow().registers() {
+                let Some(addr) = mapped.range.start().checked_add(register.offset) else {
+                    continue;
+                };
+                // a peripheral mapped over another hides it
+                if mapped.range.contains(&addr) && std::ptr::eq(self.peripheral_at(addr).unwrap(), mapped) {
+                    registers.push((addr, register));
+                }
+            }
+        }
+        registers.sort_by_key(|&(addr, _)| addr);
+        registers
+    }
+
+    fn peripheral_at(&self, addr: u16) -> Option<&MappedPeripheral> {
+        self.peripherals.iter().find(|mapped| mapped.range.contains(&addr))
     }
 }
 
 impl<const S: usize> MemoryMap for SimpleMap<S> {
     fn read_u8(&self, addr: u16) -> u8 {
+        #[cfg(feature = "heatmap")]
+        self.record_access(Access::Read, addr);
+        if let Some(mapped) = self.peripheral_at(addr) {
+            return mapped.device.borrow_mut().read(addr - mapped.range.start());
+        }
         let addr = addr as usize;
-        self.0[addr]
+        self.data[addr]
     }
 
     fn write_u8(&mut self, addr: u16, val: u8) {
+        #[cfg(feature = "heatmap")]
+        self.record_access(Access::Write, addr);
+        if let Some(mapped) = self.peripheral_at(addr) {
+            mapped.device.borrow_mut().write(addr - mapped.range.start(), val);
+            return;
+        }
+        if self.is_read_only(addr) {
+            if self.strict {
+                self.rom_writes.push(RomWrite { addr, value: val });
+            }
+            return;
+        }
         let addr = addr as usize;
-        self.0[addr] = val;
+        if let Some(journal) = &mut self.journal {
+            journal.push((addr as u16, self.data[addr]));
+        }
+        self.data[addr] = val;
     }
 
     fn load(&mut self, addr: u16, data: &[u8]) {
         let addr = addr as usize;
         let end = addr + data.len();
-        self.0[addr..end].copy_from_slice(data);
+        self.data[addr..end].copy_from_slice(data);
     }
 }
 
 /// Formats up to 16 bytes into a readable hexdump line
-fn fmt_hexdump_line(line_no: Option<u16>, data: &[u8]) -> String {
+fn fmt_hexdump_line(line_no: Option<usize>, data: &[u8]) -> String {
     let hex_body = data
         .iter()
         .enumerate()
@@ -77,21 +359,67 @@ fn fmt_hexdump_line(line_no: Option<u16>, data: &[u8]) -> String {
     }
 }
 
-impl<const S: usize> fmt::Debug for SimpleMap<S> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        let header = fmt_hexdump_line(None, &(0x00..0x10).collect::<Vec<_>>());
+/// Hexdump of `data` with a column header, lines numbered from `base`
+pub fn hexdump(data: &[u8], base: usize) -> String {
+    let header = fmt_hexdump_line(None, &(0x00..0x10).collect::<Vec<_>>());
+
+    let divider = "-".repeat(header.len());
+
+    let body = data
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| fmt_hexdump_line(Some(base + i * 16), chunk))
+        .collect::<Vec<String>>()
+        .join("\n");
 
-        let divider = "-".repeat(header.len());
+    format!("{}\n{}\n{}", header, divider, body)
+}
 
-        let body = self
-            .0
-            .chunks(16)
-            .enumerate()
-            .map(|(i, chunk)| fmt_hexdump_line(Some(i as u16 * 16), chunk))
-            .collect::<Vec<String>>()
-            .join("\n");
+/// Unchanged bytes between two changes for them to be reported as one range
+const DIFF_MERGE_GAP: usize = 4;
 
-        write!(f, "\n{}\n{}\n{}", header, divider, body)
+/// Ranges of offsets where `after` differs from `before`, up to the end of
+/// the shorter one. Changes a few bytes apart are merged.
+pub fn diff(before: &[u8], after: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for offset in (0..before.len().min(after.len())).filter(|&i| before[i] != after[i]) {
+        match ranges.last_mut() {
+            Some(range) if offset - range.end <= DIFF_MERGE_GAP => range.end = offset + 1,
+            _ => ranges.push(offset..offset + 1),
+        }
+    }
+    ranges
+}
+
+/// What `diff` found, with the hexdump lines around each change before (`-`)
+/// and after (`+`), numbered from `base`
+pub fn diff_report(before: &[u8], after: &[u8], base: usize) -> String {
+    let ranges = diff(before, after);
+    let mut out = String::new();
+    if before.len() != after.len() {
+        out += &format!("sizes differ, {} and {} bytes, comparing the first {}\n", before.len(), after.len(), before.len().min(after.len()));
+    }
+    if ranges.is_empty() {
+        return out + "no changes";
+    }
+
+    let changed: usize = ranges.iter().map(|range| range.clone().filter(|&i| before[i] != after[i]).count()).sum();
+    out += &format!("{} bytes changed in {} ranges", changed, ranges.len());
+    for range in ranges {
+        out += &format!("\n\n${:04X}-${:04X}", base + range.start, base + range.end - 1);
+        // whole hexdump lines, so each change is shown with its neighbours
+        for line in (range.start / 16 * 16..range.end).step_by(16) {
+            let end = (line + 16).min(before.len()).min(after.len());
+            out += &format!("\n- {}", fmt_hexdump_line(Some(base + line), &before[line..end]));
+            out += &format!("\n+ {}", fmt_hexdump_line(Some(base + line), &after[line..end]));
+        }
+    }
+    out
+}
+
+impl<const S: usize> fmt::Debug for SimpleMap<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "\n{}", hexdump(&self.data, 0))
     }
 }
 
@@ -110,13 +438,42 @@ mod tests {
         assert_eq!(mem.read_u8(100), 0u8);
     }
 
+    #[test]
+    fn test_hexdump() {
+        let dump = hexdump(b"NES\x1A", 0x10010);
+        assert_eq!(dump.lines().nth(2), Some("10010 | 4E 45 53 1A  | NES.             |"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = [0u8; 0x40];
+        let mut after = before;
+        // close enough to merge, then apart
+        after[0x02] = 1;
+        after[0x06] = 2;
+        after[0x20] = 3;
+        assert_eq!(diff(&before, &after), vec![0x02..0x07, 0x20..0x21]);
+        assert_eq!(diff(&before, &before), vec![]);
+
+        let report = diff_report(&before, &after, 0x0100);
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[0], "3 bytes changed in 2 ranges");
+        assert_eq!(lines[2], "$0102-$0106");
+        assert_eq!(lines[3], format!("- {}", fmt_hexdump_line(Some(0x0100), &before[..16])));
+        assert_eq!(lines[4], format!("+ {}", fmt_hexdump_line(Some(0x0100), &after[..16])));
+        assert_eq!(lines[6], "$0120-$0120");
+        assert_eq!(lines.len(), 9);
+
+        assert_eq!(diff_report(&before, &before[..0x10], 0), "sizes differ, 64 and 16 bytes, comparing the first 16\nno changes");
+    }
+
     #[test]
     fn test_memory_load() {
         let mut mem = SimpleMap::<0x100>::default();
         let addr = 0x08;
         let end = addr + DEADBEEF.len();
         mem.load(addr as u16, &DEADBEEF);
-        assert_eq!(&mem.0[addr..end], &DEADBEEF);
+        assert_eq!(&mem.data[addr..end], &DEADBEEF);
     }
 
     #[test]
@@ -140,4 +497,135 @@ mod tests {
         assert_eq!(mem.read_u16(0x00), 0xADDE);
         assert_eq!(mem.read_u16(0x02), 0xEFBE);
     }
+
+    #[test]
+    fn test_read_only_regions() {
+        let mut mem = SimpleMap::<0x100>::default();
+        mem.protect(0x80..=0xFF);
+        mem.load(0x80, &DEADBEEF);
+
+        mem.write_u8(0x7F, 1);
+        mem.write_u8(0x80, 2);
+        assert_eq!(mem.read_u8(0x7F), 1);
+        assert_eq!(mem.read_u8(0x80), 0xDE);
+        assert!(mem.rom_writes().is_empty());
+
+        mem.set_strict(true);
+        mem.write_u16(0xFE, 0x1234);
+        assert_eq!(mem.rom_writes(), &[RomWrite { addr: 0xFE, value: 0x34 }, RomWrite { addr: 0xFF, value: 0x12 }]);
+        assert_eq!(mem.read_u16(0xFE), 0);
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut mem = SimpleMap::<0x100>::default();
+        mem.protect(0x80..=0xFF);
+        mem.write_u8(0x10, 1);
+        assert!(mem.take_journal().is_empty());
+
+        mem.set_journal(true);
+        mem.write_u8(0x10, 2);
+        mem.write_u8(0x10, 3);
+        mem.write_u8(0x80, 4);
+        mem.load(0x20, &DEADBEEF);
+        assert_eq!(mem.take_journal(), vec![(0x10, 1), (0x10, 2)]);
+        assert!(mem.take_journal().is_empty());
+
+        mem.set_journal(false);
+        mem.write_u8(0x10, 5);
+        assert!(mem.take_journal().is_empty());
+    }
+
+    /// Latches the last value written to each of its two registers, counting ticks
+    #[derive(Default)]
+    struct Latches {
+        values: [u8; 2],
+        ticks: u8,
+    }
+
+    impl Peripheral for Latches {
+        fn read(&mut self, offset: u16) -> u8 {
+            match offset {
+                0 | 1 => self.values[offset as usize],
+                _ => self.ticks,
+            }
+        }
+
+        fn write(&mut self, offset: u16, val: u8) {
+            if let Some(value) = self.values.get_mut(offset as usize) {
+                *value = val;
+            }
+        }
+
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+
+        fn registers(&self) -> Vec<Register> {
+            let latch = |offset, name| Register { offset, name, description: "last value written", read: Vec::new(), write: Vec::new() };
+            let ticks = Field::new("ticks", 0, 8, "instructions run");
+            vec![latch(0, "LATCH0"), latch(1, "LATCH1"), Register { offset: 2, name: "TICKS", description: "", read: vec![ticks], write: Vec::new() }]
+        }
+    }
+
+    #[test]
+    fn test_peripherals() {
+        let mut mem = SimpleMap::<0x100>::default();
+        mem.protect(0x80..=0xFF);
+        mem.map_peripheral(0x80..=0x82, Box::new(Latches::default()));
+
+        mem.write_u16(0x80, 0x1234);
+        mem.write_u8(0x83, 0x56);
+        assert_eq!(mem.read_u16(0x80), 0x1234);
+        assert_eq!(mem.read_u8(0x83), 0x00);
+
+        mem.tick_peripherals();
+        mem.tick_peripherals();
+        assert_eq!(mem.read_u8(0x82), 2);
+        // the memory underneath is untouched
+        assert_eq!(mem.data[0x80], 0);
+    }
+
+    #[test]
+    fn test_registers() {
+        let field = Field::new("column", 1, 3, "");
+        assert_eq!(field.mask(), 0b1110);
+        assert_eq!(field.get(0b1011_0101), 0b010);
+        assert_eq!(Field::new("all", 0, 8, "").mask(), 0xFF);
+
+        let mut mem = SimpleMap::<0x100>::default();
+        mem.map_peripheral(0x80..=0x81, Box::new(Latches::default()));
+        mem.map_peripheral(0xF0..=0xFF, Box::new(Latches::default()));
+        // hidden by the first
+        mem.map_peripheral(0x81..=0x83, Box::new(Latches::default()));
+        let registers: Vec<(u16, &str)> = mem.registers().iter().map(|(addr, register)| (*addr, register.name)).collect();
+        assert_eq!(registers, [(0x80, "LATCH0"), (0x81, "LATCH1"), (0x82, "LATCH1"), (0x83, "TICKS"), (0xF0, "LATCH0"), (0xF1, "LATCH1"), (0xF2, "TICKS")]);
+    }
+
+    #[test]
+    fn test_memory_init() {
+        let mut mem = SimpleMap::<0x100>::default();
+        MemoryInit::FF.apply(&mut mem, 0x10..0x20);
+        assert_eq!(mem.read_u8(0x0F), 0x00);
+        assert_eq!(mem.read_u8(0x10), 0xFF);
+        assert_eq!(mem.read_u8(0x1F), 0xFF);
+        assert_eq!(mem.read_u8(0x20), 0x00);
+
+        MemoryInit::Pattern(0xA5).apply(&mut mem, 0x00..0x100);
+        assert!(mem.data.iter().all(|&x| x == 0xA5));
+    }
+
+    #[test]
+    fn test_memory_init_random_is_seeded() {
+        let mut a = SimpleMap::<0x100>::default();
+        let mut b = SimpleMap::<0x100>::default();
+        let mut c = SimpleMap::<0x100>::default();
+        MemoryInit::Random(1).apply(&mut a, 0x00..0x100);
+        MemoryInit::Random(1).apply(&mut b, 0x00..0x100);
+        MemoryInit::Random(2).apply(&mut c, 0x00..0x100);
+
+        assert_eq!(a.data, b.data);
+        assert_ne!(a.data, c.data);
+        assert!(a.data.iter().any(|&x| x != a.data[0]));
+    }
 }
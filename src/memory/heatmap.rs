@@ -0,0 +1,155 @@
+//! Per-address read, write and execute counts over the 64K space, for
+//! finding the variables a game actually uses and checking mirrors and
+//! banks are exercised. Needs the `heatmap` feature.
+//!
+//! Counting starts with `CPU::track_accesses`. Every bus access is counted
+//! the way the interpreter makes it, dummy reads included, while `peek`,
+//! `poke` and `load` aren't.
+
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::png::encode_greyscale;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    /// An opcode fetched to be executed, which is also counted as a read
+    Execute,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct AccessCounts {
+    pub reads: u32,
+    pub writes: u32,
+    pub executes: u32,
+}
+
+impl AccessCounts {
+    /// The count for one kind of access, or all of them
+    pub fn of(&self, access: Option<Access>) -> u32 {
+        match access {
+            Some(Access::Read) => self.reads,
+            Some(Access::Write) => self.writes,
+            Some(Access::Execute) => self.executes,
+            None => self.reads.saturating_add(self.writes).saturating_add(self.executes),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Heatmap {
+    counts: Vec<AccessCounts>,
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap { counts: vec![AccessCounts::default(); 0x10000] }
+    }
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap::default()
+    }
+
+    pub fn record(&mut self, access: Access, addr: u16) {
+        let counts = &mut self.counts[addr as usize];
+        let count = match access {
+            Access::Read => &mut counts.reads,
+            Access::Write => &mut counts.writes,
+            Access::Execute => &mut counts.executes,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    pub fn get(&self, addr: u16) -> AccessCounts {
+        self.counts[addr as usize]
+    }
+
+    /// A row for every address that was accessed
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("address,reads,writes,executes\n");
+        for (addr, counts) in self.counts.iter().enumerate().filter(|(_, counts)| counts.of(None) > 0) {
+            let _ = writeln!(out, "${:04X},{},{},{}", addr, counts.reads, counts.writes, counts.executes);
+        }
+        out
+    }
+
+    /// A 256x256 greyscale image with a row for each page, brighter for more
+    /// accesses on a log scale so rarely touched addresses still show up
+    pub fn to_png(&self, access: Option<Access>) -> Vec<u8> {
+        let max = self.counts.iter().map(|counts| counts.of(access)).max().unwrap_or(0);
+        let scale = (max as f64).ln_1p();
+        let pixels: Vec<u8> = self
+            .counts
+            .iter()
+            .map(|counts| match counts.of(access) {
+                0 => 0,
+                count => ((count as f64).ln_1p() / scale * 255.0).round() as u8,
+            })
+            .collect();
+        encode_greyscale(256, 256, &pixels)
+    }
+
+    /// Writes a PNG of all accesses when `path` ends in `.png`, otherwise CSV
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let data = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => self.to_png(None),
+            _ => self.to_csv().into_bytes(),
+        };
+        fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CPU;
+
+    #[test]
+    fn test_counts_from_cpu() {
+        let mut cpu = CPU::new();
+        cpu.track_accesses();
+        // LDX #$02, INC $10, DEX, BNE -5
+        cpu.load(0x0600, &[0xA2, 0x02, 0xE6, 0x10, 0xCA, 0xD0, 0xFB]);
+        cpu.poke(0x0610, 0x55);
+        assert_eq!(cpu.peek(0x0610), 0x55);
+        cpu.registers_mut().pc = 0x0600;
+        for _ in 0..7 {
+            cpu.step_next();
+        }
+
+        let heatmap = cpu.heatmap().unwrap();
+        assert_eq!(heatmap.get(0x0010), AccessCounts { reads: 2, writes: 2, executes: 0 });
+        assert_eq!(heatmap.get(0x0600).executes, 1);
+        assert_eq!(heatmap.get(0x0602).executes, 2);
+        assert_eq!(heatmap.get(0x0610), AccessCounts::default());
+
+        let csv = heatmap.to_csv();
+        assert!(csv.starts_with("address,reads,writes,executes\n$0010,2,2,0\n"), "{}", csv);
+        assert!(!csv.contains("$0610"), "{}", csv);
+    }
+
+    #[test]
+    fn test_png() {
+        let mut heatmap = Heatmap::new();
+        assert_eq!(heatmap.to_png(None), encode_greyscale(256, 256, &[0; 0x10000]));
+
+        heatmap.record(Access::Write, 0x0001);
+        for _ in 0..3 {
+            heatmap.record(Access::Read, 0x0100);
+        }
+        let mut pixels = vec![0; 0x10000];
+        pixels[0x0001] = 128;
+        pixels[0x0100] = 255;
+        assert_eq!(heatmap.to_png(None), encode_greyscale(256, 256, &pixels));
+
+        pixels[0x0001] = 0;
+        assert_eq!(heatmap.to_png(Some(Access::Read)), encode_greyscale(256, 256, &pixels));
+    }
+}
@@ -0,0 +1,350 @@
+//! IPS and BPS patch application, for running ROM hacks and translations.
+//!
+//! Patches are applied to the raw ROM image before it is parsed, so the
+//! result can be loaded like any other ROM.
+
+use std::error::Error;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// Applies an IPS or BPS patch, picked by the patch's header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err("unrecognised patch format".into())
+    }
+}
+
+/// Reads big endian values from the patch, erroring rather than panicking if it's cut short
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| format!("patch truncated at offset {:#x}", self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, Box<dyn Error>> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    /// BPS variable length number
+    fn number(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            value = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or("patch number overflows")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            // checked_shl only fails on the shift amount, so multiply to catch bits falling off
+            shift = shift.checked_mul(0x80).ok_or("patch number overflows")?;
+            value = value.checked_add(shift).ok_or("patch number overflows")?;
+        }
+    }
+}
+
+/// Applies an IPS patch. Records past the end of the ROM grow it, and the
+/// optional truncation length after `EOF` shrinks it.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err("missing IPS header".into());
+    }
+
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = offset.iter().fold(0, |acc, &b| acc << 8 | b as usize);
+
+        let (len, fill) = match reader.be(2)? {
+            // run length encoded record
+            0 => (reader.be(2)?, Some(reader.u8()?)),
+            len => (len, None),
+        };
+
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match fill {
+            Some(value) => out[offset..offset + len].fill(value),
+            None => out[offset..offset + len].copy_from_slice(reader.bytes(len)?),
+        }
+    }
+
+    if let Ok(len) = reader.be(3) {
+        out.truncate(len);
+    }
+
+    Ok(out)
+}
+
+/// Applies a BPS patch, checking the CRC32s of the source, result and patch
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !patch.starts_with(BPS_MAGIC) || patch.len() < BPS_MAGIC.len() + 12 {
+        return Err("missing BPS header".into());
+    }
+
+    let footer = patch.len() - 12;
+    let crc = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+    let (source_crc, target_crc, patch_crc) = (crc(footer), crc(footer + 4), crc(footer + 8));
+
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err("BPS patch is corrupt, checksum mismatch".into());
+    }
+    if crc32(rom) != source_crc {
+        return Err("BPS patch is for a different ROM, source checksum mismatch".into());
+    }
+
+    let mut reader = Reader::new(&patch[..footer], BPS_MAGIC.len());
+    let source_len = reader.number()?;
+    let target_len = reader.number()?;
+    let metadata_len = reader.number()?;
+    reader.bytes(metadata_len)?;
+
+    if source_len != rom.len() {
+        return Err(format!("BPS patch expects a {} byte ROM, got {}", source_len, rom.len()).into());
+    }
+
+    // the target size comes from the patch, so don't trust it for the allocation
+    let mut out: Vec<u8> = Vec::with_capacity(target_len.min(rom.len() + patch.len()));
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    while reader.pos < footer {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if len > target_len - out.len() {
+            return Err("BPS patch writes past the target size".into());
+        }
+
+        match action & 3 {
+            // source read
+            0 => {
+                let start = out.len();
+                let bytes = start
+                    .checked_add(len)
+                    .and_then(|end| rom.get(start..end))
+                    .ok_or("BPS source read past the end of the ROM")?;
+                out.extend_from_slice(bytes);
+            }
+            // target read
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // source copy and target copy, from a relative offset
+            command => {
+                let delta = reader.number()?;
+                let delta = if delta & 1 == 1 { -((delta >> 1) as isize) } else { (delta >> 1) as isize };
+                let offset = if command == 2 { &mut source_offset } else { &mut target_offset };
+                *offset = offset.checked_add(delta).ok_or("BPS copy offset overflows")?;
+
+                for _ in 0..len {
+                    let from = usize::try_from(*offset).map_err(|_| "BPS copy before the start of the data")?;
+                    // target copies can overlap the bytes they are writing
+                    let byte = match command {
+                        2 => rom.get(from),
+                        _ => out.get(from),
+                    };
+                    out.push(*byte.ok_or("BPS copy past the end of the data")?);
+                    *offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_len || crc32(&out) != target_crc {
+        return Err("patched ROM doesn't match the BPS target checksum".into());
+    }
+
+    Ok(out)
+}
+
+/// CRC-32 (IEEE), as used by BPS
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bps_number(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn bps_patch(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        bps_number(source.len(), &mut patch);
+        bps_number(target.len(), &mut patch);
+        bps_number(0, &mut patch);
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_apply_ips() {
+        let rom = [0u8; 8];
+        let mut patch = IPS_MAGIC.to_vec();
+        // two bytes at 2, then an RLE run of four 0xFF at 6 growing the ROM
+        patch.extend_from_slice(&[0, 0, 2, 0, 2, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xFF]);
+        patch.extend_from_slice(IPS_EOF);
+
+        assert_eq!(apply(&rom, &patch).unwrap(), vec![0, 0, 0xAA, 0xBB, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // truncation after EOF
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply(&rom, &patch).unwrap(), vec![0, 0, 0xAA]);
+
+        // missing EOF
+        assert!(apply_ips(&rom, &patch[..patch.len() - 6]).is_err());
+    }
+
+    #[test]
+    fn test_apply_bps() {
+        let source = b"HELLO WORLD".to_vec();
+        let target = b"HELLO HELLO WORLD!".to_vec();
+
+        let mut actions = Vec::new();
+        // source read "HELLO "
+        bps_number((6 - 1) << 2, &mut actions);
+        // source copy "HELLO WORLD" from offset 0
+        bps_number((11 - 1) << 2 | 2, &mut actions);
+        bps_number(0, &mut actions);
+        // target read "!"
+        bps_number(1, &mut actions);
+        actions.push(b'!');
+
+        let patch = bps_patch(&source, &target, &actions);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        // wrong source ROM
+        assert!(apply(b"HELLO THERE", &patch).is_err());
+
+        // damaged patch
+        let mut damaged = patch.clone();
+        damaged[6] ^= 1;
+        assert!(apply(&source, &damaged).unwrap_err().to_string().contains("corrupt"));
+    }
+
+    #[test]
+    fn test_apply_bps_target_copy() {
+        let source = b"AB".to_vec();
+        let target = b"ABABAB".to_vec();
+
+        let mut actions = Vec::new();
+        bps_number((2 - 1) << 2, &mut actions);
+        // overlapping target copy repeating what has just been written
+        bps_number((4 - 1) << 2 | 3, &mut actions);
+        bps_number(0, &mut actions);
+
+        assert_eq!(apply(&source, &bps_patch(&source, &target, &actions)).unwrap(), target);
+    }
+
+    #[test]
+    fn test_apply_bps_hostile_sizes() {
+        let source = b"AB".to_vec();
+        let target = b"ABAB".to_vec();
+
+        let with_header = |header: &[u8], actions: &[u8]| {
+            let mut patch = BPS_MAGIC.to_vec();
+            patch.extend_from_slice(header);
+            patch.extend_from_slice(actions);
+            patch.extend_from_slice(&crc32(&source).to_le_bytes());
+            patch.extend_from_slice(&crc32(&target).to_le_bytes());
+            let patch_crc = crc32(&patch);
+            patch.extend_from_slice(&patch_crc.to_le_bytes());
+            patch
+        };
+
+        // a source size too long for usize
+        let patch = with_header(&[0x7F; 12], &[]);
+        assert!(apply(&source, &patch).unwrap_err().to_string().contains("overflows"));
+
+        // metadata running off the end of the address space
+        let mut header = Vec::new();
+        bps_number(source.len(), &mut header);
+        bps_number(target.len(), &mut header);
+        bps_number(usize::MAX, &mut header);
+        assert!(apply(&source, &with_header(&header, &[])).unwrap_err().to_string().contains("truncated"));
+
+        // a huge target built from an endless overlapping target copy
+        let mut header = Vec::new();
+        bps_number(source.len(), &mut header);
+        bps_number(usize::MAX >> 8, &mut header);
+        bps_number(0, &mut header);
+        let mut actions = Vec::new();
+        bps_number((2 - 1) << 2, &mut actions);
+        bps_number(usize::MAX & !3 | 3, &mut actions);
+        bps_number(0, &mut actions);
+        assert!(apply(&source, &with_header(&header, &actions)).is_err());
+
+        // a source read far past the end of the ROM
+        let mut header = Vec::new();
+        bps_number(source.len(), &mut header);
+        bps_number(usize::MAX, &mut header);
+        bps_number(0, &mut header);
+        let mut actions = Vec::new();
+        bps_number(usize::MAX & !3, &mut actions);
+        assert!(apply(&source, &with_header(&header, &actions)).is_err());
+    }
+
+    #[test]
+    fn test_apply_unknown_format() {
+        assert!(apply(&[0; 4], b"NOTAPATCH").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Minimal PNG writer for debug exports, so they don't need an image crate.
+//!
+//! Only 8-bit greyscale and RGB are written, and the image data is stored uncompressed.
+
+use crate::patch::crc32;
+
+/// Largest block a stored deflate block can hold
+const STORED_BLOCK_LEN: usize = 0xFFFF;
+
+/// PNG colour types
+const GREYSCALE: u8 = 0;
+const RGB: u8 = 2;
+
+/// Encodes one byte per pixel, rows top to bottom, as a greyscale PNG
+pub fn encode_greyscale(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    encode(width, height, GREYSCALE, 1, pixels)
+}
+
+/// Encodes three bytes per pixel, rows top to bottom, as an RGB PNG
+pub fn encode_rgb(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    encode(width, height, RGB, 3, pixels)
+}
+
+fn encode(width: u32, height: u32, colour_type: u8, pixel_len: usize, pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width as usize * height as usize * pixel_len, "ERROR: pixel count doesn't match image size");
+
+    // each row starts with filter type 0, none
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks((width.max(1) as usize) * pixel_len) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bit depth, deflate, no filtering extensions, not interlaced
+    header.extend_from_slice(&[8, colour_type, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps data in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_LEN).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_greyscale() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let png = encode_greyscale(2, 2, &[0x00, 0xFF, 0x80, 0x40]);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x02\x00\x00\x00\x02\x08\x00"));
+        assert!(png.ends_with(b"IEND\xAE\x42\x60\x82"));
+        // filter byte then the two pixels of the second row, in a single stored block
+        assert!(png.windows(3).any(|w| w == [0x00, 0x80, 0x40]));
+
+        let png = encode_rgb(1, 2, &[1, 2, 3, 4, 5, 6]);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x01\x00\x00\x00\x02\x08\x02"));
+        assert!(png.windows(4).any(|w| w == [0x00, 4, 5, 6]));
+    }
+}
@@ -0,0 +1,22 @@
+//! The types most embedding code needs, for `use nes_rs::prelude::*;`
+//!
+//! ```no_run
+//! use nes_rs::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let cart = Cartridge::load(&std::fs::read("game.nes")?, &HeaderlessOptions::default())?;
+//! let mut cpu = CPU::builder().with_cartridge(cart).build()?;
+//! let reason = cpu.run();
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::cart::{Cartridge, HeaderlessOptions, Mirroring, UnsupportedMapper};
+pub use crate::cpu::builder::CpuBuilder;
+#[cfg(feature = "assembler")]
+pub use crate::cpu::prog::asm::{assemble, Assembly};
+pub use crate::cpu::prog::{disassemble, Program};
+pub use crate::cpu::{StopReason, CPU};
+pub use crate::input::{Button, ControllerPorts, InputEvent, InputState, CONTROLLER_PORTS};
+pub use crate::memory::{MemoryInit, MemoryMap, Peripheral};
+pub use crate::savestate::SaveState;
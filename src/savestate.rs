@@ -0,0 +1,305 @@
+//! Save state container: a version header followed by tagged chunks, one per subsystem.
+//!
+//! ```text
+//! "NSST"  magic
+//! u16     format version, little endian
+//! then until the end of the file:
+//! [u8; 4] chunk tag, e.g. "CPU " or "RAM "
+//! u32     chunk length, little endian
+//! ...     chunk data
+//! ```
+//!
+//! Compatibility policy:
+//! - Adding a chunk (PPU, APU, MAPPER...) doesn't change the version. Loaders
+//!   skip tags they don't know, so older builds can still read newer states.
+//! - Appending fields to the end of a chunk doesn't change the version either.
+//!   Readers must accept chunks longer than they expect.
+//! - Anything else, such as reordering or resizing a field, bumps
+//!   `FORMAT_VERSION` and adds a step to `migrate` so older states still load.
+//! - States from a newer version than this build are refused.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::cart::rom_hash;
+use crate::memory::{self, fnv1a};
+
+const MAGIC: &[u8] = b"NSST";
+/// Version written by this build
+pub const FORMAT_VERSION: u16 = 1;
+
+pub type Tag = [u8; 4];
+
+pub const CPU_CHUNK: Tag = *b"CPU ";
+pub const RAM_CHUNK: Tag = *b"RAM ";
+/// Width and height as u16s, then RGB24 pixels
+pub const THUMBNAIL_CHUNK: Tag = *b"THMB";
+
+/// Slots kept for each ROM
+pub const SLOT_COUNT: usize = 10;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct SaveState {
+    chunks: BTreeMap<Tag, Vec<u8>>,
+}
+
+impl SaveState {
+    pub fn new() -> Self {
+        SaveState::default()
+    }
+
+    pub fn chunk(&self, tag: Tag) -> Option<&[u8]> {
+        self.chunks.get(&tag).map(Vec::as_slice)
+    }
+
+    /// Like `chunk`, but a missing chunk is an error
+    pub fn require(&self, tag: Tag) -> Result<&[u8], Box<dyn Error>> {
+        self.chunk(tag)
+            .ok_or_else(|| format!("save state has no `{}` chunk", String::from_utf8_lossy(&tag)).into())
+    }
+
+    pub fn insert(&mut self, tag: Tag, data: Vec<u8>) {
+        self.chunks.insert(tag, data);
+    }
+
+    /// Keeps a small screenshot with the state for slot pickers
+    pub fn set_thumbnail(&mut self, thumbnail: &Thumbnail) {
+        let mut data = thumbnail.width.to_le_bytes().to_vec();
+        data.extend_from_slice(&thumbnail.height.to_le_bytes());
+        data.extend_from_slice(&thumbnail.rgb);
+        self.insert(THUMBNAIL_CHUNK, data);
+    }
+
+    /// The screenshot saved with the state, if it has a whole one
+    pub fn thumbnail(&self) -> Option<Thumbnail> {
+        let [w0, w1, h0, h1, rgb @ ..] = self.chunk(THUMBNAIL_CHUNK)? else {
+            return None;
+        };
+        let (width, height) = (u16::from_le_bytes([*w0, *w1]), u16::from_le_bytes([*h0, *h1]));
+        let rgb = rgb.get(..width as usize * height as usize * 3)?.to_vec();
+        Some(Thumbnail { width, height, rgb })
+    }
+
+    /// Stable hash of everything but the thumbnail, for golden tests and
+    /// spotting where two runs diverge
+    pub fn hash(&self) -> u64 {
+        let mut state = self.clone();
+        state.chunks.remove(&THUMBNAIL_CHUNK);
+        fnv1a(&state.to_bytes())
+    }
+
+    /// What changed in each chunk from `self` to `after`, as hexdump diffs.
+    /// Thumbnails are left out.
+    pub fn diff_report(&self, after: &SaveState) -> String {
+        let tags: BTreeSet<&Tag> = self.chunks.keys().chain(after.chunks.keys()).filter(|&&tag| tag != THUMBNAIL_CHUNK).collect();
+        let mut reports = Vec::new();
+        for &tag in tags {
+            let name = String::from_utf8_lossy(&tag);
+            match (self.chunk(tag), after.chunk(tag)) {
+                (Some(before), Some(after)) if before != after => {
+                    reports.push(format!("`{}` chunk: {}", name, memory::diff_report(before, after, 0)));
+                }
+                (Some(_), None) => reports.push(format!("`{}` chunk removed", name)),
+                (None, Some(_)) => reports.push(format!("`{}` chunk added", name)),
+                _ => {}
+            }
+        }
+        match reports.is_empty() {
+            true => "no changes".to_string(),
+            false => reports.join("\n\n"),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        for (tag, data) in &self.chunks {
+            bytes.extend_from_slice(tag);
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    /// Reads a state written by this or any earlier version, upgrading it to the current layout
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let rest = bytes.strip_prefix(MAGIC).ok_or("not a save state")?;
+        let (version, mut rest) = match rest {
+            [lo, hi, rest @ ..] => (u16::from_le_bytes([*lo, *hi]), rest),
+            _ => return Err("save state header is truncated".into()),
+        };
+        if version > FORMAT_VERSION {
+            return Err(format!("save state is version {}, this build reads up to {}", version, FORMAT_VERSION).into());
+        }
+
+        let mut state = SaveState::new();
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(format!("truncated chunk header at offset {:#x}", bytes.len() - rest.len()).into());
+            }
+            let tag: Tag = rest[..4].try_into().unwrap();
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let data = rest[8..]
+                .get(..len)
+                .ok_or_else(|| format!("`{}` chunk is truncated", String::from_utf8_lossy(&tag)))?;
+            state.chunks.insert(tag, data.to_vec());
+            rest = &rest[8 + len..];
+        }
+
+        migrate(version, &mut state)?;
+        Ok(state)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Thumbnail {
+    pub width: u16,
+    pub height: u16,
+    /// RGB24, rows top to bottom
+    pub rgb: Vec<u8>,
+}
+
+/// A filled save slot, as listed for a slot picker
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SlotInfo {
+    pub slot: usize,
+    pub saved: SystemTime,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// Numbered save states for one ROM, in a directory named after its hash
+pub struct SaveSlots {
+    dir: PathBuf,
+}
+
+impl SaveSlots {
+    pub fn for_rom(dir: impl AsRef<Path>, rom: &[u8]) -> Self {
+        SaveSlots { dir: dir.as_ref().join(format!("{:016x}", rom_hash(rom))) }
+    }
+
+    fn path(&self, slot: usize) -> Result<PathBuf, Box<dyn Error>> {
+        if slot >= SLOT_COUNT {
+            return Err(format!("no save slot {}, there are {}", slot, SLOT_COUNT).into());
+        }
+        Ok(self.dir.join(format!("slot{}.nsst", slot)))
+    }
+
+    pub fn save(&self, slot: usize, state: &SaveState) -> Result<(), Box<dyn Error>> {
+        let path = self.path(slot)?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(path, state.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(&self, slot: usize) -> Result<SaveState, Box<dyn Error>> {
+        let path = self.path(slot)?;
+        let bytes = fs::read(&path).map_err(|e| format!("save slot {} is empty: {}", slot, e))?;
+        SaveState::from_bytes(&bytes).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Slots holding a state that loads, in slot order
+    pub fn list(&self) -> Vec<SlotInfo> {
+        (0..SLOT_COUNT)
+            .filter_map(|slot| {
+                let saved = fs::metadata(self.path(slot).ok()?).ok()?.modified().ok()?;
+                let state = self.load(slot).ok()?;
+                Some(SlotInfo { slot, saved, thumbnail: state.thumbnail() })
+            })
+            .collect()
+    }
+}
+
+/// Upgrades chunks from `version` to `FORMAT_VERSION`, one version at a time
+fn migrate(version: u16, _state: &mut SaveState) -> Result<(), Box<dyn Error>> {
+    match version {
+        0 => Err("save state version 0 was never released".into()),
+        // the current layout, nothing to do
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut state = SaveState::new();
+        state.insert(CPU_CHUNK, vec![1, 2, 3]);
+        state.insert(RAM_CHUNK, vec![]);
+
+        let bytes = state.to_bytes();
+        assert_eq!(&bytes[..6], b"NSST\x01\x00");
+        assert_eq!(SaveState::from_bytes(&bytes).unwrap(), state);
+        assert!(state.require(*b"PPU ").is_err());
+
+        // screenshots don't change the hash, state does
+        let hash = state.hash();
+        state.set_thumbnail(&Thumbnail { width: 1, height: 1, rgb: vec![0; 3] });
+        assert_eq!(state.hash(), hash);
+        state.insert(RAM_CHUNK, vec![0]);
+        assert_ne!(state.hash(), hash);
+    }
+
+    #[test]
+    fn test_diff_report() {
+        let mut before = SaveState::new();
+        before.insert(CPU_CHUNK, vec![1, 2, 3]);
+        before.insert(RAM_CHUNK, vec![0; 0x20]);
+        assert_eq!(before.diff_report(&before), "no changes");
+
+        let mut after = before.clone();
+        let mut ram = vec![0; 0x20];
+        ram[0x11] = 0xAA;
+        after.insert(RAM_CHUNK, ram);
+        after.insert(*b"PPU ", vec![]);
+        after.set_thumbnail(&Thumbnail { width: 1, height: 1, rgb: vec![0; 3] });
+
+        let report = before.diff_report(&after);
+        assert!(report.starts_with("`PPU ` chunk added\n\n`RAM ` chunk: 1 bytes changed in 1 ranges\n\n$0011-$0011\n- 0010 | 00"), "{}", report);
+        assert!(!report.contains("THMB"), "{}", report);
+    }
+
+    #[test]
+    fn test_tolerant_loading() {
+        // a state from a later build with a chunk this one doesn't know
+        let mut bytes = b"NSST\x01\x00".to_vec();
+        bytes.extend_from_slice(b"MAPR\x02\x00\x00\x00\xAA\xBB");
+        bytes.extend_from_slice(b"CPU \x01\x00\x00\x00\x42");
+
+        let state = SaveState::from_bytes(&bytes).unwrap();
+        assert_eq!(state.chunk(CPU_CHUNK), Some(&[0x42][..]));
+
+        assert!(SaveState::from_bytes(b"NSST\x02\x00").is_err());
+        assert!(SaveState::from_bytes(b"NSST\x00\x00").is_err());
+        assert!(SaveState::from_bytes(b"NSST\x01").is_err());
+        assert!(SaveState::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SaveState::from_bytes(b"PNG").is_err());
+    }
+
+    #[test]
+    fn test_slots() {
+        let dir = std::env::temp_dir().join(format!("nes-rs-slots-{}", std::process::id()));
+        let slots = SaveSlots::for_rom(&dir, b"rom");
+        let thumbnail = Thumbnail { width: 2, height: 1, rgb: vec![0xFF, 0, 0, 0, 0xFF, 0] };
+        let mut state = SaveState::new();
+        state.insert(CPU_CHUNK, vec![1]);
+        state.set_thumbnail(&thumbnail);
+
+        assert!(slots.list().is_empty());
+        assert!(slots.load(3).is_err());
+        slots.save(3, &state).unwrap();
+        slots.save(7, &SaveState::new()).unwrap();
+        assert!(slots.save(SLOT_COUNT, &state).is_err());
+
+        let listed = slots.list();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(listed.iter().map(|info| info.slot).collect::<Vec<_>>(), vec![3, 7]);
+        assert_eq!(listed[0].thumbnail, Some(thumbnail));
+        assert_eq!(listed[1].thumbnail, None);
+        assert!(listed[0].saved <= SystemTime::now());
+    }
+}
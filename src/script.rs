@@ -0,0 +1,210 @@
+//! Optional Rhai scripting hooks for driving the emulator without recompiling.
+//!
+//! Scripts may define an `on_step()` function which is called before every
+//! instruction. Inside a script the following functions are available:
+//!
+//! - `peek(addr)` / `poke(addr, value)` read and write memory, bypassing
+//!   peripherals, so peeking a register doesn't disturb it
+//! - `reg(name)` / `set_reg(name, value)` access `a`, `x`, `y`, `p`, `sp` and `pc`
+//! - `set_buttons(port, mask)` holds down the buttons in `mask` on controller
+//!   `port`, 0-3, once the host has shared the pads with `with_pads`
+//!
+//! There's no per-frame hook yet, as the emulator doesn't produce frames.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use rhai::{Engine, EvalAltResult, Scope, AST, INT};
+
+use crate::cpu::reg::Status;
+use crate::cpu::StopReason;
+use crate::CPU;
+
+/// A CPU shared between the host and the closures registered with the engine
+type SharedCpu = Rc<RefCell<CPU>>;
+
+pub struct ScriptHost {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    cpu: SharedCpu,
+}
+
+fn register_error(name: &str) -> Box<EvalAltResult> {
+    format!("Script Error: {:?} is not a register", name).into()
+}
+
+fn register_api(engine: &mut Engine, cpu: &SharedCpu) {
+    let shared = cpu.clone();
+    engine.register_fn("peek", move |addr: INT| -> INT {
+        shared.borrow().peek(addr as u16) as INT
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("poke", move |addr: INT, value: INT| {
+        shared.borrow_mut().poke(addr as u16, value as u8);
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("reg", move |name: &str| -> Result<INT, Box<EvalAltResult>> {
+        let cpu = shared.borrow();
+        let reg = cpu.registers();
+        Ok(match name {
+            "a" => reg.a as INT,
+            "x" => reg.x as INT,
+            "y" => reg.y as INT,
+            "p" => reg.p.bits() as INT,
+            "sp" => reg.sp as INT,
+            "pc" => reg.pc as INT,
+            x => return Err(register_error(x)),
+        })
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("set_reg", move |name: &str, value: INT| -> Result<(), Box<EvalAltResult>> {
+        let mut cpu = shared.borrow_mut();
+        let reg = cpu.registers_mut();
+        match name {
+            "a" => reg.a = value as u8,
+            "x" => reg.x = value as u8,
+            "y" => reg.y = value as u8,
+            "p" => reg.p = Status::from(value as u8),
+            "sp" => reg.sp = value as u8,
+            "pc" => reg.pc = value as u16,
+            x => return Err(register_error(x)),
+        }
+        Ok(())
+    });
+}
+
+impl ScriptHost {
+    /// Compiles a script and takes ownership of the CPU it will drive
+    pub fn new(cpu: CPU, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let cpu = Rc::new(RefCell::new(cpu));
+        let mut engine = Engine::new();
+        register_api(&mut engine, &cpu);
+
+        let ast = engine.compile(source)?;
+        let mut scope = Scope::new();
+        // run any top level statements once so the script can set itself up
+        engine.run_ast_with_scope(&mut scope, &ast)?;
+
+        Ok(ScriptHost { engine, ast, scope, cpu })
+    }
+
+    /// Lets the script press buttons on the controllers read through `pads`,
+    /// the report bytes shared with `input::ControllerPorts`
+    pub fn with_pads(mut self, pads: Rc<Cell<[u8; 4]>>) -> Self {
+        self.engine.register_fn("set_buttons", move |port: INT, mask: INT| -> Result<(), Box<EvalAltResult>> {
+            let mut bytes = pads.get();
+            let pad = bytes
+                .get_mut(port as usize)
+                .ok_or_else(|| format!("Script Error: there's no controller {}", port))?;
+            *pad = mask as u8;
+            pads.set(bytes);
+            Ok(())
+        });
+        self
+    }
+
+    /// Borrow the CPU driven by this script
+    pub fn cpu(&self) -> std::cell::Ref<'_, CPU> {
+        self.cpu.borrow()
+    }
+
+    fn has_hook(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name && f.params.is_empty())
+    }
+
+    /// Calls a script function taking no arguments, if the script defines it
+    pub fn call_hook(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.has_hook(name) {
+            let _ = self.engine.call_fn::<rhai::Dynamic>(&mut self.scope, &self.ast, name, ())?;
+        }
+        Ok(())
+    }
+
+    /// Continuously run the program until BRK or a JAM opcode, calling `on_step` before each instruction
+    pub fn run(&mut self) -> Result<StopReason, Box<dyn std::error::Error>> {
+        loop {
+            self.call_hook("on_step")?;
+
+            if let Some(reason) = self.cpu.borrow_mut().step_next() {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Stops scripting and hands the CPU back
+    pub fn into_cpu(self) -> CPU {
+        drop(self.engine);
+        match Rc::try_unwrap(self.cpu) {
+            Ok(cpu) => cpu.into_inner(),
+            Err(_) => unreachable!("engine dropped, no other CPU handles remain"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{ControllerPorts, CONTROLLER_PORTS};
+
+    #[test]
+    fn test_script_memory_and_registers() {
+        let mut cpu = CPU::new();
+        // INX, INX, BRK
+        cpu.load(0, &[0xE8, 0xE8, 0x00]);
+
+        let script = r#"
+            poke(0x10, 0x42);
+            fn on_step() {
+                poke(0x11, peek(0x11) + 1);
+                if reg("x") == 0 { set_reg("a", 7); }
+            }
+        "#;
+
+        let mut host = ScriptHost::new(cpu, script).unwrap();
+        assert_eq!(host.run().unwrap(), StopReason::Break);
+
+        let cpu = host.into_cpu();
+        assert_eq!(cpu.read(0x10), 0x42);
+        // hook runs before both INX and before the BRK check
+        assert_eq!(cpu.read(0x11), 3);
+        assert_eq!(cpu.registers().a, 7);
+        assert_eq!(cpu.registers().x, 2);
+    }
+
+    #[test]
+    fn test_script_buttons() {
+        let pads = Rc::new(Cell::new([0; 4]));
+        let mut cpu = CPU::new();
+        cpu.map_peripheral(CONTROLLER_PORTS, Box::new(ControllerPorts::new(pads.clone())));
+        // strobe the controllers, then LDA $4016 twice, BRK
+        cpu.load(0, &[0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAE, 0x16, 0x40, 0x00]);
+
+        let script = r#"
+            fn on_step() {
+                set_buttons(0, 0b10);
+                poke(0x10, peek(0x4016));
+            }
+        "#;
+
+        let mut host = ScriptHost::new(cpu, script).unwrap().with_pads(pads.clone());
+        assert_eq!(host.run().unwrap(), StopReason::Break);
+        assert_eq!(pads.get(), [0b10, 0, 0, 0]);
+
+        // peeking didn't shift the report, so B is the second bit read
+        let cpu = host.into_cpu();
+        assert_eq!((cpu.registers().a, cpu.registers().x), (0, 1));
+
+        let mut host = ScriptHost::new(CPU::new(), "fn on_step() { set_buttons(4, 1); }").unwrap().with_pads(pads);
+        assert!(host.run().is_err());
+    }
+
+    #[test]
+    fn test_script_bad_register() {
+        let host = ScriptHost::new(CPU::new(), r#"reg("q")"#);
+        assert!(host.is_err());
+    }
+}
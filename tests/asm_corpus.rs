@@ -0,0 +1,28 @@
+//! Regression inputs for the assembler, mostly found by the fuzz targets in
+//! `fuzz/`. Each file in `tests/corpus` must assemble or fail with a located
+//! error in every dialect, never panic.
+
+use std::fs;
+use std::path::Path;
+
+use nes_rs::cpu::prog::asm::{Assembler, Dialect};
+use nes_rs::cpu::prog::Program;
+
+#[test]
+fn test_corpus_never_panics() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut files: Vec<_> = fs::read_dir(&corpus).unwrap().map(|entry| entry.unwrap().path()).collect();
+    files.sort();
+    assert!(!files.is_empty(), "no corpus files in {}", corpus.display());
+
+    for path in files {
+        let source = String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned();
+        for dialect in [Dialect::Native, Dialect::Ca65, Dialect::Nesasm] {
+            if let Err(e) = Assembler::new().with_dialect(dialect).assemble(&source) {
+                let message = e.to_string();
+                assert!(message.starts_with("line "), "{} ({:?}): unlocated error `{}`", path.display(), dialect, message);
+            }
+        }
+        let _ = source.parse::<Program>();
+    }
+}
@@ -0,0 +1,6 @@
+.org -1
+.org $10000
+x = x
+y = z
+z = y
+LDA y
@@ -0,0 +1 @@
+LDA #1/0
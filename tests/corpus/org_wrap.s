@@ -0,0 +1,5 @@
+.org $FFFE
+NOP
+NOP
+NOP
+.word $1234
@@ -0,0 +1,3 @@
+LDA #$7FFFFFFF*2
+LDX #-$7FFFFFFF-2
+LDY #-(-$7FFFFFFF-1)
@@ -0,0 +1,4 @@
+loop: NOP
+BNE *+200
+BEQ *-999
+BPL *+127
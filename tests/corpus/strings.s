@@ -0,0 +1,3 @@
+.byte "unterminated
+.byte ""
+.byte "a;b", 1
@@ -0,0 +1,12 @@
+LDA
+LDA #
+LDA (
+LDA ($10
+LDA $10,
+LDA $10,Z
+.org
+.include
+.include "
+=
+:
+*
@@ -0,0 +1,3 @@
+é: LDA #$é
+LDA ($é),Y
+é
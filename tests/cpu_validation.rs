@@ -0,0 +1,13 @@
+//! Runs a generated test program for every opcode and addressing mode.
+
+use nes_rs::cpu::testgen;
+
+#[test]
+fn test_every_opcode() {
+    let failures: Vec<String> = testgen::generate(0x0600)
+        .iter()
+        .filter_map(|case| case.run().err())
+        .collect();
+
+    assert!(failures.is_empty(), "{} failing cases:\n{}", failures.len(), failures.join("\n"));
+}
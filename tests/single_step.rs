@@ -0,0 +1,30 @@
+//! Runs the SingleStepTests/65x02 vectors for every emulated opcode. They
+//! aren't shipped with the crate, so point `NES_RS_SINGLE_STEP_DIR` at a
+//! checkout's `nes6502/v1` directory to run them, otherwise this is skipped.
+
+use std::env;
+use std::path::Path;
+
+use nes_rs::cpu::single_step;
+
+#[test]
+fn test_single_step_vectors() {
+    let Some(dir) = env::var_os("NES_RS_SINGLE_STEP_DIR") else {
+        eprintln!("skipped, NES_RS_SINGLE_STEP_DIR isn't set");
+        return;
+    };
+    let dir = Path::new(&dir);
+
+    let mut failures = Vec::new();
+    for code in (0..=0xFF).filter(|&code| single_step::emulated(code)) {
+        let cases = single_step::load(dir.join(format!("{:02x}.json", code))).unwrap_or_else(|e| panic!("ERROR: {}", e));
+        let failed: Vec<String> = cases
+            .iter()
+            .filter_map(|case| case.run().err().map(|e| format!("{}: {}", case.name, e)))
+            .collect();
+        if let Some(first) = failed.first() {
+            failures.push(format!("{:02x}: {} of {} failed, first {}", code, failed.len(), cases.len(), first));
+        }
+    }
+    assert!(failures.is_empty(), "{} opcodes failing:\n{}", failures.len(), failures.join("\n"));
+}